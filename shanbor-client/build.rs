@@ -0,0 +1,5 @@
+// 和服务端使用同一份 abi.proto，编码出的 spec 和服务端的完全一致
+fn main() {
+    println!("cargo:rerun-if-changed=../abi.proto");
+    prost_build::compile_protos(&["../abi.proto"], &[".."]).unwrap();
+}
@@ -0,0 +1,334 @@
+// shanbor 的 Rust 客户端：用类型构造 spec，按服务端的规则编码和签名，生成图片 URL，也可以直接获取图片
+//
+// let client = Client::new("https://img.example.com").api_key("key-a").signing_key("secret");
+// let spec = SpecBuilder::new().resize(200, 200).watermark(10, 10).build();
+// let url = client.image(&spec, "https://cdn.example.com/cat.png").requester("alice").url()?;
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use prost::Message;
+use sha2::Sha256;
+use thiserror::Error;
+
+// 由 abi.proto 生成的 spec 数据结构，和服务端的完全相同
+pub mod pb {
+    include!(concat!(env!("OUT_DIR"), "/abi.rs"));
+}
+
+use pb::{resize, spec, ImageSpec, Spec};
+
+// 客户端生成的 spec 协议版本，和服务端的 SPEC_VERSION 保持一致
+pub const SPEC_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("requester needs a signing key")]
+    MissingSigningKey,
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    // 服务端返回的错误状态码
+    #[error("server returned {0}")]
+    Status(u16),
+}
+
+// 按顺序添加操作，build() 得到 ImageSpec
+#[derive(Default, Debug, Clone)]
+pub struct SpecBuilder {
+    specs: Vec<Spec>,
+    page: u32,
+}
+
+impl SpecBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 直接添加一个操作，用于没有便捷方法的操作或者需要设置条件时
+    pub fn op(mut self, data: spec::Data) -> Self {
+        self.specs.push(Spec { data: Some(data), when: None });
+        self
+    }
+
+    pub fn resize(self, width: u32, height: u32) -> Self {
+        self.op(spec::Data::Resize(pb::Resize {
+            width,
+            height,
+            ..Default::default()
+        }))
+    }
+
+    pub fn resize_with(self, width: u32, height: u32, filter: resize::SampleFilter) -> Self {
+        self.op(spec::Data::Resize(pb::Resize {
+            width,
+            height,
+            filter: filter as i32,
+            ..Default::default()
+        }))
+    }
+
+    pub fn seam_carve(self, width: u32, height: u32) -> Self {
+        self.op(spec::Data::Resize(pb::Resize {
+            width,
+            height,
+            rtype: resize::ResizeType::SeamCarve as i32,
+            ..Default::default()
+        }))
+    }
+
+    pub fn crop(self, x1: u32, y1: u32, x2: u32, y2: u32) -> Self {
+        self.op(spec::Data::Crop(pb::Crop {
+            x1,
+            y1,
+            x2,
+            y2,
+            ..Default::default()
+        }))
+    }
+
+    pub fn flipv(self) -> Self {
+        self.op(spec::Data::Flipv(pb::Flipv {}))
+    }
+
+    pub fn fliph(self) -> Self {
+        self.op(spec::Data::Fliph(pb::Fliph {}))
+    }
+
+    pub fn contrast(self, contrast: f32) -> Self {
+        self.op(spec::Data::Contrast(pb::Contrast { contrast }))
+    }
+
+    pub fn filter(self, filter: pb::filter::Filter) -> Self {
+        self.op(spec::Data::Filter(pb::Filter { filter: filter as i32 }))
+    }
+
+    // 内置的水印，其他设置（平铺、素材、缩放）使用 op()
+    pub fn watermark(self, x: u32, y: u32) -> Self {
+        self.op(spec::Data::Watermark(pb::Watermark {
+            x,
+            y,
+            ..Default::default()
+        }))
+    }
+
+    pub fn text(self, text: &str, x: u32, y: u32) -> Self {
+        self.op(spec::Data::Text(pb::Text {
+            text: text.to_owned(),
+            x,
+            y,
+            ..Default::default()
+        }))
+    }
+
+    // 底部的说明文字条，text 中可以使用 {exif_date}、{exif_camera}、{exif_copyright} 等变量
+    pub fn caption(self, text: &str) -> Self {
+        self.op(spec::Data::Caption(pb::Caption {
+            text: text.to_owned(),
+            ..Default::default()
+        }))
+    }
+
+    pub fn auto_enhance(self) -> Self {
+        self.op(spec::Data::AutoEnhance(pb::AutoEnhance {}))
+    }
+
+    pub fn lqip(self, width: u32) -> Self {
+        self.op(spec::Data::Lqip(pb::Lqip {
+            width,
+            ..Default::default()
+        }))
+    }
+
+    // 多页图片处理第几页，从 0 开始
+    pub fn page(mut self, page: u32) -> Self {
+        self.page = page;
+        self
+    }
+
+    pub fn build(self) -> ImageSpec {
+        ImageSpec {
+            specs: self.specs,
+            page: self.page,
+            version: SPEC_VERSION,
+        }
+    }
+}
+
+// 和服务端一样，protobuf 编码之后使用 URL safe 的 base64
+pub fn encode_spec(spec: &ImageSpec) -> String {
+    base64::encode_config(spec.encode_to_vec(), base64::URL_SAFE_NO_PAD)
+}
+
+// HMAC-SHA256 签名的十六进制，用于 requester_sig
+pub fn sign(key: &str, message: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac accepts any key size");
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    base_url: String,
+    // 请求头 x-api-key，多租户时用来识别租户
+    api_key: Option<String>,
+    // 租户的 signing_key，带 requester 时需要
+    signing_key: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    // base_url 是服务的地址，比如 https://img.example.com
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            api_key: None,
+            signing_key: None,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_owned());
+        self
+    }
+
+    pub fn signing_key(mut self, key: &str) -> Self {
+        self.signing_key = Some(key.to_owned());
+        self
+    }
+
+    // 使用自己配置的 reqwest::Client（超时、代理等）
+    pub fn http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn image<'a>(&'a self, spec: &ImageSpec, source: &'a str) -> Image<'a> {
+        Image {
+            client: self,
+            spec: encode_spec(spec),
+            source,
+            query: Vec::new(),
+            requester: None,
+        }
+    }
+
+    // 使用租户配置的预设名代替 spec
+    pub fn preset<'a>(&'a self, name: &str, source: &'a str) -> Image<'a> {
+        Image {
+            client: self,
+            spec: percent_encode(name.as_bytes(), NON_ALPHANUMERIC).to_string(),
+            source,
+            query: Vec::new(),
+            requester: None,
+        }
+    }
+}
+
+// 一个图片请求：url() 生成 URL，fetch() 获取图片
+#[derive(Debug, Clone)]
+pub struct Image<'a> {
+    client: &'a Client,
+    spec: String,
+    source: &'a str,
+    query: Vec<(&'static str, String)>,
+    requester: Option<String>,
+}
+
+impl<'a> Image<'a> {
+    // 文字水印中的 {requester}，需要客户端配置 signing_key
+    pub fn requester(mut self, requester: &str) -> Self {
+        self.requester = Some(requester.to_owned());
+        self
+    }
+
+    // 输出的字节数上限，服务端会降低 JPEG 质量以满足要求
+    pub fn max_bytes(self, max_bytes: usize) -> Self {
+        self.param("max_bytes", max_bytes.to_string())
+    }
+
+    // 下载时的文件名（不含扩展名）
+    pub fn filename(self, filename: &str) -> Self {
+        self.param("filename", filename.to_owned())
+    }
+
+    // 动图只处理第一帧
+    pub fn first_frame_only(self) -> Self {
+        self.param("first_frame_only", "true".to_owned())
+    }
+
+    fn param(mut self, name: &'static str, value: String) -> Self {
+        self.query.push((name, value));
+        self
+    }
+
+    pub fn url(&self) -> Result<String, Error> {
+        let mut url = format!(
+            "{}/image/{}/{}",
+            self.client.base_url,
+            self.spec,
+            percent_encode(self.source.as_bytes(), NON_ALPHANUMERIC)
+        );
+        let mut query = self.query.clone();
+        if let Some(ref requester) = self.requester {
+            let key = self.client.signing_key.as_deref().ok_or(Error::MissingSigningKey)?;
+            query.push(("requester", requester.clone()));
+            query.push(("requester_sig", sign(key, requester)));
+        }
+        for (i, (name, value)) in query.iter().enumerate() {
+            url.push(if i == 0 { '?' } else { '&' });
+            url.push_str(name);
+            url.push('=');
+            url.extend(percent_encode(value.as_bytes(), NON_ALPHANUMERIC));
+        }
+        Ok(url)
+    }
+
+    // 获取处理后的图片，非 2xx 的状态码返回 Error::Status
+    pub async fn fetch(&self) -> Result<Bytes, Error> {
+        let mut req = self.client.http.get(self.url()?);
+        if let Some(ref key) = self.client.api_key {
+            req = req.header("x-api-key", key);
+        }
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            return Err(Error::Status(res.status().as_u16()));
+        }
+        Ok(res.bytes().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_url_should_be_encoded_and_signed() {
+        let client = Client::new("https://img.a.com/").signing_key("secret");
+        let spec = SpecBuilder::new().resize(200, 100).watermark(10, 10).build();
+        assert_eq!(spec.version, SPEC_VERSION);
+        let encoded = encode_spec(&spec);
+        assert_eq!(ImageSpec::decode(&base64::decode_config(&encoded, base64::URL_SAFE_NO_PAD).unwrap()[..]).unwrap(), spec);
+
+        let url = client
+            .image(&spec, "https://a.com/cat.png?v=1")
+            .requester("alice bob")
+            .max_bytes(20000)
+            .url()
+            .unwrap();
+        let expected = format!(
+            "https://img.a.com/image/{}/https%3A%2F%2Fa%2Ecom%2Fcat%2Epng%3Fv%3D1?max_bytes=20000&requester=alice%20bob&requester_sig={}",
+            encoded,
+            sign("secret", "alice bob")
+        );
+        assert_eq!(url, expected);
+        assert_eq!(
+            client.preset("thumb", "https://a.com/cat.png").url().unwrap(),
+            "https://img.a.com/image/thumb/https%3A%2F%2Fa%2Ecom%2Fcat%2Epng"
+        );
+        let unsigned = Client::new("https://img.a.com");
+        assert!(matches!(
+            unsigned.image(&spec, "https://a.com/cat.png").requester("alice").url(),
+            Err(Error::MissingSigningKey)
+        ));
+    }
+}
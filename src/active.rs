@@ -0,0 +1,146 @@
+// 正在处理的图片请求（"GET /admin/inflight"），延迟升高时可以看到哪些请求卡在哪个阶段
+// URL 总是只记录 hash，和 log_urls 的配置无关
+use crate::{pb::ImageSpec, redact::UrlRedaction};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+lazy_static! {
+    pub static ref ACTIVE: Active = Active::default();
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    // 下载源图片，或者等待同一个 URL 正在进行的下载
+    Fetching,
+    Decoding,
+    // 执行 spec 中的操作
+    Transforming,
+    // 编码输出，边编码边发送时直到发送完
+    Encoding,
+}
+
+#[derive(Default)]
+pub struct Active {
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<u64, Entry>>,
+}
+
+struct Entry {
+    tenant: String,
+    preset: String,
+    url: String,
+    spec: String,
+    phase: Phase,
+    started: Instant,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InflightRequest {
+    id: u64,
+    tenant: String,
+    preset: String,
+    url: String,
+    // 按顺序执行的操作名
+    spec: String,
+    phase: Phase,
+    elapsed_ms: u64,
+}
+
+// 请求结束（包括出错和客户端断开）时 drop，从列表中移除
+pub struct Tracked<'a> {
+    active: &'a Active,
+    id: u64,
+}
+
+impl Active {
+    pub fn start(&self, tenant: &str, preset: &str, url: &str, spec: &ImageSpec) -> Tracked<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Entry {
+            tenant: tenant.to_owned(),
+            preset: preset.to_owned(),
+            url: UrlRedaction::HashOnly.apply(url).into_owned(),
+            spec: summary(spec),
+            phase: Phase::Fetching,
+            started: Instant::now(),
+        };
+        self.requests.lock().unwrap().insert(id, entry);
+        Tracked { active: self, id }
+    }
+
+    // 耗时最长的在前
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn list(&self) -> Vec<InflightRequest> {
+        let mut list: Vec<_> = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, v)| InflightRequest {
+                id: *id,
+                tenant: v.tenant.clone(),
+                preset: v.preset.clone(),
+                url: v.url.clone(),
+                spec: v.spec.clone(),
+                phase: v.phase,
+                elapsed_ms: v.started.elapsed().as_millis() as u64,
+            })
+            .collect();
+        list.sort_by(|a, b| b.elapsed_ms.cmp(&a.elapsed_ms).then(a.id.cmp(&b.id)));
+        list
+    }
+}
+
+impl Tracked<'_> {
+    pub fn phase(&self, phase: Phase) {
+        if let Some(v) = self.active.requests.lock().unwrap().get_mut(&self.id) {
+            v.phase = phase;
+        }
+    }
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.active.requests.lock().unwrap().remove(&self.id);
+    }
+}
+
+fn summary(spec: &ImageSpec) -> String {
+    let ops: Vec<_> = spec.specs.iter().filter_map(|s| s.data.as_ref()).map(|v| v.name()).collect();
+    ops.join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_should_be_listed_until_dropped() {
+        let active = Active::default();
+        let spec = ImageSpec::parse("resize:w=100,h=100;watermark:x=1,y=1").unwrap();
+        let a = active.start("a", "thumb", "https://a.com/cat.png?token=secret", &spec);
+        let b = active.start("b", "adhoc", "https://b.com/dog.png", &ImageSpec::new(vec![]));
+        b.phase(Phase::Encoding);
+        let list = active.list();
+        assert_eq!(list.len(), 2);
+        let first = list.iter().find(|v| v.tenant == "a").unwrap();
+        assert_eq!((first.spec.as_str(), first.phase), ("resize;watermark", Phase::Fetching));
+        assert!(first.url.starts_with("sha256:") && !first.url.contains("secret"));
+        assert_eq!(list.iter().find(|v| v.tenant == "b").unwrap().phase, Phase::Encoding);
+
+        drop(a);
+        let list = active.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].preset, "adhoc");
+        drop(b);
+        assert!(active.list().is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    active::{InflightRequest, ACTIVE},
+    assets::{AssetError, AssetInfo, ASSETS, MAX_ASSET_BYTES},
+    config::Config,
+    fonts::{FontInfo, FONTS, MAX_FONT_BYTES},
+    publish::Publisher,
+    signing,
+    source_cache::{self, RESULTS},
+    stats::STATS,
+    tenant::Tenants,
+    uploads, Cache,
+};
+use axum::{
+    extract::{ContentLengthLimit, Extension, Path},
+    http::{HeaderMap, StatusCode},
+    response::Html,
+    Json,
+};
+use bytes::Bytes;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct AdminStats {
+    cache_entries: usize,
+    cache_capacity: usize,
+    cache_hits: u64,
+    cache_misses: u64,
+    errors: Vec<crate::stats::ErrorEntry>,
+}
+
+#[derive(Serialize)]
+pub struct Purged {
+    // 删除的缓存中的源图片（每个租户的命名空间一条）
+    sources: usize,
+    // 删除的对象存储中的结果
+    results: usize,
+}
+
+// 只有配置了 admin_token 并且请求头 Authorization: Bearer 中带上相同的 token 才可以访问
+fn check(config: &Config, headers: &HeaderMap) -> Result<(), StatusCode> {
+    match (&config.admin_token, signing::bearer(headers)) {
+        (Some(expected), Some(token)) if signing::token_eq(token, expected) => Ok(()),
+        (None, _) => Err(StatusCode::NOT_FOUND),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+// "GET /admin/ui#token=<admin_token>" 管理界面：缓存统计、最近的错误以及 spec 预览
+// 页面本身不包含数据，浏览器打开时不能带上 Authorization，token 放在 # 之后不会发给服务端，由页面带在请求头中
+pub async fn ui(Extension(config): Extension<Arc<Config>>) -> Result<Html<&'static str>, StatusCode> {
+    config.admin_token.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Html(include_str!("admin.html")))
+}
+
+// "GET /admin/stats" 管理界面定时拉取的数据
+pub async fn stats(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(cache): Extension<Cache>,
+    req_headers: HeaderMap,
+) -> Result<Json<AdminStats>, StatusCode> {
+    check(&config, &req_headers)?;
+    let (cache_entries, cache_capacity) = {
+        let g = cache.lock().await;
+        (g.len(), g.cap())
+    };
+    let (cache_hits, cache_misses) = STATS.cache_counts();
+    Ok(Json(AdminStats {
+        cache_entries,
+        cache_capacity,
+        cache_hits,
+        cache_misses,
+        errors: STATS.recent_errors(),
+    }))
+}
+
+// "GET /admin/inflight" 正在处理的图片请求，耗时最长的在前
+pub async fn inflight(
+    Extension(config): Extension<Arc<Config>>,
+    req_headers: HeaderMap,
+) -> Result<Json<Vec<InflightRequest>>, StatusCode> {
+    check(&config, &req_headers)?;
+    Ok(Json(ACTIVE.list()))
+}
+
+// "GET /admin/assets" 列出上传的水印素材
+pub async fn list_assets(
+    Extension(config): Extension<Arc<Config>>,
+    req_headers: HeaderMap,
+) -> Result<Json<Vec<AssetInfo>>, StatusCode> {
+    check(&config, &req_headers)?;
+    Ok(Json(ASSETS.list()))
+}
+
+// "PUT /admin/assets/:name" 上传或替换素材，请求体是图片文件，使用这个素材的 spec 会立刻生效
+pub async fn put_asset(
+    Path(name): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    ContentLengthLimit(body): ContentLengthLimit<Bytes, { MAX_ASSET_BYTES as u64 }>,
+    req_headers: HeaderMap,
+) -> Result<Json<AssetInfo>, StatusCode> {
+    check(&config, &req_headers)?;
+    ASSETS.put(&name, &body).map(Json).map_err(asset_status)
+}
+
+// "DELETE /admin/assets/:name" 删除素材，之后引用它的 spec 会返回 422
+pub async fn delete_asset(
+    Path(name): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    req_headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    check(&config, &req_headers)?;
+    match ASSETS.delete(&name).map_err(asset_status)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+// "GET /admin/fonts" 列出可以使用的字体
+pub async fn list_fonts(
+    Extension(config): Extension<Arc<Config>>,
+    req_headers: HeaderMap,
+) -> Result<Json<Vec<FontInfo>>, StatusCode> {
+    check(&config, &req_headers)?;
+    Ok(Json(FONTS.list()))
+}
+
+// "PUT /admin/fonts/:name" 上传或替换字体，请求体是 TTF/OTF 文件
+pub async fn put_font(
+    Path(name): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    ContentLengthLimit(body): ContentLengthLimit<Bytes, { MAX_FONT_BYTES as u64 }>,
+    req_headers: HeaderMap,
+) -> Result<Json<FontInfo>, StatusCode> {
+    check(&config, &req_headers)?;
+    FONTS.put(&name, &body).map(Json).map_err(asset_status)
+}
+
+// "DELETE /admin/fonts/:name" 删除上传的字体
+pub async fn delete_font(
+    Path(name): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    req_headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    check(&config, &req_headers)?;
+    match FONTS.delete(&name).map_err(asset_status)? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+// "DELETE /admin/uploads/:id" 删除上传的图片，以及由它得到的缓存和对象存储中的结果
+pub async fn delete_upload(
+    Path(id): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(cache): Extension<Cache>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+    Extension(publisher): Extension<Arc<Publisher>>,
+    req_headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    check(&config, &req_headers)?;
+    if config.uploads.is_none() {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    match uploads::purge(&id, None, &cache, &tenants, &publisher).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::warn!("Failed to delete upload {}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// "DELETE /admin/sources/:url" 源站替换了同一个 URL 的图片时，删除缓存的源图片，以及由它得到的所有结果
+pub async fn purge_source(
+    Path(url): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(cache): Extension<Cache>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+    Extension(publisher): Extension<Arc<Publisher>>,
+    req_headers: HeaderMap,
+) -> Result<Json<Purged>, StatusCode> {
+    check(&config, &req_headers)?;
+    let url: &str = &percent_decode_str(&url).decode_utf8_lossy();
+    let sources = source_cache::evict(&cache, &tenants, url).await;
+    let keys = RESULTS.take(url);
+    let results = keys.len();
+    for key in keys {
+        publisher.delete(key);
+    }
+    tracing::info!("Purged {} cached sources and {} published results", sources, results);
+    Ok(Json(Purged { sources, results }))
+}
+
+fn asset_status(e: AssetError) -> StatusCode {
+    match e {
+        AssetError::NotConfigured => StatusCode::NOT_IMPLEMENTED,
+        AssetError::InvalidName | AssetError::Invalid(_) => StatusCode::BAD_REQUEST,
+        AssetError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        AssetError::ReadOnly => StatusCode::CONFLICT,
+        AssetError::Io(e) => {
+            tracing::warn!("Failed to write asset or font: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// 上传的水印素材，spec 中用 watermark:asset=<name> 引用，更换活动角标不需要重新部署
+// 素材保存在 assets_dir 目录（可以是挂载的 bucket），启动时全部加载到内存
+use crate::{
+    engine::{decode, encode},
+    pb::{spec, ImageSpec},
+};
+use image::{ImageOutputFormat, RgbaImage};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tracing::{info, warn};
+
+// 上传的文件大小限制
+pub const MAX_ASSET_BYTES: usize = 2 << 20;
+// 素材的最大宽高
+pub const MAX_ASSET_DIMENSION: u32 = 1024;
+// 名字的最大长度
+const MAX_NAME_LEN: usize = 64;
+
+lazy_static! {
+    // engine 处理 spec 时从这里取素材
+    pub static ref ASSETS: AssetStore = AssetStore::default();
+}
+
+#[derive(Debug, thiserror::Error)]
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+pub enum AssetError {
+    #[error("assets_dir is not configured")]
+    NotConfigured,
+    #[error("invalid asset name")]
+    InvalidName,
+    #[error("larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("configured in the config file and cannot be changed")]
+    ReadOnly,
+    #[error("invalid image: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// 素材的元数据，GET /admin/assets 返回
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AssetInfo {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: usize,
+    pub sha256: String,
+}
+
+struct Asset {
+    image: Arc<RgbaImage>,
+    info: AssetInfo,
+    digest: [u8; 32],
+}
+
+#[derive(Default)]
+pub struct AssetStore {
+    dir: RwLock<Option<PathBuf>>,
+    assets: RwLock<HashMap<String, Asset>>,
+}
+
+// 名字只能包含小写字母、数字、- 和 _，同时用作文件名
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-' || c == b'_')
+}
+
+impl AssetStore {
+    // 加载目录中所有的 <name>.png，无法解码的文件跳过
+    pub fn open(&self, dir: &str) -> Result<(), AssetError> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        let mut assets = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = match (path.file_stem().and_then(|v| v.to_str()), path.extension()) {
+                (Some(name), Some(ext)) if ext == "png" && valid_name(name) => name.to_owned(),
+                _ => continue,
+            };
+            match load(&path, &name) {
+                Ok(asset) => {
+                    assets.insert(name, asset);
+                }
+                Err(e) => warn!("Failed to load asset {}: {}", path.display(), e),
+            }
+        }
+        info!("Loaded {} assets from {}", assets.len(), dir.display());
+        *self.assets.write().unwrap() = assets;
+        *self.dir.write().unwrap() = Some(dir);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<RgbaImage>> {
+        self.assets.read().unwrap().get(name).map(|v| v.image.clone())
+    }
+
+    // spec 引用但不存在的第一个素材
+    pub fn missing<'a>(&self, spec: &'a ImageSpec) -> Option<&'a str> {
+        let assets = self.assets.read().unwrap();
+        referenced(spec).find(|name| !assets.contains_key(*name))
+    }
+
+    // 引用的素材的内容摘要，素材被替换后缓存的 key 随之改变
+    pub fn digests(&self, spec: &ImageSpec) -> Vec<[u8; 32]> {
+        let assets = self.assets.read().unwrap();
+        referenced(spec)
+            .map(|name| assets.get(name).map(|v| v.digest).unwrap_or_default())
+            .collect()
+    }
+}
+
+// 管理接口 /admin/assets 使用
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+impl AssetStore {
+    pub fn list(&self) -> Vec<AssetInfo> {
+        let mut list: Vec<_> = self.assets.read().unwrap().values().map(|v| v.info.clone()).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    // 上传或替换素材，统一转成 PNG 保存，先写临时文件再改名
+    pub fn put(&self, name: &str, data: &[u8]) -> Result<AssetInfo, AssetError> {
+        if !valid_name(name) {
+            return Err(AssetError::InvalidName);
+        }
+        if data.len() > MAX_ASSET_BYTES {
+            return Err(AssetError::TooLarge(MAX_ASSET_BYTES));
+        }
+        let dir = self.dir.read().unwrap().clone().ok_or(AssetError::NotConfigured)?;
+        let image = decode(data, 0).map_err(|e| AssetError::Invalid(e.to_string()))?;
+        let (width, height) = image.dimensions();
+        if width > MAX_ASSET_DIMENSION || height > MAX_ASSET_DIMENSION {
+            return Err(AssetError::Invalid(format!("larger than {0}x{0}", MAX_ASSET_DIMENSION)));
+        }
+        let png = encode(image.clone(), ImageOutputFormat::Png);
+        let path = dir.join(format!("{}.png", name));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &png)?;
+        fs::rename(&tmp, &path)?;
+
+        let asset = Asset::new(name, image, &png);
+        let info = asset.info.clone();
+        self.assets.write().unwrap().insert(name.to_owned(), asset);
+        Ok(info)
+    }
+
+    // 返回素材是否存在
+    pub fn delete(&self, name: &str) -> Result<bool, AssetError> {
+        if !valid_name(name) {
+            return Err(AssetError::InvalidName);
+        }
+        let dir = self.dir.read().unwrap().clone().ok_or(AssetError::NotConfigured)?;
+        if self.assets.write().unwrap().remove(name).is_none() {
+            return Ok(false);
+        }
+        match fs::remove_file(dir.join(format!("{}.png", name))) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl Asset {
+    fn new(name: &str, image: RgbaImage, png: &[u8]) -> Self {
+        let digest: [u8; 32] = Sha256::digest(png).into();
+        let info = AssetInfo {
+            name: name.to_owned(),
+            width: image.width(),
+            height: image.height(),
+            bytes: png.len(),
+            sha256: hex::encode(digest),
+        };
+        Self {
+            image: Arc::new(image),
+            info,
+            digest,
+        }
+    }
+}
+
+fn load(path: &Path, name: &str) -> Result<Asset, AssetError> {
+    let data = fs::read(path)?;
+    let image = decode(&data, 0).map_err(|e| AssetError::Invalid(e.to_string()))?;
+    Ok(Asset::new(name, image, &data))
+}
+
+// spec 中引用的素材名
+fn referenced(spec: &ImageSpec) -> impl Iterator<Item = &str> {
+    spec.specs.iter().filter_map(|s| match s.data {
+        Some(spec::Data::Watermark(ref v)) if !v.asset.is_empty() => Some(v.asset.as_str()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::Spec;
+    use image::Rgba;
+
+    #[test]
+    fn assets_should_be_persisted() {
+        let dir = std::env::temp_dir().join(format!("shanbor-assets-{}", std::process::id()));
+        let store = AssetStore::default();
+        let png = encode(RgbaImage::from_pixel(4, 3, Rgba([255, 0, 0, 128])), ImageOutputFormat::Png);
+        assert!(matches!(store.put("badge", &png), Err(AssetError::NotConfigured)));
+
+        store.open(dir.to_str().unwrap()).unwrap();
+        assert!(matches!(store.put("Badge!", &png), Err(AssetError::InvalidName)));
+        assert!(matches!(store.put("badge", b"not an image"), Err(AssetError::Invalid(_))));
+        let info = store.put("badge", &png).unwrap();
+        assert_eq!((info.width, info.height), (4, 3));
+
+        let mut spec = ImageSpec::parse("watermark:asset=badge").unwrap();
+        assert_eq!(store.missing(&spec), None);
+        spec.specs.push(Spec::new_watermark(0, 0));
+        assert_eq!(store.digests(&spec).len(), 1);
+
+        // 重新打开时从目录加载
+        let reopened = AssetStore::default();
+        reopened.open(dir.to_str().unwrap()).unwrap();
+        assert_eq!(reopened.list(), vec![info]);
+        assert_eq!(reopened.get("badge").unwrap().get_pixel(0, 0), &Rgba([255, 0, 0, 128]));
+
+        assert!(reopened.delete("badge").unwrap());
+        assert!(!reopened.delete("badge").unwrap());
+        assert_eq!(reopened.missing(&spec), Some("badge"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
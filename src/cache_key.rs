@@ -0,0 +1,109 @@
+// 缓存和对象存储使用的 key
+// DefaultHasher 的算法在不同 Rust 版本之间不保证一致，持久化的缓存升级后会全部失效，
+// 所以这里使用 SHA-256，并且先对输入做规范化，同样的请求总是得到同样的 key
+use crate::{
+    assets::ASSETS,
+    engine::ENGINE_VERSION,
+    fonts::FONTS,
+    pb::{ImageSpec, SPEC_VERSION},
+};
+use prost::Message;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+pub type Key = [u8; 32];
+
+// 每个字段前写入长度，避免 ("ab", "c") 和 ("a", "bc") 得到同样的 key
+struct KeyHasher(Sha256);
+
+impl KeyHasher {
+    // 不同用途的 key 使用不同的前缀，互相不会冲突
+    fn new(kind: &str) -> Self {
+        let mut hasher = Self(Sha256::new());
+        hasher.field(kind.as_bytes());
+        hasher
+    }
+
+    fn field(&mut self, data: &[u8]) -> &mut Self {
+        self.0.update((data.len() as u64).to_be_bytes());
+        self.0.update(data);
+        self
+    }
+
+    fn finish(self) -> Key {
+        self.0.finalize().into()
+    }
+}
+
+// scheme 和 host 转成小写，去掉默认端口和 #fragment；无法解析的 URL 原样使用
+pub fn canonical_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut v) => {
+            v.set_fragment(None);
+            v.into()
+        }
+        Err(_) => url.to_owned(),
+    }
+}
+
+// 源图片缓存的 key
+pub fn source(namespace: &str, url: &str) -> Key {
+    let mut hasher = KeyHasher::new("source");
+    hasher.field(namespace.as_bytes()).field(canonical_url(url).as_bytes());
+    hasher.finish()
+}
+
+// 处理结果的 key，由 (url, spec, 输出格式, engine 版本) 决定
+// spec 重新编码成 protobuf，文本语法和 base64 的写法不同但内容相同时 key 也相同
+pub fn output(namespace: &str, url: &str, spec: &ImageSpec, format: &str) -> Key {
+    let mut spec = spec.clone();
+    // 没有版本号的旧 spec 和当前版本语义相同
+    if spec.version == 0 {
+        spec.version = SPEC_VERSION;
+    }
+    let mut hasher = KeyHasher::new("output");
+    hasher
+        .field(namespace.as_bytes())
+        .field(canonical_url(url).as_bytes())
+        .field(&spec.encode_to_vec())
+        .field(format.as_bytes())
+        .field(ENGINE_VERSION.as_bytes());
+    // 引用了水印素材或者字体时加入它们的内容，不引用时 key 不变
+    for digest in ASSETS.digests(&spec).into_iter().chain(FONTS.digests(&spec)) {
+        hasher.field(&digest);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::{filter, Spec};
+
+    #[test]
+    fn key_should_be_stable() {
+        // 固定的值：key 改变意味着所有持久化的缓存都会失效，不能随意修改
+        assert_eq!(
+            hex::encode(source("", "https://a.com/cat.png")),
+            "896cdb4839a814fcc2b1e7f44006b0fa3266faffc424fc1429d82d64e422c891"
+        );
+        assert_ne!(source("ab", "c"), source("a", "bc"));
+        assert_ne!(source("", "https://a.com/cat.png"), source("t", "https://a.com/cat.png"));
+    }
+
+    #[test]
+    fn equivalent_inputs_should_have_same_key() {
+        assert_eq!(
+            source("", "HTTPS://A.com:443/cat.png#x"),
+            source("", "https://a.com/cat.png")
+        );
+        let binary = ImageSpec::new(vec![Spec::new_filter(filter::Filter::Marine)]);
+        let text = ImageSpec::parse("filter:marine").unwrap();
+        let mut legacy = binary.clone();
+        legacy.version = 0;
+        let key = output("", "https://a.com/cat.png", &binary, "jpeg");
+        assert_eq!(key, output("", "https://a.com/cat.png", &text, "jpeg"));
+        assert_eq!(key, output("", "https://a.com/cat.png", &legacy, "jpeg"));
+        assert_ne!(key, output("", "https://a.com/cat.png", &binary, "png"));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{assets, collage, fonts, config::Config, contactsheet, engine::{SourceFormat, ENGINE_VERSION}, pb::{filter, resize, UnknownPolicy, MAX_BLUR_REGIONS, MAX_DIMENSION, SPEC_VERSION}, sprite, MAX_TEXT_COLUMNS};
+use axum::{extract::Extension, Json};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc};
+
+// 支持的 spec 操作，和 abi.proto 中 Spec 的 oneof 一一对应
+const OPERATIONS: &[&str] = &[
+    "resize",
+    "crop",
+    "flipv",
+    "fliph",
+    "contrast",
+    "filter",
+    "watermark",
+    "text",
+    "auto_enhance",
+    "lqip",
+    "simulate",
+    "invisible_watermark",
+    "blur_regions",
+    "color_pop",
+    "caption",
+];
+
+const SIMULATIONS: &[&str] = &["deuteranopia", "protanopia", "tritanopia"];
+
+const GRAVITIES: &[&str] = &[
+    "center",
+    "north",
+    "south",
+    "east",
+    "west",
+    "north_east",
+    "north_west",
+    "south_east",
+    "south_west",
+];
+
+// 除了图片以外的输出形式
+const OUTPUTS: &[&str] = &["text_art", "base64", "max_bytes"];
+
+#[derive(Serialize)]
+pub struct Capabilities {
+    engine_version: &'static str,
+    // 支持的最高 spec 协议版本
+    spec_version: u32,
+    // 不包括配置中关闭的操作（disabled_ops）。关闭操作也会关闭内部用到它的接口（见 features）
+    operations: Vec<&'static str>,
+    filters: Vec<&'static str>,
+    sample_filters: Vec<&'static str>,
+    // resize 没有指定 filter 时使用的 filter
+    default_sample_filter: &'static str,
+    simulations: &'static [&'static str],
+    gravities: &'static [&'static str],
+    input_formats: Vec<String>,
+    output_formats: Vec<&'static str>,
+    outputs: &'static [&'static str],
+    // 各个接口的尺寸和数量限制
+    limits: BTreeMap<&'static str, u32>,
+    // 当前部署开启的功能
+    features: BTreeMap<&'static str, bool>,
+}
+
+// "GET /capabilities" 返回当前部署支持的功能，客户端可以据此判断，不用写死
+pub async fn capabilities(Extension(config): Extension<Arc<Config>>) -> Json<Capabilities> {
+    let filters = (1..)
+        .map_while(filter::Filter::from_i32)
+        .filter_map(|f| f.to_str())
+        .collect();
+    let sample_filters = (1..)
+        .map_while(resize::SampleFilter::from_i32)
+        .filter_map(|f| f.to_str())
+        .collect();
+    let default_sample_filter = config
+        .default_sample_filter
+        .unwrap_or(resize::SampleFilter::Nearest)
+        .to_str()
+        .unwrap_or("nearest");
+
+    let mut limits = BTreeMap::new();
+    limits.insert("max_dimension", MAX_DIMENSION);
+    limits.insert("text_columns", MAX_TEXT_COLUMNS);
+    limits.insert("collage_images", collage::MAX_IMAGES as u32);
+    limits.insert("collage_cell_size", collage::MAX_CELL_SIZE);
+    limits.insert("sprite_icons", sprite::MAX_ICONS as u32);
+    limits.insert("sprite_icon_size", sprite::MAX_ICON_SIZE);
+    limits.insert("contactsheet_images", contactsheet::MAX_IMAGES as u32);
+    limits.insert("contactsheet_thumb_size", contactsheet::MAX_THUMB_SIZE);
+    limits.insert("asset_bytes", assets::MAX_ASSET_BYTES as u32);
+    limits.insert("asset_dimension", assets::MAX_ASSET_DIMENSION);
+    limits.insert("font_bytes", fonts::MAX_FONT_BYTES as u32);
+    limits.insert("blur_regions", MAX_BLUR_REGIONS as u32);
+    limits.insert("animation_frames", config.animation.max_frames as u32);
+    limits.insert("animation_megapixels", config.animation.max_megapixels as u32);
+
+    let mut features = BTreeMap::new();
+    features.insert("signed_requester", config.signing_key.is_some());
+    features.insert("publish", config.publish.is_some());
+    features.insert("debug_query", config.debug_query);
+    features.insert("client_hints", config.client_hints);
+    features.insert("shadow", config.shadow.is_some());
+    features.insert("reject_unknown_spec_fields", config.unknown_spec_fields == UnknownPolicy::Reject);
+    let enabled = |feature: &str| !config.disabled_ops.iter().any(|v| v == feature);
+    features.insert("watermark_assets", config.assets_dir.is_some() && enabled("watermark_asset"));
+    features.insert("font_uploads", config.fonts_dir.is_some());
+    features.insert("seam_carve", enabled("seam_carve"));
+    features.insert("admin", cfg!(feature = "admin") && config.admin_token.is_some());
+    // 顶层配置中接口本身或者它用到的操作关闭时不能使用，租户可能还关闭了更多
+    features.insert("collage", collage::FEATURES.iter().all(|f| enabled(f)));
+    features.insert("sprite", sprite::FEATURES.iter().all(|f| enabled(f)));
+    features.insert("contactsheet", contactsheet::FEATURES.iter().all(|f| enabled(f)));
+    features.insert("diff", enabled("diff"));
+
+    Json(Capabilities {
+        engine_version: ENGINE_VERSION,
+        spec_version: SPEC_VERSION,
+        operations: OPERATIONS.iter().copied().filter(|op| enabled(op)).collect(),
+        filters,
+        sample_filters,
+        default_sample_filter,
+        simulations: SIMULATIONS,
+        gravities: GRAVITIES,
+        input_formats: SourceFormat::ALL.iter().map(|f| f.to_string()).collect(),
+        output_formats: vec!["jpeg", "png", "gif"],
+        outputs: OUTPUTS,
+        limits,
+        features,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::syntax::OPS;
+
+    #[tokio::test]
+    async fn operations_should_list_enabled_ops() {
+        let operations = |config: Config| async move { capabilities(Extension(Arc::new(config))).await.0.operations };
+        // page 和 version 不是操作
+        let all: Vec<_> = OPS.iter().copied().filter(|op| !["page", "version"].contains(op)).collect();
+        assert_eq!(operations(Config::default()).await, all);
+
+        let config = Config { disabled_ops: vec!["text".to_owned(), "caption".to_owned()], ..Default::default() };
+        let enabled = operations(config).await;
+        assert_eq!(enabled.len(), all.len() - 2);
+        assert!(!enabled.contains(&"text") && !enabled.contains(&"caption"));
+    }
+}
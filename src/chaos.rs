@@ -0,0 +1,123 @@
+// 故障注入，只用于测试环境：设置环境变量 SHANBOR_CHAOS 后按比例给回源增加延迟、让回源失败或者让解码失败，
+// 在 staging 中验证重试、熔断和使用过期缓存等行为，不需要真的弄坏源站。配置文件中不能开启
+// SHANBOR_CHAOS="latency_ms=500,latency_rate=0.2,fetch_failure_rate=0.1,decode_error_rate=0.05"
+use crate::{
+    engine::{sniff, DecodeError},
+    source_cache::FetchError,
+};
+use lazy_static::lazy_static;
+use std::{
+    env,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+lazy_static! {
+    // 没有设置 SHANBOR_CHAOS 时为 None，格式错误时启动失败
+    pub static ref CHAOS: Option<Chaos> = from_env().expect("invalid SHANBOR_CHAOS");
+}
+
+// 读取 SHANBOR_CHAOS，`shanbor check` 用它提前发现格式错误
+pub fn from_env() -> Result<Option<Chaos>, String> {
+    env::var("SHANBOR_CHAOS").ok().map(|v| Chaos::parse(&v)).transpose()
+}
+
+#[derive(Debug, Default)]
+pub struct Chaos {
+    // 注入的回源延迟
+    latency: Duration,
+    latency_rate: Rate,
+    fetch_failure_rate: Rate,
+    decode_error_rate: Rate,
+}
+
+// 按次数均匀地命中，和 shadow 抽样一样不需要随机数，结果可以复现
+#[derive(Debug, Default)]
+struct Rate(f64, AtomicU64);
+
+impl Rate {
+    fn hit(&self) -> bool {
+        if self.0 <= 0.0 {
+            return false;
+        }
+        let n = self.1.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.0).floor() > (n * self.0).floor()
+    }
+}
+
+impl Chaos {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut chaos = Chaos::default();
+        for item in s.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+            let (key, value) = item.split_once('=').ok_or_else(|| format!("expected key=value, got {}", item))?;
+            let value: f64 = value.parse().map_err(|_| format!("invalid number for {}", key))?;
+            if key == "latency_ms" {
+                if !(value >= 0.0 && value.is_finite()) {
+                    return Err("latency_ms must be a non-negative number".to_owned());
+                }
+                chaos.latency = Duration::from_millis(value as u64);
+                continue;
+            }
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("{} must be in 0..=1", key));
+            }
+            let rate = match key {
+                "latency_rate" => &mut chaos.latency_rate,
+                "fetch_failure_rate" => &mut chaos.fetch_failure_rate,
+                "decode_error_rate" => &mut chaos.decode_error_rate,
+                _ => return Err(format!("unknown key {}", key)),
+            };
+            rate.0 = value;
+        }
+        Ok(chaos)
+    }
+
+    // 回源之前调用
+    pub async fn fetch(&self) -> Result<(), FetchError> {
+        if self.latency_rate.hit() {
+            tokio::time::sleep(self.latency).await;
+        }
+        if self.fetch_failure_rate.hit() {
+            return Err(FetchError::Failed("injected fetch failure".to_owned()));
+        }
+        Ok(())
+    }
+
+    // 完整解码之前调用，无法识别格式的数据交给正常的解码报错
+    pub fn decode(&self, data: &[u8]) -> Result<(), DecodeError> {
+        match sniff(data) {
+            Some(format) if self.decode_error_rate.hit() => {
+                Err(DecodeError::Invalid(format, "injected decode error".to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn faults_should_be_injected_at_rates() {
+        let chaos = Chaos::parse("latency_ms=1, latency_rate=1,fetch_failure_rate=0.5,decode_error_rate=0.25").unwrap();
+        assert_eq!(chaos.latency, Duration::from_millis(1));
+        let mut failures = 0;
+        for _ in 0..8 {
+            if chaos.fetch().await.is_err() {
+                failures += 1;
+            }
+        }
+        assert_eq!(failures, 4);
+
+        let png = crate::engine::encode(image::RgbaImage::new(1, 1), image::ImageOutputFormat::Png);
+        assert_eq!((0..8).filter(|_| chaos.decode(&png).is_err()).count(), 2);
+        // 无法识别的数据不计数
+        assert!((0..8).all(|_| chaos.decode(b"<html>").is_ok()));
+
+        assert!(Chaos::parse("").unwrap().fetch().await.is_ok());
+        assert!(Chaos::parse("fetch_failure_rate=2").is_err());
+        assert!(Chaos::parse("unknown=0.1").is_err());
+        assert!(Chaos::parse("latency_ms").is_err());
+    }
+}
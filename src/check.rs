@@ -0,0 +1,176 @@
+// `shanbor check`：上线之前检查配置和运行环境，全部通过时退出码为 0，否则为 1
+// 按启动时的顺序加载配置、SHANBOR_CHAOS、租户和各项设置，确认目录和对象存储可以写入，加载字体和水印素材，
+// 确认 peer 可以访问、监听的地址可以绑定，最后用每一个会用到的 engine 处理一张内置的测试图片。
+// 每一项输出一行，失败的项不影响后面的检查
+use crate::{
+    assets, chaos,
+    config::Config,
+    engine::{Engine, Native, Photon},
+    fallback::Fallback,
+    fonts, overload,
+    pb::ImageSpec,
+    peer,
+    publish::Publisher,
+    redact,
+    tenant::Tenants,
+    uploads,
+};
+use image::{GenericImageView, ImageOutputFormat};
+use std::{env, fs, net::{SocketAddr, TcpListener}, path::Path};
+
+// 内置的测试图片，和 Photon 内置的水印是同一张
+const TEST_IMAGE: &[u8] = include_bytes!("../cat.png");
+// 覆盖解码、缩放、文字、水印和编码
+const TEST_SPEC: &str = "resize:w=64,h=48;text:\"check\",size=12;watermark:x=4,y=4;invisible_watermark:1";
+const PROBE_FILE: &str = ".shanbor-check";
+
+#[derive(Default)]
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    fn add(&mut self, name: &str, result: Result<String, String>) {
+        match result {
+            Ok(detail) => println!("ok    {:<10} {}", name, detail),
+            Err(e) => {
+                self.failed += 1;
+                println!("FAIL  {:<10} {}", name, e);
+            }
+        }
+    }
+}
+
+pub async fn run() -> bool {
+    let mut report = Report::default();
+    let config = match Config::load() {
+        Ok(v) => v,
+        Err(e) => {
+            report.add("config", Err(format!("{:#}", e)));
+            return false;
+        }
+    };
+    let source = env::var("SHANBOR_CONFIG").unwrap_or_else(|_| "defaults (SHANBOR_CONFIG is not set)".to_owned());
+    report.add("config", Ok(source));
+    // 和启动时一样初始化，后面输出的错误中的 URL 按配置脱敏
+    redact::init(config.log_urls);
+    report.add(
+        "chaos",
+        chaos::from_env().map(|c| c.map_or_else(|| "disabled".to_owned(), |c| format!("enabled: {:?}", c))),
+    );
+
+    let tenants = Tenants::new(&config).map_err(|e| format!("{:#}", e));
+    report.add("tenants", tenants.as_ref().map(|t| format!("{} tenants", t.count())).map_err(Clone::clone));
+    report.add(
+        "overload",
+        overload::OVERLOAD
+            .configure(&config.overload)
+            .map(|_| format!("{} routes", config.overload.len()))
+            .map_err(|e| e.to_string()),
+    );
+    report.add(
+        "fallback",
+        Fallback::new(config.fallback.as_ref())
+            .map(|f| if f.enabled() { "enabled" } else { "disabled" }.to_owned())
+            .map_err(|e| format!("{:#}", e)),
+    );
+    let publish = match Publisher::new(config.publish.as_ref()) {
+        Ok(p) if p.enabled() => p.check().await.map(|_| "writable".to_owned()).map_err(|e| format!("{:#}", e)),
+        Ok(_) => Ok("disabled".to_owned()),
+        Err(e) => Err(format!("{:#}", e)),
+    };
+    report.add("publish", publish);
+
+    // 管理接口和上传会写入这些目录
+    if let Some(ref dir) = config.assets_dir {
+        let loaded = assets::ASSETS.open(dir).map_err(|e| e.to_string());
+        report.add("assets", loaded.and_then(|_| writable(dir)).map(|_| dir.clone()));
+    }
+    let loaded = fonts::FONTS.open(&config.fonts, config.fonts_dir.as_deref()).map_err(|e| e.to_string());
+    let loaded = match config.fonts_dir {
+        Some(ref dir) => loaded.and_then(|_| writable(dir)),
+        None => loaded,
+    };
+    report.add("fonts", loaded.map(|_| format!("{} configured", config.fonts.len())));
+    if let Some(ref c) = config.uploads {
+        let opened = uploads::UPLOADS.open(&c.dir).map_err(|e| e.to_string());
+        report.add("uploads", opened.and_then(|_| writable(&c.dir)).map(|_| c.dir.clone()));
+    }
+    // 预设中引用的素材和字体需要在上面加载之后检查
+    if let Ok(ref tenants) = tenants {
+        report.add("presets", tenants.check_resources().map(|_| "all resources loaded".to_owned()));
+    }
+
+    if let Some(ref sync) = config.peer_sync {
+        report.add("peer", peer::probe(sync).await);
+    }
+    let addr = config.listen_addr();
+    report.add("listen", bindable(addr));
+    if let Some(admin) = config.admin_listen {
+        report.add("admin", bindable(admin));
+    }
+
+    // Photon 处理所有请求，Native 只用于 shadow 对比
+    let mut spec = ImageSpec::parse(TEST_SPEC).expect("valid test spec");
+    if let Some(filter) = config.default_sample_filter {
+        spec.default_sample_filter(filter);
+    }
+    report.add("photon", Photon::open(TEST_IMAGE, 0).map_err(|e| e.to_string()).and_then(|e| render(e, &spec)));
+    if config.shadow.is_some() {
+        report.add("native", Native::open(TEST_IMAGE, 0).map_err(|e| e.to_string()).and_then(|e| render(e, &spec)));
+    }
+
+    if report.failed > 0 {
+        println!("{} checks failed", report.failed);
+    }
+    report.failed == 0
+}
+
+// 写入再删除一个探测文件
+fn writable(dir: &str) -> Result<(), String> {
+    let path = Path::new(dir).join(PROBE_FILE);
+    fs::write(&path, b"ok")
+        .and_then(|_| fs::remove_file(&path))
+        .map_err(|e| format!("{} is not writable: {}", dir, e))
+}
+
+// 绑定之后立刻释放，发现端口被占用或者没有权限
+fn bindable(addr: SocketAddr) -> Result<String, String> {
+    TcpListener::bind(addr).map(|_| addr.to_string()).map_err(|e| format!("cannot bind {}: {}", addr, e))
+}
+
+// 处理测试图片并解码输出，确认尺寸符合 spec
+fn render<E: Engine>(mut engine: E, spec: &ImageSpec) -> Result<String, String> {
+    engine.apply(&spec.specs);
+    let output = engine.generate(ImageOutputFormat::Jpeg(85));
+    let img = image::load_from_memory(&output).map_err(|e| format!("invalid output: {}", e))?;
+    match img.dimensions() {
+        (64, 48) => Ok(format!("{} bytes", output.len())),
+        (w, h) => Err(format!("unexpected output size {}x{}", w, h)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn engines_should_render_test_image() {
+        let spec = ImageSpec::parse(TEST_SPEC).unwrap();
+        assert!(render(Photon::open(TEST_IMAGE, 0).unwrap(), &spec).is_ok());
+        assert!(render(Native::open(TEST_IMAGE, 0).unwrap(), &spec).is_ok());
+        let spec = ImageSpec::parse("resize:w=10,h=10").unwrap();
+        assert!(render(Photon::open(TEST_IMAGE, 0).unwrap(), &spec).is_err());
+
+        let dir = env::temp_dir();
+        assert!(writable(dir.to_str().unwrap()).is_ok());
+        assert!(!dir.join(PROBE_FILE).exists());
+        assert!(writable("/nonexistent/shanbor").is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(bindable(addr).is_err());
+        drop(listener);
+        assert_eq!(bindable(addr), Ok(addr.to_string()));
+    }
+}
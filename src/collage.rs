@@ -0,0 +1,155 @@
+use crate::{
+    config::Config,
+    error::AppError,
+    engine::{encode, Engine, Photon},
+    load_engine,
+    pb::{resize, Spec},
+    tenant::Tenants,
+    Cache,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::{HeaderMap, HeaderValue, StatusCode},
+};
+use image::{imageops, ImageOutputFormat, Rgba, RgbaImage};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::info;
+
+// 一次最多拼接的图片数量
+pub const MAX_IMAGES: usize = 16;
+// 每个格子的最大边长
+pub const MAX_CELL_SIZE: u32 = 1024;
+// 接口本身和缩放、裁剪每张图片用到的操作，disabled_ops 中关闭任何一个都不能使用
+pub const FEATURES: &[&str] = &["collage", "resize", "crop"];
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    // 尽量接近正方形的网格
+    Grid,
+    // 横向一字排开
+    Strip,
+    // 2x2 四宫格，只取前 4 张
+    #[serde(rename = "2x2")]
+    Quad,
+}
+
+#[derive(Deserialize)]
+pub struct CollageRequest {
+    urls: Vec<String>,
+    #[serde(default = "default_layout")]
+    layout: Layout,
+    #[serde(default = "default_cell_size")]
+    cell_width: u32,
+    #[serde(default = "default_cell_size")]
+    cell_height: u32,
+    // 格子之间的间隔
+    #[serde(default)]
+    gap: u32,
+    // 背景色 RGB，默认白色
+    #[serde(default = "default_background")]
+    background: [u8; 3],
+}
+
+fn default_layout() -> Layout {
+    Layout::Grid
+}
+
+fn default_cell_size() -> u32 {
+    300
+}
+
+fn default_background() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+impl Layout {
+    // 根据图片数量计算列数和行数
+    fn shape(self, n: usize) -> (u32, u32) {
+        match self {
+            Layout::Grid => {
+                let cols = (n as f64).sqrt().ceil().max(1.0) as u32;
+                (cols, (n as u32).div_ceil(cols))
+            }
+            Layout::Strip => (n as u32, 1),
+            Layout::Quad => (2, 2),
+        }
+    }
+}
+
+// "POST /collage" 把多张图片按布局拼接成一张
+pub async fn generate_collage(
+    Json(req): Json<CollageRequest>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let tenant = tenants.admit(&req_headers)?;
+    let _permit = crate::overload::OVERLOAD.admit("collage").await?;
+    tenant.check_features(FEATURES)?;
+    let limit = if req.layout == Layout::Quad { 4 } else { MAX_IMAGES };
+    if req.urls.is_empty() || req.urls.len() > limit {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let (cw, ch) = (req.cell_width, req.cell_height);
+    if cw == 0 || ch == 0 || cw > MAX_CELL_SIZE || ch > MAX_CELL_SIZE {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let mut cells = Vec::with_capacity(req.urls.len());
+    for url in req.urls.iter() {
+        let mut engine = load_engine(url, cache.clone(), &config, &tenant).await?;
+        cover(&mut engine, cw, ch);
+        cells.push(engine.to_rgba());
+    }
+
+    let (cols, rows) = req.layout.shape(cells.len());
+    let [r, g, b] = req.background;
+    let mut canvas = RgbaImage::from_pixel(
+        cols * cw + (cols + 1) * req.gap,
+        rows * ch + (rows + 1) * req.gap,
+        Rgba([r, g, b, 255]),
+    );
+    for (i, cell) in cells.iter().enumerate() {
+        let (col, row) = (i as u32 % cols, i as u32 / cols);
+        let x = req.gap + col * (cw + req.gap);
+        let y = req.gap + row * (ch + req.gap);
+        imageops::overlay(&mut canvas, cell, x, y);
+    }
+
+    let image = encode(canvas, ImageOutputFormat::Jpeg(85));
+
+    info!("Finished collage: {} images, size {}", cells.len(), image.len());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("image/jpeg"));
+    Ok((headers, image))
+}
+
+// 等比缩放到刚好覆盖 width x height，再居中裁掉多余的部分
+pub fn cover(engine: &mut Photon, width: u32, height: u32) {
+    let (w, h) = engine.dimensions();
+    let scale = (width as f64 / w as f64).max(height as f64 / h as f64);
+    let sw = ((w as f64 * scale).round() as u32).max(width);
+    let sh = ((h as f64 * scale).round() as u32).max(height);
+    let (x, y) = ((sw - width) / 2, (sh - height) / 2);
+    engine.apply(&[
+        Spec::new_resize(sw, sh, resize::SampleFilter::CatmullRom),
+        Spec::new_crop(x, y, x + width, y + height),
+    ]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_shape_should_fit_all_images() {
+        assert_eq!(Layout::Grid.shape(5), (3, 2));
+        assert_eq!(Layout::Grid.shape(4), (2, 2));
+        assert_eq!(Layout::Strip.shape(3), (3, 1));
+        assert_eq!(Layout::Quad.shape(3), (2, 2));
+    }
+}
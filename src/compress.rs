@@ -0,0 +1,87 @@
+// JSON、文本等非图片响应的压缩（[compression]），按 Accept-Encoding 协商 gzip 或 br
+// 图片本身已经压缩过，再压缩只会浪费 CPU，边编码边发送时还会被缓冲：tower-http 的 CompressionLayer 不会重新压缩
+// 已经设置了 Content-Encoding 的响应，所以在它之前给图片加上标记，之后再去掉
+use axum::{
+    body::HttpBody,
+    http::{header, HeaderValue, Response},
+};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub gzip: bool,
+    pub br: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self { gzip: true, br: true }
+    }
+}
+
+// 放在 CompressionLayer 里面（先执行）。经过 CompressionLayer 之后响应体的长度信息会丢失，没有压缩的响应
+// 会变成 chunked 编码，所以同时写入 Content-Length（压缩时 CompressionLayer 会去掉）
+pub fn mark_images<B: HttpBody>(mut res: Response<B>) -> Response<B> {
+    if let Some(len) = res.body().size_hint().exact() {
+        res.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    }
+    let image = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("image/"));
+    if image && !res.headers().contains_key(header::CONTENT_ENCODING) {
+        res.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static("identity"));
+    }
+    res
+}
+
+// 放在 CompressionLayer 外面，identity 不应该出现在响应中
+pub fn unmark_images<B>(mut res: Response<B>) -> Response<B> {
+    if res.headers().get(header::CONTENT_ENCODING).is_some_and(|v| v == "identity") {
+        res.headers_mut().remove(header::CONTENT_ENCODING);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{Body, HttpBody},
+        http::Request,
+    };
+    use std::convert::Infallible;
+    use tower::{util::MapResponseLayer, ServiceBuilder, ServiceExt};
+    use tower_http::compression::CompressionLayer;
+
+    #[tokio::test]
+    async fn only_non_image_responses_should_be_compressed() {
+        let respond = |content_type: &'static str| {
+            ServiceBuilder::new()
+                .layer(MapResponseLayer::new(unmark_images))
+                .layer(CompressionLayer::new())
+                .layer(MapResponseLayer::new(mark_images))
+                .service_fn(move |_: Request<Body>| async move {
+                    let body = Body::from(vec![b'a'; 4096]);
+                    Ok::<_, Infallible>(Response::builder().header("content-type", content_type).body(body).unwrap())
+                })
+                .oneshot(Request::builder().header("accept-encoding", "gzip").body(Body::empty()).unwrap())
+        };
+
+        let res = respond("application/json").await.unwrap();
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        let mut body = res.into_body();
+        let mut len = 0;
+        while let Some(chunk) = body.data().await {
+            len += chunk.unwrap().len();
+        }
+        assert!(len < 4096);
+
+        let res = respond("image/jpeg").await.unwrap();
+        assert!(!res.headers().contains_key("content-encoding"));
+        assert_eq!(res.headers()["content-length"], "4096");
+        assert_eq!(res.into_body().data().await.unwrap().unwrap().len(), 4096);
+    }
+}
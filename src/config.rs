@@ -0,0 +1,125 @@
+use crate::{compress::CompressionConfig, fallback::FallbackConfig, limits::{AnimationLimits, SourceLimits}, overload::OverloadPolicy, redact::UrlRedaction, pb::{resize::SampleFilter, UnknownPolicy}, peer::PeerSyncConfig, policy::OutputDefaults, publish::PublishConfig, resume::ResumeConfig, shadow::ShadowConfig, sigv4::OriginCredentials, source_cache::SourceCacheConfig, tenant::TenantConfig, uploads::UploadConfig};
+use anyhow::{anyhow, Context, Result};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{collections::HashMap, env, fs, net::SocketAddr};
+
+// 服务的配置，从 SHANBOR_CONFIG 指向的 TOML 文件中加载，所有字段都有默认值
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // 图片等公开接口监听的地址，默认 127.0.0.1:3000
+    pub listen: Option<SocketAddr>,
+    // /admin、/metrics 和 /peer/cache 单独监听的地址，比如只绑定内网网卡，不配置则和公开接口共用 listen
+    pub admin_listen: Option<SocketAddr>,
+    // 校验签名参数（比如 requester）使用的密钥
+    pub signing_key: Option<String>,
+    // 处理结果写入对象存储，不配置则不写
+    pub publish: Option<PublishConfig>,
+    // 是否允许通过 ?debug=true 输出调试用的响应头
+    pub debug_query: bool,
+    // 请求带上 x-shanbor-debug: <debug_token> 时输出调试用的响应头
+    pub debug_token: Option<String>,
+    // 抽样用另一个 engine 处理请求并对比结果，不配置则不开启
+    pub shadow: Option<ShadowConfig>,
+    // 访问管理接口需要的 token，放在 Authorization: Bearer 中（需要开启 admin feature）
+    pub admin_token: Option<String>,
+    // spec 中有不认识的字段或操作时忽略（ignore，默认）还是拒绝（reject）
+    pub unknown_spec_fields: UnknownPolicy,
+    // 是否根据 Sec-CH-Width、Sec-CH-DPR、Save-Data 请求头自动调整尺寸和质量
+    pub client_hints: bool,
+    // 上传的水印素材保存的目录（可以是挂载的 bucket），不配置则只能使用内置的水印
+    pub assets_dir: Option<String>,
+    // 额外加载的字体文件（TTF/OTF）或目录，字体名是小写的文件名（不含扩展名），比如 Brand-Bold.otf 是 brand-bold
+    pub fonts: Vec<String>,
+    // 通过管理接口上传的字体保存的目录，不配置则不能上传
+    pub fonts_dir: Option<String>,
+    // 源图片缓存的有效期，租户可以单独配置
+    pub source_cache: SourceCacheConfig,
+    // 源图片的字节数和像素数限制，租户可以单独配置
+    pub source_limits: SourceLimits,
+    // 源图片下载到一半连接断开时用 Range 请求续传，不配置则直接失败
+    pub fetch_resume: Option<ResumeConfig>,
+    // 访问 S3 兼容源站的凭证，按 URL 前缀匹配，匹配的请求使用 SigV4 签名
+    pub origin_credentials: Vec<OriginCredentials>,
+    // 没有指定 filter 的缩放使用的 filter（nearest、triangle、catmull_rom、gaussian、lanczos3），默认 nearest
+    #[serde(deserialize_with = "sample_filter")]
+    pub default_sample_filter: Option<SampleFilter>,
+    // 源图片下载或者解码失败时返回的备用图片，不配置则返回错误状态码
+    pub fallback: Option<FallbackConfig>,
+    // 日志、追踪和统计中源图片 URL 的输出方式：full（默认）、hash_only 或者 host_only
+    pub log_urls: UrlRedaction,
+    // 副本之间同步源图片缓存，不配置则不开启
+    pub peer_sync: Option<PeerSyncConfig>,
+    // 输出的像素数达到这个值时边编码边发送（没有 ETag），不配置则总是编码完再发送
+    pub stream_min_pixels: Option<u64>,
+    // 动图的帧数、输出大小和帧率限制
+    pub animation: AnimationLimits,
+    // 静态图片默认的输出格式（jpeg 或 png）和 JPEG 质量，不配置则输出质量 85 的 JPEG
+    pub output: OutputDefaults,
+    // 预签名上传，不配置则不开启
+    pub uploads: Option<UploadConfig>,
+    // 按路由（image、collage 等）配置过载时排队还是立刻拒绝，不配置的路由不限制
+    pub overload: HashMap<String, OverloadPolicy>,
+    // JSON 等非图片响应使用的压缩算法，图片总是原样返回
+    pub compression: CompressionConfig,
+    // 关闭的操作（比如 text、seam_carve、watermark_asset 或者 collage 等接口，见 pb::GATED_FEATURES），请求中使用时返回 422
+    pub disabled_ops: Vec<String>,
+    // 多租户配置，为空时所有请求共用上面的配置
+    pub tenants: Vec<TenantConfig>,
+}
+
+fn sample_filter<'de, D: Deserializer<'de>>(d: D) -> Result<Option<SampleFilter>, D::Error> {
+    let name = String::deserialize(d)?;
+    SampleFilter::from_name(&name)
+        .map(Some)
+        .map_err(|e| D::Error::custom(format!("unknown sample filter {}, expected {}", name, e)))
+}
+
+impl Config {
+    // 没有设置 SHANBOR_CONFIG 时使用默认配置
+    pub fn load() -> Result<Self> {
+        match env::var("SHANBOR_CONFIG") {
+            Ok(path) => Self::from_file(&path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    // 公开接口监听的地址，默认 127.0.0.1:3000
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen.unwrap_or_else(|| ([127, 0, 0, 1], 3000).into())
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).with_context(|| format!("read config {}", path))?;
+        let config: Self = toml::from_str(&content).with_context(|| format!("parse config {}", path))?;
+        config.check().with_context(|| format!("invalid config {}", path))?;
+        Ok(config)
+    }
+
+    // 解析之后才能检查的约束
+    fn check(&self) -> Result<()> {
+        for c in &self.origin_credentials {
+            c.check().map_err(|e| anyhow!(e))?;
+        }
+        // 两个地址相同时管理接口会绑定失败
+        if self.admin_listen == Some(self.listen_addr()) {
+            return Err(anyhow!("admin_listen {} is the same as listen", self.listen_addr()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_listen_should_differ_from_listen() {
+        let check = |s: &str| toml::from_str::<Config>(s).unwrap().check();
+        assert!(check("").is_ok());
+        assert!(check("admin_listen = \"127.0.0.1:3001\"").is_ok());
+        assert!(check("admin_listen = \"127.0.0.1:3000\"").is_err());
+        assert!(check("listen = \"0.0.0.0:8080\"\nadmin_listen = \"0.0.0.0:8080\"").is_err());
+        assert!(check("listen = \"0.0.0.0:8080\"\nadmin_listen = \"127.0.0.1:3000\"").is_ok());
+    }
+}
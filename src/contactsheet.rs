@@ -0,0 +1,120 @@
+use crate::{
+    config::Config,
+    error::AppError,
+    engine::{encode, text, Engine},
+    load_engine,
+    pb::{resize, Spec},
+    tenant::Tenants,
+    source_filename, Cache,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::{HeaderMap, HeaderValue, StatusCode},
+};
+use image::{imageops, ImageOutputFormat, Rgba, RgbaImage};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::info;
+
+// 一张 contact sheet 最多包含的图片数量
+pub const MAX_IMAGES: usize = 100;
+// 缩略图的最大边长
+pub const MAX_THUMB_SIZE: u32 = 512;
+// 接口本身和缩略图、文件名说明用到的操作，disabled_ops 中关闭任何一个都不能使用
+pub const FEATURES: &[&str] = &["contactsheet", "resize", "text"];
+// 缩略图之间以及和文字之间的间隔
+const MARGIN: u32 = 12;
+// 说明文字的颜色
+const CAPTION_COLOR: [u8; 4] = [48, 48, 48, 255];
+
+#[derive(Deserialize)]
+pub struct ContactSheetRequest {
+    urls: Vec<String>,
+    #[serde(default = "default_columns")]
+    columns: u32,
+    // 缩略图等比缩放到不超过 thumb_size x thumb_size
+    #[serde(default = "default_thumb_size")]
+    thumb_size: u32,
+    // 说明文字的字号
+    #[serde(default = "default_caption_size")]
+    caption_size: f32,
+}
+
+fn default_columns() -> u32 {
+    4
+}
+
+fn default_thumb_size() -> u32 {
+    200
+}
+
+fn default_caption_size() -> f32 {
+    14.0
+}
+
+// "POST /contactsheet" 生成带文件名说明的缩略图网格
+pub async fn generate_contactsheet(
+    Json(req): Json<ContactSheetRequest>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let tenant = tenants.admit(&req_headers)?;
+    let _permit = crate::overload::OVERLOAD.admit("contactsheet").await?;
+    tenant.check_features(FEATURES)?;
+    if req.urls.is_empty() || req.urls.len() > MAX_IMAGES {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let size = req.thumb_size;
+    if size == 0 || size > MAX_THUMB_SIZE || req.columns == 0 {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let caption_size = req.caption_size.clamp(6.0, 64.0);
+
+    let mut thumbs = Vec::with_capacity(req.urls.len());
+    for url in req.urls.iter() {
+        let mut engine = load_engine(url, cache.clone(), &config, &tenant).await?;
+        let (w, h) = engine.dimensions();
+        let scale = (size as f64 / w as f64).min(size as f64 / h as f64).min(1.0);
+        let sw = ((w as f64 * scale).round() as u32).max(1);
+        let sh = ((h as f64 * scale).round() as u32).max(1);
+        engine.apply(&[Spec::new_resize(sw, sh, resize::SampleFilter::CatmullRom)]);
+        thumbs.push(engine.to_rgba());
+    }
+
+    let cols = req.columns.min(thumbs.len() as u32);
+    let rows = (thumbs.len() as u32).div_ceil(cols);
+    let font = text::builtin();
+    let (_, caption_height) = text::measure(&font, "Ag", caption_size);
+    let cell_w = size + MARGIN;
+    let cell_h = size + MARGIN + caption_height + MARGIN;
+    let mut canvas = RgbaImage::from_pixel(
+        cols * cell_w + MARGIN,
+        rows * cell_h + MARGIN,
+        Rgba([255, 255, 255, 255]),
+    );
+
+    for (i, (thumb, url)) in thumbs.iter().zip(req.urls.iter()).enumerate() {
+        let x = MARGIN + (i as u32 % cols) * cell_w;
+        let y = MARGIN + (i as u32 / cols) * cell_h;
+        // 缩略图在格子里居中
+        let tx = x + (size - thumb.width()) / 2;
+        let ty = y + (size - thumb.height()) / 2;
+        imageops::overlay(&mut canvas, thumb, tx, ty);
+
+        let caption = text::truncate(&font, &source_filename(url), caption_size, size);
+        let (caption_width, _) = text::measure(&font, &caption, caption_size);
+        let cx = x + size.saturating_sub(caption_width) / 2;
+        let cy = y + size + MARGIN;
+        text::draw(&mut canvas, &font, &caption, cx as i32, cy as i32, caption_size, CAPTION_COLOR);
+    }
+
+    let image = encode(canvas, ImageOutputFormat::Jpeg(85));
+
+    info!("Finished contact sheet: {} images, size {}", thumbs.len(), image.len());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("image/jpeg"));
+    Ok((headers, image))
+}
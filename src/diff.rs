@@ -0,0 +1,144 @@
+use crate::{accepts, config::Config, engine::encode, error::AppError, load_engine, tenant::Tenants, Cache};
+use axum::{
+    extract::{Extension, Query},
+    http::{HeaderMap, HeaderValue, StatusCode},
+};
+use image::{imageops, imageops::FilterType, ImageOutputFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+// 每个通道的差值超过这个阈值才认为像素不同，用来忽略编码带来的细微误差
+const THRESHOLD: u8 = 16;
+
+#[derive(Deserialize)]
+pub struct DiffParams {
+    a: String,
+    b: String,
+}
+
+#[derive(Serialize)]
+struct DiffReport {
+    // 相似度，1 表示完全相同
+    similarity: f64,
+    different_pixels: u64,
+    total_pixels: u64,
+    width: u32,
+    height: u32,
+    // base64 编码的 PNG 差异图
+    image: String,
+}
+
+// 对比两张图片：默认返回 JSON（包含相似度和差异图），
+// 客户端要求 image/png 时直接返回差异图，相似度放在响应头中
+pub async fn generate_diff(
+    Query(DiffParams { a, b }): Query<DiffParams>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let tenant = tenants.admit(&req_headers)?;
+    let _permit = crate::overload::OVERLOAD.admit("diff").await?;
+    tenant.check_features(&["diff"])?;
+    let a = load_engine(&a, cache.clone(), &config, &tenant).await?.to_rgba();
+    let b = load_engine(&b, cache, &config, &tenant).await?.to_rgba();
+    // 尺寸不一致时需要缩放
+    if a.dimensions() != b.dimensions() {
+        tenant.check_features(&["resize"])?;
+    }
+
+    let (diff, similarity, different_pixels) = compare(&a, &b);
+    let (width, height) = diff.dimensions();
+    let image = encode(diff, ImageOutputFormat::Png);
+
+    info!("Finished diff: similarity {:.4}", similarity);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("vary", HeaderValue::from_static("accept"));
+
+    if accepts(&req_headers, "image/png") {
+        headers.insert("content-type", HeaderValue::from_static("image/png"));
+        headers.insert(
+            "x-shanbor-similarity",
+            HeaderValue::from_str(&format!("{:.6}", similarity)).unwrap(),
+        );
+        return Ok((headers, image));
+    }
+
+    let report = DiffReport {
+        similarity,
+        different_pixels,
+        total_pixels: width as u64 * height as u64,
+        width,
+        height,
+        image: base64::encode(image),
+    };
+    headers.insert("content-type", HeaderValue::from_static("application/json"));
+    let body = serde_json::to_vec(&report).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((headers, body))
+}
+
+// 逐像素对比，返回差异图、相似度以及不同的像素个数
+// 尺寸不一致时，先把 b 缩放到 a 的尺寸
+pub fn compare(a: &RgbaImage, b: &RgbaImage) -> (RgbaImage, f64, u64) {
+    let resized;
+    let b = if a.dimensions() == b.dimensions() {
+        b
+    } else {
+        resized = imageops::resize(b, a.width(), a.height(), FilterType::Triangle);
+        &resized
+    };
+
+    let mut diff = RgbaImage::new(a.width(), a.height());
+    let mut total: u64 = 0;
+    let mut different: u64 = 0;
+    for ((pa, pb), out) in a.pixels().zip(b.pixels()).zip(diff.pixels_mut()) {
+        let delta = (0..4).map(|c| pa[c].max(pb[c]) - pa[c].min(pb[c]));
+        let max = delta.clone().max().unwrap_or(0);
+        total += delta.take(3).map(|d| d as u64).sum::<u64>();
+
+        out.0 = if max > THRESHOLD {
+            different += 1;
+            [255, 0, 0, 255]
+        } else {
+            // 相同的部分淡化成浅灰色，突出差异
+            let luma = (pa[0] as u32 * 299 + pa[1] as u32 * 587 + pa[2] as u32 * 114) / 1000;
+            let gray = (255 - (255 - luma) / 4) as u8;
+            [gray, gray, gray, 255]
+        };
+    }
+
+    let n = a.width() as u64 * a.height() as u64;
+    let similarity = if n == 0 {
+        1.0
+    } else {
+        1.0 - total as f64 / (n * 3 * 255) as f64
+    };
+    (diff, similarity, different)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn identical_images_should_be_fully_similar() {
+        let a = RgbaImage::from_pixel(4, 4, Rgba([10, 20, 30, 255]));
+        let (_, similarity, different) = compare(&a, &a.clone());
+        assert_eq!(similarity, 1.0);
+        assert_eq!(different, 0);
+    }
+
+    #[test]
+    fn changed_pixels_should_be_counted() {
+        let a = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 255]));
+        let mut b = a.clone();
+        b.put_pixel(0, 0, Rgba([255, 255, 255, 255]));
+        let (diff, similarity, different) = compare(&a, &b);
+        assert_eq!(different, 1);
+        assert_eq!(similarity, 0.75);
+        assert_eq!(diff.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,211 @@
+// 和具体 engine 无关的像素调整，输入都是 RGBA 排列的像素数据
+use image::{imageops, Rgba, RgbaImage};
+
+// 灰度世界假设的自动白平衡：把三个通道的均值拉到一致
+pub fn white_balance(pixels: &mut [u8]) {
+    let mut sum = [0u64; 3];
+    let mut n = 0u64;
+    for p in pixels.chunks_exact(4).filter(|p| p[3] > 0) {
+        for c in 0..3 {
+            sum[c] += p[c] as u64;
+        }
+        n += 1;
+    }
+    if n == 0 {
+        return;
+    }
+    let mean = sum.map(|s| s as f32 / n as f32);
+    let gray = (mean[0] + mean[1] + mean[2]) / 3.0;
+    // 限制增益，避免大面积单色的图片被校正得过头
+    let gain = mean.map(|m| if m > 0.0 { (gray / m).clamp(0.5, 2.0) } else { 1.0 });
+    for p in pixels.chunks_exact_mut(4) {
+        for c in 0..3 {
+            p[c] = (p[c] as f32 * gain[c]).round().min(255.0) as u8;
+        }
+    }
+}
+
+// 自动色阶：按亮度直方图去掉两端 0.5% 的像素，把剩下的范围拉伸到 0~255
+pub fn auto_levels(pixels: &mut [u8]) {
+    let mut hist = [0u64; 256];
+    for p in pixels.chunks_exact(4) {
+        hist[luma(p) as usize] += 1;
+    }
+    let total: u64 = hist.iter().sum();
+    if total == 0 {
+        return;
+    }
+    let clip = total / 200;
+    let lo = percentile(hist.iter(), clip);
+    let hi = 255 - percentile(hist.iter().rev(), clip);
+    if hi <= lo {
+        return;
+    }
+    let scale = 255.0 / (hi - lo) as f32;
+    for p in pixels.chunks_exact_mut(4) {
+        for v in p.iter_mut().take(3) {
+            *v = ((*v as f32 - lo as f32) * scale).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+// 在线性 RGB 空间里对每个像素乘上一个 3x3 的颜色矩阵
+pub fn color_matrix(pixels: &mut [u8], m: &[[f32; 3]; 3]) {
+    let decode: Vec<f32> = (0..256).map(|v| to_linear(v as f32 / 255.0)).collect();
+    for p in pixels.chunks_exact_mut(4) {
+        let rgb = [decode[p[0] as usize], decode[p[1] as usize], decode[p[2] as usize]];
+        for (c, row) in m.iter().enumerate() {
+            let v = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+            p[c] = (to_srgb(v.clamp(0.0, 1.0)) * 255.0).round() as u8;
+        }
+    }
+}
+
+fn to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// 按比例混合两份像素：dst = dst * (1 - amount) + src * amount，alpha 保持不变
+pub fn mix(dst: &mut [u8], src: &[u8], amount: f32) {
+    for (d, s) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        for c in 0..3 {
+            d[c] = (d[c] as f32 * (1.0 - amount) + s[c] as f32 * amount).round() as u8;
+        }
+    }
+}
+
+// 色相和 hue 相差不超过 tolerance 的像素保留原来的颜色，其余的变成灰度；
+// 在 tolerance 之外再留一半的宽度逐渐过渡，避免边缘出现明显的色块
+pub fn color_pop(pixels: &mut [u8], hue: f32, tolerance: f32) {
+    let feather = tolerance * 0.5;
+    for p in pixels.chunks_exact_mut(4) {
+        let keep = match pixel_hue(p) {
+            Some(h) => {
+                let d = (h - hue).abs() % 360.0;
+                let d = d.min(360.0 - d);
+                1.0 - ((d - tolerance) / feather).clamp(0.0, 1.0)
+            }
+            None => 0.0,
+        };
+        let gray = luma(p) as f32;
+        for v in &mut p[..3] {
+            *v = (gray + (*v as f32 - gray) * keep).round() as u8;
+        }
+    }
+}
+
+// HSV 的色相（度），灰色没有色相
+fn pixel_hue(p: &[u8]) -> Option<f32> {
+    let (r, g, b) = (p[0] as f32, p[1] as f32, p[2] as f32);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta == 0.0 {
+        return None;
+    }
+    let h = if max == r {
+        (g - b) / delta
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    Some((h * 60.0).rem_euclid(360.0))
+}
+
+// 分别模糊每个区域 (x1, y1, x2, y2)，区域外的像素不参与计算，也不会被改变
+pub fn blur_regions(pixels: &mut [u8], width: u32, regions: &[(u32, u32, u32, u32)], sigma: f32) {
+    let index = |x: u32, y: u32| (y as usize * width as usize + x as usize) * 4;
+    for &(x1, y1, x2, y2) in regions {
+        let area = RgbaImage::from_fn(x2 - x1, y2 - y1, |x, y| {
+            let i = index(x1 + x, y1 + y);
+            Rgba([pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]])
+        });
+        for (x, y, p) in imageops::blur(&area, sigma).enumerate_pixels() {
+            let i = index(x1 + x, y1 + y);
+            pixels[i..i + 4].copy_from_slice(&p.0);
+        }
+    }
+}
+
+// 亮度（Rec. 601）
+pub fn luma(p: &[u8]) -> u8 {
+    ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114) / 1000) as u8
+}
+
+// 累计数量超过 clip 时的下标
+fn percentile<'a>(hist: impl Iterator<Item = &'a u64>, clip: u64) -> u8 {
+    let mut acc = 0;
+    for (i, &count) in hist.enumerate() {
+        acc += count;
+        if acc > clip {
+            return i as u8;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_levels_should_stretch_to_full_range() {
+        let mut pixels: Vec<u8> = (0..100u8)
+            .flat_map(|i| {
+                let v = 100 + i / 2;
+                [v, v, v, 255]
+            })
+            .collect();
+        auto_levels(&mut pixels);
+        assert_eq!(pixels[0], 0);
+        assert_eq!(pixels[pixels.len() - 4], 255);
+    }
+
+    #[test]
+    fn color_pop_should_keep_selected_hue() {
+        // 红、绿、蓝、接近红色的橙红
+        let mut pixels = vec![255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 255, 60, 0, 255];
+        color_pop(&mut pixels, 350.0, 20.0);
+        assert_eq!(&pixels[0..4], &[255, 0, 0, 255]);
+        assert_eq!(&pixels[4..8], &[149, 149, 149, 255]);
+        assert_eq!(&pixels[8..12], &[29, 29, 29, 255]);
+        // 色相 14 度，在过渡范围内
+        assert!(pixels[12] < 255 && pixels[12] > pixels[13]);
+    }
+
+    #[test]
+    fn only_regions_should_be_blurred() {
+        let img = RgbaImage::from_fn(20, 10, |x, y| {
+            let v = if (x + y) % 2 == 0 { 0 } else { 255 };
+            Rgba([v, v, v, 255])
+        });
+        let mut pixels = img.clone().into_raw();
+        blur_regions(&mut pixels, 20, &[(0, 0, 8, 8), (12, 2, 20, 10)], 2.0);
+        let blurred = RgbaImage::from_raw(20, 10, pixels).unwrap();
+        assert_eq!(blurred.get_pixel(10, 5), img.get_pixel(10, 5));
+        assert_eq!(blurred.get_pixel(3, 9), img.get_pixel(3, 9));
+        let v = blurred.get_pixel(4, 4)[0];
+        assert!((64..192).contains(&v));
+        assert!((64..192).contains(&blurred.get_pixel(15, 5)[0]));
+    }
+
+    #[test]
+    fn white_balance_should_neutralize_color_cast() {
+        let mut pixels = vec![200, 100, 100, 255, 100, 50, 50, 255];
+        white_balance(&mut pixels);
+        let (r, g) = (pixels[0], pixels[1]);
+        assert!((r as i32 - g as i32).abs() <= 1);
+    }
+}
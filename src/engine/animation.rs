@@ -0,0 +1,180 @@
+use super::{sniff, DecodeError, Engine, Photon, SourceFormat};
+use crate::pb::Spec;
+use image::{
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    AnimationDecoder, Delay, Frame, ImageDecoder, ImageResult,
+};
+use std::io::{self, Write};
+
+// 动图默认最多处理的帧数，防止很小的文件展开成大量的帧
+pub const MAX_FRAMES: usize = 300;
+// 动图默认所有帧合计最多的像素数（百万像素）。每一帧都合成为完整的 RGBA 画面，内存按帧数 × 宽 × 高增长
+pub const MAX_ANIMATION_MEGAPIXELS: f64 = 100.0;
+// GIF 量化颜色的速度（1 ~ 30），1 最慢质量最好
+const GIF_SPEED: i32 = 10;
+
+// 解码动图的所有帧，每一帧都已经合成为完整的画面
+// 不是 GIF 或者只有一帧时返回 None，按静态图片处理
+// 帧数超出 max_frames，或者已经解码的帧合计超出 max_pixels 个像素时立即停止
+// image 0.23 还不能解码 WebP 动图，这类图片只处理第一帧
+pub fn decode_frames(data: &[u8], max_frames: usize, max_pixels: u64) -> Result<Option<Vec<Frame>>, DecodeError> {
+    if sniff(data) != Some(SourceFormat::Gif) {
+        return Ok(None);
+    }
+    let invalid = |e: &dyn std::fmt::Display| DecodeError::Invalid(SourceFormat::Gif, e.to_string());
+    let decoder = GifDecoder::new(data).map_err(|e| invalid(&e))?;
+    let (width, height) = decoder.dimensions();
+    let frame_pixels = width as u64 * height as u64;
+    let too_many_pixels = || invalid(&format!("frames of {}x{} use more than {} pixels", width, height, max_pixels));
+    if frame_pixels > max_pixels {
+        return Err(too_many_pixels());
+    }
+    let mut frames = vec![];
+    for frame in decoder.into_frames() {
+        if frames.len() == max_frames {
+            return Err(invalid(&format!("more than {} frames", max_frames)));
+        }
+        if (frames.len() as u64 + 1) * frame_pixels > max_pixels {
+            return Err(too_many_pixels());
+        }
+        frames.push(frame.map_err(|e| invalid(&e))?);
+    }
+    Ok(if frames.len() > 1 { Some(frames) } else { None })
+}
+
+// 把帧率限制在 max_fps 以内：一帧显示的时间不到 1/max_fps 秒时合并后面的帧，总时长不变
+pub fn decimate_frames(frames: Vec<Frame>, max_fps: f64) -> Vec<Frame> {
+    let min_ms = 1000.0 / max_fps;
+    let mut kept: Vec<(Frame, f64)> = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let ms = numer as f64 / denom as f64;
+        match kept.last_mut() {
+            Some((_, total)) if *total < min_ms => *total += ms,
+            _ => kept.push((frame, ms)),
+        }
+    }
+    kept.into_iter()
+        .map(|(frame, ms)| {
+            let delay = Delay::from_numer_denom_ms(ms.round() as u32, 1);
+            Frame::from_parts(frame.into_buffer(), 0, 0, delay)
+        })
+        .collect()
+}
+
+// 对每一帧应用同样的 specs，保留原来的帧间隔
+pub fn transform_frames(frames: Vec<Frame>, specs: &[Spec]) -> Vec<Frame> {
+    frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            let mut engine = Photon::from_rgba(frame.into_buffer());
+            engine.apply(specs);
+            Frame::from_parts(engine.to_rgba(), 0, 0, delay)
+        })
+        .collect()
+}
+
+// 编码成循环播放的 GIF
+pub fn encode_gif(frames: Vec<Frame>) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(32768);
+    encode_gif_into(frames, &mut buffer).unwrap();
+    buffer
+}
+
+// 编码结果超出 max_bytes 时立即停止并返回 None
+pub fn encode_gif_within(frames: Vec<Frame>, max_bytes: usize) -> Option<Vec<u8>> {
+    let mut out = LimitedWriter {
+        buffer: Vec::with_capacity(32768),
+        max_bytes,
+        exceeded: false,
+    };
+    // GIF 的结束标记在 encoder drop 时写入，错误会被忽略，所以另外记录是否超出
+    encode_gif_into(frames, &mut out).ok()?;
+    (!out.exceeded).then_some(out.buffer)
+}
+
+fn encode_gif_into(frames: Vec<Frame>, out: &mut dyn Write) -> ImageResult<()> {
+    let mut encoder = GifEncoder::new_with_speed(out, GIF_SPEED);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(frames)
+}
+
+struct LimitedWriter {
+    buffer: Vec<u8>,
+    max_bytes: usize,
+    exceeded: bool,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.max_bytes {
+            self.exceeded = true;
+            return Err(io::Error::other("output is too large"));
+        }
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::{resize::SampleFilter, Spec};
+    use image::{Delay, Rgba, RgbaImage};
+
+    // 生成每帧颜色不同的动图
+    fn sample_gif(colors: &[[u8; 4]], delay_ms: u32) -> Vec<u8> {
+        let frames = colors.iter().map(|&c| {
+            let img = RgbaImage::from_pixel(16, 12, Rgba(c));
+            Frame::from_parts(img, 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+        });
+        encode_gif(frames.collect())
+    }
+
+    #[test]
+    fn every_frame_should_be_transformed() {
+        let data = sample_gif(&[[255, 0, 0, 255], [0, 0, 255, 255], [0, 255, 0, 255]], 120);
+        let frames = decode_frames(&data, MAX_FRAMES, u64::MAX).unwrap().unwrap();
+        assert_eq!(frames.len(), 3);
+
+        let frames = transform_frames(frames, &[Spec::new_resize(8, 6, SampleFilter::Nearest)]);
+        let output = decode_frames(&encode_gif(frames), MAX_FRAMES, u64::MAX).unwrap().unwrap();
+        assert_eq!(output.len(), 3);
+        for (frame, expected) in output.iter().zip([[255, 0, 0], [0, 0, 255]]) {
+            assert_eq!(frame.buffer().dimensions(), (8, 6));
+            assert_eq!(frame.delay().numer_denom_ms(), (120, 1));
+            assert_eq!(frame.buffer().get_pixel(3, 3).0[..3], expected);
+        }
+    }
+
+    #[test]
+    fn static_images_should_not_be_animated() {
+        assert!(decode_frames(&sample_gif(&[[1, 2, 3, 255]], 100), MAX_FRAMES, u64::MAX).unwrap().is_none());
+        assert!(decode_frames(b"\x89PNG\r\n\x1a\n", MAX_FRAMES, u64::MAX).unwrap().is_none());
+    }
+
+    #[test]
+    fn animation_limits_should_bound_output() {
+        let colors: Vec<[u8; 4]> = (0..10).map(|i| [i * 20, 0, 0, 255]).collect();
+        let data = sample_gif(&colors, 20);
+        assert!(decode_frames(&data, 9, u64::MAX).is_err());
+        // 每一帧 16x12 = 192 个像素
+        assert!(decode_frames(&data, 10, 192 * 10 - 1).is_err());
+        assert!(decode_frames(&data, 10, 191).is_err());
+        let frames = decode_frames(&data, 10, 192 * 10).unwrap().unwrap();
+
+        // 50fps 限制到 10fps：每 5 帧合并成一帧，总时长不变
+        let frames = decimate_frames(frames, 10.0);
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|f| f.delay().numer_denom_ms() == (100, 1)));
+        assert_eq!(frames[1].buffer().get_pixel(0, 0).0[0], 100);
+
+        let gif = encode_gif(frames.clone());
+        assert_eq!(encode_gif_within(frames.clone(), gif.len()), Some(gif.clone()));
+        assert!(encode_gif_within(frames, gif.len() - 1).is_none());
+    }
+}
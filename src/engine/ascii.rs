@@ -0,0 +1,111 @@
+use image::{imageops, imageops::FilterType, RgbaImage};
+
+// 由暗到亮的字符，越暗的像素用越"密"的字符
+const RAMP: &[u8] = b"@%#*+=-:. ";
+
+// 字符画输出的选项
+#[derive(Debug, Clone, Copy)]
+pub struct TextArt {
+    // 每行字符数
+    pub columns: u32,
+    // 使用 ANSI 真彩色输出，否则输出纯 ASCII
+    pub ansi: bool,
+}
+
+// 把图片渲染成字符画
+pub fn render(img: &RgbaImage, opts: TextArt) -> String {
+    let columns = opts.columns.clamp(1, img.width().max(1));
+    // 终端字符的高度大约是宽度的两倍
+    let rows = ((img.height() as f32 * columns as f32 / img.width().max(1) as f32) / 2.0)
+        .round()
+        .max(1.0) as u32;
+
+    if opts.ansi {
+        // 每个字符用上半块 "▀" 表示两行像素：前景色是上面的像素，背景色是下面的像素
+        let small = imageops::resize(img, columns, rows * 2, FilterType::Triangle);
+        let mut out = String::with_capacity((columns * rows * 40) as usize);
+        for y in 0..rows {
+            for x in 0..columns {
+                let t = flatten(small.get_pixel(x, y * 2).0);
+                let b = flatten(small.get_pixel(x, y * 2 + 1).0);
+                out.push_str(&format!(
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                    t[0], t[1], t[2], b[0], b[1], b[2]
+                ));
+            }
+            out.push_str("\x1b[0m\n");
+        }
+        out
+    } else {
+        let small = imageops::resize(img, columns, rows, FilterType::Triangle);
+        let mut out = String::with_capacity(((columns + 1) * rows) as usize);
+        for y in 0..rows {
+            for x in 0..columns {
+                let p = flatten(small.get_pixel(x, y).0);
+                let luma = 0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32;
+                let idx = (luma / 256.0 * RAMP.len() as f32) as usize;
+                out.push(RAMP[idx.min(RAMP.len() - 1)] as char);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+// 透明的部分按白色背景处理
+fn flatten(p: [u8; 4]) -> [u8; 3] {
+    let a = p[3] as u32;
+    let c = |v: u8| ((v as u32 * a + 255 * (255 - a)) / 255) as u8;
+    [c(p[0]), c(p[1]), c(p[2])]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn plain(img: &RgbaImage, columns: u32) -> Vec<String> {
+        render(img, TextArt { columns, ansi: false }).lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn text_art_should_keep_aspect_ratio() {
+        // 列数不超过图片宽度，至少一列
+        let img = RgbaImage::from_pixel(4, 8, Rgba([0, 0, 0, 255]));
+        assert!(plain(&img, 80).iter().all(|l| l.len() == 4));
+        assert!(plain(&img, 0).iter().all(|l| l.len() == 1));
+        // 行数按字符高度是宽度的两倍计算，至少一行
+        let img = RgbaImage::from_pixel(40, 20, Rgba([0, 0, 0, 255]));
+        assert_eq!(plain(&img, 40).len(), 10);
+        assert_eq!(plain(&img, 20).len(), 5);
+        assert_eq!(plain(&RgbaImage::new(10, 1), 10).len(), 1);
+    }
+
+    #[test]
+    fn text_art_should_map_brightness_to_ramp() {
+        let black = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 255]));
+        assert_eq!(plain(&black, 2), vec!["@@"]);
+        let white = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]));
+        assert_eq!(plain(&white, 2), vec!["  "]);
+        // 透明的像素按白色处理
+        assert_eq!(plain(&RgbaImage::new(2, 2), 2), vec!["  "]);
+        assert_eq!(flatten([0, 0, 0, 0]), [255, 255, 255]);
+        assert_eq!(flatten([10, 20, 30, 255]), [10, 20, 30]);
+    }
+
+    #[test]
+    fn ansi_text_art_should_use_half_blocks() {
+        // 上面两行红色，下面两行蓝色：每个字符的前景色是上面的像素，背景色是下面的像素
+        let img = RgbaImage::from_fn(2, 4, |_, y| if y < 2 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 255, 255]) });
+        let out = render(&img, TextArt { columns: 2, ansi: true });
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert_eq!(line.matches('\u{2580}').count(), 2);
+            assert!(line.ends_with("\x1b[0m"));
+        }
+        assert!(lines[0].starts_with("\x1b[38;2;255;0;0m\x1b[48;2;255;0;0m\u{2580}"));
+        assert!(lines[1].starts_with("\x1b[38;2;0;0;255m\x1b[48;2;0;0;255m\u{2580}"));
+        assert!(out.ends_with("\x1b[0m\n"));
+    }
+}
@@ -1,20 +1,287 @@
 use super::pb::Spec;
-use image::ImageOutputFormat;
+use image::{
+    codecs::{
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilter, PngEncoder},
+    },
+    imageops,
+    imageops::FilterType,
+    ColorType, DynamicImage, ImageOutputFormat, ImageResult, RgbaImage,
+};
+use std::io::Write;
 
+mod adjust;
+mod animation;
+mod ascii;
+mod multipage;
+mod native;
+mod overlay;
 mod photon;
+mod probe;
+mod sniff;
+pub mod stego;
+pub mod text;
+pub use animation::{
+    decimate_frames, decode_frames, encode_gif, encode_gif_within, transform_frames, MAX_ANIMATION_MEGAPIXELS, MAX_FRAMES,
+};
+pub use ascii::TextArt;
+pub use native::Native;
 pub use photon::Photon;
+pub use probe::{exif, probe, Exif};
+pub use sniff::{sniff, SourceFormat};
+
+// 解码源图片时的错误
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    // 文件头不是任何支持的图片格式
+    #[error("unsupported image format")]
+    Unsupported,
+    // 格式可以识别，但内容有问题，或者选择的页不存在
+    #[error("invalid {0} image: {1}")]
+    Invalid(SourceFormat, String),
+}
 
 // Engine trait: 未来可以添加更多的 engine，只需在主流程中替换 engine
 pub trait Engine {
+    // engine 的名字，用于调试信息
+    fn name(&self) -> &'static str;
+
     // 对 engine 按照 specs 进行一系列有序的处理
     fn apply(&mut self, specs: &[Spec]);
 
     // 从 engine 中生成目标图片，注意这里用的 self，非 self 的引用
     fn generate(self, format: ImageOutputFormat) -> Vec<u8>;
+
+    // 把目标图片边编码边写入 out，很大的输出不需要先放在内存里
+    fn generate_into(self, format: ImageOutputFormat, out: &mut dyn Write) -> ImageResult<()>;
+
+    // 从 engine 中生成字符画，用于终端里预览图片
+    fn generate_text(self, opts: TextArt) -> String;
 }
 
 // SpecTransform: 未来如果添加更多的 spec，只需要实现它即可
 pub trait SpecTransform<T> {
     // 对图片使用 op 做 transform
     fn transform(&mut self, op: T);
-}
\ No newline at end of file
+}
+// 按文件头识别的格式解码图片，所有 engine 共用
+// page 用于选择多页 TIFF 中的某一页
+pub fn decode(data: &[u8], page: u32) -> Result<RgbaImage, DecodeError> {
+    let format = sniff(data).ok_or(DecodeError::Unsupported)?;
+    let invalid = |e: &dyn std::fmt::Display| DecodeError::Invalid(format, e.to_string());
+    let img = match format {
+        SourceFormat::Tiff => multipage::decode_page(data, page).map_err(|e| invalid(&e))?,
+        _ if page > 0 => return Err(invalid(&"page selection is only supported for tiff")),
+        _ => image::load_from_memory_with_format(data, format.into())
+            .map_err(|e| invalid(&e))?
+            .to_rgba8(),
+    };
+    Ok(img)
+}
+
+// 只读取文件头得到宽高，用于在完整解码之前检查尺寸限制
+pub fn dimensions(data: &[u8], page: u32) -> Result<(u32, u32), DecodeError> {
+    let format = sniff(data).ok_or(DecodeError::Unsupported)?;
+    let invalid = |e: &dyn std::fmt::Display| DecodeError::Invalid(format, e.to_string());
+    match format {
+        SourceFormat::Tiff => multipage::page_dimensions(data, page).map_err(|e| invalid(&e)),
+        _ => image::io::Reader::with_format(std::io::Cursor::new(data), format.into())
+            .into_dimensions()
+            .map_err(|e| invalid(&e)),
+    }
+}
+
+// 输出的版本号：同样的源图片、spec 和版本号总是得到完全相同的字节
+// 任何会改变输出的修改（算法、编码参数、依赖升级）都需要增加这个版本号
+pub const ENGINE_VERSION: &str = "1";
+
+// 把像素数据编码成目标格式，各个 engine 以及拼接、对比等功能共用
+// 编码参数都是固定的，不写入时间戳之类的元数据，保证输出可以复现
+pub fn encode(img: RgbaImage, format: ImageOutputFormat) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(32768);
+    // 写入内存不会失败
+    encode_into(img, format, &mut buffer).unwrap();
+    buffer
+}
+
+// 和 encode 相同，但编码结果直接写入 out（比如发送给客户端的 body）
+pub fn encode_into(img: RgbaImage, format: ImageOutputFormat, mut out: &mut dyn Write) -> ImageResult<()> {
+    let (width, height) = img.dimensions();
+    match format {
+        ImageOutputFormat::Jpeg(quality) => JpegEncoder::new_with_quality(&mut out, quality)
+            .encode(img.as_raw(), width, height, ColorType::Rgba8),
+        ImageOutputFormat::Png => PngEncoder::new_with_quality(out, CompressionType::Fast, PngFilter::Sub)
+            .encode(img.as_raw(), width, height, ColorType::Rgba8),
+        format => DynamicImage::ImageRgba8(img).write_to(&mut out, format),
+    }
+}
+
+// 按字节预算输出时允许的最低 JPEG 质量
+const MIN_QUALITY: u8 = 10;
+// 缩小尺寸的最多轮数
+const MAX_DOWNSCALE_ROUNDS: usize = 6;
+
+// 在 max_bytes 的预算内编码 JPEG：先二分查找不超过 quality 的最高质量，
+// 最低质量仍然超出预算时，如果允许则按比例缩小尺寸再试。返回编码结果和最终使用的质量
+// quality 本身低于 MIN_QUALITY 时（比如策略的 max_quality 更低）以它为最低质量，不会提高质量
+pub fn encode_within(
+    mut img: RgbaImage,
+    quality: u8,
+    max_bytes: usize,
+    downscale: bool,
+) -> Option<(Vec<u8>, u8)> {
+    let min_quality = MIN_QUALITY.min(quality);
+    for _ in 0..MAX_DOWNSCALE_ROUNDS {
+        let best = encode(img.clone(), ImageOutputFormat::Jpeg(quality));
+        if best.len() <= max_bytes {
+            return Some((best, quality));
+        }
+        let worst = encode(img.clone(), ImageOutputFormat::Jpeg(min_quality));
+        if worst.len() <= max_bytes {
+            // 不变式：lo 满足预算，hi 不满足
+            let (mut lo, mut hi, mut found) = (min_quality, quality, worst);
+            while hi - lo > 1 {
+                let mid = lo + (hi - lo) / 2;
+                let buf = encode(img.clone(), ImageOutputFormat::Jpeg(mid));
+                if buf.len() <= max_bytes {
+                    lo = mid;
+                    found = buf;
+                } else {
+                    hi = mid;
+                }
+            }
+            return Some((found, lo));
+        }
+        if !downscale || img.width() <= 1 || img.height() <= 1 {
+            return None;
+        }
+        // 文件大小大致和像素数成正比
+        let ratio = ((max_bytes as f64 / worst.len() as f64).sqrt() * 0.95).clamp(0.1, 0.9);
+        let width = ((img.width() as f64 * ratio) as u32).max(1);
+        let height = ((img.height() as f64 * ratio) as u32).max(1);
+        img = imageops::resize(&img, width, height, FilterType::Triangle);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noisy(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            let v = ((x * 7919 + y * 104_729) % 251) as u8;
+            image::Rgba([v, v.wrapping_mul(3), v.wrapping_mul(7), 255])
+        })
+    }
+
+    #[test]
+    fn encoded_image_should_fit_byte_budget() {
+        let img = noisy(128, 128);
+        let full = encode(img.clone(), ImageOutputFormat::Jpeg(85)).len();
+        let (buf, quality) = encode_within(img.clone(), 85, full / 2, false).unwrap();
+        assert!(buf.len() <= full / 2);
+        assert!((MIN_QUALITY..85).contains(&quality));
+
+        assert!(encode_within(img.clone(), 85, 1000, false).is_none());
+        let (buf, _) = encode_within(img.clone(), 85, 1000, true).unwrap();
+        assert!(buf.len() <= 1000);
+
+        // 低于 MIN_QUALITY 的质量不会下溢，也不会被提高
+        let low = encode(img.clone(), ImageOutputFormat::Jpeg(5)).len();
+        assert!(encode_within(img.clone(), 5, low - 1, false).is_none());
+        let (_, quality) = encode_within(img.clone(), 5, 1000, true).unwrap();
+        assert_eq!(quality, 5);
+        let (_, quality) = encode_within(img, 1, full, false).unwrap();
+        assert_eq!(quality, 1);
+    }
+
+    #[test]
+    fn validated_specs_should_not_panic() {
+        let ops = [
+            "resize:w={},h={}",
+            "resize:w={},h={},type=seam_carve",
+            "crop:x1={},y1={},x2={},y2={}",
+            "watermark:x={},y={}",
+            "watermark:mode=tiled,spacing={},angle={},opacity={}",
+            "text:\"hi\",x={},y={},size={}",
+            "lqip:{},q={}",
+            "contrast:{}",
+            "invisible_watermark:{},strength={}",
+            "blur_regions:{}:{}:{}:{}|{}:{}:{}:{},sigma={}",
+            "color_pop:{},tolerance={}",
+            "caption:\"hi\",size={}",
+            "text:\"a\",y={}",
+        ];
+        let numbers = ["0", "1", "3", "31", "47", "100", "2147483647", "4294967295"];
+        let source = encode(noisy(32, 24), ImageOutputFormat::Png);
+        let mut rng = 0x9E37_79B9_7F4A_7C15;
+        for _ in 0..300 {
+            let mut spec = ops[crate::pb::tests::xorshift(&mut rng) as usize % ops.len()].to_owned();
+            while spec.contains("{}") {
+                let n = numbers[crate::pb::tests::xorshift(&mut rng) as usize % numbers.len()];
+                spec = spec.replacen("{}", n, 1);
+            }
+            // 只有通过校验的 spec 才会交给 engine，这些 spec 不能让 engine panic
+            if let Ok(spec) = crate::pb::ImageSpec::parse(&spec) {
+                let mut photon = Photon::open(&source, 0).unwrap();
+                photon.apply(&spec.specs);
+                let mut native = Native::open(&source, 0).unwrap();
+                native.apply(&spec.specs);
+            }
+        }
+    }
+
+    // CDN 上缓存着大量旧的 URL，这些 spec 字符串（没有 version 字段）的输出不能改变
+    // 如果有意改变了输出，需要升级 SPEC_VERSION 并让旧版本保持原来的行为
+    const LEGACY_SPECS: &[(&str, &str)] = &[
+        ("CggKBggwECAgBQoEMgIIAQ", "8ab34ccdbc49806a12aa5468c7ff4ddd2f5e631ced099705811bf6a137824ca9"),
+        ("CgYSBBgoIB4KAiIACgcqBQ0AAPBB", "2746f8f1102f25bdd00692c00513d30cd610f43d5389aed9042fce1da465ee4f"),
+        ("CgY6BAgEEAQKFUITCgJ2MBACGAIlAABAQSj_gYD4Dw", "49c682c9859859af91fad2c4440ded194230c7ba480ebbf9924b3083707db129"),
+        ("CggKBggyECgYAQoEWgIIAgoEYgIIBw", "76c239b6d91ac15fbbc1c40025a7725baab290c21daadcc1dd2d2703bde6a394"),
+        ("CgZSBAgQEAEKAhoACgJKAA", "a6612d4e55e8673b36c577488cd11952185a3f73b22770483aeefdb5b4d3863b"),
+        // 带 padding 的旧 URL
+        ("CgZSBAgQEAEKAhoACgJKAA==", "a6612d4e55e8673b36c577488cd11952185a3f73b22770483aeefdb5b4d3863b"),
+        ("ChA6DhgBIAgtAADwQTUAAAA_CgQyAggD", "555d421400f535bf00478cdb4d5237fdd2cbf1eae6a242020b597e7813ac3b29"),
+    ];
+
+    #[test]
+    fn legacy_spec_strings_should_produce_identical_output() {
+        use crate::pb::{ImageSpec, SPEC_VERSION};
+        use sha2::{Digest, Sha256};
+
+        let source = encode(noisy(64, 48), ImageOutputFormat::Png);
+        let run = |spec: &ImageSpec| {
+            let mut engine = Photon::open(&source, spec.page).unwrap();
+            engine.apply(&spec.specs);
+            hex::encode(Sha256::digest(engine.generate(ImageOutputFormat::Png)))
+        };
+        for (s, expected) in LEGACY_SPECS {
+            let mut spec = ImageSpec::parse(s).unwrap();
+            assert_eq!(spec.version, 0);
+            assert_eq!(&run(&spec), expected, "spec {}", s);
+            // 版本 1 和没有版本号的 spec 语义相同
+            spec.version = SPEC_VERSION;
+            assert_eq!(&run(&spec), expected, "spec {} with version", s);
+        }
+    }
+
+    #[test]
+    fn same_input_should_produce_identical_bytes() {
+        let source = encode(noisy(300, 200), ImageOutputFormat::Png);
+        let specs = vec![
+            Spec::new_resize(240, 160, crate::pb::resize::SampleFilter::Lanczos3),
+            Spec::new_filter(crate::pb::filter::Filter::Marine),
+            Spec::new_auto_enhance(),
+            Spec::new_text("shanbor", 10, 10, 20.0, 0),
+            Spec::new_invisible_watermark(42),
+        ];
+        let run = || {
+            let mut engine = Photon::open(&source, 0).unwrap();
+            engine.apply(&specs);
+            engine.generate(ImageOutputFormat::Jpeg(85))
+        };
+        assert_eq!(run(), run());
+        assert_eq!(encode(noisy(64, 64), ImageOutputFormat::Png), encode(noisy(64, 64), ImageOutputFormat::Png));
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{anyhow, bail, Result};
+use image::RgbaImage;
+use std::io::Cursor;
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    ColorType,
+};
+
+// 指定页的宽高，只读取文件头，不解码像素
+pub fn page_dimensions(data: &[u8], page: u32) -> Result<(u32, u32)> {
+    let mut decoder = Decoder::new(Cursor::new(data))?;
+    for i in 0..page {
+        if !decoder.more_images() {
+            bail!("tiff has only {} page(s), page {} requested", i + 1, page);
+        }
+        decoder.next_image()?;
+    }
+    Ok(decoder.dimensions()?)
+}
+
+// image crate 只会解码 TIFF 的第一页，这里直接用 tiff crate 定位到指定页
+pub fn decode_page(data: &[u8], page: u32) -> Result<RgbaImage> {
+    let mut decoder = Decoder::new(Cursor::new(data))?;
+    for i in 0..page {
+        if !decoder.more_images() {
+            bail!("tiff has only {} page(s), page {} requested", i + 1, page);
+        }
+        decoder.next_image()?;
+    }
+
+    let (width, height) = decoder.dimensions()?;
+    let color = decoder.colortype()?;
+    // 16 位的通道只保留高 8 位
+    let samples: Vec<u8> = match decoder.read_image()? {
+        DecodingResult::U8(v) => v,
+        DecodingResult::U16(v) => v.into_iter().map(|s| (s >> 8) as u8).collect(),
+        _ => bail!("unsupported tiff sample format"),
+    };
+
+    let rgba = match color {
+        ColorType::Gray(_) => samples.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+        ColorType::GrayA(_) => samples
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        ColorType::RGB(_) => samples
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        ColorType::RGBA(_) => samples,
+        ColorType::CMYK(_) => samples
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let k = 255 - p[3] as u32;
+                let c = |v: u8| ((255 - v as u32) * k / 255) as u8;
+                [c(p[0]), c(p[1]), c(p[2]), 255]
+            })
+            .collect(),
+        other => bail!("unsupported tiff color type {:?}", other),
+    };
+
+    RgbaImage::from_raw(width, height, rgba).ok_or_else(|| anyhow!("tiff pixel data truncated"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    fn two_page_tiff() -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        encoder
+            .write_image::<colortype::Gray8>(2, 2, &[10, 10, 10, 10])
+            .unwrap();
+        encoder
+            .write_image::<colortype::RGB8>(1, 1, &[1, 2, 3])
+            .unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn selected_tiff_page_should_be_decoded() {
+        let data = two_page_tiff();
+        let first = decode_page(&data, 0).unwrap();
+        assert_eq!(first.dimensions(), (2, 2));
+        assert_eq!(first.get_pixel(0, 0).0, [10, 10, 10, 255]);
+        let second = decode_page(&data, 1).unwrap();
+        assert_eq!(second.dimensions(), (1, 1));
+        assert_eq!(second.get_pixel(0, 0).0, [1, 2, 3, 255]);
+        assert!(decode_page(&data, 2).is_err());
+    }
+}
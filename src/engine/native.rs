@@ -0,0 +1,270 @@
+use super::{
+    adjust, ascii, decode, encode, encode_into, overlay, stego, text, DecodeError, Engine, SpecTransform,
+    TextArt,
+};
+use crate::{assets::ASSETS, pb::*};
+use image::{imageops, imageops::FilterType, ImageOutputFormat, ImageResult, RgbaImage};
+use lazy_static::lazy_static;
+use std::io::Write;
+
+lazy_static! {
+    // 和 Photon 使用同一个水印文件
+    static ref WATERMARK: RgbaImage = {
+        let img = image::load_from_memory(include_bytes!("../../cat.png")).unwrap().to_rgba8();
+        imageops::resize(&img, 64, 64, FilterType::Nearest)
+    };
+}
+
+// 只依赖 image crate 的 engine，用于和 Photon 做对比（shadow 模式），验证后可以替换 Photon
+pub struct Native(RgbaImage);
+
+impl Native {
+    pub fn open(data: &[u8], page: u32) -> Result<Self, DecodeError> {
+        Ok(Self(decode(data, page)?))
+    }
+
+    pub fn to_rgba(&self) -> RgbaImage {
+        self.0.clone()
+    }
+}
+
+impl Engine for Native {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn apply(&mut self, specs: &[Spec]) {
+        for spec in specs.iter() {
+            match spec.data {
+                Some(spec::Data::Crop(ref v)) => self.transform(v),
+                Some(spec::Data::Contrast(ref v)) => self.transform(v),
+                Some(spec::Data::Filter(ref v)) => self.transform(v),
+                Some(spec::Data::Fliph(ref v)) => self.transform(v),
+                Some(spec::Data::Flipv(ref v)) => self.transform(v),
+                Some(spec::Data::Resize(ref v)) => self.transform(v),
+                Some(spec::Data::Watermark(ref v)) => self.transform(v),
+                Some(spec::Data::Text(ref v)) => self.transform(v),
+                Some(spec::Data::AutoEnhance(ref v)) => self.transform(v),
+                Some(spec::Data::Lqip(ref v)) => self.transform(v),
+                Some(spec::Data::Simulate(ref v)) => self.transform(v),
+                Some(spec::Data::InvisibleWatermark(ref v)) => self.transform(v),
+                Some(spec::Data::BlurRegions(ref v)) => self.transform(v),
+                Some(spec::Data::ColorPop(ref v)) => self.transform(v),
+                Some(spec::Data::Caption(ref v)) => self.transform(v),
+                _ => {}
+            }
+        }
+    }
+
+    fn generate(self, format: ImageOutputFormat) -> Vec<u8> {
+        encode(self.0, format)
+    }
+
+    fn generate_into(self, format: ImageOutputFormat, out: &mut dyn Write) -> ImageResult<()> {
+        encode_into(self.0, format, out)
+    }
+
+    fn generate_text(self, opts: TextArt) -> String {
+        ascii::render(&self.0, opts)
+    }
+}
+
+impl From<resize::SampleFilter> for FilterType {
+    fn from(v: resize::SampleFilter) -> Self {
+        match v {
+            resize::SampleFilter::Undefined => FilterType::Nearest,
+            resize::SampleFilter::Nearest => FilterType::Nearest,
+            resize::SampleFilter::Triangle => FilterType::Triangle,
+            resize::SampleFilter::CatmullRom => FilterType::CatmullRom,
+            resize::SampleFilter::Gaussian => FilterType::Gaussian,
+            resize::SampleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl SpecTransform<&Crop> for Native {
+    fn transform(&mut self, op: &Crop) {
+        let (w, h) = self.0.dimensions();
+        let (x1, y1, x2, y2) = op.area(w, h);
+        self.0 = imageops::crop_imm(&self.0, x1, y1, x2 - x1, y2 - y1).to_image();
+    }
+}
+
+impl SpecTransform<&Contrast> for Native {
+    fn transform(&mut self, op: &Contrast) {
+        // 和 Photon 相同的公式，结果不透明
+        let c = op.contrast.clamp(-255.0, 255.0);
+        let factor = (259.0 * (c + 255.0)) / (255.0 * (259.0 - c));
+        let lut: Vec<u8> = (0..256)
+            .map(|i| (i as f32 * factor - 128.0 * factor + 128.0).clamp(0.0, 255.0) as u8)
+            .collect();
+        for p in self.0.pixels_mut() {
+            p.0 = [lut[p[0] as usize], lut[p[1] as usize], lut[p[2] as usize], 255];
+        }
+    }
+}
+
+impl SpecTransform<&Flipv> for Native {
+    fn transform(&mut self, _op: &Flipv) {
+        imageops::flip_vertical_in_place(&mut self.0);
+    }
+}
+
+impl SpecTransform<&Fliph> for Native {
+    fn transform(&mut self, _op: &Fliph) {
+        imageops::flip_horizontal_in_place(&mut self.0);
+    }
+}
+
+impl SpecTransform<&Filter> for Native {
+    fn transform(&mut self, op: &Filter) {
+        // Photon 的这几个滤镜都是和一个颜色按 20% 混合
+        let color = match filter::Filter::from_i32(op.filter) {
+            Some(filter::Filter::Oceanic) => [0.0, 89.0, 173.0],
+            Some(filter::Filter::Islands) => [0.0, 24.0, 95.0],
+            Some(filter::Filter::Marine) => [0.0, 14.0, 119.0],
+            _ => return,
+        };
+        for p in self.0.pixels_mut() {
+            for c in 0..3 {
+                p[c] = (color[c] * 0.2 + p[c] as f32 * 0.8) as u8;
+            }
+        }
+    }
+}
+
+impl SpecTransform<&Resize> for Native {
+    fn transform(&mut self, op: &Resize) {
+        // 暂不支持 seam carving，按普通缩放处理
+        let filter = resize::SampleFilter::from_i32(op.filter).unwrap_or(resize::SampleFilter::Undefined);
+        self.0 = imageops::resize(&self.0, op.width, op.height, filter.into());
+    }
+}
+
+impl SpecTransform<&Watermark> for Native {
+    fn transform(&mut self, op: &Watermark) {
+        let opacity = if op.opacity > 0.0 && op.opacity < 1.0 {
+            op.opacity
+        } else {
+            1.0
+        };
+        let asset = match op.asset.as_str() {
+            "" => None,
+            name => match ASSETS.get(name) {
+                Some(v) => Some(v),
+                None => return,
+            },
+        };
+        let mark = overlay::scale(asset.as_deref().unwrap_or(&WATERMARK), self.0.width(), op.scale, op.min_width);
+        let mark = overlay::with_opacity(&mark, opacity);
+        match watermark::Mode::from_i32(op.mode) {
+            Some(watermark::Mode::Tiled) => {
+                let mark = overlay::rotate(&mark, op.angle);
+                overlay::tile(&mut self.0, &mark, op.spacing, op.angle);
+            }
+            _ => imageops::overlay(&mut self.0, &mark, op.x, op.y),
+        }
+    }
+}
+
+impl SpecTransform<&Text> for Native {
+    fn transform(&mut self, op: &Text) {
+        // 字体不存在时不绘制（处理请求前已经检查过）
+        let font = match text::font(&op.font) {
+            Some(v) => v,
+            None => return,
+        };
+        let size = if op.size > 0.0 { op.size } else { text::DEFAULT_SIZE };
+        text::draw(&mut self.0, &font, &op.text, op.x as i32, op.y as i32, size, op.rgba());
+    }
+}
+
+impl SpecTransform<&Caption> for Native {
+    fn transform(&mut self, op: &Caption) {
+        let font = match text::font(&op.font) {
+            Some(v) => v,
+            None => return,
+        };
+        let size = if op.size > 0.0 { op.size } else { text::DEFAULT_SIZE };
+        let top = op.position == caption::Position::Top as i32;
+        text::caption(&mut self.0, &font, &op.text, size, top, op.rgba(), op.background_rgba());
+    }
+}
+
+impl SpecTransform<&AutoEnhance> for Native {
+    fn transform(&mut self, _op: &AutoEnhance) {
+        adjust::white_balance(&mut self.0);
+        adjust::auto_levels(&mut self.0);
+        let sharpened = imageops::filter3x3(&self.0, &[0.0, -1.0, 0.0, -1.0, 5.0, -1.0, 0.0, -1.0, 0.0]);
+        adjust::mix(&mut self.0, &sharpened, 0.5);
+    }
+}
+
+impl SpecTransform<&Simulate> for Native {
+    fn transform(&mut self, op: &Simulate) {
+        if let Some(m) = simulate::Deficiency::from_i32(op.deficiency).and_then(|d| d.matrix()) {
+            adjust::color_matrix(&mut self.0, &m);
+        }
+    }
+}
+
+impl SpecTransform<&InvisibleWatermark> for Native {
+    fn transform(&mut self, op: &InvisibleWatermark) {
+        let strength = if op.strength > 0.0 {
+            op.strength
+        } else {
+            stego::DEFAULT_STRENGTH
+        };
+        let (width, height) = self.0.dimensions();
+        stego::embed(&mut self.0, width, height, op.id, strength);
+    }
+}
+
+impl SpecTransform<&ColorPop> for Native {
+    fn transform(&mut self, op: &ColorPop) {
+        adjust::color_pop(&mut self.0, op.hue, op.tolerance());
+    }
+}
+
+impl SpecTransform<&BlurRegions> for Native {
+    fn transform(&mut self, op: &BlurRegions) {
+        let (width, height) = self.0.dimensions();
+        let regions: Vec<_> = op.regions.iter().filter_map(|r| r.area(width, height)).collect();
+        adjust::blur_regions(&mut self.0, width, &regions, op.sigma());
+    }
+}
+
+impl SpecTransform<&Lqip> for Native {
+    fn transform(&mut self, op: &Lqip) {
+        let (w, h) = self.0.dimensions();
+        let width = op.width().min(w).max(1);
+        let height = ((h as f64 * width as f64 / w as f64).round() as u32).max(1);
+        self.0 = imageops::resize(&self.0, width, height, FilterType::Triangle);
+        if op.blur {
+            self.0 = imageops::blur(&self.0, (width / 16).max(1) as f32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Photon;
+
+    #[test]
+    fn native_should_match_photon_on_basic_ops() {
+        let img = RgbaImage::from_fn(64, 48, |x, y| image::Rgba([(x * 4) as u8, (y * 5) as u8, 128, 255]));
+        let data = encode(img, ImageOutputFormat::Png);
+        // 不包含坐标 crop：photon 0.3.1 的 crop 忽略了起点，总是取左上角
+        let specs = vec![
+            Spec::new_filter(filter::Filter::Oceanic),
+            Spec::new_watermark(2, 2),
+            Spec::new_crop_gravity(crop::Gravity::SouthEast, 40, 30, 3, 2),
+        ];
+        let mut photon = Photon::open(&data, 0).unwrap();
+        let mut native = Native::open(&data, 0).unwrap();
+        photon.apply(&specs);
+        native.apply(&specs);
+        assert_eq!(photon.to_rgba(), native.to_rgba());
+    }
+}
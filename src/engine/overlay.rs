@@ -0,0 +1,124 @@
+// 水印叠加相关的辅助函数，和具体的 engine 无关
+use image::{imageops, RgbaImage};
+
+// 按比例缩小水印的 alpha 通道
+pub fn with_opacity(mark: &RgbaImage, opacity: f32) -> RgbaImage {
+    let mut mark = mark.clone();
+    for a in mark.iter_mut().skip(3).step_by(4) {
+        *a = (*a as f32 * opacity).round() as u8;
+    }
+    mark
+}
+
+// 把水印缩放到图片宽度的 scale 倍，不小于 min_width，也不超过图片宽度，保持宽高比；scale 为 0 时不缩放
+pub fn scale(mark: &RgbaImage, width: u32, scale: f32, min_width: u32) -> RgbaImage {
+    if scale <= 0.0 || mark.width() == 0 {
+        return mark.clone();
+    }
+    let w = ((width as f32 * scale).round() as u32).max(min_width).min(width).max(1);
+    if w == mark.width() {
+        return mark.clone();
+    }
+    let h = ((mark.height() as f32 * w as f32 / mark.width() as f32).round() as u32).max(1);
+    imageops::resize(mark, w, h, imageops::FilterType::Triangle)
+}
+
+// 以中心为原点旋转图片（角度制），画布扩大到能容纳旋转后的整张图，空白处透明
+pub fn rotate(img: &RgbaImage, angle: f32) -> RgbaImage {
+    if angle % 360.0 == 0.0 {
+        return img.clone();
+    }
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let nw = (w * cos.abs() + h * sin.abs()).ceil() as u32;
+    let nh = (w * sin.abs() + h * cos.abs()).ceil() as u32;
+    let src = img.as_raw();
+    let mut dst = vec![0u8; (nw * nh * 4) as usize];
+    let (cx, cy, ncx, ncy) = (w / 2.0, h / 2.0, nw as f32 / 2.0, nh as f32 / 2.0);
+    for y in 0..nh {
+        for x in 0..nw {
+            // 反向映射回原图坐标，取最近的像素
+            let dx = x as f32 + 0.5 - ncx;
+            let dy = y as f32 + 0.5 - ncy;
+            let sx = (dx * cos + dy * sin + cx).floor();
+            let sy = (-dx * sin + dy * cos + cy).floor();
+            if sx < 0.0 || sy < 0.0 || sx >= w || sy >= h {
+                continue;
+            }
+            let si = ((sy as u32 * img.width() + sx as u32) * 4) as usize;
+            let di = ((y * nw + x) * 4) as usize;
+            dst[di..di + 4].copy_from_slice(&src[si..si + 4]);
+        }
+    }
+    RgbaImage::from_raw(nw, nh, dst).unwrap()
+}
+
+// 沿旋转后的网格把水印铺满整张图片，相邻行错开半格，形成对角线排列
+pub fn tile(img: &mut RgbaImage, mark: &RgbaImage, spacing: u32, angle: f32) {
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    let (mw, mh) = (mark.width() as i64, mark.height() as i64);
+    let step = (mw.max(mh) + spacing as i64).max(1) as f32;
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    // 网格需要覆盖整张图的对角线长度
+    let n = ((width as f32).hypot(height as f32) / step / 2.0).ceil() as i64 + 1;
+
+    let src = mark.as_raw();
+    for j in -n..=n {
+        for i in -n..=n {
+            let u = (i as f32 + if j % 2 == 0 { 0.0 } else { 0.5 }) * step;
+            let v = j as f32 * step;
+            let x = (cx + u * cos - v * sin).round() as i64 - mw / 2;
+            let y = (cy + u * sin + v * cos).round() as i64 - mh / 2;
+            if x >= width || y >= height || x + mw <= 0 || y + mh <= 0 {
+                continue;
+            }
+            blend(img, width, height, src, mw, mh, x, y);
+        }
+    }
+}
+
+// 把 src 以 alpha 混合的方式叠加到 dst 的 (x, y) 处，允许越界（超出部分被裁掉）
+#[allow(clippy::too_many_arguments)]
+fn blend(dst: &mut [u8], dw: i64, dh: i64, src: &[u8], sw: i64, sh: i64, x: i64, y: i64) {
+    for sy in 0.max(-y)..sh.min(dh - y) {
+        for sx in 0.max(-x)..sw.min(dw - x) {
+            let si = ((sy * sw + sx) * 4) as usize;
+            let di = (((y + sy) * dw + x + sx) * 4) as usize;
+            let alpha = src[si + 3] as u32;
+            if alpha == 0 {
+                continue;
+            }
+            for c in 0..3 {
+                let s = src[si + c] as u32;
+                let d = dst[di + c] as u32;
+                dst[di + c] = ((s * alpha + d * (255 - alpha)) / 255) as u8;
+            }
+            let da = dst[di + 3] as u32;
+            dst[di + 3] = (alpha + da * (255 - alpha) / 255) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watermark_should_scale_with_image() {
+        let mark = RgbaImage::new(200, 100);
+        assert_eq!(scale(&mark, 1000, 0.0, 64).dimensions(), (200, 100));
+        // 大图按比例放大，缩略图上不小于 min_width
+        assert_eq!(scale(&mark, 4000, 0.1, 64).dimensions(), (400, 200));
+        assert_eq!(scale(&mark, 200, 0.1, 64).dimensions(), (64, 32));
+        // 不超过图片宽度
+        assert_eq!(scale(&mark, 50, 0.1, 64).dimensions(), (50, 25));
+
+        let spec = crate::pb::ImageSpec::parse("resize:w=300,h=200;watermark:scale=0.1,min_width=64").unwrap();
+        match spec.specs[1].data {
+            Some(crate::pb::spec::Data::Watermark(ref v)) => assert_eq!((v.scale, v.min_width), (0.1, 64)),
+            _ => panic!("expected watermark"),
+        }
+        assert!(crate::pb::ImageSpec::parse("watermark:scale=2").is_err());
+    }
+}
@@ -1,13 +1,16 @@
-use super::{Engine, SpecTransform};
-use crate::pb::*;
+use super::{
+    adjust, ascii, decode, encode, encode_into, overlay, stego, text, DecodeError, Engine, SpecTransform,
+    TextArt,
+};
+use crate::{assets::ASSETS, pb::*};
 use anyhow::Result;
 use bytes::Bytes;
-use image::{DynamicImage, ImageBuffer, ImageOutputFormat};
+use image::{imageops, ImageBuffer, ImageOutputFormat, ImageResult, RgbaImage};
 use lazy_static::lazy_static;
 use photon_rs::{
-    effects, filters, multiple, native::open_image_from_bytes, transform, PhotonImage,
+    conv, effects, filters, multiple, native::open_image_from_bytes, transform, PhotonImage,
 };
-use std::convert::TryFrom;
+use std::{convert::TryFrom, io::Write};
 
 lazy_static! {
     // 预先把水印文件加载为静态变量
@@ -27,11 +30,37 @@ impl TryFrom<Bytes> for Photon {
     type Error = anyhow::Error;
 
     fn try_from(value: Bytes) -> Result<Self, Self::Error> {
-        Ok(Self(open_image_from_bytes(&value)?))
+        Ok(Self::open(&value, 0)?)
+    }
+}
+
+impl Photon {
+    // page 用于选择多页 TIFF 中的某一页
+    pub fn open(data: &[u8], page: u32) -> Result<Self, DecodeError> {
+        Ok(Self::from_rgba(decode(data, page)?))
+    }
+
+    pub fn from_rgba(img: RgbaImage) -> Self {
+        let (width, height) = img.dimensions();
+        Self(PhotonImage::new(img.into_raw(), width, height))
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.0.get_width(), self.0.get_height())
+    }
+
+    // 导出当前的像素数据，方便和其他图片做比较、拼接等处理
+    pub fn to_rgba(&self) -> RgbaImage {
+        let (width, height) = (self.0.get_width(), self.0.get_height());
+        ImageBuffer::from_vec(width, height, self.0.get_raw_pixels()).unwrap()
     }
 }
 
 impl Engine for Photon {
+    fn name(&self) -> &'static str {
+        "photon"
+    }
+
     fn apply(&mut self, specs: &[Spec]) {
         for spec in specs.iter() {
             match spec.data {
@@ -42,6 +71,14 @@ impl Engine for Photon {
                 Some(spec::Data::Flipv(ref v)) => self.transform(v),
                 Some(spec::Data::Resize(ref v)) => self.transform(v),
                 Some(spec::Data::Watermark(ref v)) => self.transform(v),
+                Some(spec::Data::Text(ref v)) => self.transform(v),
+                Some(spec::Data::AutoEnhance(ref v)) => self.transform(v),
+                Some(spec::Data::Lqip(ref v)) => self.transform(v),
+                Some(spec::Data::Simulate(ref v)) => self.transform(v),
+                Some(spec::Data::InvisibleWatermark(ref v)) => self.transform(v),
+                Some(spec::Data::BlurRegions(ref v)) => self.transform(v),
+                Some(spec::Data::ColorPop(ref v)) => self.transform(v),
+                Some(spec::Data::Caption(ref v)) => self.transform(v),
                 _ => {},
             }
         }
@@ -50,11 +87,34 @@ impl Engine for Photon {
     fn generate(self, format: ImageOutputFormat) -> Vec<u8> {
         image_to_buf(self.0, format)
     }
+
+    fn generate_into(self, format: ImageOutputFormat, out: &mut dyn Write) -> ImageResult<()> {
+        encode_into(self.to_rgba(), format, out)
+    }
+
+    fn generate_text(self, opts: TextArt) -> String {
+        ascii::render(&self.to_rgba(), opts)
+    }
 }
 
 impl SpecTransform<&Crop> for Photon {
     fn transform(&mut self, op: &Crop) {
-        let img = transform::crop(&mut self.0, op.x1, op.y1, op.x2, op.y2);
+        // photon 的 crop 忽略了起点，总是从左上角开始。带锚点的裁剪用 image crate 实现，
+        // 原来的坐标裁剪保持 photon 的行为，避免已有 URL 的输出发生变化
+        if op.gravity != crop::Gravity::Unspecified as i32 {
+            let (w, h) = self.dimensions();
+            let (x1, y1, x2, y2) = op.area(w, h);
+            let img = imageops::crop_imm(&self.to_rgba(), x1, y1, x2 - x1, y2 - y1).to_image();
+            *self = Self::from_rgba(img);
+            return;
+        }
+        // 超出图片的部分会让 photon panic，先限制在图片范围内
+        let (w, h) = self.dimensions();
+        let (x2, y2) = (op.x2.min(w), op.y2.min(h));
+        if op.x1 >= x2 || op.y1 >= y2 {
+            return;
+        }
+        let img = transform::crop(&mut self.0, op.x1, op.y1, x2, y2);
         self.0 = img;
     }
 }
@@ -104,10 +164,152 @@ impl SpecTransform<&Resize> for Photon {
 
 impl SpecTransform<&Watermark> for Photon {
     fn transform(&mut self, op: &Watermark) {
-        multiple::watermark(&mut self.0, &WATERMARK, op.x, op.y);
+        let opacity = if op.opacity > 0.0 && op.opacity < 1.0 {
+            op.opacity
+        } else {
+            1.0
+        };
+        // 引用的素材不存在时不加水印（处理请求前已经检查过）
+        let asset = match op.asset.as_str() {
+            "" => None,
+            name => match ASSETS.get(name) {
+                Some(v) => Some(v),
+                None => return,
+            },
+        };
+        let width = self.dimensions().0;
+        let mark = || {
+            let mark = asset.as_ref().map_or_else(mark_rgba, |v| RgbaImage::clone(v));
+            overlay::scale(&mark, width, op.scale, op.min_width)
+        };
+        match watermark::Mode::from_i32(op.mode) {
+            Some(watermark::Mode::Tiled) => {
+                let mark = overlay::rotate(&overlay::with_opacity(&mark(), opacity), op.angle);
+                let mut img = self.to_rgba();
+                overlay::tile(&mut img, &mark, op.spacing, op.angle);
+                let (width, height) = img.dimensions();
+                self.0 = PhotonImage::new(img.into_raw(), width, height);
+            }
+            _ if opacity < 1.0 || asset.is_some() || op.scale > 0.0 => {
+                let mark = overlay::with_opacity(&mark(), opacity);
+                let (width, height) = mark.dimensions();
+                let mark = PhotonImage::new(mark.into_raw(), width, height);
+                multiple::watermark(&mut self.0, &mark, op.x, op.y);
+            }
+            _ => multiple::watermark(&mut self.0, &WATERMARK, op.x, op.y),
+        }
     }
 }
 
+impl SpecTransform<&Text> for Photon {
+    fn transform(&mut self, op: &Text) {
+        let font = match text::font(&op.font) {
+            Some(v) => v,
+            None => return,
+        };
+        let size = if op.size > 0.0 { op.size } else { text::DEFAULT_SIZE };
+        let mut img = self.to_rgba();
+        text::draw(&mut img, &font, &op.text, op.x as i32, op.y as i32, size, op.rgba());
+        let (width, height) = img.dimensions();
+        self.0 = PhotonImage::new(img.into_raw(), width, height);
+    }
+}
+
+impl SpecTransform<&Caption> for Photon {
+    fn transform(&mut self, op: &Caption) {
+        let font = match text::font(&op.font) {
+            Some(v) => v,
+            None => return,
+        };
+        let size = if op.size > 0.0 { op.size } else { text::DEFAULT_SIZE };
+        let top = op.position == caption::Position::Top as i32;
+        let mut img = self.to_rgba();
+        text::caption(&mut img, &font, &op.text, size, top, op.rgba(), op.background_rgba());
+        let (width, height) = img.dimensions();
+        self.0 = PhotonImage::new(img.into_raw(), width, height);
+    }
+}
+
+impl SpecTransform<&AutoEnhance> for Photon {
+    fn transform(&mut self, _op: &AutoEnhance) {
+        let (width, height) = self.dimensions();
+        let mut pixels = self.0.get_raw_pixels();
+        adjust::white_balance(&mut pixels);
+        adjust::auto_levels(&mut pixels);
+
+        // photon 的锐化比较强，和原图各取一半得到轻度锐化的效果
+        let mut sharpened = PhotonImage::new(pixels.clone(), width, height);
+        conv::sharpen(&mut sharpened);
+        adjust::mix(&mut pixels, &sharpened.get_raw_pixels(), 0.5);
+        self.0 = PhotonImage::new(pixels, width, height);
+    }
+}
+
+impl SpecTransform<&Simulate> for Photon {
+    fn transform(&mut self, op: &Simulate) {
+        let matrix = simulate::Deficiency::from_i32(op.deficiency).and_then(|d| d.matrix());
+        if let Some(m) = matrix {
+            let (width, height) = self.dimensions();
+            let mut pixels = self.0.get_raw_pixels();
+            adjust::color_matrix(&mut pixels, &m);
+            self.0 = PhotonImage::new(pixels, width, height);
+        }
+    }
+}
+
+impl SpecTransform<&InvisibleWatermark> for Photon {
+    fn transform(&mut self, op: &InvisibleWatermark) {
+        let strength = if op.strength > 0.0 {
+            op.strength
+        } else {
+            stego::DEFAULT_STRENGTH
+        };
+        let (width, height) = self.dimensions();
+        let mut pixels = self.0.get_raw_pixels();
+        if stego::embed(&mut pixels, width, height, op.id, strength) {
+            self.0 = PhotonImage::new(pixels, width, height);
+        }
+    }
+}
+
+impl SpecTransform<&ColorPop> for Photon {
+    fn transform(&mut self, op: &ColorPop) {
+        let (width, height) = self.dimensions();
+        let mut pixels = self.0.get_raw_pixels();
+        adjust::color_pop(&mut pixels, op.hue, op.tolerance());
+        self.0 = PhotonImage::new(pixels, width, height);
+    }
+}
+
+impl SpecTransform<&BlurRegions> for Photon {
+    fn transform(&mut self, op: &BlurRegions) {
+        let (width, height) = self.dimensions();
+        let regions: Vec<_> = op.regions.iter().filter_map(|r| r.area(width, height)).collect();
+        if !regions.is_empty() {
+            let mut pixels = self.0.get_raw_pixels();
+            adjust::blur_regions(&mut pixels, width, &regions, op.sigma());
+            self.0 = PhotonImage::new(pixels, width, height);
+        }
+    }
+}
+
+impl SpecTransform<&Lqip> for Photon {
+    fn transform(&mut self, op: &Lqip) {
+        let (w, h) = self.dimensions();
+        let width = op.width().min(w).max(1);
+        let height = ((h as f64 * width as f64 / w as f64).round() as u32).max(1);
+        self.0 = transform::resize(&self.0, width, height, transform::SamplingFilter::Triangle);
+        if op.blur {
+            conv::gaussian_blur(&mut self.0, (width / 16).max(1) as i32);
+        }
+    }
+}
+
+fn mark_rgba() -> RgbaImage {
+    let (width, height) = (WATERMARK.get_width(), WATERMARK.get_height());
+    ImageBuffer::from_vec(width, height, WATERMARK.get_raw_pixels()).unwrap()
+}
+
 // photon 没有提供在内存中对图片转换格式的方法，需自己实现
 fn image_to_buf(img: PhotonImage, format: ImageOutputFormat) -> Vec<u8> {
     let raw_pixels = img.get_raw_pixels();
@@ -115,9 +317,5 @@ fn image_to_buf(img: PhotonImage, format: ImageOutputFormat) -> Vec<u8> {
     let height = img.get_height();
 
     let img_buffer = ImageBuffer::from_vec(width, height, raw_pixels).unwrap();
-    let dynimage = DynamicImage::ImageRgba8(img_buffer);
-
-    let mut buffer = Vec::with_capacity(32768);
-    dynimage.write_to(&mut buffer, format).unwrap();
-    buffer
+    encode(img_buffer, format)
 }
\ No newline at end of file
@@ -0,0 +1,216 @@
+// 只读文件头得到图片的基本信息，不解码像素。/meta 只需要下载图片开头的一小部分，
+// 条件操作也在完整解码之前根据这些信息决定是否执行
+use super::{dimensions, sniff, DecodeError, SourceFormat};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Probe {
+    pub format: SourceFormat,
+    // 存储的宽高，没有按 EXIF 方向旋转
+    pub width: u32,
+    pub height: u32,
+    // EXIF 中的方向（1-8），没有 EXIF 或者没有这一项时为 None
+    pub orientation: Option<u16>,
+}
+
+// EXIF 中用于图片说明的文字字段，没有 EXIF 或者没有这一项时为 None
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Exif {
+    // 拍摄时间 DateTimeOriginal，没有时使用 DateTime，格式是 "YYYY:MM:DD HH:MM:SS"
+    pub date_time: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub copyright: Option<String>,
+}
+
+pub fn probe(data: &[u8], page: u32) -> Result<Probe, DecodeError> {
+    let format = sniff(data).ok_or(DecodeError::Unsupported)?;
+    let (width, height) = match png_size(data) {
+        Some(v) if page == 0 => v,
+        _ => dimensions(data, page)?,
+    };
+    let orientation = exif_tiff(data).and_then(|t| t.orientation());
+    Ok(Probe {
+        format,
+        width,
+        height,
+        orientation,
+    })
+}
+
+// PNG 的宽高在固定位置的 IHDR 中，image crate 需要读到第一个 IDAT 才能给出宽高
+fn png_size(data: &[u8]) -> Option<(u32, u32)> {
+    match data.get(8..24)? {
+        [_, _, _, _, b'I', b'H', b'D', b'R', w0, w1, w2, w3, h0, h1, h2, h3] if sniff(data) == Some(SourceFormat::Png) => {
+            Some((u32::from_be_bytes([*w0, *w1, *w2, *w3]), u32::from_be_bytes([*h0, *h1, *h2, *h3])))
+        }
+        _ => None,
+    }
+}
+
+// 只读文件头中的 EXIF，不支持的格式返回空的 Exif
+pub fn exif(data: &[u8]) -> Exif {
+    let tiff = match exif_tiff(data) {
+        Some(v) => v,
+        None => return Exif::default(),
+    };
+    let ifd0 = tiff.first_ifd();
+    // 拍摄时间在 IFD0 的 ExifIFDPointer（0x8769）指向的子 IFD 中
+    let sub_ifd = ifd0
+        .and_then(|ifd| tiff.entry(ifd, 0x8769))
+        .and_then(|entry| tiff.u32_at(entry + 8))
+        .map(|v| v as usize);
+    let ascii = |ifd: Option<usize>, tag| ifd.and_then(|ifd| tiff.ascii(ifd, tag));
+    Exif {
+        date_time: ascii(sub_ifd, 0x9003).or_else(|| ascii(ifd0, 0x0132)),
+        make: ascii(ifd0, 0x010F),
+        model: ascii(ifd0, 0x0110),
+        copyright: ascii(ifd0, 0x8298),
+    }
+}
+
+// JPEG 的 EXIF 段或者 TIFF 文件本身
+fn exif_tiff(data: &[u8]) -> Option<Tiff<'_>> {
+    match sniff(data)? {
+        SourceFormat::Jpeg => Tiff::new(jpeg_exif(data)?),
+        SourceFormat::Tiff => Tiff::new(data),
+        _ => None,
+    }
+}
+
+// 在 SOS（压缩数据开始）之前的段中找 APP1 的 EXIF
+fn jpeg_exif(data: &[u8]) -> Option<&[u8]> {
+    let mut i = 2;
+    while let Some(&[0xFF, marker, hi, lo]) = data.get(i..i + 4) {
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([hi, lo]) as usize;
+        let segment = data.get(i + 4..i + 2 + len)?;
+        if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            return Some(&segment[6..]);
+        }
+        i += 2 + len;
+    }
+    None
+}
+
+// EXIF 和 TIFF 的格式相同：文件头说明字节序，之后是若干个 12 字节条目组成的 IFD
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(0..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16_at(&self, i: usize) -> Option<u16> {
+        let b = [*self.data.get(i)?, *self.data.get(i + 1)?];
+        Some(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    fn u32_at(&self, i: usize) -> Option<u32> {
+        let b = [*self.data.get(i)?, *self.data.get(i + 1)?, *self.data.get(i + 2)?, *self.data.get(i + 3)?];
+        Some(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    fn first_ifd(&self) -> Option<usize> {
+        self.u32_at(4).map(|v| v as usize)
+    }
+
+    // IFD 中 tag 的条目位置：tag（2 字节）、类型（2）、个数（4）、值或者值的偏移（4）
+    fn entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        (0..self.u16_at(ifd)? as usize)
+            .map(|n| ifd + 2 + n * 12)
+            .find(|&entry| self.u16_at(entry) == Some(tag))
+    }
+
+    // 第一个 IFD 中 0x0112 就是方向
+    fn orientation(&self) -> Option<u16> {
+        let entry = self.entry(self.first_ifd()?, 0x0112)?;
+        self.u16_at(entry + 8).filter(|v| (1..=8).contains(v))
+    }
+
+    // ASCII 类型（2）的值，不超过 4 字节时直接放在条目中，去掉结尾的 NUL 和空白
+    fn ascii(&self, ifd: usize, tag: u16) -> Option<String> {
+        let entry = self.entry(ifd, tag)?;
+        if self.u16_at(entry + 2)? != 2 {
+            return None;
+        }
+        let count = self.u32_at(entry + 4)? as usize;
+        let start = if count <= 4 { entry + 8 } else { self.u32_at(entry + 8)? as usize };
+        let bytes = self.data.get(start..start.checked_add(count)?)?;
+        let value = String::from_utf8_lossy(bytes);
+        let value = value.trim_end_matches('\0').trim();
+        (!value.is_empty()).then(|| value.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, RgbaImage};
+
+    #[test]
+    fn probe_should_read_headers_only() {
+        let jpeg = crate::engine::encode(RgbaImage::new(40, 30), ImageOutputFormat::Jpeg(80));
+        // 在 SOI 之后插入只有方向一项的 EXIF（大端序）
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1, 0x00, 0x06, 0, 0, 0, 0, 0, 0]);
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(&exif);
+        data.extend_from_slice(&jpeg[2..]);
+
+        // 截断的数据也能得到文件头中的信息
+        let probe = probe(&data[..data.len() / 2], 0).unwrap();
+        assert_eq!((probe.format, probe.width, probe.height), (SourceFormat::Jpeg, 40, 30));
+        assert_eq!(probe.orientation, Some(6));
+
+        assert_eq!(super::exif(&data), Exif::default());
+
+        let png = crate::engine::encode(RgbaImage::new(5, 7), ImageOutputFormat::Png);
+        let probe = super::probe(&png[..40], 0).unwrap();
+        assert_eq!((probe.width, probe.height, probe.orientation), (5, 7, None));
+        assert!(matches!(super::probe(b"<html>", 0), Err(DecodeError::Unsupported)));
+    }
+
+    #[test]
+    fn exif_should_read_caption_fields() {
+        // 小端序的 TIFF：IFD0 有 Make、Model（不超过 4 字节，放在条目中）、Copyright 和子 IFD，子 IFD 中有拍摄时间
+        fn entry(tag: u16, kind: u16, count: u32, value: u32) -> Vec<u8> {
+            [&tag.to_le_bytes()[..], &kind.to_le_bytes(), &count.to_le_bytes(), &value.to_le_bytes()].concat()
+        }
+        let mut tiff = b"II\x2a\0\x08\0\0\0\x04\0".to_vec();
+        tiff.extend(entry(0x010F, 2, 6, 62));
+        tiff.extend(entry(0x0110, 2, 4, u32::from_le_bytes(*b"X10\0")));
+        tiff.extend(entry(0x8298, 2, 9, 68));
+        tiff.extend(entry(0x8769, 4, 1, 77));
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"Canon\0Jane Doe\0");
+        tiff.extend_from_slice(b"\x01\0");
+        tiff.extend(entry(0x9003, 2, 20, 95));
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"2021:10:01 14:30:05\0");
+
+        let jpeg = crate::engine::encode(RgbaImage::new(4, 4), ImageOutputFormat::Jpeg(80));
+        let segment = [&b"Exif\0\0"[..], &tiff].concat();
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(&segment);
+        data.extend_from_slice(&jpeg[2..]);
+
+        let exif = exif(&data);
+        assert_eq!(exif.make.as_deref(), Some("Canon"));
+        assert_eq!(exif.model.as_deref(), Some("X10"));
+        assert_eq!(exif.copyright.as_deref(), Some("Jane Doe"));
+        assert_eq!(exif.date_time.as_deref(), Some("2021:10:01 14:30:05"));
+        assert_eq!(probe(&data, 0).unwrap().orientation, None);
+    }
+}
@@ -0,0 +1,82 @@
+use image::ImageFormat;
+use std::fmt;
+
+// 通过文件头识别出来的源图片格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Gif,
+    Bmp,
+    Tiff,
+}
+
+impl SourceFormat {
+    // 所有可以解码的格式
+    pub const ALL: [SourceFormat; 6] = [
+        SourceFormat::Jpeg,
+        SourceFormat::Png,
+        SourceFormat::WebP,
+        SourceFormat::Gif,
+        SourceFormat::Bmp,
+        SourceFormat::Tiff,
+    ];
+}
+
+// 只看开头的几个字节判断格式，不信任 URL 的扩展名或 content-type
+pub fn sniff(data: &[u8]) -> Option<SourceFormat> {
+    match data {
+        [0xFF, 0xD8, 0xFF, ..] => Some(SourceFormat::Jpeg),
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n', ..] => Some(SourceFormat::Png),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(SourceFormat::WebP),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(SourceFormat::Gif),
+        [b'B', b'M', ..] => Some(SourceFormat::Bmp),
+        [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some(SourceFormat::Tiff),
+        _ => None,
+    }
+}
+
+impl From<SourceFormat> for ImageFormat {
+    fn from(v: SourceFormat) -> Self {
+        match v {
+            SourceFormat::Jpeg => ImageFormat::Jpeg,
+            SourceFormat::Png => ImageFormat::Png,
+            SourceFormat::WebP => ImageFormat::WebP,
+            SourceFormat::Gif => ImageFormat::Gif,
+            SourceFormat::Bmp => ImageFormat::Bmp,
+            SourceFormat::Tiff => ImageFormat::Tiff,
+        }
+    }
+}
+
+impl fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SourceFormat::Jpeg => "jpeg",
+            SourceFormat::Png => "png",
+            SourceFormat::WebP => "webp",
+            SourceFormat::Gif => "gif",
+            SourceFormat::Bmp => "bmp",
+            SourceFormat::Tiff => "tiff",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_should_be_sniffed_from_magic_bytes() {
+        let png = include_bytes!("../../cat.png");
+        assert_eq!(sniff(png), Some(SourceFormat::Png));
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some(SourceFormat::Jpeg));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some(SourceFormat::WebP));
+        assert_eq!(sniff(b"GIF89a"), Some(SourceFormat::Gif));
+        assert_eq!(sniff(b"MM\0*"), Some(SourceFormat::Tiff));
+        assert_eq!(sniff(b"<html>"), None);
+        assert_eq!(sniff(&[]), None);
+    }
+}
@@ -0,0 +1,187 @@
+// 不可见水印：把一个 32 位的 id 以扩频的方式叠加到亮度上
+//
+// 图片被切成 8x6 个区域，每个区域承载 1 个 bit（16 位同步头 + 32 位 id）。
+// 区域内再分成 chip x chip 大小的小块，每个小块按伪随机序列加上 +strength 或 -strength，
+// bit 为 1 时使用原序列，为 0 时取反。检测时用小块的平均亮度减去相邻小块的平均亮度，
+// 再和伪随机序列做相关，符号即 bit。小块的尺寸接近 JPEG 的 8x8 块，重新压缩后依然可以检出，
+// 但不能经过缩放或裁剪。
+
+const SYNC: u64 = 0xA5C3;
+const BITS: u32 = 48;
+const GRID_COLS: u32 = 8;
+const GRID_ROWS: u32 = 6;
+// 每个区域至少需要的小块数
+const MIN_CHIPS: u32 = 2;
+// 默认的强度（亮度变化），肉眼几乎不可见
+pub const DEFAULT_STRENGTH: f32 = 3.0;
+
+// 检测结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    pub id: u32,
+    // 所有 bit 相关值的平均强度，越大越可信
+    pub confidence: f32,
+}
+
+// 根据图片尺寸决定小块的边长，嵌入和检测时必须一致
+fn chip_size(width: u32, height: u32) -> u32 {
+    (width.min(height) / 100).clamp(4, 16)
+}
+
+// 每个区域覆盖的小块范围
+fn region(bit: u32, chips_x: u32, chips_y: u32) -> (u32, u32, u32, u32) {
+    let (col, row) = (bit % GRID_COLS, bit / GRID_COLS);
+    (
+        col * chips_x / GRID_COLS,
+        (col + 1) * chips_x / GRID_COLS,
+        row * chips_y / GRID_ROWS,
+        (row + 1) * chips_y / GRID_ROWS,
+    )
+}
+
+// 小块 (x, y) 的伪随机符号，固定种子保证结果可复现
+fn pn(x: u32, y: u32) -> f32 {
+    let mut z = ((x as u64) << 32 | y as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    if (z ^ (z >> 31)) & 1 == 0 {
+        1.0
+    } else {
+        -1.0
+    }
+}
+
+fn grid(width: u32, height: u32) -> Option<(u32, u32, u32)> {
+    let chip = chip_size(width, height);
+    let (chips_x, chips_y) = (width / chip, height / chip);
+    if chips_x < GRID_COLS * MIN_CHIPS || chips_y < GRID_ROWS * MIN_CHIPS {
+        return None;
+    }
+    Some((chip, chips_x, chips_y))
+}
+
+// 嵌入 id，图片太小时不做处理并返回 false
+pub fn embed(pixels: &mut [u8], width: u32, height: u32, id: u32, strength: f32) -> bool {
+    let (chip, chips_x, chips_y) = match grid(width, height) {
+        Some(g) => g,
+        None => return false,
+    };
+    let payload = SYNC << 32 | id as u64;
+    for bit in 0..BITS {
+        let sign = if payload >> (BITS - 1 - bit) & 1 == 1 { 1.0 } else { -1.0 };
+        let (x0, x1, y0, y1) = region(bit, chips_x, chips_y);
+        for cy in y0..y1 {
+            for cx in x0..x1 {
+                let delta = pn(cx, cy) * sign * strength;
+                for y in cy * chip..(cy + 1) * chip {
+                    for x in cx * chip..(cx + 1) * chip {
+                        let i = ((y * width + x) * 4) as usize;
+                        for v in pixels[i..i + 3].iter_mut() {
+                            *v = (*v as f32 + delta).round().clamp(0.0, 255.0) as u8;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    true
+}
+
+// 检测 id，同步头不匹配时返回 None
+pub fn detect(pixels: &[u8], width: u32, height: u32) -> Option<Detection> {
+    let (chip, chips_x, chips_y) = grid(width, height)?;
+
+    // 每个小块的平均亮度
+    let mut means = vec![0f32; (chips_x * chips_y) as usize];
+    for cy in 0..chips_y {
+        for cx in 0..chips_x {
+            let mut sum = 0u32;
+            for y in cy * chip..(cy + 1) * chip {
+                for x in cx * chip..(cx + 1) * chip {
+                    let i = ((y * width + x) * 4) as usize;
+                    sum += super::adjust::luma(&pixels[i..i + 3]) as u32;
+                }
+            }
+            means[(cy * chips_x + cx) as usize] = sum as f32 / (chip * chip) as f32;
+        }
+    }
+    let mean = |x: u32, y: u32| means[(y * chips_x + x) as usize];
+
+    let mut payload = 0u64;
+    let mut total = 0f32;
+    for bit in 0..BITS {
+        let (x0, x1, y0, y1) = region(bit, chips_x, chips_y);
+        let mut corr = 0f32;
+        let mut n = 0f32;
+        for cy in y0..y1 {
+            for cx in x0..x1 {
+                // 减去相邻小块的均值，去掉图片本身的低频内容
+                let mut neighbors = Vec::with_capacity(4);
+                if cx > 0 {
+                    neighbors.push(mean(cx - 1, cy));
+                }
+                if cx + 1 < chips_x {
+                    neighbors.push(mean(cx + 1, cy));
+                }
+                if cy > 0 {
+                    neighbors.push(mean(cx, cy - 1));
+                }
+                if cy + 1 < chips_y {
+                    neighbors.push(mean(cx, cy + 1));
+                }
+                let local = neighbors.iter().sum::<f32>() / neighbors.len() as f32;
+                corr += pn(cx, cy) * (mean(cx, cy) - local);
+                n += 1.0;
+            }
+        }
+        payload = payload << 1 | (corr > 0.0) as u64;
+        total += corr.abs() / n;
+    }
+
+    if payload >> 32 != SYNC {
+        return None;
+    }
+    Some(Detection {
+        id: payload as u32,
+        confidence: total / BITS as f32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, RgbaImage};
+
+    fn sample(width: u32, height: u32) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                let v = ((x * 7 + y * 3) % 200 + (x / 40 + y / 30) % 3 * 20) as u8;
+                [v, v / 2 + 60, 255 - v, 255]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn embedded_id_should_survive_jpeg_recompression() {
+        let (width, height) = (400, 300);
+        let mut pixels = sample(width, height);
+        assert!(embed(&mut pixels, width, height, 0xDEAD_BEEF, DEFAULT_STRENGTH));
+
+        let img = RgbaImage::from_raw(width, height, pixels).unwrap();
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(75))
+            .unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap().to_rgba8();
+
+        let found = detect(decoded.as_raw(), width, height).unwrap();
+        assert_eq!(found.id, 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn unmarked_image_should_not_be_detected() {
+        let (width, height) = (400, 300);
+        assert_eq!(detect(&sample(width, height), width, height), None);
+    }
+}
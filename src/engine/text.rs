@@ -0,0 +1,157 @@
+use crate::fonts::FONTS;
+use image::RgbaImage;
+use lazy_static::lazy_static;
+use rusttype::{point, Font, PositionedGlyph, Scale};
+
+lazy_static! {
+    // 内置字体，编译时直接打包进二进制
+    static ref FONT: Font<'static> =
+        Font::try_from_bytes(include_bytes!("../../fonts/Roboto-Regular.ttf")).unwrap();
+}
+
+// 默认字号（像素）
+pub const DEFAULT_SIZE: f32 = 24.0;
+// 绘制时字号的上限，spec 允许的字号更大，但每个字形都要完整光栅化，太大的字号会占用很长时间的 CPU
+pub const MAX_SIZE: f32 = 512.0;
+
+// 实际绘制使用的字号，不超过 MAX_SIZE 和图片的高度
+fn effective_size(size: f32, height: u32) -> f32 {
+    size.min(MAX_SIZE).min(height.max(1) as f32)
+}
+
+pub fn builtin() -> Font<'static> {
+    FONT.clone()
+}
+
+// 按名字查找注册的字体（见 fonts.rs），名字为空时使用内置字体
+pub fn font(name: &str) -> Option<Font<'static>> {
+    match name {
+        "" => Some(builtin()),
+        name => FONTS.get(name),
+    }
+}
+
+// 文字的排版结果，原点在文字框的左上角
+fn layout<'a>(font: &'a Font, text: &str, size: f32) -> Vec<PositionedGlyph<'a>> {
+    let scale = Scale::uniform(size);
+    let ascent = font.v_metrics(scale).ascent;
+    font.layout(text, scale, point(0.0, ascent)).collect()
+}
+
+// 计算文字渲染后的宽高
+pub fn measure(font: &Font, text: &str, size: f32) -> (u32, u32) {
+    let scale = Scale::uniform(size);
+    let v = font.v_metrics(scale);
+    let width = layout(font, text, size)
+        .last()
+        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0);
+    (width.ceil() as u32, (v.ascent - v.descent).ceil() as u32)
+}
+
+// 文字太长时从尾部截断并加上省略号，保证宽度不超过 max_width
+pub fn truncate(font: &Font, text: &str, size: f32, max_width: u32) -> String {
+    if measure(font, text, size).0 <= max_width {
+        return text.to_owned();
+    }
+    let mut chars: Vec<char> = text.chars().collect();
+    while !chars.is_empty() {
+        chars.pop();
+        let candidate: String = chars.iter().chain(['…'].iter()).collect();
+        if measure(font, &candidate, size).0 <= max_width {
+            return candidate;
+        }
+    }
+    String::new()
+}
+
+// 在图片顶部或底部画一条横跨整个宽度的文字条，四周留 size / 2 的边距，文字太长时截断
+// 文字为空（比如 EXIF 中没有对应的字段）时不画
+pub fn caption(img: &mut RgbaImage, font: &Font, text: &str, size: f32, top: bool, color: [u8; 4], background: [u8; 4]) {
+    let (width, height) = img.dimensions();
+    let size = effective_size(size, height);
+    let padding = (size / 2.0).round() as u32;
+    let text = truncate(font, text, size, width.saturating_sub(padding * 2));
+    if text.is_empty() {
+        return;
+    }
+    let strip = (measure(font, &text, size).1 + padding * 2).min(height);
+    let y0 = if top { 0 } else { height - strip };
+    let alpha = background[3] as f32 / 255.0;
+    for y in y0..y0 + strip {
+        for x in 0..width {
+            let p = img.get_pixel_mut(x, y);
+            for c in 0..3 {
+                p[c] = (background[c] as f32 * alpha + p[c] as f32 * (1.0 - alpha)).round() as u8;
+            }
+            p[3] = (255.0 * alpha + p[3] as f32 * (1.0 - alpha)).round() as u8;
+        }
+    }
+    draw(img, font, &text, padding as i32, (y0 + padding) as i32, size, color);
+}
+
+// 在 (x, y) 处（文字框左上角）绘制文字，color 为 RGBA，按字形覆盖率做 alpha 混合
+pub fn draw(img: &mut RgbaImage, font: &Font, text: &str, x: i32, y: i32, size: f32, color: [u8; 4]) {
+    let size = effective_size(size, img.height());
+    // 位置按 i64 计算，x、y 很大时也不会溢出
+    let (x, y) = (x as i64, y as i64);
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    for glyph in layout(font, text, size) {
+        // 从左到右排版，之后的字形都在图片右边之外
+        if x as f32 + glyph.position().x >= width as f32 {
+            break;
+        }
+        let bb = match glyph.pixel_bounding_box() {
+            Some(bb) => bb,
+            None => continue,
+        };
+        // 完全在图片之外的字形不光栅化
+        if x + bb.max.x as i64 <= 0 || y + bb.max.y as i64 <= 0 || y + bb.min.y as i64 >= height {
+            continue;
+        }
+        glyph.draw(|gx, gy, coverage| {
+            let px = x + bb.min.x as i64 + gx as i64;
+            let py = y + bb.min.y as i64 + gy as i64;
+            if px < 0 || py < 0 || px >= width || py >= height {
+                return;
+            }
+            let alpha = (coverage * color[3] as f32 / 255.0).min(1.0);
+            let p = img.get_pixel_mut(px as u32, py as u32);
+            for c in 0..3 {
+                p[c] = (color[c] as f32 * alpha + p[c] as f32 * (1.0 - alpha)).round() as u8;
+            }
+            p[3] = (255.0 * alpha + p[3] as f32 * (1.0 - alpha)).round() as u8;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn draw_should_skip_invisible_glyphs() {
+        let blank = RgbaImage::from_pixel(64, 64, Rgba([0, 0, 0, 255]));
+        let white = [255, 255, 255, 255];
+        let text = "W".repeat(1024);
+
+        // 字号不超过 MAX_SIZE 和图片高度
+        assert_eq!(effective_size(8192.0, 64), 64.0);
+        assert_eq!(effective_size(8192.0, 4000), MAX_SIZE);
+        assert_eq!(effective_size(12.0, 64), 12.0);
+        assert_eq!(effective_size(12.0, 0), 1.0);
+        let mut huge = blank.clone();
+        draw(&mut huge, &FONT, &text, 0, 0, 8192.0, white);
+        let mut clamped = blank.clone();
+        draw(&mut clamped, &FONT, &text, 0, 0, 64.0, white);
+        assert!(huge == clamped && huge != blank);
+
+        // 完全在图片之外的文字不改变图片
+        for (x, y) in [(100, 0), (0, 100), (-100_000, 0), (0, -1000), (i32::MAX, i32::MAX), (0, i32::MAX), (i32::MIN, 0)] {
+            let mut img = blank.clone();
+            draw(&mut img, &FONT, "hello", x, y, 24.0, white);
+            assert!(img == blank);
+        }
+    }
+}
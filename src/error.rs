@@ -0,0 +1,79 @@
+use crate::pb::SpecError;
+use axum::{
+    body::Full,
+    http::{header, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+};
+use bytes::Bytes;
+use std::convert::Infallible;
+
+// 请求处理的错误：大部分只需要状态码，spec 解析失败时额外返回 JSON 说明出错的位置
+#[derive(Debug)]
+pub enum AppError {
+    Status(StatusCode),
+    Spec(SpecError),
+    // 租户不允许使用的操作，返回 403 和 spec 错误一样的 JSON（见 tenant.rs）
+    Forbidden(SpecError),
+    // 超出源图片的限制，返回 413 和区分原因的 code（见 limits.rs）
+    Limit(&'static str, String),
+    // 源图片下载或者解码失败，配置了备用图片时用它代替（见 fallback.rs）
+    Source(StatusCode),
+    // 过载时拒绝，返回 503 和 Retry-After（秒，见 overload.rs）
+    Overloaded(u64),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Status(status) | AppError::Source(status) => *status,
+            AppError::Spec(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Limit(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl From<StatusCode> for AppError {
+    fn from(status: StatusCode) -> Self {
+        AppError::Status(status)
+    }
+}
+
+impl From<SpecError> for AppError {
+    fn from(e: SpecError) -> Self {
+        AppError::Spec(e)
+    }
+}
+
+impl IntoResponse for AppError {
+    type Body = Full<Bytes>;
+    type BodyError = Infallible;
+
+    fn into_response(self) -> Response<Self::Body> {
+        let status = self.status();
+        let body = match self {
+            AppError::Status(_) | AppError::Source(_) => return status.into_response().map(|_| Full::default()),
+            AppError::Overloaded(retry_after) => {
+                let mut res = status.into_response().map(|_| Full::default());
+                res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+                return res;
+            }
+            AppError::Spec(e) | AppError::Forbidden(e) => serde_json::json!({
+                "error": e.message,
+                "offset": e.offset,
+                "expected": e.expected,
+                "op": e.op,
+            }),
+            AppError::Limit(code, message) => serde_json::json!({
+                "error": message,
+                "code": code,
+            }),
+        };
+        let mut res = Response::new(Full::from(body.to_string()));
+        *res.status_mut() = status;
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        res
+    }
+}
@@ -0,0 +1,308 @@
+// "GET /estimate/:spec/:url" 预估一个 spec 的处理结果和开销，不做实际处理：输出尺寸、CPU 开销的等级，以及是否会被限制拒绝
+// 只下载源图片并读取文件头，客户端可以在上线新的 spec 之前先用样例图片检查
+use crate::{
+    assets, check_source, config::Config, decode_status, engine, error::AppError, fetch_error, fonts, output_format,
+    pb::{spec, ImageSpec, SourceInfo, SpecError},
+    policy::OutputKind,
+    retrieve_image, static_output,
+    tenant::Tenants,
+    Cache,
+};
+use axum::{
+    extract::{Extension, Path},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// 开销等级的分界，单位见 op_weight()
+const MODERATE_WORK: f64 = 10.0;
+const EXPENSIVE_WORK: f64 = 200.0;
+
+#[derive(Deserialize)]
+pub struct Params {
+    spec: String,
+    url: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Cost {
+    Cheap,
+    Moderate,
+    Expensive,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Estimate {
+    source: Source,
+    // 按顺序执行的操作，条件不满足的操作已经去掉
+    ops: Vec<Op>,
+    output: Output,
+    cost: Cost,
+    // 预估的相对工作量，只用于比较不同的 spec
+    work: f64,
+    // 实际请求时会返回的错误，为空表示不会被拒绝
+    rejected: Option<Rejection>,
+}
+
+#[derive(Serialize, Debug)]
+struct Source {
+    format: String,
+    width: u32,
+    height: u32,
+    bytes: usize,
+    frames: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Op {
+    op: &'static str,
+    // 执行之后的尺寸
+    width: u32,
+    height: u32,
+    work: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct Output {
+    format: &'static str,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct Rejection {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    message: String,
+}
+
+// 和 /image 使用同样的租户、预设和限制；源图片无法下载或者解码时直接返回错误
+pub async fn estimate(
+    Path(params): Path<Params>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<Json<Estimate>, AppError> {
+    let tenant = tenants.admit(&req_headers)?;
+    let _permit = crate::overload::OVERLOAD.admit("estimate").await?;
+    let raw_spec = percent_decode_str(&params.spec).decode_utf8_lossy().into_owned();
+    let mut spec = tenant.spec(&raw_spec)?;
+    let policy = tenant.policy(&raw_spec);
+    let url: &str = &percent_decode_str(&params.url).decode_utf8_lossy();
+    if !tenant.allows(url) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let limits = &tenant.source_limits;
+    let (data, _) = retrieve_image(&tenant.cache_namespace, url, cache, &tenant.source_cache, limits.max_bytes, &config.origin_credentials, config.fetch_resume.as_ref())
+        .await
+        .map_err(fetch_error)?;
+    let probe = engine::probe(&data, spec.page).map_err(decode_status)?;
+    policy.enforce(&mut spec);
+    spec.resolve_conditions(&SourceInfo {
+        width: probe.width,
+        height: probe.height,
+        format: Some(probe.format),
+    });
+    let frames = gif_frames(&data).unwrap_or(1);
+    let (_, _, static_kind) = static_output(&output_format(&spec, tenant.output(&raw_spec), false));
+
+    // 和 /image 的检查顺序一致，只报告第一个错误
+    let animated = frames > 1 && policy.allows(OutputKind::Gif);
+    let rejected = if let Err(e) = tenant.check_ops(&spec) {
+        Some(e)
+    } else if let Some(name) = assets::ASSETS.missing(&spec) {
+        Some(SpecError::new(0, "uploaded asset", None, format!("unknown asset {}", name)).into())
+    } else if let Some(name) = fonts::FONTS.missing(&spec) {
+        Some(SpecError::new(0, "registered font", None, format!("unknown font {}", name)).into())
+    } else if !tenant.allows_fonts(&spec) {
+        Some(StatusCode::FORBIDDEN.into())
+    } else if let Err(e) = check_source(&data, spec.page, limits) {
+        Some(e)
+    } else if animated
+        && (frames > config.animation.max_frames
+            || frames as u64 * probe.width as u64 * probe.height as u64 > config.animation.max_pixels())
+    {
+        Some(StatusCode::UNPROCESSABLE_ENTITY.into())
+    } else if !animated && !policy.allows(static_kind) {
+        Some(StatusCode::NOT_ACCEPTABLE.into())
+    } else {
+        None
+    };
+
+    let source = Source {
+        format: probe.format.to_string(),
+        width: probe.width,
+        height: probe.height,
+        bytes: data.len(),
+        frames,
+    };
+    let format = if animated { OutputKind::Gif } else { static_kind };
+    Ok(Json(estimate_spec(&spec, source, format, rejected.map(rejection))))
+}
+
+fn estimate_spec(spec: &ImageSpec, source: Source, format: OutputKind, rejected: Option<Rejection>) -> Estimate {
+    let animated = format == OutputKind::Gif;
+    // 动图的每一帧都要处理
+    let frames = if animated { source.frames as f64 } else { 1.0 };
+    let mut size = (source.width, source.height);
+    let mut total = megapixels(size) * frames;
+    let mut ops = Vec::new();
+    for data in spec.specs.iter().filter_map(|s| s.data.as_ref()) {
+        let work = op_weight(data) * megapixels(size) * frames;
+        size = output_size(data, size);
+        total += work;
+        ops.push(Op {
+            op: data.name(),
+            width: size.0,
+            height: size.1,
+            work,
+        });
+    }
+    // GIF 的编码（量化颜色）比 JPEG 慢得多
+    total += megapixels(size) * if animated { 8.0 * frames } else { 2.0 };
+    let cost = if total >= EXPENSIVE_WORK {
+        Cost::Expensive
+    } else if total >= MODERATE_WORK {
+        Cost::Moderate
+    } else {
+        Cost::Cheap
+    };
+    Estimate {
+        source,
+        ops,
+        output: Output {
+            format: format.to_str(),
+            width: size.0,
+            height: size.1,
+        },
+        cost,
+        work: total,
+        rejected,
+    }
+}
+
+fn megapixels((width, height): (u32, u32)) -> f64 {
+    width as f64 * height as f64 / 1_000_000.0
+}
+
+// 每百万像素大致的相对开销，解码一百万像素为 1
+fn op_weight(data: &spec::Data) -> f64 {
+    match data {
+        spec::Data::Resize(v) if v.rtype == crate::pb::resize::ResizeType::SeamCarve as i32 => 200.0,
+        spec::Data::Resize(_) | spec::Data::Lqip(_) | spec::Data::Simulate(_) | spec::Data::ColorPop(_) => 2.0,
+        spec::Data::Crop(_) | spec::Data::Flipv(_) | spec::Data::Fliph(_) => 0.5,
+        spec::Data::Contrast(_) | spec::Data::Filter(_) | spec::Data::Watermark(_) | spec::Data::Text(_) | spec::Data::Caption(_) => 1.0,
+        spec::Data::AutoEnhance(_) => 6.0,
+        spec::Data::InvisibleWatermark(_) => 4.0,
+        spec::Data::BlurRegions(_) => 8.0,
+    }
+}
+
+// 和 engine 中的实现保持一致
+fn output_size(data: &spec::Data, (width, height): (u32, u32)) -> (u32, u32) {
+    match data {
+        spec::Data::Resize(v) => (v.width, v.height),
+        spec::Data::Crop(v) => {
+            let (x1, y1, x2, y2) = v.area(width, height);
+            (x2.saturating_sub(x1), y2.saturating_sub(y1))
+        }
+        spec::Data::Lqip(v) => {
+            let w = v.width().min(width).max(1);
+            (w, ((height as f64 * w as f64 / width.max(1) as f64).round() as u32).max(1))
+        }
+        _ => (width, height),
+    }
+}
+
+fn rejection(e: AppError) -> Rejection {
+    let status = e.status();
+    let (code, message) = match e {
+        AppError::Spec(e) | AppError::Forbidden(e) => (None, e.message),
+        AppError::Limit(code, message) => (Some(code), message),
+        AppError::Status(_) | AppError::Source(_) | AppError::Overloaded(_) => (None, status.canonical_reason().unwrap_or("").to_owned()),
+    };
+    Rejection {
+        status: status.as_u16(),
+        code,
+        message,
+    }
+}
+
+// 不解码像素，按块结构数出 GIF 的帧数；不是 GIF 或者结构有问题时返回 None
+fn gif_frames(data: &[u8]) -> Option<usize> {
+    if !data.starts_with(b"GIF8") {
+        return None;
+    }
+    // 颜色表的字节数
+    let table = |packed: u8| if packed & 0x80 != 0 { 3 << ((packed & 0x07) + 1) } else { 0 };
+    // 跳过以 0 结尾的数据子块
+    let skip_blocks = |mut i: usize| loop {
+        let len = *data.get(i)? as usize;
+        i += 1 + len;
+        if len == 0 {
+            return Some(i);
+        }
+    };
+    let mut i = 13 + table(*data.get(10)?);
+    let mut frames = 0;
+    loop {
+        match *data.get(i)? {
+            // 扩展块：标签之后是数据子块
+            0x21 => i = skip_blocks(i + 2)?,
+            // 图像描述符：10 个字节，可能有局部颜色表，然后是 LZW 编码长度和数据子块
+            0x2c => {
+                frames += 1;
+                i = skip_blocks(i + 10 + table(*data.get(i + 9)?) + 1)?;
+            }
+            0x3b => return Some(frames),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Delay, Frame, RgbaImage};
+
+    fn source(width: u32, height: u32, frames: usize) -> Source {
+        Source {
+            format: "jpeg".to_owned(),
+            width,
+            height,
+            bytes: 0,
+            frames,
+        }
+    }
+
+    #[test]
+    fn estimate_should_follow_spec_chain() {
+        let spec = ImageSpec::parse("crop:center,w=800,h=600;resize:w=200,h=150;lqip:w=20").unwrap();
+        let estimate = estimate_spec(&spec, source(4000, 3000, 1), OutputKind::Jpeg, None);
+        let sizes: Vec<_> = estimate.ops.iter().map(|v| (v.op, v.width, v.height)).collect();
+        assert_eq!(sizes, vec![("crop", 800, 600), ("resize", 200, 150), ("lqip", 20, 15)]);
+        assert_eq!((estimate.output.width, estimate.output.height), (20, 15));
+        assert_eq!(estimate.cost, Cost::Moderate);
+
+        let thumb = ImageSpec::parse("resize:w=100,h=100").unwrap();
+        assert_eq!(estimate_spec(&thumb, source(800, 600, 1), OutputKind::Jpeg, None).cost, Cost::Cheap);
+        let seam = ImageSpec::parse("resize:w=100,h=100,type=seam_carve").unwrap();
+        assert_eq!(estimate_spec(&seam, source(2000, 1500, 1), OutputKind::Jpeg, None).cost, Cost::Expensive);
+        let gif = estimate_spec(&thumb, source(800, 600, 200), OutputKind::Gif, None);
+        assert_eq!((gif.output.format, gif.cost), ("gif", Cost::Expensive));
+
+        let frames = (0..3)
+            .map(|_| Frame::from_parts(RgbaImage::new(4, 4), 0, 0, Delay::from_numer_denom_ms(100, 1)))
+            .collect();
+        assert_eq!(gif_frames(&engine::encode_gif(frames)), Some(3));
+        assert_eq!(gif_frames(&engine::encode(RgbaImage::new(4, 4), image::ImageOutputFormat::Gif)), Some(1));
+        assert_eq!(gif_frames(b"GIF89a"), None);
+        assert_eq!(gif_frames(b"\x89PNG"), None);
+    }
+}
@@ -0,0 +1,99 @@
+// 源图片下载或者解码失败时返回备用图片，而不是错误状态码，商品页不会显示破图
+// 备用图片按请求的尺寸缩放裁剪，短时间缓存，源站恢复之后很快就能看到正常的图片
+use crate::{
+    engine,
+    pb::{spec, ImageSpec},
+};
+use anyhow::{Context, Result};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use image::{imageops::FilterType, DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use serde::Deserialize;
+use tracing::info;
+
+// 没有配置图片时使用浅灰色的纯色图片，没有缩放操作时的尺寸
+const PLACEHOLDER_SIZE: u32 = 100;
+const PLACEHOLDER_COLOR: Rgba<u8> = Rgba([238, 238, 238, 255]);
+
+// 备用图片的配置（[fallback]），不配置则返回错误状态码
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackConfig {
+    // 备用图片的文件路径，不配置则使用浅灰色的纯色图片
+    pub image: Option<String>,
+    // 响应的 Cache-Control: max-age（秒）
+    pub max_age: u64,
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        Self { image: None, max_age: 60 }
+    }
+}
+
+pub struct Fallback(Option<(RgbaImage, u64)>);
+
+impl Fallback {
+    pub fn new(config: Option<&FallbackConfig>) -> Result<Self> {
+        let config = match config {
+            Some(v) => v,
+            None => return Ok(Self(None)),
+        };
+        let image = match config.image {
+            Some(ref path) => {
+                let data = std::fs::read(path).with_context(|| format!("read fallback image {}", path))?;
+                engine::decode(&data, 0).with_context(|| format!("decode fallback image {}", path))?
+            }
+            None => RgbaImage::from_pixel(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE, PLACEHOLDER_COLOR),
+        };
+        Ok(Self(Some((image, config.max_age))))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    // status 是原本要返回的错误，放在 x-shanbor-fallback 中方便监控
+    pub fn response(&self, spec: Option<&ImageSpec>, status: StatusCode) -> Option<(HeaderMap, Vec<u8>)> {
+        let (image, max_age) = self.0.as_ref()?;
+        info!("Serving fallback image for {}", status);
+        let img = match spec.and_then(requested_size) {
+            Some((width, height)) => DynamicImage::ImageRgba8(image.clone())
+                .resize_to_fill(width, height, FilterType::Triangle)
+                .to_rgba8(),
+            None => image.clone(),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("image/jpeg"));
+        headers.insert("cache-control", HeaderValue::from_str(&format!("public, max-age={}", max_age)).unwrap());
+        headers.insert("x-shanbor-fallback", HeaderValue::from(status.as_u16()));
+        Some((headers, engine::encode(img, ImageOutputFormat::Jpeg(85))))
+    }
+}
+
+// 请求的输出尺寸：最后一个缩放操作的宽高
+fn requested_size(spec: &ImageSpec) -> Option<(u32, u32)> {
+    spec.specs.iter().rev().find_map(|s| match s.data {
+        Some(spec::Data::Resize(ref v)) => Some((v.width, v.height)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fallback_should_match_requested_size() {
+        assert!(Fallback::new(None).unwrap().response(None, StatusCode::BAD_REQUEST).is_none());
+
+        let fallback = Fallback::new(Some(&FallbackConfig::default())).unwrap();
+        let spec = ImageSpec::parse("resize:w=300,h=200;filter:marine;resize:w=120,h=80").unwrap();
+        let (headers, body) = fallback.response(Some(&spec), StatusCode::UNPROCESSABLE_ENTITY).unwrap();
+        assert_eq!(headers["cache-control"], "public, max-age=60");
+        assert_eq!(headers["x-shanbor-fallback"], "422");
+        assert_eq!(engine::dimensions(&body, 0).unwrap(), (120, 80));
+
+        let (_, body) = fallback.response(None, StatusCode::BAD_REQUEST).unwrap();
+        assert_eq!(engine::dimensions(&body, 0).unwrap(), (PLACEHOLDER_SIZE, PLACEHOLDER_SIZE));
+    }
+}
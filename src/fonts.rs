@@ -0,0 +1,241 @@
+// 文字水印可以使用的字体，spec 中用 text:...,font=<name> 引用
+// 字体来自配置中的文件或目录（fonts），以及通过管理接口上传到 fonts_dir 的文件，启动时全部加载到内存
+use crate::{
+    assets::{valid_name, AssetError},
+    pb::{spec, ImageSpec},
+};
+use lazy_static::lazy_static;
+use rusttype::Font;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+use tracing::{info, warn};
+
+// 上传的字体文件大小限制
+pub const MAX_FONT_BYTES: usize = 16 << 20;
+
+lazy_static! {
+    // 绘制文字时从这里取字体
+    pub static ref FONTS: FontStore = FontStore::default();
+}
+
+// 字体的元数据，GET /admin/fonts 返回
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FontInfo {
+    pub name: String,
+    pub bytes: usize,
+    pub sha256: String,
+    // 通过管理接口上传的字体，可以替换和删除；配置文件中的字体只读
+    pub uploaded: bool,
+}
+
+struct Entry {
+    font: Font<'static>,
+    info: FontInfo,
+    digest: [u8; 32],
+}
+
+#[derive(Default)]
+pub struct FontStore {
+    dir: RwLock<Option<PathBuf>>,
+    fonts: RwLock<HashMap<String, Entry>>,
+}
+
+// 文件名去掉扩展名作为字体名，转成小写，其它字符替换成 -，比如 Brand-Bold.otf 是 brand-bold
+fn font_name(path: &Path) -> Option<String> {
+    let name: String = path
+        .file_stem()?
+        .to_str()?
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9' | '_' | '-') => c,
+            _ => '-',
+        })
+        .collect();
+    Some(name).filter(|v| valid_name(v))
+}
+
+fn is_font_file(path: &Path) -> bool {
+    matches!(path.extension().and_then(|v| v.to_str()), Some("ttf" | "otf" | "TTF" | "OTF"))
+}
+
+fn parse(name: &str, data: Vec<u8>, uploaded: bool) -> Result<Entry, AssetError> {
+    let digest: [u8; 32] = Sha256::digest(&data).into();
+    let info = FontInfo {
+        name: name.to_owned(),
+        bytes: data.len(),
+        sha256: hex::encode(digest),
+        uploaded,
+    };
+    let font = Font::try_from_vec(data).ok_or_else(|| AssetError::Invalid("not a TTF/OTF font".to_owned()))?;
+    Ok(Entry { font, info, digest })
+}
+
+// 目录中的字体文件，无法读取的跳过
+fn load_dir(dir: &Path, uploaded: bool, fonts: &mut HashMap<String, Entry>) -> Result<(), AssetError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match font_name(&path) {
+            Some(name) if is_font_file(&path) => name,
+            _ => continue,
+        };
+        match fs::read(&path).map_err(AssetError::from).and_then(|data| parse(&name, data, uploaded)) {
+            Ok(v) => {
+                fonts.insert(name, v);
+            }
+            Err(e) => warn!("Failed to load font {}: {}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+impl FontStore {
+    // paths 是配置中的字体文件或目录，dir 保存上传的字体；同名时配置中的字体优先
+    pub fn open(&self, paths: &[String], dir: Option<&str>) -> Result<(), AssetError> {
+        let mut fonts = HashMap::new();
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+            load_dir(Path::new(dir), true, &mut fonts)?;
+        }
+        for path in paths.iter().map(Path::new) {
+            if path.is_dir() {
+                load_dir(path, false, &mut fonts)?;
+                continue;
+            }
+            // 明确配置的文件必须能加载
+            let name = font_name(path).ok_or(AssetError::InvalidName)?;
+            fonts.insert(name.clone(), parse(&name, fs::read(path)?, false)?);
+        }
+        info!("Loaded {} fonts", fonts.len());
+        *self.fonts.write().unwrap() = fonts;
+        *self.dir.write().unwrap() = dir.map(PathBuf::from);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Font<'static>> {
+        self.fonts.read().unwrap().get(name).map(|v| v.font.clone())
+    }
+
+    // spec 引用但没有注册的第一个字体
+    pub fn missing<'a>(&self, spec: &'a ImageSpec) -> Option<&'a str> {
+        let fonts = self.fonts.read().unwrap();
+        referenced(spec).find(|name| !fonts.contains_key(*name))
+    }
+
+    // 引用的字体文件的摘要，字体被替换后缓存的 key 随之改变
+    pub fn digests(&self, spec: &ImageSpec) -> Vec<[u8; 32]> {
+        let fonts = self.fonts.read().unwrap();
+        referenced(spec)
+            .map(|name| fonts.get(name).map(|v| v.digest).unwrap_or_default())
+            .collect()
+    }
+}
+
+// 管理接口 /admin/fonts 使用
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+impl FontStore {
+    pub fn list(&self) -> Vec<FontInfo> {
+        let mut list: Vec<_> = self.fonts.read().unwrap().values().map(|v| v.info.clone()).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    // 上传或替换字体，按文件头选择扩展名，先写临时文件再改名
+    pub fn put(&self, name: &str, data: &[u8]) -> Result<FontInfo, AssetError> {
+        if !valid_name(name) {
+            return Err(AssetError::InvalidName);
+        }
+        if data.len() > MAX_FONT_BYTES {
+            return Err(AssetError::TooLarge(MAX_FONT_BYTES));
+        }
+        let dir = self.dir.read().unwrap().clone().ok_or(AssetError::NotConfigured)?;
+        if self.fonts.read().unwrap().get(name).is_some_and(|v| !v.info.uploaded) {
+            return Err(AssetError::ReadOnly);
+        }
+        let entry = parse(name, data.to_vec(), true)?;
+        let ext = if data.starts_with(b"OTTO") { "otf" } else { "ttf" };
+        let path = dir.join(format!("{}.{}", name, ext));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        // 换了格式时删除另一种扩展名的旧文件
+        let other = path.with_extension(if ext == "otf" { "ttf" } else { "otf" });
+        let _ = fs::remove_file(other);
+
+        let info = entry.info.clone();
+        self.fonts.write().unwrap().insert(name.to_owned(), entry);
+        Ok(info)
+    }
+
+    // 返回字体是否存在，配置文件中的字体不能删除
+    pub fn delete(&self, name: &str) -> Result<bool, AssetError> {
+        if !valid_name(name) {
+            return Err(AssetError::InvalidName);
+        }
+        let dir = self.dir.read().unwrap().clone().ok_or(AssetError::NotConfigured)?;
+        let mut fonts = self.fonts.write().unwrap();
+        match fonts.get(name) {
+            None => return Ok(false),
+            Some(v) if !v.info.uploaded => return Err(AssetError::ReadOnly),
+            _ => {}
+        }
+        fonts.remove(name);
+        for ext in ["ttf", "otf"] {
+            match fs::remove_file(dir.join(format!("{}.{}", name, ext))) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+}
+
+// spec 中引用的字体名，内置字体不算
+pub fn referenced(spec: &ImageSpec) -> impl Iterator<Item = &str> {
+    spec.specs.iter().filter_map(|s| match s.data {
+        Some(spec::Data::Text(ref v)) if !v.font.is_empty() => Some(v.font.as_str()),
+        Some(spec::Data::Caption(ref v)) if !v.font.is_empty() => Some(v.font.as_str()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROBOTO: &[u8] = include_bytes!("../fonts/Roboto-Regular.ttf");
+
+    #[test]
+    fn fonts_should_be_loaded_from_config_and_uploads() {
+        let root = std::env::temp_dir().join(format!("shanbor-fonts-{}", std::process::id()));
+        let (configured, uploads) = (root.join("brand"), root.join("uploads"));
+        fs::create_dir_all(&configured).unwrap();
+        fs::write(configured.join("Brand Bold.TTF"), ROBOTO).unwrap();
+        fs::write(configured.join("README.txt"), "not a font").unwrap();
+
+        let store = FontStore::default();
+        store.open(&[configured.to_str().unwrap().to_owned()], uploads.to_str()).unwrap();
+        assert!(store.get("brand-bold").is_some());
+        assert!(matches!(store.put("brand-bold", ROBOTO), Err(AssetError::ReadOnly)));
+        assert!(matches!(store.delete("brand-bold"), Err(AssetError::ReadOnly)));
+        assert!(matches!(store.put("campaign", b"not a font"), Err(AssetError::Invalid(_))));
+        assert!(store.put("campaign", ROBOTO).unwrap().uploaded);
+
+        let spec = ImageSpec::parse("text:a,font=campaign;text:b,font=nope").unwrap();
+        assert_eq!(store.missing(&spec), Some("nope"));
+        assert_eq!(store.digests(&spec)[0], <[u8; 32]>::from(Sha256::digest(ROBOTO)));
+
+        // 重新打开时加载上传的字体
+        let reopened = FontStore::default();
+        reopened.open(&[], uploads.to_str()).unwrap();
+        assert_eq!(reopened.list().len(), 1);
+        assert!(reopened.delete("campaign").unwrap());
+        assert!(!reopened.delete("campaign").unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use crate::pb::{resize, spec, ImageSpec, Spec, MAX_DIMENSION};
+use axum::http::HeaderMap;
+
+// 浏览器只在响应里有 Accept-CH 之后才会发送这些请求头
+pub const ACCEPT_CH: &str = "Sec-CH-Width, Sec-CH-DPR, Save-Data";
+// 输出会随这些请求头变化，缓存时需要区分
+pub const VARY: &str = "accept, sec-ch-width, sec-ch-dpr, save-data";
+
+// 支持的最大 DPR，防止请求把图片放大太多
+const MAX_DPR: f32 = 4.0;
+// Save-Data: on 时 JPEG 的最高质量
+const SAVE_DATA_QUALITY: u8 = 50;
+
+// HTTP Client Hints：根据设备自动调整输出的尺寸和质量，不需要为每种设备构造不同的 URL
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientHints {
+    // Sec-CH-Width：图片在页面上的宽度，单位是物理像素
+    pub width: Option<u32>,
+    // Sec-CH-DPR：设备像素比
+    pub dpr: Option<f32>,
+    // Save-Data: on
+    pub save_data: bool,
+}
+
+impl ClientHints {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        Self {
+            width: header("sec-ch-width")
+                .and_then(|v| v.parse().ok())
+                .filter(|&v| v > 0)
+                .map(|v: u32| v.min(MAX_DIMENSION)),
+            dpr: header("sec-ch-dpr")
+                .and_then(|v| v.parse().ok())
+                .filter(|v: &f32| v.is_finite() && *v > 0.0)
+                .map(|v| v.min(MAX_DPR)),
+            save_data: header("save-data").is_some_and(|v| v.eq_ignore_ascii_case("on")),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // spec 中缩放的尺寸按 CSS 像素理解，乘以 DPR 得到物理像素
+    pub fn scale_spec(&self, spec: &mut ImageSpec) {
+        let dpr = match self.dpr {
+            Some(v) if v != 1.0 => v,
+            _ => return,
+        };
+        let scale = |v: u32| ((v as f32 * dpr).round() as u32).clamp(1, MAX_DIMENSION);
+        for s in spec.specs.iter_mut() {
+            if let Some(spec::Data::Resize(ref mut v)) = s.data {
+                v.width = scale(v.width);
+                v.height = scale(v.height);
+            }
+        }
+    }
+
+    // 处理后的图片比 Sec-CH-Width 宽时，等比缩小到这个宽度，不会放大
+    pub fn fit(&self, (width, height): (u32, u32)) -> Option<Spec> {
+        let target = self.width.filter(|&v| v < width)?;
+        let h = ((height as f64 * target as f64 / width as f64).round() as u32).max(1);
+        Some(Spec::new_resize(target, h, resize::SampleFilter::Triangle))
+    }
+
+    pub fn quality(&self, quality: u8) -> u8 {
+        if self.save_data {
+            quality.min(SAVE_DATA_QUALITY)
+        } else {
+            quality
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn hints_should_adjust_output() {
+        let mut headers = HeaderMap::new();
+        assert!(ClientHints::from_headers(&headers).is_empty());
+
+        headers.insert("sec-ch-width", HeaderValue::from_static("320"));
+        headers.insert("sec-ch-dpr", HeaderValue::from_static("2"));
+        headers.insert("save-data", HeaderValue::from_static("on"));
+        let hints = ClientHints::from_headers(&headers);
+        assert_eq!(hints.quality(85), SAVE_DATA_QUALITY);
+
+        let mut spec = ImageSpec::new(vec![Spec::new_resize(200, 100, resize::SampleFilter::Nearest)]);
+        hints.scale_spec(&mut spec);
+        assert_eq!(spec, ImageSpec::new(vec![Spec::new_resize(400, 200, resize::SampleFilter::Nearest)]));
+
+        assert_eq!(hints.fit((400, 200)), Some(Spec::new_resize(320, 160, resize::SampleFilter::Triangle)));
+        assert_eq!(hints.fit((300, 200)), None);
+    }
+
+    #[test]
+    fn invalid_hints_should_be_ignored() {
+        let mut headers = HeaderMap::new();
+        headers.insert("sec-ch-width", HeaderValue::from_static("0"));
+        headers.insert("sec-ch-dpr", HeaderValue::from_static("NaN"));
+        headers.insert("save-data", HeaderValue::from_static("off"));
+        assert!(ClientHints::from_headers(&headers).is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+// 图片的统计信息：各通道的直方图、亮度和清晰度，QA 流程用来自动标记太暗或者模糊的商品图片
+use crate::{config::Config, error::AppError, load_engine, tenant::Tenants, Cache};
+use axum::{
+    extract::{Extension, Path},
+    http::{HeaderMap, HeaderValue, StatusCode},
+};
+use image::RgbaImage;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Histograms {
+    red: Vec<u32>,
+    green: Vec<u32>,
+    blue: Vec<u32>,
+    luminance: Vec<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImageStats {
+    width: u32,
+    height: u32,
+    // 每个通道 256 个桶，透明度不参与统计
+    histograms: Histograms,
+    // 亮度的平均值和中位数，范围 0-255
+    mean_luminance: f64,
+    median_luminance: u8,
+    // 亮度的拉普拉斯算子的方差，值越小越模糊
+    sharpness: f64,
+}
+
+// "GET /stats/:url"
+pub async fn image_stats(
+    Path(url): Path<String>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let tenant = tenants.admit(&req_headers)?;
+    let _permit = crate::overload::OVERLOAD.admit("stats").await?;
+    let url: &str = &percent_decode_str(&url).decode_utf8_lossy();
+    let img = load_engine(url, cache, &config, &tenant).await?.to_rgba();
+    let stats = analyze(&img);
+    info!("Finished stats: mean luminance {:.1}, sharpness {:.1}", stats.mean_luminance, stats.sharpness);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/json"));
+    let body = serde_json::to_vec(&stats).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((headers, body))
+}
+
+// ITU-R BT.601 的亮度
+fn luma(p: &image::Rgba<u8>) -> u8 {
+    ((p[0] as u32 * 299 + p[1] as u32 * 587 + p[2] as u32 * 114 + 500) / 1000) as u8
+}
+
+pub fn analyze(img: &RgbaImage) -> ImageStats {
+    let (width, height) = img.dimensions();
+    let mut histograms = Histograms {
+        red: vec![0; 256],
+        green: vec![0; 256],
+        blue: vec![0; 256],
+        luminance: vec![0; 256],
+    };
+    let lumas: Vec<u8> = img.pixels().map(luma).collect();
+    for (p, l) in img.pixels().zip(&lumas) {
+        histograms.red[p[0] as usize] += 1;
+        histograms.green[p[1] as usize] += 1;
+        histograms.blue[p[2] as usize] += 1;
+        histograms.luminance[*l as usize] += 1;
+    }
+
+    let total = lumas.len() as u64;
+    let sum: u64 = lumas.iter().map(|v| *v as u64).sum();
+    let mean_luminance = if total == 0 { 0.0 } else { sum as f64 / total as f64 };
+    // 累计数量超过一半的第一个桶
+    let mut seen = 0;
+    let median_luminance = histograms
+        .luminance
+        .iter()
+        .position(|v| {
+            seen += *v as u64;
+            seen * 2 >= total
+        })
+        .unwrap_or(0) as u8;
+
+    ImageStats {
+        width,
+        height,
+        histograms,
+        mean_luminance,
+        median_luminance,
+        sharpness: sharpness(&lumas, width as usize, height as usize),
+    }
+}
+
+// 4 邻域的拉普拉斯算子，边缘的像素不参与计算；小于 3x3 的图片返回 0
+fn sharpness(lumas: &[u8], width: usize, height: usize) -> f64 {
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let at = |x: usize, y: usize| lumas[y * width + x] as f64;
+    let mut values = Vec::with_capacity((width - 2) * (height - 2));
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            values.push(at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y));
+        }
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{imageops, Rgba};
+
+    #[test]
+    fn stats_should_flag_dark_and_blurry_images() {
+        let dark = analyze(&RgbaImage::from_pixel(10, 10, Rgba([20, 20, 20, 255])));
+        assert_eq!(dark.median_luminance, 20);
+        assert!((dark.mean_luminance - 20.0).abs() < 1e-9);
+        assert_eq!(dark.histograms.red[20], 100);
+        assert_eq!(dark.histograms.luminance.iter().sum::<u32>(), 100);
+        assert_eq!(dark.sharpness, 0.0);
+
+        let checker = RgbaImage::from_fn(64, 64, |x, y| {
+            let v = if (x / 4 + y / 4) % 2 == 0 { 0 } else { 255 };
+            Rgba([v, v, v, 255])
+        });
+        let sharp = analyze(&checker);
+        let blurry = analyze(&imageops::blur(&checker, 4.0));
+        assert!(sharp.sharpness > blurry.sharpness * 10.0);
+        assert_eq!(sharp.median_luminance, 0);
+        assert!((sharp.mean_luminance - 127.5).abs() < 1e-9);
+    }
+}
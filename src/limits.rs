@@ -0,0 +1,115 @@
+// 源图片的大小限制，防止个别很大的文件（比如几百 MB 的 TIFF）拖慢所有请求
+// 字节数在下载时检查（Content-Length 和实际读到的数据），像素数在识别格式之后、完整解码之前只读取文件头检查
+use crate::{engine, error::AppError};
+use serde::Deserialize;
+
+// 源文件太大
+pub const SOURCE_TOO_LARGE: &str = "source_too_large";
+// 源图片的像素太多
+pub const SOURCE_TOO_MANY_PIXELS: &str = "source_too_many_pixels";
+// 输出的动图太大
+pub const ANIMATION_TOO_LARGE: &str = "animation_too_large";
+
+// 源图片的限制（[source_limits]，租户中也可以单独配置），不配置则不限制
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceLimits {
+    // 源文件的最大字节数
+    pub max_bytes: Option<u64>,
+    // 最大的像素数，单位是百万像素
+    pub max_megapixels: Option<f64>,
+}
+
+// 动图的限制（[animation]），一个很长的 GIF 不能占用无限的 CPU 和内存
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnimationLimits {
+    // 源图片最多的帧数，超出时返回 422
+    pub max_frames: usize,
+    // 所有帧合计最多的像素数，单位是百万像素，解码时超出立即停止并返回 422
+    pub max_megapixels: f64,
+    // 输出 GIF 的最大字节数，超出时停止编码并返回 413
+    pub max_output_bytes: Option<usize>,
+    // 帧率的上限，更快的动图合并相邻的帧（总时长不变），不配置则保留所有帧
+    pub max_fps: Option<f64>,
+}
+
+impl Default for AnimationLimits {
+    fn default() -> Self {
+        Self {
+            max_frames: engine::MAX_FRAMES,
+            max_megapixels: engine::MAX_ANIMATION_MEGAPIXELS,
+            max_output_bytes: None,
+            max_fps: None,
+        }
+    }
+}
+
+impl AnimationLimits {
+    pub fn max_pixels(&self) -> u64 {
+        (self.max_megapixels.max(0.0) * 1_000_000.0) as u64
+    }
+}
+
+impl SourceLimits {
+    pub fn check_bytes(&self, len: u64) -> Result<(), AppError> {
+        match self.max_bytes {
+            Some(max) if len > max => Err(too_large(max)),
+            _ => Ok(()),
+        }
+    }
+
+    // 文件头有问题时不在这里报错，交给后面的解码
+    pub fn check_pixels(&self, data: &[u8], page: u32) -> Result<(), AppError> {
+        let max = match self.max_megapixels {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let (width, height) = match engine::probe(data, page) {
+            Ok(v) => (v.width, v.height),
+            Err(_) => return Ok(()),
+        };
+        if width as f64 * height as f64 > max * 1_000_000.0 {
+            return Err(AppError::Limit(
+                SOURCE_TOO_MANY_PIXELS,
+                format!("source is {}x{}, more than {} megapixels", width, height, max),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn too_large(max: u64) -> AppError {
+    AppError::Limit(SOURCE_TOO_LARGE, format!("source is larger than {} bytes", max))
+}
+
+pub fn animation_too_large(max: usize) -> AppError {
+    AppError::Limit(ANIMATION_TOO_LARGE, format!("animation is larger than {} bytes", max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, RgbaImage};
+
+    #[test]
+    fn limits_should_be_checked_before_decoding() {
+        let limits = SourceLimits {
+            max_bytes: Some(1000),
+            max_megapixels: Some(0.01),
+        };
+        assert!(limits.check_bytes(1000).is_ok());
+        let err = limits.check_bytes(1001).unwrap_err();
+        assert!(matches!(err, AppError::Limit(SOURCE_TOO_LARGE, _)));
+        assert_eq!(err.status().as_u16(), 413);
+
+        let small = engine::encode(RgbaImage::new(100, 100), ImageOutputFormat::Png);
+        assert!(limits.check_pixels(&small, 0).is_ok());
+        let large = engine::encode(RgbaImage::new(101, 100), ImageOutputFormat::Png);
+        let err = limits.check_pixels(&large, 0).unwrap_err();
+        assert!(matches!(err, AppError::Limit(SOURCE_TOO_MANY_PIXELS, _)));
+        // 只读取文件头：截断的文件也能得到尺寸
+        assert!(limits.check_pixels(&large[..64], 0).is_err());
+        assert!(SourceLimits::default().check_pixels(&large, 0).is_ok());
+    }
+}
@@ -1,26 +1,83 @@
 use anyhow::Result;
 use axum::{
-    extract::{Path, Extension}, 
-    handler::get, 
+    body::{Body, HttpBody},
+    extract::{Path, Extension, Query}, 
+    handler::{get, post}, 
     http::{StatusCode, HeaderMap, HeaderValue}, 
     Router,
     AddExtensionLayer,
 };
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use lru::LruCache;
-use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::Deserialize;
-use std::{collections::hash_map::DefaultHasher, convert::TryInto, hash::{Hash, Hasher}, sync::Arc};
+use std::{sync::Arc, time::Instant};
 use tokio::sync::Mutex;
-use tower::ServiceBuilder;
-use tracing::{info, instrument};
+use tower::{util::MapResponseLayer, ServiceBuilder};
+use tower_http::compression::CompressionLayer;
+use tracing::{info, instrument, Instrument};
 
 // 声明 pb, engine 模块，Rust 根据名字去加载该模块内容
 mod pb;
 mod engine;
+#[cfg(feature = "admin")]
+mod admin;
+mod active;
+mod assets;
+mod cache_key;
+mod capabilities;
+mod chaos;
+mod check;
+mod collage;
+mod compress;
+mod config;
+mod contactsheet;
+mod diff;
+mod error;
+mod estimate;
+mod fallback;
+mod fonts;
+mod hints;
+mod histogram;
+mod meta;
+mod metrics;
+mod overload;
+mod peer;
+mod policy;
+mod publish;
+mod redact;
+mod render;
+mod resume;
+mod routes;
+mod shadow;
+mod limits;
+mod signing;
+mod sigv4;
+mod source_cache;
+mod spec_api;
+mod stats;
+mod sprite;
+mod stream;
+mod template;
+mod tenant;
+mod trace;
+mod uploads;
+mod verify;
 
+use config::Config;
+use active::Phase;
+use error::AppError;
+use fallback::Fallback;
+use hints::ClientHints;
 use pb::*;
-use engine::{Engine, Photon};
+use policy::{OutputDefaults, OutputKind};
+use publish::Publisher;
+use limits::SourceLimits;
+use resume::{ResumeConfig, Resumed};
+use sigv4::OriginCredentials;
+use source_cache::{CachedSource, FetchError, SourceCacheConfig, INFLIGHT};
+use tenant::{Tenant, Tenants};
+use engine::{encode_within, DecodeError, Engine, Photon, TextArt};
 use image::ImageOutputFormat;
 
 // 参数使用 serde 做 Deserialize，axum 会自动识别并解析
@@ -30,94 +87,810 @@ use image::ImageOutputFormat;
      url: String,
  }
 
- type Cache = Arc<Mutex<LruCache<u64, Bytes>>>;
+ // 输出相关的参数通过 query string 传递
+ #[derive(Deserialize)]
+ struct OutputParams {
+     // 字符画每行的字符数
+     cols: Option<u32>,
+     // 字符画是否使用 ANSI 颜色
+     ansi: Option<bool>,
+     // 响应体的编码方式
+     encoding: Option<Encoding>,
+     // 输出的字节数上限，会自动降低质量以满足要求
+     max_bytes: Option<usize>,
+     // 设置了 max_bytes 且最低质量仍然超出时，是否允许缩小尺寸
+     downscale: Option<bool>,
+     // Content-Disposition 的类型，默认 inline
+     disposition: Option<Disposition>,
+     // 下载时的文件名（不含扩展名），默认根据源图片的 URL 生成
+     filename: Option<String>,
+     // 输出 x-shanbor-* 调试响应头，需要配置中开启 debug_query
+     debug: Option<bool>,
+     // 动图只处理第一帧，输出静态图片，用于便宜的缩略图
+     first_frame_only: Option<bool>,
+ }
+
+ #[derive(Deserialize, Clone, Copy, PartialEq)]
+ #[serde(rename_all = "lowercase")]
+ enum Disposition {
+     // 在浏览器中直接显示
+     Inline,
+     // 作为附件下载
+     Attachment,
+ }
+
+ #[derive(Deserialize, Clone, Copy, PartialEq)]
+ #[serde(rename_all = "lowercase")]
+ enum Encoding {
+     // 直接返回图片的二进制
+     Binary,
+     // 返回 data URI（data:image/jpeg;base64,...），方便直接内联到 HTML
+     Base64,
+ }
+
+ // 需要校验签名的参数，签名为 HMAC-SHA256(signing_key, 参数值) 的十六进制
+ #[derive(Deserialize)]
+ struct SignedParams {
+     // 请求者，用于文字水印中的 {requester} 变量，便于追踪泄露的图片
+     requester: Option<String>,
+     requester_sig: Option<String>,
+ }
+
+ type Cache = Arc<Mutex<LruCache<cache_key::Key, CachedSource>>>;
 
 #[tokio::main]
 async fn main() {
+    // 子命令：shanbor verify <file>，shanbor check，shanbor render <engine> <spec> <format> <file>
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, cmd, engine, spec, format, path] = &args[..] {
+        if cmd == "render" {
+            if let Err(e) = render::run(engine, spec, format, path) {
+                eprintln!("render failed: {:#}", e);
+                std::process::exit(2)
+            }
+            return;
+        }
+    }
+    if let [_, cmd] = &args[..] {
+        if cmd == "check" {
+            std::process::exit(if check::run().await { 0 } else { 1 });
+        }
+    }
+    if let [_, cmd, path] = &args[..] {
+        if cmd == "verify" {
+            let found = verify::run(path).unwrap_or_else(|e| {
+                eprintln!("verify failed: {:#}", e);
+                std::process::exit(2)
+            });
+            std::process::exit(if found { 0 } else { 1 });
+        }
+    }
+
     // 初始化 tracing 日志追踪
     tracing_subscriber::fmt::init();
+    let config = Arc::new(Config::load().expect("failed to load config"));
+    redact::init(config.log_urls);
+    if let Some(ref chaos) = *chaos::CHAOS {
+        tracing::warn!("Fault injection is enabled: {:?}", chaos);
+    }
     let cache: Cache = Arc::new(Mutex::new(LruCache::new(1024)));
+    let publisher = Arc::new(Publisher::new(config.publish.as_ref()).expect("invalid publish config"));
+    let fallback = Arc::new(Fallback::new(config.fallback.as_ref()).expect("invalid fallback config"));
+    let tenants = Arc::new(Tenants::new(&config).expect("invalid tenant config"));
+    if let Some(ref dir) = config.assets_dir {
+        assets::ASSETS.open(dir).expect("failed to load assets");
+    }
+    if let Some(ref c) = config.uploads {
+        uploads::UPLOADS.open(&c.dir).expect("failed to open uploads dir");
+        uploads::spawn_gc(c, cache.clone(), tenants.clone(), publisher.clone());
+    }
+    overload::OVERLOAD.configure(&config.overload).expect("invalid overload config");
+    fonts::FONTS.open(&config.fonts, config.fonts_dir.as_deref()).expect("failed to load fonts");
+    // 开始监听之前先从已有的副本拉取缓存，健康检查通过时缓存已经是热的
+    if let Some(ref sync) = config.peer_sync {
+        peer::warm(sync, &cache).await;
+    }
 
     // 构建路由
     let app = Router::new()
         // "GET /image" 会执行 generate 函数，并把 spec 和 url 传递过去
         .route("/image/:spec/:url", get(generate))
-        .layer(
-            ServiceBuilder::new()
-                .layer(AddExtensionLayer::new(cache))
-                .into_inner(),
-        );
-    
+        // "GET /diff?a=<url>&b=<url>" 对比两张图片
+        .route("/diff", get(diff::generate_diff))
+        // "GET /meta/:url" 只读取文件头，返回格式、宽高和 EXIF 方向
+        .route("/meta/:url", get(meta::meta))
+        // "GET /stats/:url" 图片的直方图、亮度和清晰度
+        .route("/stats/:url", get(histogram::image_stats))
+        // "POST /collage" 把多张图片拼接成一张
+        .route("/collage", post(collage::generate_collage))
+        // "POST /sprite" 把多个图标打包成 sprite sheet
+        .route("/sprite", post(sprite::generate_sprite))
+        // "POST /contactsheet" 生成带文件名的缩略图网格
+        .route("/contactsheet", post(contactsheet::generate_contactsheet))
+        // "POST /verify" 检测图片中的不可见水印
+        .route("/verify", post(verify::verify_upload))
+        // "GET /estimate/:spec/:url" 预估 spec 的输出尺寸、开销和是否会被拒绝，不做实际处理
+        .route("/estimate/:spec/:url", get(estimate::estimate))
+        // "GET /capabilities" 列出支持的操作、格式和限制
+        .route("/capabilities", get(capabilities::capabilities))
+        // "GET /spec/:spec" 查看 spec 的 JSON 表示，"POST /spec" 把 JSON 编码成 spec 字符串
+        .route("/spec/:spec", get(spec_api::describe))
+        .route("/spec", post(spec_api::encode))
+        // "POST /uploads/token" 签发上传 token，"PUT /uploads/:token" 上传图片，"DELETE /uploads/:id" 删除图片，
+        // 需要配置 [uploads]
+        .route("/uploads/token", post(uploads::issue_token))
+        .route("/uploads/:token", axum::handler::put(uploads::upload).delete(uploads::delete_upload));
+
+    // 内部接口，配置了 admin_listen 时单独监听，否则和公开接口共用一个地址
+    let internal = Router::new()
+        // "GET /metrics" Prometheus 格式的运行统计
+        .route("/metrics", get(metrics::metrics))
+        // "GET /peer/cache" 导出缓存给新启动的副本，需要配置 [peer_sync]
+        .route("/peer/cache", get(peer::export));
+
+    // "GET /admin/ui" 管理界面，需要开启 admin feature
+    #[cfg(feature = "admin")]
+    let internal = internal
+        .route("/admin/ui", get(admin::ui))
+        .route("/admin/stats", get(admin::stats))
+        // 管理界面的预览，和 "GET /image" 相同；配置了 admin_listen 时公开接口不在管理地址上
+        .route("/admin/preview/:spec/:url", get(generate))
+        // 正在处理的图片请求和所处的阶段
+        .route("/admin/inflight", get(admin::inflight))
+        // 水印素材：GET 列出，PUT 上传或替换，DELETE 删除
+        .route("/admin/assets", get(admin::list_assets))
+        .route("/admin/assets/:name", axum::handler::put(admin::put_asset).delete(admin::delete_asset))
+        // 字体：GET 列出，PUT 上传或替换，DELETE 删除（配置文件中的字体只读）
+        .route("/admin/fonts", get(admin::list_fonts))
+        .route("/admin/fonts/:name", axum::handler::put(admin::put_font).delete(admin::delete_font))
+        // 删除上传的图片，以及由它得到的缓存和对象存储中的结果
+        .route("/admin/uploads/:id", axum::handler::delete(admin::delete_upload))
+        // 删除缓存的源图片，以及由它得到的所有结果（所有租户、spec 和格式）
+        .route("/admin/sources/:url", axum::handler::delete(admin::purge_source));
+
+    // 租户配置的公开路径，"GET <path>/<rest>" 使用预设处理源站上的 <rest>
+    // 其他路由都不匹配时才会尝试，只对这一小组路由做 boxed，避免整个 Router 的类型过大
+    let public = tenants
+        .route_paths()
+        .iter()
+        .fold(Router::new().boxed(), |r, path| r.nest(&format!("{}/", path), get(routes::generate_route)).boxed());
+    let app = app.or(public);
+
+    let compression = CompressionLayer::new()
+        .gzip(config.compression.gzip)
+        .br(config.compression.br)
+        .no_deflate();
+    let layers = ServiceBuilder::new()
+        .layer(AddExtensionLayer::new(cache))
+        .layer(AddExtensionLayer::new(config.clone()))
+        .layer(AddExtensionLayer::new(publisher))
+        .layer(AddExtensionLayer::new(tenants))
+        .layer(AddExtensionLayer::new(fallback))
+        // 非图片的响应按 Accept-Encoding 压缩
+        .layer(MapResponseLayer::new(compress::unmark_images))
+        .layer(compression)
+        .layer(MapResponseLayer::new(compress::mark_images))
+        .into_inner();
+
     // 运行 web 服务器
-    let addr = "127.0.0.1:3000".parse().unwrap();
-    
+    let addr = config.listen_addr();
+
     // 辅助调试
     print_test_url("https://p8.pstatp.com/origin/pgc-image/e80c318c4b84494abd47302647f4b6e3.jpeg");
     // print_test_url("https://images.pexels.com/photos/1562477/pexels-photo-1562477.jpeg?auto=compress&cs=tinysrgb&dpr=3&h=750&w=1260");
 
-    info!("Listening on {}", addr);
-
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    match config.admin_listen {
+        Some(admin_addr) => {
+            info!("Listening on {}, admin on {}", addr, admin_addr);
+            let public = axum::Server::bind(&addr).serve(app.layer(layers.clone()).into_make_service());
+            let admin = axum::Server::bind(&admin_addr).serve(internal.layer(layers).into_make_service());
+            tokio::try_join!(public, admin).unwrap();
+        }
+        None => {
+            info!("Listening on {}", addr);
+            axum::Server::bind(&addr)
+                .serve(app.or(internal).layer(layers).into_make_service())
+                .await
+                .unwrap();
+        }
+    }
 }
 
+// axum 通过参数提取请求的各个部分，参数多一些是正常的
+#[allow(clippy::too_many_arguments)]
 async fn generate(
-    Path(Params {spec, url}): Path<Params>,
-    Extension(cache): Extension<Cache>
-) -> Result<(HeaderMap, Vec<u8>), StatusCode> {
-    // 图片转换指令 ImageSpec
-    let spec: ImageSpec = spec
-        .as_str()
-        .try_into()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    Path(params): Path<Params>,
+    Query(output): Query<OutputParams>,
+    Query(signed): Query<SignedParams>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(publisher): Extension<Arc<Publisher>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+    Extension(fallback): Extension<Arc<Fallback>>,
+) -> Result<(HeaderMap, Body), AppError> {
+    let started = Instant::now();
+    let path = format!("/image/{}/{}", params.spec, redact::encoded(&params.url));
+    let raw_spec = percent_decode_str(&params.spec).decode_utf8_lossy().into_owned();
+    // 指标和访问日志按租户和预设区分，无法识别租户时为 unknown
+    let (tenant_label, preset_label) = match tenants.resolve(&req_headers) {
+        Ok(t) => (t.name.clone(), t.preset_label(&raw_spec).to_owned()),
+        Err(_) => ("unknown".to_owned(), tenant::ADHOC.to_owned()),
+    };
+    let trace_id = trace::trace_id(&req_headers);
+    let span = tracing::info_span!("image", tenant = %tenant_label, preset = %preset_label, trace_id = tracing::field::Empty);
+    if let Some(id) = trace_id {
+        span.record("trace_id", trace::format(id).as_str());
+    }
+    // 只在失败时用于生成备用图片
+    let retry = fallback.enabled().then(|| req_headers.clone());
+    let ctx = (cache, config, publisher, tenants.clone());
+    let result = process(params, output, signed, req_headers, ctx).instrument(span.clone()).await;
+    let failed = result.is_err();
+    // 记录最近的错误，方便在管理界面中查看
+    if let Err(ref e) = result {
+        stats::STATS.error(path.clone(), e.status().as_u16());
+    }
+    let result = match (result, retry) {
+        // 源图片下载或者解码失败时返回备用图片，尺寸按请求的 spec 决定
+        (Err(AppError::Source(status)), Some(req_headers)) => {
+            let spec = tenants.resolve(&req_headers).ok().and_then(|t| t.spec(&raw_spec).ok());
+            match fallback.response(spec.as_ref(), status) {
+                Some((headers, image)) => Ok((headers, Body::from(image))),
+                None => Err(status.into()),
+            }
+        }
+        (result, _) => result,
+    };
+    // 访问日志，边编码边发送的响应在发送完之后才计入字节数
+    let (status, bytes) = match result {
+        Ok((_, ref body)) => (StatusCode::OK, body.size_hint().exact().unwrap_or(0)),
+        Err(ref e) => (e.status(), 0),
+    };
+    let elapsed = started.elapsed();
+    stats::STATS.request(&tenant_label, &preset_label, failed, elapsed, bytes, trace_id);
+    span.in_scope(|| info!(status = status.as_u16(), bytes, elapsed_ms = elapsed.as_millis() as u64, "GET {}", path));
+    result
+}
+
+async fn process(
+    Params {spec: raw_spec, url}: Params,
+    output: OutputParams,
+    signed: SignedParams,
+    req_headers: HeaderMap,
+    (cache, config, publisher, tenants): (Cache, Arc<Config>, Arc<Publisher>, Arc<Tenants>),
+) -> Result<(HeaderMap, Body), AppError> {
+    // 先识别租户，未认证的请求不占用排队的位置
+    let tenant = tenants.admit(&req_headers)?;
+    // 边编码边发送时持有到发送完
+    let permit = overload::OVERLOAD.admit("image").await?;
+    // 图片转换指令 ImageSpec，可以是租户的预设名，文本语法中可能有被转义的字符
+    let raw_spec = percent_decode_str(&raw_spec).decode_utf8_lossy().into_owned();
+    let mut spec = tenant.spec(&raw_spec)?;
+    let policy = tenant.policy(&raw_spec);
+    tenant.check_ops(&spec)?;
+    if let Some(filter) = config.default_sample_filter {
+        spec.default_sample_filter(filter);
+    }
+    // 引用的水印素材需要已经上传
+    if let Some(name) = assets::ASSETS.missing(&spec) {
+        return Err(SpecError::new(0, "uploaded asset", None, format!("unknown asset {}", name)).into());
+    }
+    if let Some(name) = fonts::FONTS.missing(&spec) {
+        return Err(SpecError::new(0, "registered font", None, format!("unknown font {}", name)).into());
+    }
+    if !tenant.allows_fonts(&spec) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    // 替换文字中的模板变量
+    let vars = template_vars(&tenant, &signed)?;
+    template::render_spec(&mut spec, &vars).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // 需要在配置中开启，否则浏览器不会发送这些请求头
+    let hints = if config.client_hints {
+        ClientHints::from_headers(&req_headers)
+    } else {
+        ClientHints::default()
+    };
+    hints.scale_spec(&mut spec);
     // 图片 URL
     let url: &str = &percent_decode_str(&url).decode_utf8_lossy();
+    if !tenant.allows(url) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    // 管理接口中可以看到正在处理的请求和所处的阶段
+    let request = active::ACTIVE.start(&tenant.name, tenant.preset_label(&raw_spec), url, &spec);
     // 图片数据 Bytes
-    let data = retrieve_image(&url, cache)
+    let limits = &tenant.source_limits;
+    let (data, cached) = retrieve_image(&tenant.cache_namespace, url, cache, &tenant.source_cache, limits.max_bytes, &config.origin_credentials, config.fetch_resume.as_ref())
         .await
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+        .map_err(fetch_error)?;
+    check_source(&data, spec.page, limits)?;
+    // 带条件的操作在完整解码之前，根据文件头中的信息决定是否执行
+    policy.enforce(&mut spec);
+    let probe = engine::probe(&data, spec.page).map_err(source_error)?;
+    // caption 中可以使用源图片 EXIF 中的拍摄时间、相机和版权
+    let mut vars = vars;
+    template::insert_exif(&mut vars, &engine::exif(&data));
+    template::render_captions(&mut spec, &vars).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let (width, height) = (probe.width, probe.height);
+    spec.resolve_conditions(&SourceInfo {
+        width,
+        height,
+        format: Some(probe.format),
+    });
 
     // 根据图片指令处理图片
     // 使用 image engine 处理
-    let mut engine: Photon = data
-        .try_into()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    engine.apply(&spec.specs);
+    request.phase(Phase::Decoding);
+    let started = Instant::now();
+    let text_art = accepts(&req_headers, "text/plain") && policy.allows(OutputKind::Text);
+    // 动图对每一帧做同样的处理；只要第一帧、输出字符画、限制字节数或者策略不允许动图时按静态图片处理
+    let frames = if output.first_frame_only == Some(true)
+        || text_art
+        || output.max_bytes.is_some()
+        || !policy.allows(OutputKind::Gif)
+    {
+        None
+    } else {
+        let frames = engine::decode_frames(&data, config.animation.max_frames, config.animation.max_pixels()).map_err(source_error)?;
+        match config.animation.max_fps {
+            Some(fps) if fps > 0.0 => frames.map(|v| engine::decimate_frames(v, fps)),
+            _ => frames,
+        }
+    };
+    let format = output_format(&spec, tenant.output(&raw_spec), output.max_bytes.is_some());
+    let (static_mime, static_ext, static_kind) = static_output(&format);
+    if !text_art && frames.is_none() && !policy.allows(static_kind) {
+        return Err(StatusCode::NOT_ACCEPTABLE.into());
+    }
+    // 动图的帧已经解码过了，不再解码一次
+    let mut engine = match frames {
+        Some(ref frames) => Photon::from_rgba(frames[0].buffer().clone()),
+        None => Photon::open(&data, spec.page).map_err(source_error)?,
+    };
+    let engine_name = engine.name();
+    request.phase(Phase::Transforming);
+    // client hints 追加的缩放，shadow 对比时也要执行
+    let mut fit = None;
+    let frames = match frames {
+        Some(frames) => {
+            let frames = engine::transform_frames(frames, &spec.specs);
+            match hints.fit(frames[0].buffer().dimensions()) {
+                Some(op) => Some(engine::transform_frames(frames, &[op])),
+                None => Some(frames),
+            }
+        }
+        None => {
+            engine.apply(&spec.specs);
+            fit = hints.fit(engine.dimensions());
+            engine.apply(fit.as_slice());
+            None
+        }
+    };
+    let elapsed = started.elapsed();
+    request.phase(Phase::Encoding);
 
-    let image = engine.generate(ImageOutputFormat::Jpeg(85));
+    let mut headers = HeaderMap::new();
+    // 同一个 URL 会根据 Accept 返回不同的内容
+    headers.insert("vary", HeaderValue::from_static("accept"));
+    if config.client_hints {
+        headers.insert("vary", HeaderValue::from_static(hints::VARY));
+        headers.insert("accept-ch", HeaderValue::from_static(hints::ACCEPT_CH));
+    }
+    let debug = debug_enabled(&config, &output, &req_headers);
+    if debug {
+        let format = probe.format.to_string();
+        let cache = if cached { "hit" } else { "miss" };
+        headers.insert("x-shanbor-cache", HeaderValue::from_static(cache));
+        headers.insert("x-shanbor-engine", HeaderValue::from_static(engine_name));
+        headers.insert("x-shanbor-tenant", HeaderValue::from_str(&tenant.name).unwrap());
+        headers.insert("x-shanbor-source-format", HeaderValue::from_str(&format).unwrap());
+        headers.insert(
+            "x-shanbor-source-size",
+            HeaderValue::from_str(&format!("{}x{}", width, height)).unwrap(),
+        );
+    }
+
+    // 客户端明确要求纯文本时，输出字符画
+    if text_art {
+        let opts = TextArt {
+            columns: output.cols.unwrap_or(80).min(MAX_TEXT_COLUMNS),
+            ansi: output.ansi.unwrap_or(false),
+        };
+        let text = engine.generate_text(opts);
+        info!("Finished processing: text size {}", text.len());
+        if debug {
+            insert_elapsed(&mut headers, started);
+        }
+        headers.insert("content-type", HeaderValue::from_static("text/plain; charset=utf-8"));
+        return Ok((headers, Body::from(text)));
+    }
+
+    let format = match format {
+        ImageOutputFormat::Jpeg(quality) => ImageOutputFormat::Jpeg(policy.quality(hints.quality(quality))),
+        format => format,
+    };
+    // 默认输出的质量是缓存 key 的一部分，配置改变后不会用到旧的结果
+    let quality = match (&frames, &format) {
+        (None, ImageOutputFormat::Jpeg(quality)) => Some(*quality),
+        _ => None,
+    };
+    let name = download_filename(url, output.filename.as_deref(), if frames.is_some() { "gif" } else { static_ext });
+    let disposition = content_disposition(output.disposition.unwrap_or(Disposition::Inline), &name);
+    // 只写入默认的输出，带 requester 的结果因人而异，不适合共享，client hints 调整过的结果也不写入
+    let publish = publisher.enabled() && output.max_bytes.is_none() && signed.requester.is_none() && hints.is_empty();
+
+    // 很大的静态输出边编码边发送：没有 ETag，也不做 shadow 对比和写入对象存储
+    if frames.is_none()
+        && !publish
+        && output.max_bytes.is_none()
+        && output.encoding != Some(Encoding::Base64)
+        && stream::enabled(config.stream_min_pixels, engine.dimensions())
+    {
+        if debug {
+            insert_elapsed(&mut headers, started);
+        }
+        headers.insert("content-type", HeaderValue::from_static(static_mime));
+        headers.insert("x-shanbor-engine-version", HeaderValue::from_static(engine::ENGINE_VERSION));
+        headers.insert("content-disposition", disposition);
+        let (tenant, preset) = (tenant.name.clone(), tenant.preset_label(&raw_spec).to_owned());
+        let body = stream::body(
+            move |out| engine.generate_into(format, out),
+            move |sent| {
+                drop((request, permit));
+                stats::STATS.sent(&tenant, &preset, sent as u64)
+            },
+        );
+        return Ok((headers, body));
+    }
+
+    let (image, mime, ext) = match frames {
+        Some(frames) => {
+            let image = match config.animation.max_output_bytes {
+                Some(max) => engine::encode_gif_within(frames, max).ok_or_else(|| limits::animation_too_large(max))?,
+                None => engine::encode_gif(frames),
+            };
+            (image, "image/gif", "gif")
+        }
+        None => {
+            // shadow 模式只对比静态图片的输出
+            if shadow::sample(config.shadow.as_ref()) {
+                let mut spec = spec.clone();
+                spec.specs.extend(fit);
+                shadow::compare(data.clone(), spec, engine.to_rgba(), elapsed);
+            }
+            let image = match (output.max_bytes, format) {
+                (Some(max_bytes), ImageOutputFormat::Jpeg(quality)) => {
+                    let downscale = output.downscale.unwrap_or(false);
+                    let (image, quality) = encode_within(engine.to_rgba(), quality, max_bytes, downscale)
+                        .ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+                    headers.insert("x-shanbor-quality", HeaderValue::from(quality as u16));
+                    image
+                }
+                (_, format) => engine.generate(format),
+            };
+            (image, static_mime, static_ext)
+        }
+    };
 
     info!("Finished processing: image size {}", image.len());
+    if debug {
+        insert_elapsed(&mut headers, started);
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert("content-type", HeaderValue::from_static("image/jpeg"));
+    if output.encoding == Some(Encoding::Base64) {
+        let uri = format!("data:{};base64,{}", mime, base64::encode(image));
+        headers.insert("content-type", HeaderValue::from_static("text/plain; charset=utf-8"));
+        return Ok((headers, Body::from(uri)));
+    }
+
+    if publish {
+        let key = publish::object_key(&tenant.cache_namespace, &spec, url, ext, quality);
+        source_cache::RESULTS.record(url, &key);
+        // 上传的图片被删除时，写入对象存储的结果一起删除
+        if let Some(id) = url.strip_prefix(uploads::SCHEME) {
+            if let Err(e) = uploads::UPLOADS.record(id, &key) {
+                tracing::warn!("Failed to record published result of upload {}: {}", id, e);
+            }
+        }
+        publisher.publish(key, Bytes::from(image.clone()));
+    }
+
+    headers.insert("content-type", HeaderValue::from_static(mime));
+    headers.insert("etag", etag(&image));
+    headers.insert("x-shanbor-engine-version", HeaderValue::from_static(engine::ENGINE_VERSION));
+    headers.insert("content-disposition", disposition);
+
+    Ok((headers, Body::from(image)))
+}
+
+// 静态图片的输出格式按预设、租户的配置决定，LQIP 使用它自己的质量设置
+// 限制字节数时需要调整 JPEG 的质量，总是输出 JPEG
+fn output_format(spec: &ImageSpec, defaults: OutputDefaults, max_bytes: bool) -> ImageOutputFormat {
+    let lqip = spec.specs.iter().rev().find_map(|s| match s.data {
+        Some(spec::Data::Lqip(ref v)) => Some(v.quality()),
+        _ => None,
+    });
+    match lqip {
+        Some(quality) => ImageOutputFormat::Jpeg(quality),
+        None if max_bytes => ImageOutputFormat::Jpeg(defaults.quality.unwrap_or(policy::DEFAULT_QUALITY)),
+        None => defaults.image_format(),
+    }
+}
+
+// 静态图片的 Content-Type、扩展名和策略中的格式
+fn static_output(format: &ImageOutputFormat) -> (&'static str, &'static str, OutputKind) {
+    match format {
+        ImageOutputFormat::Png => ("image/png", "png", OutputKind::Png),
+        _ => ("image/jpeg", "jpg", OutputKind::Jpeg),
+    }
+}
+
+// 每个请求可用的模板变量，带签名的参数只有校验通过才能使用
+fn template_vars(tenant: &Tenant, signed: &SignedParams) -> Result<template::Vars, StatusCode> {
+    let now = chrono::Utc::now();
+    let mut vars = template::Vars::new();
+    vars.insert("date", now.format("%Y-%m-%d").to_string());
+    vars.insert("datetime", now.format("%Y-%m-%d %H:%M UTC").to_string());
+
+    if let Some(ref requester) = signed.requester {
+        let key = tenant.signing_key.as_deref().ok_or(StatusCode::FORBIDDEN)?;
+        let sig = signed.requester_sig.as_deref().ok_or(StatusCode::FORBIDDEN)?;
+        if !signing::verify(key, requester, sig) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        vars.insert("requester", requester.clone());
+    }
+    Ok(vars)
+}
 
-    Ok((headers, image))
+// 调试响应头只对可信的请求开放：配置了 debug_token 时校验请求头，或者配置允许 ?debug=true
+fn debug_enabled(config: &Config, output: &OutputParams, headers: &HeaderMap) -> bool {
+    let trusted = match (&config.debug_token, headers.get("x-shanbor-debug")) {
+        (Some(token), Some(v)) => v.to_str().is_ok_and(|v| signing::token_eq(v, token)),
+        _ => false,
+    };
+    trusted || (config.debug_query && output.debug.unwrap_or(false))
+}
+
+// 解码、处理和编码的总耗时（毫秒），不包括获取源图片
+fn insert_elapsed(headers: &mut HeaderMap, started: Instant) {
+    let ms = started.elapsed().as_secs_f64() * 1000.0;
+    headers.insert("x-shanbor-processing-ms", HeaderValue::from_str(&format!("{:.1}", ms)).unwrap());
+}
+
+// 字符画每行最多的字符数
+const MAX_TEXT_COLUMNS: u32 = 400;
+
+// 判断请求的 Accept 头中是否包含指定的 mime 类型
+fn accepts(headers: &HeaderMap, mime: &str) -> bool {
+    headers
+        .get_all("accept")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.split(';').next().unwrap_or("").trim() == mime)
+}
+
+// 从图片 URL 中取出文件名，比如 https://a.com/x/cat%20one.jpg?w=1 得到 "cat one.jpg"
+fn source_filename(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or("");
+    let path = path.splitn(2, "://").last().unwrap_or("");
+    let name = match path.find('/') {
+        Some(i) => path[i..].rsplit('/').next().unwrap_or(""),
+        None => "",
+    };
+    let name = percent_decode_str(name).decode_utf8_lossy();
+    if name.is_empty() {
+        "image".to_owned()
+    } else {
+        name.into_owned()
+    }
 }
 
-#[instrument(level = "info", skip(cache))]
-async fn retrieve_image(url: &str, cache: Cache) -> Result<Bytes> {
-    let mut hasher = DefaultHasher::new();
-    url.hash(&mut hasher);
-    let key = hasher.finish();
+// 输出是可以复现的，ETag 直接使用内容的 SHA-256，下游可以用来去重
+fn etag(body: &[u8]) -> HeaderValue {
+    use sha2::{Digest, Sha256};
+    HeaderValue::from_str(&format!("\"{}\"", hex::encode(Sha256::digest(body)))).unwrap()
+}
 
-    let g = &mut cache.lock().await;
-    let data = match g.get(&key) {
-        Some(v) => {
-            info!("Mache cache {}", key);
-            v.to_owned()
-        },
+// 下载文件名：优先使用请求中的 filename，否则取源文件名去掉扩展名，再加上输出格式的扩展名
+fn download_filename(url: &str, filename: Option<&str>, ext: &str) -> String {
+    let name = match filename {
+        Some(v) => v.to_owned(),
         None => {
-            info!("Retrieve url");
-            let resp = reqwest::get(url).await?;
-            let data = resp.bytes().await?;
-            g.put(key, data.clone());
-            data
+            let name = source_filename(url);
+            match name.rfind('.') {
+                Some(i) if i > 0 => name[..i].to_owned(),
+                _ => name,
+            }
         }
     };
+    // 去掉路径分隔符和控制字符，避免奇怪的文件名
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '/' && *c != '\\')
+        .collect();
+    let name = name.trim();
+    let name = if name.is_empty() { "image" } else { name };
+    format!("{}.{}", name, ext)
+}
+
+// RFC 5987 中 filename* 允许不编码的字符
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!').remove(b'#').remove(b'$').remove(b'&').remove(b'+').remove(b'-')
+    .remove(b'.').remove(b'^').remove(b'_').remove(b'`').remove(b'|').remove(b'~');
 
-    Ok(data)
+// filename 给老的客户端使用，只保留 ASCII；filename* 是 UTF-8 的完整文件名
+fn content_disposition(disposition: Disposition, name: &str) -> HeaderValue {
+    let kind = match disposition {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment",
+    };
+    let ascii: String = name
+        .chars()
+        .map(|c| if c.is_ascii() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded = percent_encode(name.as_bytes(), ATTR_CHAR);
+    let value = format!("{}; filename=\"{}\"; filename*=UTF-8''{}", kind, ascii, encoded);
+    HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static("inline"))
+}
+
+// 获取图片并交给 engine 解码，拼接、对比等功能共用；和 /image 一样使用租户允许的源站、缓存命名空间和限制
+async fn load_engine(url: &str, cache: Cache, config: &Config, tenant: &Tenant) -> Result<Photon, AppError> {
+    if !tenant.allows(url) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let limits = &tenant.source_limits;
+    let (data, _) = retrieve_image(&tenant.cache_namespace, url, cache, &tenant.source_cache, limits.max_bytes, &config.origin_credentials, config.fetch_resume.as_ref())
+        .await
+        .map_err(fetch_error)?;
+    check_source(&data, 0, limits)?;
+    Photon::open(&data, 0).map_err(source_error)
+}
+
+// 下载失败按请求的问题处理
+fn fetch_error(e: FetchError) -> AppError {
+    info!("Failed to fetch source: {}", e);
+    match e {
+        FetchError::TooLarge(max) => limits::too_large(max),
+        FetchError::Failed(_) => AppError::Source(StatusCode::BAD_REQUEST),
+    }
+}
+
+// 按文件头识别格式之后、完整解码之前检查源图片的限制
+// 缓存中的数据可能是没有限制的请求下载的，所以这里再检查一次字节数
+fn check_source(data: &[u8], page: u32, limits: &SourceLimits) -> Result<(), AppError> {
+    engine::sniff(data).ok_or_else(|| source_error(DecodeError::Unsupported))?;
+    limits.check_bytes(data.len() as u64)?;
+    limits.check_pixels(data, page)?;
+    // 测试环境中注入的解码错误
+    if let Some(ref chaos) = *chaos::CHAOS {
+        chaos.decode(data).map_err(source_error)?;
+    }
+    Ok(())
+}
+
+// 图片请求中源图片解码失败时，可以用备用图片代替
+fn source_error(e: DecodeError) -> AppError {
+    AppError::Source(decode_status(e))
+}
+
+// 源图片本身的问题属于客户端错误，不应该返回 500
+fn decode_status(e: DecodeError) -> StatusCode {
+    info!("Failed to decode source: {}", e);
+    match e {
+        DecodeError::Unsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        DecodeError::Invalid(..) => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+#[instrument(level = "info", skip(url, cache, policy, credentials, resume), fields(url = %redact::url(url)))]
+// 返回图片数据，以及是否命中了缓存
+// namespace 用来隔离不同租户的缓存；max_bytes 限制下载的大小，超出时不再继续下载
+// credentials 中匹配 URL 的凭证用于给请求签名；配置了 resume 时连接断开后续传
+async fn retrieve_image(
+    namespace: &str,
+    url: &str,
+    cache: Cache,
+    policy: &SourceCacheConfig,
+    max_bytes: Option<u64>,
+    credentials: &[OriginCredentials],
+    resume: Option<&ResumeConfig>,
+) -> Result<(Bytes, bool), FetchError> {
+    let key = cache_key::source(namespace, url);
+
+    if let Some(v) = cache.lock().await.get(&key).filter(|v| v.fresh()) {
+        info!("Mache cache {}", hex::encode(key));
+        stats::STATS.cache(true);
+        return Ok((v.data.clone(), true));
+    }
+    stats::STATS.cache(false);
+
+    // 下载期间不持有缓存的锁，同一个源图片只下载一次
+    let (fetched, leader) = INFLIGHT
+        .run(key, || async {
+            info!("Retrieve url");
+            let (data, headers) = download(url, max_bytes, credentials, resume).await?;
+            // 过期的缓存会被新的结果替换；不允许缓存时删除过期的缓存
+            let mut g = cache.lock().await;
+            match policy.entry(data.clone(), &headers) {
+                Some(entry) => {
+                    g.put(key, entry);
+                }
+                None => {
+                    g.pop(&key);
+                }
+            }
+            Ok((data, headers))
+        })
+        .await;
+    if !leader {
+        stats::STATS.coalesced();
+    }
+    let (data, _) = fetched?;
+    Ok((data, false))
+}
+
+// Content-Length 已经超出限制时不下载，没有 Content-Length 时边下载边检查
+async fn download(
+    url: &str,
+    max_bytes: Option<u64>,
+    credentials: &[OriginCredentials],
+    resume: Option<&ResumeConfig>,
+) -> Result<(Bytes, HeaderMap), FetchError> {
+    let failed = |e: reqwest::Error| FetchError::Failed(redact::fetch_error(e));
+    // 上传的图片直接从本地读取
+    if let Some(id) = url.strip_prefix(uploads::SCHEME) {
+        let data = uploads::UPLOADS.get(id).ok_or_else(|| FetchError::Failed(format!("upload {} not found", id)))?;
+        return match max_bytes {
+            Some(limit) if data.len() as u64 > limit => Err(FetchError::TooLarge(limit)),
+            _ => Ok((data, HeaderMap::new())),
+        };
+    }
+    if let Some(ref chaos) = *chaos::CHAOS {
+        chaos.fetch().await?;
+    }
+    let mut resp = sigv4::get(credentials, url, None).send().await.map_err(failed)?;
+    let limit = max_bytes.unwrap_or(u64::MAX);
+    if resp.content_length().is_some_and(|len| len > limit) {
+        return Err(FetchError::TooLarge(limit));
+    }
+    let mut headers = resp.headers().clone();
+    let mut progress = resume::Progress::new(&headers);
+    let mut data = BytesMut::new();
+    loop {
+        let chunk = match resp.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                // 连接断开时从已经收到的位置继续，不能续传时按下载失败处理
+                let (range, if_range) = match resume.and_then(|c| progress.next(c, data.len())) {
+                    Some(v) => v,
+                    None => return Err(failed(e)),
+                };
+                info!("Resuming download at byte {} (attempt {})", data.len(), progress.attempts);
+                stats::STATS.resumed();
+                resp = sigv4::get(credentials, url, Some(&range))
+                    .header(axum::http::header::IF_RANGE, if_range)
+                    .send()
+                    .await
+                    .map_err(failed)?;
+                // 源站的图片已经被替换时返回完整的新图片
+                let resumed = resume::check(resp.status(), resp.headers(), data.len()).map_err(FetchError::Failed)?;
+                if resumed == Resumed::Restart {
+                    data.clear();
+                    headers = resp.headers().clone();
+                }
+                continue;
+            }
+        };
+        if (data.len() + chunk.len()) as u64 > limit {
+            return Err(FetchError::TooLarge(limit));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok((data.freeze(), headers))
 }
 
 // 调试辅助函数
@@ -130,4 +903,61 @@ fn print_test_url(url: &str) {
     let s: String = image_spec.borrow().into();
     let test_image = percent_encode(url.as_bytes(), NON_ALPHANUMERIC).to_string();
     println!("test url: http://localhost:3000/image/{}/{}", s, test_image);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_filename_should_follow_source_url() {
+        assert_eq!(download_filename("https://a.com/x/cat%20one.png?w=1", None, "jpg"), "cat one.jpg");
+        assert_eq!(download_filename("https://a.com/", None, "jpg"), "image.jpg");
+        assert_eq!(download_filename("https://a.com/a.png", Some("../b"), "jpg"), "..b.jpg");
+    }
+
+    #[test]
+    fn debug_should_require_token_or_debug_query() {
+        let query = |debug: bool| serde_json::from_value::<OutputParams>(serde_json::json!({ "debug": debug })).unwrap();
+        let header = |token| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-shanbor-debug", HeaderValue::from_static(token));
+            headers
+        };
+        let mut config = Config { debug_token: Some("secret".to_owned()), ..Default::default() };
+        assert!(debug_enabled(&config, &query(false), &header("secret")));
+        assert!(!debug_enabled(&config, &query(false), &header("wrong")));
+        assert!(!debug_enabled(&config, &query(false), &HeaderMap::new()));
+        // 没有开启 debug_query 时忽略 ?debug=true
+        assert!(!debug_enabled(&config, &query(true), &HeaderMap::new()));
+        config.debug_query = true;
+        assert!(debug_enabled(&config, &query(true), &HeaderMap::new()));
+        assert!(!debug_enabled(&config, &query(false), &HeaderMap::new()));
+        // 没有配置 debug_token 时不接受任何请求头
+        config = Config::default();
+        assert!(!debug_enabled(&config, &query(false), &header("")));
+    }
+
+    #[tokio::test]
+    async fn load_engine_should_use_tenant_origins() {
+        let config: Config = toml::from_str("[[tenants]]\nname = \"a\"\napi_keys = [\"key-a\"]\norigins = [\"https://a.com/\"]").unwrap();
+        let tenants = Tenants::new(&config).unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(tenants.admit(&headers).err(), Some(StatusCode::UNAUTHORIZED));
+        headers.insert("x-api-key", HeaderValue::from_static("key-a"));
+        let tenant = tenants.admit(&headers).unwrap();
+        let cache: Cache = Arc::new(Mutex::new(LruCache::new(1)));
+        for url in ["https://b.com/x.png", "https://a.com.evil.net/x.png", "upload://abc"] {
+            let e = load_engine(url, cache.clone(), &config, &tenant).await.err().unwrap();
+            assert_eq!(e.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn content_disposition_should_escape_filename() {
+        let value = content_disposition(Disposition::Attachment, "猫 \"1\".jpg");
+        assert_eq!(
+            value.to_str().unwrap(),
+            "attachment; filename=\"_ _1_.jpg\"; filename*=UTF-8''%E7%8C%AB%20%221%22.jpg"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+// "GET /meta/:url" 返回源图片的格式、宽高和 EXIF 方向，不解码像素
+// 没有缓存时只用 Range 请求下载开头的 PROBE_BYTES，文件头不完整时才下载整个文件
+use crate::{cache_key, config::Config, decode_status, engine, error::AppError, fetch_error, redact, retrieve_image, sigv4, tenant::Tenants, uploads, Cache};
+use axum::{
+    extract::{Extension, Path},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use bytes::{Bytes, BytesMut};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
+
+// 绝大多数图片的文件头（包括 EXIF）都在开头的 64KB 内
+const PROBE_BYTES: usize = 64 << 10;
+
+#[derive(Serialize, Debug)]
+pub struct Meta {
+    format: String,
+    width: u32,
+    height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    orientation: Option<u16>,
+    // 源文件的大小，源站没有给出时为空
+    bytes: Option<u64>,
+    // 为了得到这些信息实际读取的字节数
+    read_bytes: usize,
+}
+
+pub async fn meta(
+    Path(url): Path<String>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<Json<Meta>, AppError> {
+    let tenant = tenants.admit(&req_headers)?;
+    let url: &str = &percent_decode_str(&url).decode_utf8_lossy();
+    if !tenant.allows(url) {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+    let (namespace, policy, max_bytes) = (&tenant.cache_namespace, &tenant.source_cache, tenant.source_limits.max_bytes);
+    let key = cache_key::source(namespace, url);
+    let cached = cache.lock().await.get(&key).filter(|v| v.fresh()).map(|v| v.data.clone());
+    let (data, total) = match cached {
+        Some(data) => {
+            let len = data.len() as u64;
+            (data, Some(len))
+        }
+        // 上传的图片在本地，直接读取整个文件
+        None if url.starts_with(uploads::SCHEME) => {
+            let (data, _) = retrieve_image(namespace, url, cache, policy, max_bytes, &config.origin_credentials, config.fetch_resume.as_ref())
+                .await
+                .map_err(fetch_error)?;
+            let len = data.len() as u64;
+            (data, Some(len))
+        }
+        None => {
+            let (data, total) = fetch_head(url, &config.origin_credentials).await.map_err(|e| {
+                info!("Failed to fetch source: {}", redact::fetch_error(e));
+                StatusCode::BAD_REQUEST
+            })?;
+            let complete = data.len() < PROBE_BYTES || total == Some(data.len() as u64);
+            match engine::probe(&data, 0) {
+                Err(engine::DecodeError::Invalid(..)) if !complete => {
+                    // 文件头超出了开头的部分，回退到完整下载（结果会写入缓存）
+                    let (data, _) = retrieve_image(namespace, url, cache, policy, max_bytes, &config.origin_credentials, config.fetch_resume.as_ref())
+                        .await
+                        .map_err(fetch_error)?;
+                    let len = data.len() as u64;
+                    (data, Some(len))
+                }
+                _ => (data, total),
+            }
+        }
+    };
+    let probe = engine::probe(&data, 0).map_err(decode_status)?;
+    Ok(Json(Meta {
+        format: probe.format.to_string(),
+        width: probe.width,
+        height: probe.height,
+        orientation: probe.orientation,
+        bytes: total,
+        read_bytes: data.len(),
+    }))
+}
+
+// 下载开头的 PROBE_BYTES 字节，以及源文件的总大小
+// 源站不支持 Range 时会返回整个文件，读够之后同样停止
+async fn fetch_head(url: &str, credentials: &[sigv4::OriginCredentials]) -> Result<(Bytes, Option<u64>), reqwest::Error> {
+    let range = format!("bytes=0-{}", PROBE_BYTES - 1);
+    let mut resp = sigv4::get(credentials, url, Some(&range))
+        .send()
+        .await?
+        .error_for_status()?;
+    let total = if resp.status() == StatusCode::PARTIAL_CONTENT {
+        // Content-Range: bytes 0-65535/1234567
+        resp.headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse().ok())
+    } else {
+        resp.content_length()
+    };
+    let mut data = BytesMut::new();
+    while let Some(chunk) = resp.chunk().await? {
+        data.extend_from_slice(&chunk);
+        if data.len() >= PROBE_BYTES {
+            break;
+        }
+    }
+    data.truncate(PROBE_BYTES);
+    Ok((data.freeze(), total))
+}
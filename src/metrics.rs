@@ -0,0 +1,121 @@
+use crate::{
+    overload::OVERLOAD,
+    stats::{Usage, LATENCY_BUCKETS, STATS},
+    trace,
+};
+use axum::http::{header, HeaderMap, HeaderValue};
+use std::fmt::Write;
+
+const OPENMETRICS: &str = "application/openmetrics-text";
+
+// "GET /metrics" 以 Prometheus 的文本格式输出运行统计
+// 抓取时 Accept 中有 application/openmetrics-text 则使用 OpenMetrics 格式，耗时直方图中带有 trace id 的 exemplar
+pub async fn metrics(req_headers: HeaderMap) -> (HeaderMap, String) {
+    let openmetrics = req_headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains(OPENMETRICS));
+    let (hits, misses) = STATS.cache_counts();
+    let shadow = STATS.shadow_stats();
+
+    let mut body = String::new();
+    let mut counter = |name: &str, help: &str, value: f64| {
+        family(&mut body, openmetrics, name, "counter", help);
+        writeln!(body, "{} {}", name, value).unwrap();
+    };
+    counter("shanbor_cache_hits_total", "Source image cache hits.", hits as f64);
+    counter("shanbor_cache_misses_total", "Source image cache misses.", misses as f64);
+    counter(
+        "shanbor_source_fetches_coalesced_total",
+        "Cache misses served by an in-flight download of the same source.",
+        STATS.coalesced_count() as f64,
+    );
+    counter(
+        "shanbor_source_fetches_resumed_total",
+        "Interrupted source downloads continued with a Range request.",
+        STATS.resumed_count() as f64,
+    );
+    counter("shanbor_shadow_requests_total", "Requests processed by the shadow engine.", shadow.requests as f64);
+    counter("shanbor_shadow_mismatches_total", "Shadow results that differ from the primary.", shadow.mismatches as f64);
+    counter("shanbor_shadow_similarity_sum", "Sum of primary/shadow pixel similarity.", shadow.similarity_sum);
+    counter("shanbor_shadow_primary_seconds_total", "Primary engine time on shadowed requests.", shadow.primary_seconds);
+    counter("shanbor_shadow_seconds_total", "Shadow engine time on shadowed requests.", shadow.shadow_seconds);
+
+    // 图片请求按租户和预设（不是预设时为 adhoc）区分
+    let usage = STATS.usage();
+    let mut labeled = |name: &str, help: &str, value: fn(&Usage) -> f64| {
+        family(&mut body, openmetrics, name, "counter", help);
+        for (tenant, preset, u) in &usage {
+            let (tenant, preset) = (label(tenant), label(preset));
+            writeln!(body, "{}{{tenant=\"{}\",preset=\"{}\"}} {}", name, tenant, preset, value(u)).unwrap();
+        }
+    };
+    labeled("shanbor_image_requests_total", "Image requests.", |u| u.requests as f64);
+    labeled("shanbor_image_errors_total", "Image requests that failed, including ones served a fallback.", |u| {
+        u.errors as f64
+    });
+    labeled("shanbor_image_seconds_total", "Time spent on image requests.", |u| u.seconds);
+    labeled("shanbor_image_response_bytes_total", "Response body bytes of image requests.", |u| u.bytes as f64);
+    image_latency(&mut body, openmetrics, &usage);
+
+    // 只包括配置了 [overload] 的路由
+    let overload = OVERLOAD.counts();
+    family(&mut body, openmetrics, "shanbor_overload_queued", "gauge", "Requests waiting for a slot.");
+    for (route, queued, _) in &overload {
+        writeln!(body, "shanbor_overload_queued{{route=\"{}\"}} {}", route, queued).unwrap();
+    }
+    let help = "Requests rejected with 503 by overload protection.";
+    family(&mut body, openmetrics, "shanbor_overload_rejected_total", "counter", help);
+    for (route, _, rejected) in &overload {
+        writeln!(body, "shanbor_overload_rejected_total{{route=\"{}\"}} {}", route, rejected).unwrap();
+    }
+
+    let mut headers = HeaderMap::new();
+    if openmetrics {
+        body.push_str("# EOF\n");
+        headers.insert("content-type", HeaderValue::from_static("application/openmetrics-text; version=1.0.0; charset=utf-8"));
+    } else {
+        headers.insert("content-type", HeaderValue::from_static("text/plain; version=0.0.4"));
+    }
+    (headers, body)
+}
+
+// HELP 和 TYPE。OpenMetrics 中 counter 的名字不带 _total，样本的名字必须以 _total 结尾，不是的按 unknown 输出
+fn family(body: &mut String, openmetrics: bool, name: &str, kind: &str, help: &str) {
+    let (name, kind) = match (openmetrics, kind, name.strip_suffix("_total")) {
+        (true, "counter", Some(family)) => (family, kind),
+        (true, "counter", None) => (name, "unknown"),
+        _ => (name, kind),
+    };
+    writeln!(body, "# HELP {} {}", name, help).unwrap();
+    writeln!(body, "# TYPE {} {}", name, kind).unwrap();
+}
+
+// 图片请求耗时的直方图，按租户和预设区分
+fn image_latency(body: &mut String, openmetrics: bool, usage: &[(String, String, Usage)]) {
+    let name = "shanbor_image_duration_seconds";
+    family(body, openmetrics, name, "histogram", "Image request latency.");
+    for (tenant, preset, u) in usage {
+        let labels = format!("tenant=\"{}\",preset=\"{}\"", label(tenant), label(preset));
+        let mut count = 0;
+        for (i, n) in u.latency.iter().enumerate() {
+            count += n;
+            let le = LATENCY_BUCKETS.get(i).map_or_else(|| "+Inf".to_owned(), |v| v.to_string());
+            write!(body, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count).unwrap();
+            match u.exemplars[i] {
+                Some(e) if openmetrics => {
+                    let trace_id = trace::format(e.trace_id);
+                    writeln!(body, " # {{trace_id=\"{}\"}} {} {}", trace_id, e.seconds, e.timestamp).unwrap();
+                }
+                _ => body.push('\n'),
+            }
+        }
+        writeln!(body, "{}_sum{{{}}} {}", name, labels, u.seconds).unwrap();
+        writeln!(body, "{}_count{{{}}} {}", name, labels, count).unwrap();
+    }
+}
+
+// Prometheus 标签值中的反斜杠、双引号和换行需要转义
+fn label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
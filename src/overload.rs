@@ -0,0 +1,191 @@
+// 过载保护（[overload.<route>]）：限制每个路由同时处理的请求数，超出时按配置排队等待或者立刻拒绝
+// 交互式的调用方宁可马上收到 503 去重试或者降级，批量任务宁可多等一会儿也不要失败
+use crate::error::AppError;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// 可以配置的路由
+pub const ROUTES: &[&str] = &["image", "collage", "sprite", "contactsheet", "diff", "stats", "estimate"];
+
+lazy_static! {
+    pub static ref OVERLOAD: Overload = Overload::default();
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverloadMode {
+    // 立刻返回 503 和 Retry-After
+    #[default]
+    Reject,
+    // 排队等待，队列已满或者等待超时时返回 503
+    Queue,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverloadPolicy {
+    // 同时处理的请求数
+    pub max_concurrency: usize,
+    pub mode: OverloadMode,
+    // 排队时最多等待的请求数
+    pub queue_depth: usize,
+    // 排队时最多等待的时间（毫秒）
+    pub queue_timeout_ms: u64,
+    // 503 响应的 Retry-After（秒）
+    pub retry_after: u64,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 16,
+            mode: OverloadMode::Reject,
+            queue_depth: 64,
+            queue_timeout_ms: 5000,
+            retry_after: 1,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Overload {
+    routes: RwLock<HashMap<&'static str, Arc<Limiter>>>,
+}
+
+struct Limiter {
+    policy: OverloadPolicy,
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+// 排队的请求被取消（客户端断开）时也要离开队列
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Overload {
+    // 启动时调用一次，没有配置的路由不限制
+    pub fn configure(&self, config: &HashMap<String, OverloadPolicy>) -> Result<(), String> {
+        let mut routes = HashMap::new();
+        for (name, policy) in config {
+            let route = *ROUTES
+                .iter()
+                .find(|v| *v == name)
+                .ok_or_else(|| format!("unknown route {}, expected one of {}", name, ROUTES.join(", ")))?;
+            if policy.max_concurrency == 0 {
+                return Err(format!("{}: max_concurrency must be positive", name));
+            }
+            routes.insert(route, Arc::new(Limiter::new(policy.clone())));
+        }
+        *self.routes.write().unwrap() = routes;
+        Ok(())
+    }
+
+    // 处理请求之前调用，持有返回的 permit 直到处理完
+    pub async fn admit(&self, route: &str) -> Result<Option<OwnedSemaphorePermit>, AppError> {
+        let limiter = match self.routes.read().unwrap().get(route) {
+            Some(v) => v.clone(),
+            None => return Ok(None),
+        };
+        limiter.admit().await.map(Some)
+    }
+
+    // 每个配置了的路由：(路由, 正在排队的请求数, 累计拒绝的请求数)
+    pub fn counts(&self) -> Vec<(&'static str, usize, u64)> {
+        let mut counts: Vec<_> = self
+            .routes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(route, v)| (*route, v.queued.load(Ordering::Relaxed), v.rejected.load(Ordering::Relaxed)))
+            .collect();
+        counts.sort_unstable();
+        counts
+    }
+}
+
+impl Limiter {
+    fn new(policy: OverloadPolicy) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(policy.max_concurrency)),
+            policy,
+            queued: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    async fn admit(&self) -> Result<OwnedSemaphorePermit, AppError> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        if self.policy.mode == OverloadMode::Queue {
+            let position = self.queued.fetch_add(1, Ordering::Relaxed);
+            let _queued = Queued(&self.queued);
+            let deadline = Duration::from_millis(self.policy.queue_timeout_ms);
+            if position < self.policy.queue_depth {
+                if let Ok(Ok(permit)) = tokio::time::timeout(deadline, self.permits.clone().acquire_owned()).await {
+                    return Ok(permit);
+                }
+            }
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        Err(AppError::Overloaded(self.policy.retry_after))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn overload_should_follow_route_policy() {
+        let overload = Overload::default();
+        let config: HashMap<String, OverloadPolicy> = toml::from_str(
+            r#"
+            image = { max_concurrency = 1, retry_after = 3 }
+            collage = { max_concurrency = 1, mode = "queue", queue_depth = 1, queue_timeout_ms = 50 }
+            "#,
+        )
+        .unwrap();
+        overload.configure(&config).unwrap();
+        assert!(overload.admit("sprite").await.unwrap().is_none());
+
+        // reject：超出时立刻拒绝，处理完之后恢复
+        let permit = overload.admit("image").await.unwrap();
+        assert!(matches!(overload.admit("image").await, Err(AppError::Overloaded(3))));
+        drop(permit);
+        assert!(overload.admit("image").await.unwrap().is_some());
+
+        // queue：等到前一个请求处理完，队列已满时立刻拒绝
+        let permit = overload.admit("collage").await.unwrap();
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(permit);
+        };
+        let (queued, full, _) = tokio::join!(overload.admit("collage"), overload.admit("collage"), release);
+        assert!(queued.unwrap().is_some());
+        assert!(matches!(full, Err(AppError::Overloaded(1))));
+        // 等待超时
+        let permit = overload.admit("collage").await.unwrap();
+        assert!(overload.admit("collage").await.is_err());
+        drop(permit);
+        assert_eq!(overload.counts(), vec![("collage", 0, 2), ("image", 0, 1)]);
+
+        let unknown: HashMap<String, OverloadPolicy> = toml::from_str("upload = {}").unwrap();
+        assert!(overload.configure(&unknown).is_err());
+    }
+}
@@ -3,6 +3,12 @@
 pub struct ImageSpec {
     #[prost(message, repeated, tag="1")]
     pub specs: ::prost::alloc::vec::Vec<Spec>,
+    /// 多页图片（如 TIFF）选择处理第几页，从 0 开始
+    #[prost(uint32, tag="2")]
+    pub page: u32,
+    /// spec 协议版本，旧的 spec 没有这个字段，按 0 处理
+    #[prost(uint32, tag="3")]
+    pub version: u32,
 }
 /// 处理图片改变大小
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -46,6 +52,42 @@ pub struct Crop {
     pub x2: u32,
     #[prost(uint32, tag="4")]
     pub y2: u32,
+    #[prost(enumeration="crop::Gravity", tag="5")]
+    pub gravity: i32,
+    /// 0 表示和图片一样宽
+    #[prost(uint32, tag="6")]
+    pub width: u32,
+    /// 0 表示和图片一样高
+    #[prost(uint32, tag="7")]
+    pub height: u32,
+    /// 相对锚点的偏移，正数向图片内部移动；CENTER 时正数向右、向下
+    #[prost(int32, tag="8")]
+    pub offset_x: i32,
+    #[prost(int32, tag="9")]
+    pub offset_y: i32,
+    /// 设置了锚点时按 ratio_width:ratio_height 裁剪出最大的区域，此时忽略 width、height
+    #[prost(uint32, tag="10")]
+    pub ratio_width: u32,
+    #[prost(uint32, tag="11")]
+    pub ratio_height: u32,
+}
+/// Nested message and enum types in `Crop`.
+pub mod crop {
+    /// 锚点，设置后忽略 x1/y1/x2/y2，按 width x height 从锚点的位置裁剪
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Gravity {
+        Unspecified = 0,
+        Center = 1,
+        North = 2,
+        South = 3,
+        East = 4,
+        West = 5,
+        NorthEast = 6,
+        NorthWest = 7,
+        SouthEast = 8,
+        SouthWest = 9,
+    }
 }
 /// 处理图片水平翻转
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -86,11 +128,178 @@ pub struct Watermark {
     pub x: u32,
     #[prost(uint32, tag="2")]
     pub y: u32,
+    #[prost(enumeration="watermark::Mode", tag="3")]
+    pub mode: i32,
+    /// 平铺时水印之间的间距（像素）
+    #[prost(uint32, tag="4")]
+    pub spacing: u32,
+    /// 平铺时水印及排列方向的旋转角度（度）
+    #[prost(float, tag="5")]
+    pub angle: f32,
+    /// 不透明度 (0, 1]，0 表示未设置，按完全不透明处理
+    #[prost(float, tag="6")]
+    pub opacity: f32,
+    /// 上传的水印素材名，为空时使用内置的水印
+    #[prost(string, tag="7")]
+    pub asset: ::prost::alloc::string::String,
+    /// 按执行水印时的图片宽度缩放水印（放在缩放操作之后即相对输出尺寸），同一个预设在缩略图和大图上都清晰可见
+    ///
+    /// 水印宽度占图片宽度的比例 (0, 1]，0 表示使用素材的原始尺寸
+    #[prost(float, tag="8")]
+    pub scale: f32,
+    /// 按比例缩放后水印的最小宽度（像素），不超过图片宽度
+    #[prost(uint32, tag="9")]
+    pub min_width: u32,
+}
+/// Nested message and enum types in `Watermark`.
+pub mod watermark {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Mode {
+        /// 在 (x, y) 处放置一个水印
+        Single = 0,
+        /// 沿对角线方向平铺整张图片，此时忽略 x, y
+        Tiled = 1,
+    }
+}
+/// 处理图片文字
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Text {
+    #[prost(string, tag="1")]
+    pub text: ::prost::alloc::string::String,
+    /// 文字框左上角的位置
+    #[prost(uint32, tag="2")]
+    pub x: u32,
+    #[prost(uint32, tag="3")]
+    pub y: u32,
+    /// 字号（像素），0 表示使用默认字号
+    #[prost(float, tag="4")]
+    pub size: f32,
+    /// 0xRRGGBBAA，0 表示默认的白色
+    #[prost(uint32, tag="5")]
+    pub color: u32,
+    /// 注册的字体名，为空时使用内置的 Roboto
+    #[prost(string, tag="6")]
+    pub font: ::prost::alloc::string::String,
+}
+/// 自动增强：依次做自动白平衡、自动色阶和轻度锐化
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AutoEnhance {
+}
+/// 模拟色觉障碍者看到的效果，用于无障碍检查
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Simulate {
+    #[prost(enumeration="simulate::Deficiency", tag="1")]
+    pub deficiency: i32,
+}
+/// Nested message and enum types in `Simulate`.
+pub mod simulate {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Deficiency {
+        Unspecified = 0,
+        /// 绿色盲
+        Deuteranopia = 1,
+        /// 红色盲
+        Protanopia = 2,
+        /// 蓝色盲
+        Tritanopia = 3,
+    }
+}
+/// 生成极小的低质量预览图（LQIP），适合以 base64 内联到 HTML 中
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lqip {
+    /// 预览图宽度，0 表示默认 32，高度按比例计算
+    #[prost(uint32, tag="1")]
+    pub width: u32,
+    /// 是否模糊
+    #[prost(bool, tag="2")]
+    pub blur: bool,
+    /// JPEG 质量，0 表示默认 30
+    #[prost(uint32, tag="3")]
+    pub quality: u32,
+}
+/// 嵌入不可见的水印 id，可以通过 /verify 或 `shanbor verify` 检出
+/// 能抵抗重新压缩，但不能经过缩放和裁剪，所以一般放在最后一步
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InvisibleWatermark {
+    #[prost(uint32, tag="1")]
+    pub id: u32,
+    /// 亮度变化幅度，0 表示默认值
+    #[prost(float, tag="2")]
+    pub strength: f32,
+}
+/// 一次模糊多个矩形区域，用于遮盖截图中的邮箱、token 等固定位置的信息，一般写在租户的预设中
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BlurRegions {
+    /// 超出图片的部分被忽略
+    #[prost(message, repeated, tag="1")]
+    pub regions: ::prost::alloc::vec::Vec<blur_regions::Region>,
+    /// 高斯模糊的 sigma，0 表示默认值
+    #[prost(float, tag="2")]
+    pub sigma: f32,
+}
+/// Nested message and enum types in `BlurRegions`.
+pub mod blur_regions {
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct Region {
+        #[prost(uint32, tag="1")]
+        pub x1: u32,
+        #[prost(uint32, tag="2")]
+        pub y1: u32,
+        #[prost(uint32, tag="3")]
+        pub x2: u32,
+        #[prost(uint32, tag="4")]
+        pub y2: u32,
+    }
+}
+/// 只保留一个色相范围的颜色，其余部分变成灰度，用于活动图片突出主体
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ColorPop {
+    /// 保留的色相（度），0 是红色，120 是绿色，240 是蓝色
+    #[prost(float, tag="1")]
+    pub hue: f32,
+    /// 色相两侧保留的范围（度），0 表示默认值
+    #[prost(float, tag="2")]
+    pub tolerance: f32,
+}
+/// 在图片顶部或底部加一条半透明的文字条，文字是模板，可以使用源图片 EXIF 中的拍摄时间、相机和版权
+/// 比如 "{exif_date} · {exif_camera} · {exif_copyright}"，EXIF 中没有的字段替换为空
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Caption {
+    #[prost(string, tag="1")]
+    pub text: ::prost::alloc::string::String,
+    /// 字号（像素），0 表示默认字号
+    #[prost(float, tag="2")]
+    pub size: f32,
+    /// 文字颜色 0xRRGGBBAA，0 表示默认的白色
+    #[prost(uint32, tag="3")]
+    pub color: u32,
+    /// 文字条的颜色 0xRRGGBBAA，0 表示默认的半透明黑色
+    #[prost(uint32, tag="4")]
+    pub background: u32,
+    #[prost(enumeration="caption::Position", tag="5")]
+    pub position: i32,
+    /// 注册的字体名，为空时使用内置的 Roboto
+    #[prost(string, tag="6")]
+    pub font: ::prost::alloc::string::String,
+}
+/// Nested message and enum types in `Caption`.
+pub mod caption {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Position {
+        Bottom = 0,
+        Top = 1,
+    }
 }
 /// 一个 spec 可以包含上述的处理方式之一
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Spec {
-    #[prost(oneof="spec::Data", tags="1, 2, 3, 4, 5, 6, 7")]
+    /// 执行这个操作的条件，不设置则总是执行。操作的编号留给 oneof，条件使用较大的编号
+    #[prost(message, optional, tag="32")]
+    pub when: ::core::option::Option<Condition>,
+    #[prost(oneof="spec::Data", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15")]
     pub data: ::core::option::Option<spec::Data>,
 }
 /// Nested message and enum types in `Spec`.
@@ -111,5 +320,55 @@ pub mod spec {
         Filter(super::Filter),
         #[prost(message, tag="7")]
         Watermark(super::Watermark),
+        #[prost(message, tag="8")]
+        Text(super::Text),
+        #[prost(message, tag="9")]
+        AutoEnhance(super::AutoEnhance),
+        #[prost(message, tag="10")]
+        Lqip(super::Lqip),
+        #[prost(message, tag="11")]
+        Simulate(super::Simulate),
+        #[prost(message, tag="12")]
+        InvisibleWatermark(super::InvisibleWatermark),
+        #[prost(message, tag="13")]
+        BlurRegions(super::BlurRegions),
+        #[prost(message, tag="14")]
+        ColorPop(super::ColorPop),
+        #[prost(message, tag="15")]
+        Caption(super::Caption),
+    }
+}
+/// 根据源图片的属性决定是否执行一个操作，所有设置了的条件都满足时才执行
+/// 比如只在宽度超过 1200 时缩小，或者图片太小时不加水印
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Condition {
+    /// 源图片宽度 >= min_width
+    #[prost(uint32, tag="1")]
+    pub min_width: u32,
+    /// 源图片宽度 <= max_width，0 表示不限制
+    #[prost(uint32, tag="2")]
+    pub max_width: u32,
+    #[prost(uint32, tag="3")]
+    pub min_height: u32,
+    #[prost(uint32, tag="4")]
+    pub max_height: u32,
+    /// 源图片格式是其中之一，比如 jpeg、png
+    #[prost(string, repeated, tag="5")]
+    pub source_formats: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// 源图片的方向，比如竖图裁剪成 4:5，横图裁剪成 16:9
+    #[prost(enumeration="condition::Orientation", tag="6")]
+    pub orientation: i32,
+}
+/// Nested message and enum types in `Condition`.
+pub mod condition {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Orientation {
+        Any = 0,
+        /// 高度 > 宽度
+        Portrait = 1,
+        /// 宽度 > 高度
+        Landscape = 2,
+        Square = 3,
     }
 }
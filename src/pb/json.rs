@@ -0,0 +1,574 @@
+// ImageSpec 的 JSON 表示，同样用于 TOML 配置文件。字段名和取值是稳定的 schema，
+// 枚举使用和文本语法相同的名字，颜色使用 #RRGGBBAA，比如：
+//   {"version": 1, "ops": [{"op": "resize", "width": 200, "height": 100, "filter": "lanczos3"},
+//                          {"op": "watermark", "x": 10, "y": 10, "when": {"min_width": 300}}]}
+// 和 protobuf 可以无损地互相转换
+use super::syntax::{
+    enum_name, enum_value, parse_color, CAPTION_POSITIONS, DEFICIENCIES, FILTERS, GRAVITIES, ORIENTATIONS, RESIZE_TYPES,
+    SAMPLE_FILTERS, WATERMARK_MODES,
+};
+use super::*;
+use serde::{de, Deserializer, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct JsonSpec {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub page: u32,
+    pub ops: Vec<JsonOp>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct JsonOp {
+    #[serde(flatten)]
+    pub data: JsonData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<JsonCondition>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct JsonCondition {
+    #[serde(skip_serializing_if = "is_default")]
+    pub min_width: u32,
+    #[serde(skip_serializing_if = "is_default")]
+    pub max_width: u32,
+    #[serde(skip_serializing_if = "is_default")]
+    pub min_height: u32,
+    #[serde(skip_serializing_if = "is_default")]
+    pub max_height: u32,
+    #[serde(skip_serializing_if = "is_default")]
+    pub source_formats: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<String>,
+}
+
+// serde 的 flatten 不支持 deny_unknown_fields，解码时先取出 when，剩下的字段按 op 解码，
+// 拼错的字段（比如 "opactiy"）会报错，而不是静默使用默认值
+impl<'de> Deserialize<'de> for JsonOp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields = serde_json::Map::deserialize(deserializer)?;
+        let when = match fields.remove("when") {
+            Some(serde_json::Value::Null) | None => None,
+            Some(v) => Some(serde_json::from_value(v).map_err(de::Error::custom)?),
+        };
+        let data = serde_json::from_value(serde_json::Value::Object(fields)).map_err(de::Error::custom)?;
+        Ok(Self { data, when })
+    }
+}
+
+// 省略的字段都使用 protobuf 中的默认值
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum JsonData {
+    Resize {
+        width: u32,
+        height: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<String>,
+        #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+        rtype: Option<String>,
+    },
+    Crop {
+        #[serde(default, skip_serializing_if = "is_default")]
+        x1: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        y1: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        x2: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        y2: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gravity: Option<String>,
+        #[serde(default, skip_serializing_if = "is_default")]
+        width: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        height: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        offset_x: i32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        offset_y: i32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        ratio_width: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        ratio_height: u32,
+    },
+    // 没有参数的操作也写成 {}，unit variant 不会检查多余的字段
+    Flipv {},
+    Fliph {},
+    Contrast {
+        contrast: f32,
+    },
+    Filter {
+        filter: String,
+    },
+    Watermark {
+        #[serde(default, skip_serializing_if = "is_default")]
+        x: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        y: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<String>,
+        #[serde(default, skip_serializing_if = "is_default")]
+        spacing: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        angle: f32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        opacity: f32,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        asset: String,
+        #[serde(default, skip_serializing_if = "is_default")]
+        scale: f32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        min_width: u32,
+    },
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "is_default")]
+        x: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        y: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        size: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color: Option<String>,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        font: String,
+    },
+    AutoEnhance {},
+    Lqip {
+        #[serde(default, skip_serializing_if = "is_default")]
+        width: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        blur: bool,
+        #[serde(default, skip_serializing_if = "is_default")]
+        quality: u32,
+    },
+    Simulate {
+        deficiency: String,
+    },
+    InvisibleWatermark {
+        id: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        strength: f32,
+    },
+    ColorPop {
+        hue: f32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        tolerance: f32,
+    },
+    Caption {
+        text: String,
+        #[serde(default, skip_serializing_if = "is_default")]
+        size: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        background: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        position: Option<String>,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        font: String,
+    },
+    // 每个区域是 [x1, y1, x2, y2]
+    BlurRegions {
+        regions: Vec<[u32; 4]>,
+        #[serde(default, skip_serializing_if = "is_default")]
+        sigma: f32,
+    },
+}
+
+// 配置文件中的 spec，可以是 spec 字符串，也可以直接写成表
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SpecValue {
+    String(String),
+    Json(JsonSpec),
+}
+
+impl SpecValue {
+    pub fn parse(&self, policy: UnknownPolicy) -> Result<ImageSpec, SpecError> {
+        match self {
+            SpecValue::String(v) => ImageSpec::parse_with(v, policy),
+            SpecValue::Json(v) => ImageSpec::try_from(v.clone()),
+        }
+    }
+}
+
+fn default_version() -> u32 {
+    SPEC_VERSION
+}
+
+fn is_default<T: Default + PartialEq>(v: &T) -> bool {
+    *v == T::default()
+}
+
+fn name(value: i32, names: &[(&'static str, i32)]) -> Option<String> {
+    enum_name(value, names).map(str::to_owned)
+}
+
+impl From<&ImageSpec> for JsonSpec {
+    fn from(spec: &ImageSpec) -> Self {
+        // 不认识的操作（ignore 模式下解码出来的空操作）无法表示，直接跳过
+        let ops = spec.specs.iter().filter_map(JsonOp::from_spec).collect();
+        Self {
+            version: spec.version,
+            page: spec.page,
+            ops,
+        }
+    }
+}
+
+impl JsonOp {
+    fn from_spec(spec: &Spec) -> Option<Self> {
+        let data = match spec.data.as_ref()? {
+            spec::Data::Resize(v) => JsonData::Resize {
+                width: v.width,
+                height: v.height,
+                filter: name(v.filter, SAMPLE_FILTERS),
+                rtype: name(v.rtype, RESIZE_TYPES).filter(|v| *v != "normal"),
+            },
+            spec::Data::Crop(v) => JsonData::Crop {
+                x1: v.x1,
+                y1: v.y1,
+                x2: v.x2,
+                y2: v.y2,
+                gravity: name(v.gravity, GRAVITIES),
+                width: v.width,
+                height: v.height,
+                offset_x: v.offset_x,
+                offset_y: v.offset_y,
+                ratio_width: v.ratio_width,
+                ratio_height: v.ratio_height,
+            },
+            spec::Data::Flipv(_) => JsonData::Flipv {},
+            spec::Data::Fliph(_) => JsonData::Fliph {},
+            spec::Data::Contrast(v) => JsonData::Contrast { contrast: v.contrast },
+            spec::Data::Filter(v) => JsonData::Filter {
+                filter: name(v.filter, FILTERS).unwrap_or_default(),
+            },
+            spec::Data::Watermark(v) => JsonData::Watermark {
+                x: v.x,
+                y: v.y,
+                mode: name(v.mode, WATERMARK_MODES).filter(|v| *v != "single"),
+                spacing: v.spacing,
+                angle: v.angle,
+                opacity: v.opacity,
+                asset: v.asset.clone(),
+                scale: v.scale,
+                min_width: v.min_width,
+            },
+            spec::Data::Text(v) => JsonData::Text {
+                text: v.text.clone(),
+                x: v.x,
+                y: v.y,
+                size: v.size,
+                color: (v.color != 0).then(|| format!("#{:08x}", v.color)),
+                font: v.font.clone(),
+            },
+            spec::Data::AutoEnhance(_) => JsonData::AutoEnhance {},
+            spec::Data::Lqip(v) => JsonData::Lqip {
+                width: v.width,
+                blur: v.blur,
+                quality: v.quality,
+            },
+            spec::Data::Simulate(v) => JsonData::Simulate {
+                deficiency: name(v.deficiency, DEFICIENCIES).unwrap_or_default(),
+            },
+            spec::Data::InvisibleWatermark(v) => JsonData::InvisibleWatermark {
+                id: v.id,
+                strength: v.strength,
+            },
+            spec::Data::ColorPop(v) => JsonData::ColorPop {
+                hue: v.hue,
+                tolerance: v.tolerance,
+            },
+            spec::Data::Caption(v) => JsonData::Caption {
+                text: v.text.clone(),
+                size: v.size,
+                color: (v.color != 0).then(|| format!("#{:08x}", v.color)),
+                background: (v.background != 0).then(|| format!("#{:08x}", v.background)),
+                position: name(v.position, CAPTION_POSITIONS).filter(|v| *v != "bottom"),
+                font: v.font.clone(),
+            },
+            spec::Data::BlurRegions(v) => JsonData::BlurRegions {
+                regions: v.regions.iter().map(|r| [r.x1, r.y1, r.x2, r.y2]).collect(),
+                sigma: v.sigma,
+            },
+        };
+        let when = spec.when.as_ref().map(|w| JsonCondition {
+            min_width: w.min_width,
+            max_width: w.max_width,
+            min_height: w.min_height,
+            max_height: w.max_height,
+            source_formats: w.source_formats.clone(),
+            orientation: name(w.orientation, ORIENTATIONS),
+        });
+        Some(Self { data, when })
+    }
+
+    fn into_spec(self, index: usize) -> Result<Spec, SpecError> {
+        let invalid = |message: String| SpecError::new(0, message, Some(index), "invalid value in json spec");
+        let value = |v: Option<String>, names| v.map(|v| enum_value(&v, names).map_err(invalid)).transpose();
+        let data = match self.data {
+            JsonData::Resize {
+                width,
+                height,
+                filter,
+                rtype,
+            } => spec::Data::Resize(Resize {
+                width,
+                height,
+                filter: value(filter, SAMPLE_FILTERS)?.unwrap_or(0),
+                rtype: value(rtype, RESIZE_TYPES)?.unwrap_or(0),
+            }),
+            JsonData::Crop {
+                x1,
+                y1,
+                x2,
+                y2,
+                gravity,
+                width,
+                height,
+                offset_x,
+                offset_y,
+                ratio_width,
+                ratio_height,
+            } => spec::Data::Crop(Crop {
+                x1,
+                y1,
+                x2,
+                y2,
+                gravity: value(gravity, GRAVITIES)?.unwrap_or(0),
+                width,
+                height,
+                offset_x,
+                offset_y,
+                ratio_width,
+                ratio_height,
+            }),
+            JsonData::Flipv {} => spec::Data::Flipv(Flipv {}),
+            JsonData::Fliph {} => spec::Data::Fliph(Fliph {}),
+            JsonData::Contrast { contrast } => spec::Data::Contrast(Contrast { contrast }),
+            JsonData::Filter { filter } => spec::Data::Filter(Filter {
+                filter: value(Some(filter), FILTERS)?.unwrap_or(0),
+            }),
+            JsonData::Watermark {
+                x,
+                y,
+                mode,
+                spacing,
+                angle,
+                opacity,
+                asset,
+                scale,
+                min_width,
+            } => spec::Data::Watermark(Watermark {
+                x,
+                y,
+                mode: value(mode, WATERMARK_MODES)?.unwrap_or(0),
+                spacing,
+                angle,
+                opacity,
+                asset,
+                scale,
+                min_width,
+            }),
+            JsonData::Text {
+                text,
+                x,
+                y,
+                size,
+                color,
+                font,
+            } => spec::Data::Text(Text {
+                text,
+                x,
+                y,
+                size,
+                color: color.map(|v| parse_color(&v).map_err(invalid)).transpose()?.unwrap_or(0),
+                font,
+            }),
+            JsonData::AutoEnhance {} => spec::Data::AutoEnhance(AutoEnhance {}),
+            JsonData::Lqip { width, blur, quality } => spec::Data::Lqip(Lqip { width, blur, quality }),
+            JsonData::Simulate { deficiency } => spec::Data::Simulate(Simulate {
+                deficiency: value(Some(deficiency), DEFICIENCIES)?.unwrap_or(0),
+            }),
+            JsonData::InvisibleWatermark { id, strength } => {
+                spec::Data::InvisibleWatermark(InvisibleWatermark { id, strength })
+            }
+            JsonData::ColorPop { hue, tolerance } => spec::Data::ColorPop(ColorPop { hue, tolerance }),
+            JsonData::Caption {
+                text,
+                size,
+                color,
+                background,
+                position,
+                font,
+            } => spec::Data::Caption(Caption {
+                text,
+                size,
+                color: color.map(|v| parse_color(&v).map_err(invalid)).transpose()?.unwrap_or(0),
+                background: background.map(|v| parse_color(&v).map_err(invalid)).transpose()?.unwrap_or(0),
+                position: value(position, CAPTION_POSITIONS)?.unwrap_or(0),
+                font,
+            }),
+            JsonData::BlurRegions { regions, sigma } => spec::Data::BlurRegions(BlurRegions {
+                regions: regions
+                    .into_iter()
+                    .map(|[x1, y1, x2, y2]| blur_regions::Region { x1, y1, x2, y2 })
+                    .collect(),
+                sigma,
+            }),
+        };
+        let when = match self.when {
+            Some(w) => Some(Condition {
+                min_width: w.min_width,
+                max_width: w.max_width,
+                min_height: w.min_height,
+                max_height: w.max_height,
+                source_formats: w.source_formats.iter().map(|f| f.to_ascii_lowercase()).collect(),
+                orientation: value(w.orientation, ORIENTATIONS)?.unwrap_or(0),
+            }),
+            None => None,
+        };
+        Ok(Spec { data: Some(data), when })
+    }
+}
+
+// JSON 中没有字符串的位置信息，出错时 offset 总是 0，通过 op 定位到具体的操作
+impl TryFrom<JsonSpec> for ImageSpec {
+    type Error = SpecError;
+
+    fn try_from(value: JsonSpec) -> Result<Self, Self::Error> {
+        let specs = value
+            .ops
+            .into_iter()
+            .enumerate()
+            .map(|(i, op)| op.into_spec(i))
+            .collect::<Result<_, _>>()?;
+        let spec = ImageSpec {
+            specs,
+            page: value.page,
+            version: value.version,
+        };
+        spec.validate(&[])?;
+        Ok(spec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_ops() -> ImageSpec {
+        let mut spec = ImageSpec::new(vec![
+            Spec::new_resize(200, 100, resize::SampleFilter::Lanczos3),
+            Spec::new_resize_seam_carve(50, 40),
+            Spec::new_crop(1, 2, 30, 40),
+            Spec::new_crop_gravity(crop::Gravity::SouthWest, 10, 0, -2, 3),
+            Spec::new_filter(filter::Filter::Islands),
+            Spec::new_watermark(3, 4),
+            Spec::new_watermark_tiled(20, 30.0, 0.5),
+            Spec::new_watermark_asset("summer-sale", 5, 6),
+            Spec::new_text("hi, \"there\"", 1, 2, 14.0, 0xff0000ff),
+            ImageSpec::parse("text:hi,font=brand-bold").unwrap().specs.remove(0),
+            Spec::new_auto_enhance(),
+            Spec::new_lqip(16, true),
+            Spec::new_simulate(simulate::Deficiency::Tritanopia),
+            Spec::new_invisible_watermark(9),
+            Spec::new_blur_regions(&[(0, 0, 20, 10), (5, 5, 15, 40)], 6.0),
+            Spec::new_color_pop(200.0, 0.0),
+            Spec::new_caption("{exif_date} {exif_camera}", 14.0),
+            ImageSpec::parse("caption:\"© {exif_copyright}\",position=top,bg=#00000080").unwrap().specs.remove(0),
+            Spec::new_crop_ratio(crop::Gravity::North, 4, 5).when(Condition {
+                orientation: condition::Orientation::Portrait as i32,
+                ..Default::default()
+            }),
+            Spec::new_watermark(0, 0).when(Condition {
+                min_width: 300,
+                source_formats: vec!["jpeg".to_owned()],
+                ..Default::default()
+            }),
+        ]);
+        spec.page = 1;
+        spec
+    }
+
+    #[test]
+    fn json_spec_should_round_trip() {
+        let spec = all_ops();
+        let json = serde_json::to_string(&JsonSpec::from(&spec)).unwrap();
+        let parsed: JsonSpec = serde_json::from_str(&json).unwrap();
+        assert_eq!(ImageSpec::try_from(parsed).unwrap(), spec);
+    }
+
+    #[test]
+    fn json_spec_should_reject_unknown_fields() {
+        let parse = |op: &str| serde_json::from_str::<JsonSpec>(&format!(r#"{{"ops":[{}]}}"#, op));
+        let op = parse(r#"{"op":"watermark","opacity":0.5,"when":{"min_width":300}}"#).unwrap().ops.remove(0);
+        assert_eq!(op.when.unwrap().min_width, 300);
+        assert!(parse(r#"{"op":"watermark","opacity":0.5,"when":null}"#).unwrap().ops[0].when.is_none());
+        for op in [
+            r#"{"op":"watermark","opactiy":0.5}"#,
+            r#"{"op":"flipv","x":1}"#,
+            r#"{"op":"resize","width":1,"height":1,"when":{"min_widht":1}}"#,
+            r#"{"op":"blur"}"#,
+            r#"{"width":1}"#,
+        ] {
+            assert!(parse(op).is_err(), "{}", op);
+        }
+        // 配置文件中的表也一样
+        let preset: Result<SpecValue, _> = toml::from_str(r#"ops = [{ op = "watermark", opactiy = 0.5 }]"#);
+        assert!(preset.is_err());
+        let preset: SpecValue = toml::from_str(r#"ops = [{ op = "watermark", opacity = 0.5 }]"#).unwrap();
+        assert!(preset.parse(UnknownPolicy::Reject).is_ok());
+    }
+
+    #[test]
+    fn json_schema_should_be_stable() {
+        let spec = ImageSpec::new(vec![
+            Spec::new_resize(200, 100, resize::SampleFilter::Nearest),
+            Spec {
+                data: Some(spec::Data::Fliph(Fliph {})),
+                when: None,
+            },
+        ]);
+        let json = serde_json::to_string(&JsonSpec::from(&spec)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"ops":[{"op":"resize","width":200,"height":100,"filter":"nearest"},{"op":"fliph"}]}"#
+        );
+    }
+
+    #[test]
+    fn spec_value_should_accept_string_or_table() {
+        #[derive(Deserialize)]
+        struct Presets {
+            a: SpecValue,
+            b: SpecValue,
+        }
+        let presets: Presets = toml::from_str(
+            r#"
+            a = "filter:marine"
+            b = { ops = [{ op = "filter", filter = "marine" }] }
+            "#,
+        )
+        .unwrap();
+        let a = presets.a.parse(UnknownPolicy::Ignore).unwrap();
+        assert_eq!(a, presets.b.parse(UnknownPolicy::Ignore).unwrap());
+
+        let err = ImageSpec::try_from(JsonSpec {
+            version: 1,
+            page: 0,
+            ops: vec![JsonOp {
+                data: JsonData::Filter { filter: "sepia".to_owned() },
+                when: None,
+            }],
+        })
+        .unwrap_err();
+        assert_eq!(err.op, Some(0));
+    }
+}
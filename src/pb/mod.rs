@@ -1,14 +1,30 @@
+use crate::engine::SourceFormat;
 use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
 use photon_rs::transform::SamplingFilter;
 use prost::Message;
+use serde::Deserialize;
 use std::convert::TryFrom;
 
 mod abi;
+mod json;
+pub(crate) mod syntax;
 pub use abi::*;
+pub use json::{JsonSpec, SpecValue};
+pub use syntax::SpecError;
 
 impl ImageSpec {
     pub fn new(specs: Vec<Spec>) -> Self {
-        Self { specs }
+        Self {
+            specs,
+            page: 0,
+            version: SPEC_VERSION,
+        }
+    }
+
+    // 指定处理多页图片的第几页
+    pub fn with_page(mut self, page: u32) -> Self {
+        self.page = page;
+        self
     }
 }
 
@@ -24,14 +40,265 @@ impl From<&ImageSpec> for String {
 impl TryFrom<&str> for ImageSpec {
     type Error = anyhow::Error;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let data = decode_config(value, URL_SAFE_NO_PAD)?;
-        Ok(ImageSpec::decode(&data[..])?)
+        Ok(ImageSpec::parse(value)?)
+    }
+}
+
+// 当前的 spec 协议版本。没有 version 字段的旧 spec 按 0 处理，语义和 1 相同
+// 改变已有操作的含义时必须升级版本，旧版本的 spec 要保持原来的输出
+pub const SPEC_VERSION: u32 = 1;
+
+// spec 中出现不认识的字段或操作（比如新版本客户端生成的 spec）时的处理方式
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownPolicy {
+    // 忽略不认识的部分，其余操作照常处理
+    #[default]
+    Ignore,
+    // 返回 422
+    Reject,
+}
+
+// 缩放、LQIP 等操作允许的最大边长
+pub const MAX_DIMENSION: u32 = 8192;
+// 文字水印最多的字符数
+const MAX_TEXT_LEN: usize = 1024;
+// 一个 blur_regions 操作最多的区域数
+pub const MAX_BLUR_REGIONS: usize = 64;
+// 模糊的最大 sigma
+const MAX_BLUR_SIGMA: f32 = 100.0;
+
+impl ImageSpec {
+    // 解析 URL 中的 spec：文本语法，或者 base64url 编码的 protobuf
+    // 失败时返回出错的位置，解析成功的 spec 都经过了校验，可以放心交给 engine
+    pub fn parse(value: &str) -> Result<Self, SpecError> {
+        Self::parse_with(value, UnknownPolicy::default())
+    }
+
+    // 以 { 开头的是 JSON 格式（见 json.rs）
+    // 文本语法和 JSON 总是拒绝不认识的操作和参数，policy 只影响 protobuf 编码的 spec
+    pub fn parse_with(value: &str, policy: UnknownPolicy) -> Result<Self, SpecError> {
+        if value.trim_start().starts_with('{') {
+            return Self::from_json(value);
+        }
+        let (spec, offsets) = if syntax::is_text(value) {
+            syntax::parse(value)?
+        } else {
+            decode_binary(value, policy)?
+        };
+        spec.validate(&offsets)?;
+        Ok(spec)
+    }
+
+    pub fn from_json(value: &str) -> Result<Self, SpecError> {
+        let json: JsonSpec = serde_json::from_str(value).map_err(|e| {
+            // serde_json 只给出行号和列号，换算成字节偏移
+            let line_start: usize = value.split('\n').take(e.line().saturating_sub(1)).map(|l| l.len() + 1).sum();
+            let offset = (line_start + e.column().saturating_sub(1)).min(value.len());
+            SpecError::new(offset, "json spec", None, e.to_string())
+        })?;
+        Self::try_from(json)
+    }
+
+    fn validate(&self, offsets: &[usize]) -> Result<(), SpecError> {
+        // 更新的版本可能改变了操作的含义，不能按当前版本处理
+        if self.version > SPEC_VERSION {
+            return Err(SpecError::new(
+                0,
+                format!("version <= {}", SPEC_VERSION),
+                None,
+                format!("unsupported spec version {}", self.version),
+            ));
+        }
+        for (i, spec) in self.specs.iter().enumerate() {
+            let invalid = |expected: &str, message: &str| {
+                SpecError::new(offsets.get(i).copied().unwrap_or(0), expected, Some(i), message)
+            };
+            let dimension = |v: u32| v > 0 && v <= MAX_DIMENSION;
+            if let Some(ref when) = spec.when {
+                let known = |f: &String| SourceFormat::ALL.iter().any(|v| v.to_string() == *f);
+                if !when.source_formats.iter().all(known) {
+                    return Err(invalid("known source format", "unknown source format in condition"));
+                }
+                if condition::Orientation::from_i32(when.orientation).is_none() {
+                    return Err(invalid("valid orientation", "unknown orientation in condition"));
+                }
+            }
+            match spec.data {
+                Some(spec::Data::Resize(ref v)) => {
+                    if resize::ResizeType::from_i32(v.rtype).is_none() {
+                        return Err(invalid("valid resize type", "unknown resize type"));
+                    }
+                    if resize::SampleFilter::from_i32(v.filter).is_none() {
+                        return Err(invalid("valid sample filter", "unknown sample filter"));
+                    }
+                    if !dimension(v.width) || !dimension(v.height) {
+                        return Err(invalid(&format!("width and height in 1..={}", MAX_DIMENSION), "invalid size"));
+                    }
+                }
+                Some(spec::Data::Crop(ref v)) => match crop::Gravity::from_i32(v.gravity) {
+                    None => return Err(invalid("valid gravity", "unknown gravity")),
+                    Some(crop::Gravity::Unspecified) if v.x1 >= v.x2 || v.y1 >= v.y2 => {
+                        return Err(invalid("x1 < x2 and y1 < y2", "empty crop area"));
+                    }
+                    _ if (v.ratio_width == 0) != (v.ratio_height == 0) => {
+                        return Err(invalid("ratio of w:h", "incomplete crop ratio"));
+                    }
+                    _ => {}
+                },
+                Some(spec::Data::Contrast(ref v)) if !v.contrast.is_finite() => {
+                    return Err(invalid("finite number", "invalid contrast"));
+                }
+                Some(spec::Data::Filter(ref v)) if filter::Filter::from_i32(v.filter).is_none() => {
+                    return Err(invalid("valid filter", "unknown filter"));
+                }
+                Some(spec::Data::Watermark(ref v)) => {
+                    if watermark::Mode::from_i32(v.mode).is_none() {
+                        return Err(invalid("valid watermark mode", "unknown watermark mode"));
+                    }
+                    if !v.angle.is_finite() || !v.opacity.is_finite() {
+                        return Err(invalid("finite number", "invalid angle or opacity"));
+                    }
+                    if !(0.0..=1.0).contains(&v.scale) || v.min_width > MAX_DIMENSION {
+                        return Err(invalid("scale in 0..=1", "invalid watermark scale"));
+                    }
+                    if !v.asset.is_empty() && !crate::assets::valid_name(&v.asset) {
+                        return Err(invalid("asset name of [a-z0-9_-]", "invalid asset name"));
+                    }
+                }
+                Some(spec::Data::Text(ref v)) => {
+                    if v.text.chars().count() > MAX_TEXT_LEN {
+                        return Err(invalid(&format!("at most {} characters", MAX_TEXT_LEN), "text too long"));
+                    }
+                    if !v.size.is_finite() || v.size < 0.0 || v.size > MAX_DIMENSION as f32 {
+                        return Err(invalid("valid font size", "invalid font size"));
+                    }
+                    // 绘制时按 i32 计算位置
+                    if v.x > MAX_DIMENSION || v.y > MAX_DIMENSION {
+                        return Err(invalid(&format!("position in 0..={}", MAX_DIMENSION), "invalid text position"));
+                    }
+                    if !v.font.is_empty() && !crate::assets::valid_name(&v.font) {
+                        return Err(invalid("font name of [a-z0-9_-]", "invalid font name"));
+                    }
+                }
+                Some(spec::Data::Caption(ref v)) => {
+                    if v.text.chars().count() > MAX_TEXT_LEN {
+                        return Err(invalid(&format!("at most {} characters", MAX_TEXT_LEN), "text too long"));
+                    }
+                    if !v.size.is_finite() || v.size < 0.0 || v.size > MAX_DIMENSION as f32 {
+                        return Err(invalid("valid font size", "invalid font size"));
+                    }
+                    if !v.font.is_empty() && !crate::assets::valid_name(&v.font) {
+                        return Err(invalid("font name of [a-z0-9_-]", "invalid font name"));
+                    }
+                }
+                Some(spec::Data::Lqip(ref v)) if v.width > MAX_DIMENSION || v.quality > 100 => {
+                    return Err(invalid("width and quality in range", "invalid lqip"));
+                }
+                Some(spec::Data::Simulate(ref v)) if simulate::Deficiency::from_i32(v.deficiency).is_none() => {
+                    return Err(invalid("valid deficiency", "unknown deficiency"));
+                }
+                Some(spec::Data::InvisibleWatermark(ref v)) if !v.strength.is_finite() || v.strength < 0.0 => {
+                    return Err(invalid("non-negative number", "invalid strength"));
+                }
+                Some(spec::Data::ColorPop(ref v)) => {
+                    if !v.hue.is_finite() || !(0.0..360.0).contains(&v.hue) {
+                        return Err(invalid("hue in 0..360", "invalid hue"));
+                    }
+                    if !v.tolerance.is_finite() || !(0.0..=180.0).contains(&v.tolerance) {
+                        return Err(invalid("tolerance in 0..=180", "invalid tolerance"));
+                    }
+                }
+                Some(spec::Data::BlurRegions(ref v)) => {
+                    if v.regions.is_empty() || v.regions.len() > MAX_BLUR_REGIONS {
+                        return Err(invalid(&format!("1..={} regions", MAX_BLUR_REGIONS), "invalid region count"));
+                    }
+                    if v.regions.iter().any(|r| r.x1 >= r.x2 || r.y1 >= r.y2) {
+                        return Err(invalid("x1 < x2 and y1 < y2", "empty blur region"));
+                    }
+                    if !v.sigma.is_finite() || v.sigma < 0.0 || v.sigma > MAX_BLUR_SIGMA {
+                        return Err(invalid(&format!("sigma in 0..={}", MAX_BLUR_SIGMA), "invalid sigma"));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// 宽松地解码 base64：兼容标准字符集（+ /）和末尾的 =
+fn decode_base64(value: &str) -> Result<Vec<u8>, SpecError> {
+    let value: String = value
+        .trim()
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+    decode_config(&value, URL_SAFE_NO_PAD).map_err(|e| match e {
+        base64::DecodeError::InvalidByte(offset, _) => {
+            SpecError::new(offset, "base64url character", None, "invalid base64")
+        }
+        base64::DecodeError::InvalidLastSymbol(offset, _) => {
+            SpecError::new(offset, "valid final base64 character", None, "truncated base64")
+        }
+        base64::DecodeError::InvalidLength => {
+            SpecError::new(value.len(), "more base64 data", None, "truncated base64")
+        }
+    })
+}
+
+// 逐个字段解码 ImageSpec，这样出错时可以知道是第几个操作
+fn decode_binary(value: &str, policy: UnknownPolicy) -> Result<(ImageSpec, Vec<usize>), SpecError> {
+    use prost::encoding::{decode_key, decode_varint, skip_field, DecodeContext, WireType};
+
+    let data = decode_base64(value)?;
+    // 字节偏移换算成 base64 字符串中的偏移
+    let position = |rest: &[u8]| (data.len() - rest.len()) * 4 / 3;
+    let mut buf = &data[..];
+    // 旧的 spec 没有 version 字段，不能用 ImageSpec::new 的默认版本
+    let mut spec = ImageSpec::default();
+    let mut offsets = vec![];
+    let reject = policy == UnknownPolicy::Reject;
+    while !buf.is_empty() {
+        let start = position(buf);
+        let op = Some(spec.specs.len());
+        let malformed = |e: prost::DecodeError| SpecError::new(start, "protobuf field", op, e.to_string());
+        let (tag, wire_type) = decode_key(&mut buf).map_err(malformed)?;
+        match (tag, wire_type) {
+            (1, WireType::LengthDelimited) => {
+                let len = decode_varint(&mut buf).map_err(malformed)? as usize;
+                if len > buf.len() {
+                    return Err(SpecError::new(start, "complete spec message", op, "truncated spec"));
+                }
+                let item = Spec::decode(&buf[..len]).map_err(malformed)?;
+                // prost 会丢掉不认识的字段，重新编码后长度不同说明有不认识的操作或参数
+                // 显式写入默认值的 spec 也会被判定为不一致，reject 模式下同样拒绝
+                if reject && (item.data.is_none() || item.encoded_len() != len) {
+                    return Err(SpecError::new(start, "known op and arguments", op, "unknown op or argument"));
+                }
+                buf = &buf[len..];
+                spec.specs.push(item);
+                offsets.push(start);
+            }
+            (2, WireType::Varint) => spec.page = decode_varint(&mut buf).map_err(malformed)? as u32,
+            (3, WireType::Varint) => spec.version = decode_varint(&mut buf).map_err(malformed)? as u32,
+            _ if reject => {
+                return Err(SpecError::new(start, "spec field", None, format!("unknown field {}", tag)));
+            }
+            _ => skip_field(wire_type, tag, &mut buf, DecodeContext::default()).map_err(malformed)?,
+        }
     }
+    Ok((spec, offsets))
 }
 
 // 辅助函数，为 Filter enum 实现 to_str 方法
 impl filter::Filter {
-    pub fn to_str(&self) -> Option<&'static str> {
+    pub fn to_str(self) -> Option<&'static str> {
         match self {
             filter::Filter::Unspecified => None,
             filter::Filter::Oceanic => Some("oceanic"),
@@ -41,6 +308,41 @@ impl filter::Filter {
     }
 }
 
+// 辅助函数，SampleFilter 和文本语法中的名字互相转换，配置文件和 /capabilities 也使用这些名字
+impl resize::SampleFilter {
+    pub fn to_str(self) -> Option<&'static str> {
+        syntax::enum_name(self as i32, syntax::SAMPLE_FILTERS)
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        syntax::enum_value(name, syntax::SAMPLE_FILTERS).map(|v| Self::from_i32(v).unwrap())
+    }
+}
+
+// 辅助函数，返回色觉障碍模拟用的矩阵（Machado 2009，严重程度 1.0，作用于线性 RGB）
+impl simulate::Deficiency {
+    pub fn matrix(self) -> Option<[[f32; 3]; 3]> {
+        match self {
+            simulate::Deficiency::Unspecified => None,
+            simulate::Deficiency::Protanopia => Some([
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ]),
+            simulate::Deficiency::Deuteranopia => Some([
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ]),
+            simulate::Deficiency::Tritanopia => Some([
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ]),
+        }
+    }
+}
+
 // 在我们定义的 SampleFilter 和 photon_rs 的 SamplingFilter 间转换
 impl From<resize::SampleFilter> for SamplingFilter {
     fn from(v: resize::SampleFilter) -> Self {
@@ -55,8 +357,229 @@ impl From<resize::SampleFilter> for SamplingFilter {
     }
 }
 
+impl Lqip {
+    pub fn width(&self) -> u32 {
+        if self.width == 0 {
+            32
+        } else {
+            self.width
+        }
+    }
+
+    pub fn quality(&self) -> u8 {
+        match self.quality {
+            0 => 30,
+            q => q.min(100) as u8,
+        }
+    }
+}
+
+// 条件判断用到的源图片属性
+pub struct SourceInfo {
+    pub width: u32,
+    pub height: u32,
+    pub format: Option<SourceFormat>,
+}
+
+impl Condition {
+    pub fn matches(&self, source: &SourceInfo) -> bool {
+        let within = |v: u32, min: u32, max: u32| v >= min && (max == 0 || v <= max);
+        within(source.width, self.min_width, self.max_width)
+            && within(source.height, self.min_height, self.max_height)
+            && (self.source_formats.is_empty()
+                || source
+                    .format
+                    .is_some_and(|f| self.source_formats.iter().any(|v| *v == f.to_string())))
+            && match condition::Orientation::from_i32(self.orientation) {
+                Some(condition::Orientation::Portrait) => source.height > source.width,
+                Some(condition::Orientation::Landscape) => source.width > source.height,
+                Some(condition::Orientation::Square) => source.width == source.height,
+                _ => true,
+            }
+    }
+}
+
+impl ImageSpec {
+    // 没有指定 filter 的缩放使用 filter（服务端配置的默认值）
+    pub fn default_sample_filter(&mut self, filter: resize::SampleFilter) {
+        for s in self.specs.iter_mut() {
+            if let Some(spec::Data::Resize(ref mut v)) = s.data {
+                if v.filter == resize::SampleFilter::Undefined as i32 {
+                    v.filter = filter as i32;
+                }
+            }
+        }
+    }
+
+    // 去掉条件不满足的操作，剩下的操作交给 engine
+    pub fn resolve_conditions(&mut self, source: &SourceInfo) {
+        self.specs
+            .retain(|s| s.when.as_ref().is_none_or(|w| w.matches(source)));
+    }
+}
+
+// 除了操作名以外可以在配置中单独关闭的功能（disabled_ops），包括不经过 spec 的接口。
+// 这些接口同时受它们内部用到的操作限制，比如关闭 text 之后 contactsheet 也不能使用
+pub const GATED_FEATURES: &[&str] = &["seam_carve", "watermark_asset", "collage", "sprite", "contactsheet", "diff"];
+
+// disabled_ops 中的名字：文本语法中的操作名，或者 GATED_FEATURES 之一
+pub fn check_features(names: &[String]) -> Result<(), String> {
+    let known = |name: &str| GATED_FEATURES.contains(&name) || (syntax::OPS.contains(&name) && name != "page" && name != "version");
+    match names.iter().find(|v| !known(v)) {
+        Some(name) => Err(format!("unknown operation {}", name)),
+        None => Ok(()),
+    }
+}
+
+impl spec::Data {
+    // 操作用到的可以关闭的功能：操作名，以及 seam_carve（resize 的 type=seam_carve）或者 watermark_asset（使用上传的素材）
+    pub fn features(&self) -> impl Iterator<Item = &'static str> {
+        let extra = match self {
+            spec::Data::Resize(v) if v.rtype == resize::ResizeType::SeamCarve as i32 => Some("seam_carve"),
+            spec::Data::Watermark(v) if !v.asset.is_empty() => Some("watermark_asset"),
+            _ => None,
+        };
+        std::iter::once(self.name()).chain(extra)
+    }
+
+    // 操作名，和文本语法中的一致
+    pub fn name(&self) -> &'static str {
+        match self {
+            spec::Data::Resize(_) => "resize",
+            spec::Data::Crop(_) => "crop",
+            spec::Data::Flipv(_) => "flipv",
+            spec::Data::Fliph(_) => "fliph",
+            spec::Data::Contrast(_) => "contrast",
+            spec::Data::Filter(_) => "filter",
+            spec::Data::Watermark(_) => "watermark",
+            spec::Data::Text(_) => "text",
+            spec::Data::AutoEnhance(_) => "auto_enhance",
+            spec::Data::Lqip(_) => "lqip",
+            spec::Data::Simulate(_) => "simulate",
+            spec::Data::InvisibleWatermark(_) => "invisible_watermark",
+            spec::Data::BlurRegions(_) => "blur_regions",
+            spec::Data::ColorPop(_) => "color_pop",
+            spec::Data::Caption(_) => "caption",
+        }
+    }
+}
+
+impl BlurRegions {
+    // 默认的模糊程度足以让常见字号的文字无法辨认
+    pub fn sigma(&self) -> f32 {
+        if self.sigma > 0.0 {
+            self.sigma
+        } else {
+            12.0
+        }
+    }
+}
+
+impl ColorPop {
+    pub fn tolerance(&self) -> f32 {
+        if self.tolerance > 0.0 {
+            self.tolerance
+        } else {
+            30.0
+        }
+    }
+}
+
+impl Caption {
+    // 未设置时为白色的文字
+    pub fn rgba(&self) -> [u8; 4] {
+        match self.color {
+            0 => [255, 255, 255, 255],
+            c => c.to_be_bytes(),
+        }
+    }
+
+    // 未设置时为半透明的黑色，浅色的照片上也能看清文字
+    pub fn background_rgba(&self) -> [u8; 4] {
+        match self.background {
+            0 => [0, 0, 0, 160],
+            c => c.to_be_bytes(),
+        }
+    }
+}
+
+impl blur_regions::Region {
+    // 限制在 width x height 的图片范围内，和图片没有交集时返回 None
+    pub fn area(&self, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        let (x2, y2) = (self.x2.min(width), self.y2.min(height));
+        (self.x1 < x2 && self.y1 < y2).then_some((self.x1, self.y1, x2, y2))
+    }
+}
+
+impl Crop {
+    // 在 width x height 的图片中实际裁剪的区域 (x1, y1, x2, y2)，不会超出图片
+    pub fn area(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let gravity = match crop::Gravity::from_i32(self.gravity) {
+            None | Some(crop::Gravity::Unspecified) => {
+                let (x2, y2) = (self.x2.min(width), self.y2.min(height));
+                return (self.x1.min(x2), self.y1.min(y2), x2, y2);
+            }
+            Some(v) => v,
+        };
+        let size = |v: u32, total: u32| if v == 0 { total } else { v.min(total) };
+        let (w, h) = match (self.ratio_width, self.ratio_height) {
+            (rw, rh) if rw > 0 && rh > 0 => {
+                // 先按整个宽度计算高度，超出时改为按整个高度计算宽度
+                let h = width as u64 * rh as u64 / rw as u64;
+                if h <= height as u64 {
+                    (width, (h as u32).max(1))
+                } else {
+                    (((height as u64 * rw as u64 / rh as u64) as u32).clamp(1, width), height)
+                }
+            }
+            _ => (size(self.width, width), size(self.height, height)),
+        };
+        // 锚点在每个方向上的位置：-1 靠左（上），0 居中，1 靠右（下）
+        use crop::Gravity::*;
+        let (gx, gy) = match gravity {
+            Unspecified | Center => (0, 0),
+            North => (0, -1),
+            South => (0, 1),
+            East => (1, 0),
+            West => (-1, 0),
+            NorthEast => (1, -1),
+            NorthWest => (-1, -1),
+            SouthEast => (1, 1),
+            SouthWest => (-1, 1),
+        };
+        let place = |anchor: i32, total: u32, size: u32, offset: i32| {
+            let free = (total - size) as i64;
+            let pos = match anchor {
+                -1 => offset as i64,
+                0 => free / 2 + offset as i64,
+                _ => free - offset as i64,
+            };
+            pos.clamp(0, free) as u32
+        };
+        let x = place(gx, width, w, self.offset_x);
+        let y = place(gy, height, h, self.offset_y);
+        (x, y, x + w, y + h)
+    }
+}
+
+impl Text {
+    // 把 0xRRGGBBAA 拆成 RGBA，未设置时为白色
+    pub fn rgba(&self) -> [u8; 4] {
+        match self.color {
+            0 => [255, 255, 255, 255],
+            c => c.to_be_bytes(),
+        }
+    }
+}
+
 // 提供一些辅助函数，让创建一个 Spec 的过程简单一些
 impl Spec {
+    // 只在满足条件时执行
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.when = Some(condition);
+        self
+    }
+
     // Resize SeamCarve
     pub fn new_resize_seam_carve(width: u32, height: u32) -> Self {
         Self {
@@ -66,6 +589,7 @@ impl Spec {
                 rtype: resize::ResizeType::SeamCarve as i32,
                 filter: resize::SampleFilter::Undefined as i32,
             })),
+            when: None,
         }
     }
 
@@ -78,6 +602,49 @@ impl Spec {
                 rtype: resize::ResizeType::Normal as i32,
                 filter: filter as i32,
             })),
+            when: None,
+        }
+    }
+
+    // Crop
+    pub fn new_crop(x1: u32, y1: u32, x2: u32, y2: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Crop(Crop {
+                x1,
+                y1,
+                x2,
+                y2,
+                ..Default::default()
+            })),
+            when: None,
+        }
+    }
+
+    // 按锚点裁剪出 width x height，比如保留图片的上半部分
+    pub fn new_crop_gravity(gravity: crop::Gravity, width: u32, height: u32, offset_x: i32, offset_y: i32) -> Self {
+        Self {
+            data: Some(spec::Data::Crop(Crop {
+                gravity: gravity as i32,
+                width,
+                height,
+                offset_x,
+                offset_y,
+                ..Default::default()
+            })),
+            when: None,
+        }
+    }
+
+    // 按锚点裁剪出 ratio_width:ratio_height 的最大区域
+    pub fn new_crop_ratio(gravity: crop::Gravity, ratio_width: u32, ratio_height: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Crop(Crop {
+                gravity: gravity as i32,
+                ratio_width,
+                ratio_height,
+                ..Default::default()
+            })),
+            when: None,
         }
     }
 
@@ -87,6 +654,95 @@ impl Spec {
             data: Some(spec::Data::Filter(Filter {
                 filter: filter as i32,
             })),
+            when: None,
+        }
+    }
+
+    // Text
+    pub fn new_text(text: &str, x: u32, y: u32, size: f32, color: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Text(Text {
+                text: text.to_owned(),
+                x,
+                y,
+                size,
+                color,
+                ..Default::default()
+            })),
+            when: None,
+        }
+    }
+
+    // AutoEnhance
+    pub fn new_auto_enhance() -> Self {
+        Self {
+            data: Some(spec::Data::AutoEnhance(AutoEnhance {})),
+            when: None,
+        }
+    }
+
+    // Simulate
+    pub fn new_simulate(deficiency: simulate::Deficiency) -> Self {
+        Self {
+            data: Some(spec::Data::Simulate(Simulate {
+                deficiency: deficiency as i32,
+            })),
+            when: None,
+        }
+    }
+
+    // Lqip
+    pub fn new_lqip(width: u32, blur: bool) -> Self {
+        Self {
+            data: Some(spec::Data::Lqip(Lqip {
+                width,
+                blur,
+                quality: 0,
+            })),
+            when: None,
+        }
+    }
+
+    // InvisibleWatermark
+    pub fn new_invisible_watermark(id: u32) -> Self {
+        Self {
+            data: Some(spec::Data::InvisibleWatermark(InvisibleWatermark {
+                id,
+                strength: 0.0,
+            })),
+            when: None,
+        }
+    }
+
+    // ColorPop
+    pub fn new_color_pop(hue: f32, tolerance: f32) -> Self {
+        Self {
+            data: Some(spec::Data::ColorPop(ColorPop { hue, tolerance })),
+            when: None,
+        }
+    }
+
+    // Caption，在底部加一条文字
+    pub fn new_caption(text: &str, size: f32) -> Self {
+        Self {
+            data: Some(spec::Data::Caption(Caption {
+                text: text.to_owned(),
+                size,
+                ..Default::default()
+            })),
+            when: None,
+        }
+    }
+
+    // BlurRegions，regions 是 (x1, y1, x2, y2)
+    pub fn new_blur_regions(regions: &[(u32, u32, u32, u32)], sigma: f32) -> Self {
+        let regions = regions
+            .iter()
+            .map(|&(x1, y1, x2, y2)| blur_regions::Region { x1, y1, x2, y2 })
+            .collect();
+        Self {
+            data: Some(spec::Data::BlurRegions(BlurRegions { regions, sigma })),
+            when: None,
         }
     }
 
@@ -95,14 +751,43 @@ impl Spec {
         Self {
             data: Some(spec::Data::Watermark(Watermark {
                 x,
-                y
+                y,
+                ..Default::default()
             })),
+            when: None,
+        }
+    }
+
+    // 使用上传的素材作为水印
+    pub fn new_watermark_asset(asset: &str, x: u32, y: u32) -> Self {
+        Self {
+            data: Some(spec::Data::Watermark(Watermark {
+                x,
+                y,
+                asset: asset.to_owned(),
+                ..Default::default()
+            })),
+            when: None,
+        }
+    }
+
+    // Watermark Tiled
+    pub fn new_watermark_tiled(spacing: u32, angle: f32, opacity: f32) -> Self {
+        Self {
+            data: Some(spec::Data::Watermark(Watermark {
+                mode: watermark::Mode::Tiled as i32,
+                spacing,
+                angle,
+                opacity,
+                ..Default::default()
+            })),
+            when: None,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::borrow::Borrow;
     use std::convert::TryInto;
@@ -115,4 +800,203 @@ mod tests {
         let s: String = image_spec.borrow().into();
         assert_eq!(image_spec, s.as_str().try_into().unwrap());
     }
+
+    #[test]
+    fn client_specs_should_be_decoded() {
+        use shanbor_client::{encode_spec, SpecBuilder};
+        assert_eq!(shanbor_client::SPEC_VERSION, SPEC_VERSION);
+        let spec = SpecBuilder::new().resize(600, 600).watermark(10, 20).text("hi", 1, 2).page(1).build();
+        let expected = ImageSpec::new(vec![
+            Spec::new_resize(600, 600, resize::SampleFilter::Undefined),
+            Spec::new_watermark(10, 20),
+            Spec::new_text("hi", 1, 2, 0.0, 0),
+        ])
+        .with_page(1);
+        assert_eq!(ImageSpec::parse(&encode_spec(&spec)).unwrap(), expected);
+    }
+
+    // 简单的伪随机数，保证每次运行的输入一致
+    pub(crate) fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn malformed_specs_should_not_panic() {
+        let mut rng = 0x2545_F491_4F6C_DD1D;
+        let valid: String = ImageSpec::new(vec![
+            Spec::new_resize(100, 80, resize::SampleFilter::Nearest),
+            Spec::new_text("hi", 1, 2, 12.0, 0),
+            Spec::new_watermark_tiled(8, 30.0, 0.5),
+        ])
+        .borrow()
+        .into();
+        let alphabet = b"abcdefghijklmnopqrstuvwxyz_0123456789,;:=\" \\-#.";
+        for _ in 0..20000 {
+            // 随机字节、随机修改过的合法 spec，以及随机的文本语法
+            let len = (xorshift(&mut rng) % 40) as usize;
+            let bytes: Vec<u8> = (0..len).map(|_| xorshift(&mut rng) as u8).collect();
+            let _ = ImageSpec::parse(&encode_config(&bytes, URL_SAFE_NO_PAD));
+
+            let mut data = decode_config(&valid, URL_SAFE_NO_PAD).unwrap();
+            let i = xorshift(&mut rng) as usize % data.len();
+            data[i] = xorshift(&mut rng) as u8;
+            data.truncate(data.len() - (xorshift(&mut rng) % 3) as usize);
+            let _ = ImageSpec::parse(&encode_config(&data, URL_SAFE_NO_PAD));
+
+            let text: String = (0..len)
+                .map(|_| alphabet[xorshift(&mut rng) as usize % alphabet.len()] as char)
+                .collect();
+            let _ = ImageSpec::parse(&text);
+            let _ = ImageSpec::parse(&format!("resize:{}", text));
+        }
+    }
+
+    #[test]
+    fn unknown_fields_should_follow_policy() {
+        let mut data = ImageSpec::new(vec![Spec::new_filter(filter::Filter::Marine)]).encode_to_vec();
+        // 字段 9：新版本中才有的 ImageSpec 字段
+        data.extend_from_slice(&[0x48, 0x01]);
+        let s = encode_config(&data, URL_SAFE_NO_PAD);
+        assert_eq!(ImageSpec::parse(&s).unwrap().specs.len(), 1);
+        let err = ImageSpec::parse_with(&s, UnknownPolicy::Reject).unwrap_err();
+        assert_eq!(err.op, None);
+
+        // Spec 中不认识的操作（oneof 字段 30），ignore 时交给 engine 跳过
+        let mut data = ImageSpec::new(vec![Spec::new_filter(filter::Filter::Marine)]).encode_to_vec();
+        data.extend_from_slice(&[0x0a, 0x03, 0xf2, 0x01, 0x00]);
+        let s = encode_config(&data, URL_SAFE_NO_PAD);
+        let spec = ImageSpec::parse(&s).unwrap();
+        assert!(spec.specs[1].data.is_none());
+        assert_eq!(ImageSpec::parse_with(&s, UnknownPolicy::Reject).unwrap_err().op, Some(1));
+
+        // 已知操作中的未知参数
+        let mut op = Spec::new_crop(0, 0, 10, 10).encode_to_vec();
+        let crop_len = op[1];
+        op[1] = crop_len + 2;
+        op.extend_from_slice(&[0x78, 0x05]);
+        let mut data = vec![0x0a, op.len() as u8];
+        data.extend_from_slice(&op);
+        let s = encode_config(&data, URL_SAFE_NO_PAD);
+        assert!(ImageSpec::parse(&s).is_ok());
+        assert!(ImageSpec::parse_with(&s, UnknownPolicy::Reject).is_err());
+    }
+
+    #[test]
+    fn spec_version_should_be_checked() {
+        let spec = ImageSpec::new(vec![Spec::new_filter(filter::Filter::Marine)]);
+        assert_eq!(spec.version, SPEC_VERSION);
+        let s: String = spec.borrow().into();
+        assert_eq!(ImageSpec::parse(&s).unwrap(), spec);
+
+        let newer = ImageSpec::new(vec![]);
+        let newer = ImageSpec { version: SPEC_VERSION + 1, ..newer };
+        let s: String = newer.borrow().into();
+        assert!(ImageSpec::parse(&s).is_err());
+        assert!(ImageSpec::parse(&format!("filter:marine;version:{}", SPEC_VERSION + 1)).is_err());
+        assert_eq!(ImageSpec::parse("filter:marine;version:1").unwrap().version, 1);
+    }
+
+    #[test]
+    fn conditions_should_match_source() {
+        let source = SourceInfo {
+            width: 1600,
+            height: 900,
+            format: Some(SourceFormat::Jpeg),
+        };
+        let wide = Condition {
+            min_width: 1201,
+            ..Default::default()
+        };
+        let small = Condition {
+            max_width: 299,
+            ..Default::default()
+        };
+        let png = Condition {
+            source_formats: vec!["png".to_owned()],
+            ..Default::default()
+        };
+        let mut spec = ImageSpec::new(vec![
+            Spec::new_resize(1200, 675, resize::SampleFilter::Lanczos3).when(wide),
+            Spec::new_watermark(10, 10).when(small),
+            Spec::new_filter(filter::Filter::Marine).when(png),
+            Spec::new_auto_enhance(),
+        ]);
+        spec.resolve_conditions(&source);
+        assert_eq!(spec.specs.len(), 2);
+        assert!(matches!(spec.specs[0].data, Some(spec::Data::Resize(_))));
+        assert!(matches!(spec.specs[1].data, Some(spec::Data::AutoEnhance(_))));
+    }
+
+    #[test]
+    fn orientation_should_select_crop_ratio() {
+        let spec = ImageSpec::parse("crop:ratio=4:5,when_orientation=portrait;crop:ratio=16:9,when_orientation=landscape")
+            .unwrap();
+        let area = |width, height| {
+            let mut spec = spec.clone();
+            spec.resolve_conditions(&SourceInfo { width, height, format: None });
+            match spec.specs[..] {
+                [Spec { data: Some(spec::Data::Crop(ref v)), .. }] => Some(v.area(width, height)),
+                _ => None,
+            }
+        };
+        assert_eq!(area(1000, 2000), Some((0, 375, 1000, 1625)));
+        assert_eq!(area(1600, 1000), Some((0, 50, 1600, 950)));
+        assert_eq!(area(3000, 1000), Some((611, 0, 2388, 1000)));
+        assert_eq!(area(500, 500), None);
+        assert!(ImageSpec::parse("crop:ratio=4").is_err());
+    }
+
+    #[test]
+    fn gravity_crop_should_be_anchored() {
+        use crop::Gravity::*;
+        let area = |gravity, w, h, dx, dy| match Spec::new_crop_gravity(gravity, w, h, dx, dy).data {
+            Some(spec::Data::Crop(v)) => v.area(100, 80),
+            _ => unreachable!(),
+        };
+        assert_eq!(area(North, 0, 30, 0, 0), (0, 0, 100, 30));
+        assert_eq!(area(Center, 50, 40, 0, 0), (25, 20, 75, 60));
+        assert_eq!(area(Center, 50, 40, 5, -5), (30, 15, 80, 55));
+        assert_eq!(area(SouthEast, 20, 10, 2, 3), (78, 67, 98, 77));
+        assert_eq!(area(West, 20, 10, -5, 0), (0, 35, 20, 45));
+        // 超出图片时限制在图片范围内
+        assert_eq!(area(SouthWest, 500, 500, 0, 0), (0, 0, 100, 80));
+        assert_eq!(area(East, 20, 10, 1000, 0), (0, 35, 20, 45));
+    }
+
+    #[test]
+    fn default_sample_filter_should_fill_unset_filters() {
+        use resize::{SampleFilter, SampleFilter::*};
+        for filter in [Nearest, Triangle, CatmullRom, Gaussian, Lanczos3] {
+            let name = filter.to_str().unwrap();
+            assert_eq!(SampleFilter::from_name(name), Ok(filter));
+            let spec = ImageSpec::parse(&format!("resize:w=10,h=10,filter={}", name)).unwrap();
+            assert_eq!(spec, ImageSpec::new(vec![Spec::new_resize(10, 10, filter)]));
+        }
+        assert_eq!(Undefined.to_str(), None);
+        assert!(SampleFilter::from_name("undefined").is_err());
+
+        // 链式的缩放中只有没有指定 filter 的使用默认值
+        let mut spec = ImageSpec::parse("resize:w=100,h=100;resize:w=10,h=10,filter=nearest").unwrap();
+        spec.default_sample_filter(Lanczos3);
+        let expected = ImageSpec::new(vec![Spec::new_resize(100, 100, Lanczos3), Spec::new_resize(10, 10, Nearest)]);
+        assert_eq!(spec, expected);
+    }
+
+    #[test]
+    fn binary_spec_errors_should_have_op_index() {
+        let mut data = ImageSpec::new(vec![Spec::new_filter(filter::Filter::Marine)]).encode_to_vec();
+        // 第二个操作声明了 127 字节，但数据被截断
+        data.extend_from_slice(&[0x0a, 0x7f, 0x00]);
+        let err = ImageSpec::parse(&encode_config(&data, URL_SAFE_NO_PAD)).unwrap_err();
+        assert_eq!(err.op, Some(1));
+
+        let err = ImageSpec::parse("CgA*").unwrap_err();
+        assert_eq!((err.offset, err.op), (3, None));
+
+        let err = ImageSpec::parse("resize:w=0,h=10").unwrap_err();
+        assert_eq!((err.offset, err.op), (0, Some(0)));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,650 @@
+// 文本形式的 spec，方便手写和调试，比如：
+//   resize:w=200,h=100,filter=lanczos3;filter:oceanic;text:"hello, world",x=10,y=20
+// 操作之间用 ; 分隔，参数之间用 , 分隔。部分操作有一个主参数，可以省略 key 放在第一个，
+// 比如 filter:oceanic、contrast:20、lqip:32。包含 , 或 ; 的值用双引号括起来，\ 用于转义
+// 所有操作都可以带 when_* 条件，比如 resize:w=1200,h=800,when_min_width=1201
+// 或者 watermark:x=10,y=10,when_min_width=300,when_source=jpeg|png
+use super::*;
+use serde::Serialize;
+
+// 解析 spec 失败时的详细信息，会原样返回给客户端
+#[derive(Debug, Clone, PartialEq, Serialize, thiserror::Error)]
+#[error("{message} at offset {offset}")]
+pub struct SpecError {
+    // 出错的位置，spec 字符串中的字节偏移
+    pub offset: usize,
+    // 期望的内容
+    pub expected: String,
+    // 出错的是第几个操作（从 0 开始）
+    pub op: Option<usize>,
+    pub message: String,
+}
+
+impl SpecError {
+    pub fn new(offset: usize, expected: impl Into<String>, op: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            offset,
+            expected: expected.into(),
+            op,
+            message: message.into(),
+        }
+    }
+}
+
+// 所有操作的名字，另外 page 用来选择多页图片的页码，version 指定 spec 协议版本
+pub const OPS: &[&str] = &[
+    "resize",
+    "crop",
+    "flipv",
+    "fliph",
+    "contrast",
+    "filter",
+    "watermark",
+    "text",
+    "auto_enhance",
+    "lqip",
+    "simulate",
+    "invisible_watermark",
+    "blur_regions",
+    "color_pop",
+    "caption",
+    "page",
+    "version",
+];
+
+// base64url 的字符集中没有 : ; = , 和空格，出现这些字符，或者整个字符串就是一个操作名时，按文本语法解析
+// 末尾的 = 是旧 URL 中 base64 的 padding，不算文本语法
+pub fn is_text(value: &str) -> bool {
+    let value = value.trim_end_matches('=');
+    value.contains([':', ';', '=', ',', ' ', '"']) || OPS.contains(&value)
+}
+
+struct Arg {
+    key: Option<String>,
+    value: String,
+    // 参数（包括 key）的起始位置
+    start: usize,
+    // 值的起始位置
+    offset: usize,
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+    op: usize,
+}
+
+// 解析文本语法，返回 ImageSpec 以及每个操作的起始位置
+pub fn parse(s: &str) -> Result<(ImageSpec, Vec<usize>), SpecError> {
+    let mut p = Parser { s, pos: 0, op: 0 };
+    let mut spec = ImageSpec::new(vec![]);
+    let mut offsets = vec![];
+    loop {
+        p.skip_ws();
+        if p.peek().is_none() {
+            break;
+        }
+        let start = p.pos;
+        let name = p.ident("op name")?;
+        let args = p.args()?;
+        if name == "page" {
+            let mut args = Args::new(&name, args);
+            spec.page = args.get(&["page"], true, &p, parse_u32)?.unwrap_or(0);
+            args.finish(&p)?;
+        } else if name == "version" {
+            let mut args = Args::new(&name, args);
+            spec.version = args.get(&["version"], true, &p, parse_u32)?.unwrap_or(0);
+            args.finish(&p)?;
+        } else {
+            spec.specs.push(p.build(&name, start, args)?);
+            offsets.push(start);
+            p.op += 1;
+        }
+        p.skip_ws();
+        match p.peek() {
+            Some(';') => p.pos += 1,
+            None => break,
+            Some(_) => return Err(p.error("';' or end of spec", "unexpected character")),
+        }
+    }
+    Ok((spec, offsets))
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn error(&self, expected: &str, message: &str) -> SpecError {
+        SpecError::new(self.pos, expected, Some(self.op), message)
+    }
+
+    fn ident(&mut self, expected: &str) -> Result<String, SpecError> {
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            self.pos += c.len_utf8();
+        }
+        if self.pos == start {
+            return Err(self.error(expected, "missing name"));
+        }
+        Ok(self.s[start..self.pos].to_ascii_lowercase())
+    }
+
+    // 值：双引号括起来的字符串，或者到下一个 , ; 为止的内容
+    fn value(&mut self) -> Result<String, SpecError> {
+        if self.peek() != Some('"') {
+            let start = self.pos;
+            while let Some(c) = self.peek().filter(|c| *c != ',' && *c != ';') {
+                self.pos += c.len_utf8();
+            }
+            return Ok(self.s[start..self.pos].trim().to_owned());
+        }
+        self.pos += 1;
+        let mut value = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("'\"'", "unterminated string")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    let c = self.peek().ok_or_else(|| self.error("escaped character", "unterminated escape"))?;
+                    value.push(c);
+                    self.pos += c.len_utf8();
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+    }
+
+    // 参数列表：[':' arg (',' arg)*]，arg 是 key=value 或者省略 key 的主参数
+    fn args(&mut self) -> Result<Vec<Arg>, SpecError> {
+        let mut args = vec![];
+        self.skip_ws();
+        if self.peek() != Some(':') {
+            return Ok(args);
+        }
+        self.pos += 1;
+        loop {
+            self.skip_ws();
+            let offset = self.pos;
+            // 先尝试 key=value，不是的话退回来按主参数处理
+            let key = match self.ident("key") {
+                Ok(key) => {
+                    self.skip_ws();
+                    if self.peek() == Some('=') {
+                        self.pos += 1;
+                        self.skip_ws();
+                        Some(key)
+                    } else {
+                        self.pos = offset;
+                        None
+                    }
+                }
+                Err(_) => None,
+            };
+            let value_offset = self.pos;
+            let value = self.value()?;
+            args.push(Arg {
+                key,
+                value,
+                start: offset,
+                offset: value_offset,
+            });
+            self.skip_ws();
+            if self.peek() != Some(',') {
+                return Ok(args);
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn build(&self, name: &str, start: usize, args: Vec<Arg>) -> Result<Spec, SpecError> {
+        let mut a = Args::new(name, args);
+        let mut spec = match name {
+            "resize" => {
+                let width = a.get(&["w", "width"], false, self, parse_u32)?.unwrap_or(0);
+                let height = a.get(&["h", "height"], false, self, parse_u32)?.unwrap_or(0);
+                let filter = a
+                    .get(&["filter"], false, self, |v| enum_value(v, SAMPLE_FILTERS))?
+                    .unwrap_or(resize::SampleFilter::Undefined as i32);
+                let rtype = a
+                    .get(&["type"], false, self, |v| enum_value(v, RESIZE_TYPES))?
+                    .unwrap_or(resize::ResizeType::Normal as i32);
+                Spec {
+                    data: Some(spec::Data::Resize(Resize { width, height, rtype, filter })),
+                    when: None,
+                }
+            }
+            // crop:x1=0,y1=0,x2=100,y2=100、crop:north,h=300 或者 crop:ratio=16:9（默认居中）
+            "crop" => {
+                let gravity = a.get(&["gravity"], true, self, |v| enum_value(v, GRAVITIES))?;
+                let ratio = a.get(&["ratio"], false, self, parse_ratio)?;
+                match (gravity, ratio) {
+                    (gravity, Some((rw, rh))) => {
+                        let gravity = gravity.and_then(crop::Gravity::from_i32).unwrap_or(crop::Gravity::Center);
+                        let mut spec = Spec::new_crop_ratio(gravity, rw, rh);
+                        if let Some(spec::Data::Crop(ref mut v)) = spec.data {
+                            v.offset_x = a.get(&["dx", "offset_x"], false, self, parse_i32)?.unwrap_or(0);
+                            v.offset_y = a.get(&["dy", "offset_y"], false, self, parse_i32)?.unwrap_or(0);
+                        }
+                        spec
+                    }
+                    (Some(gravity), None) => Spec::new_crop_gravity(
+                        crop::Gravity::from_i32(gravity).unwrap(),
+                        a.get(&["w", "width"], false, self, parse_u32)?.unwrap_or(0),
+                        a.get(&["h", "height"], false, self, parse_u32)?.unwrap_or(0),
+                        a.get(&["dx", "offset_x"], false, self, parse_i32)?.unwrap_or(0),
+                        a.get(&["dy", "offset_y"], false, self, parse_i32)?.unwrap_or(0),
+                    ),
+                    (None, None) => {
+                        let x1 = a.get(&["x1"], false, self, parse_u32)?.unwrap_or(0);
+                        let y1 = a.get(&["y1"], false, self, parse_u32)?.unwrap_or(0);
+                        let x2 = a.get(&["x2"], false, self, parse_u32)?.unwrap_or(0);
+                        let y2 = a.get(&["y2"], false, self, parse_u32)?.unwrap_or(0);
+                        Spec::new_crop(x1, y1, x2, y2)
+                    }
+                }
+            }
+            "flipv" => Spec {
+                data: Some(spec::Data::Flipv(Flipv {})),
+                when: None,
+            },
+            "fliph" => Spec {
+                data: Some(spec::Data::Fliph(Fliph {})),
+                when: None,
+            },
+            "contrast" => {
+                let contrast = a.get(&["contrast", "value"], true, self, parse_f32)?.unwrap_or(0.0);
+                Spec {
+                    data: Some(spec::Data::Contrast(Contrast { contrast })),
+                    when: None,
+                }
+            }
+            "filter" => {
+                let filter = a
+                    .get(&["filter", "name"], true, self, |v| enum_value(v, FILTERS))?
+                    .unwrap_or(filter::Filter::Unspecified as i32);
+                Spec {
+                    data: Some(spec::Data::Filter(Filter { filter })),
+                    when: None,
+                }
+            }
+            "watermark" => {
+                let mut w = Watermark {
+                    x: a.get(&["x"], false, self, parse_u32)?.unwrap_or(0),
+                    y: a.get(&["y"], false, self, parse_u32)?.unwrap_or(0),
+                    ..Default::default()
+                };
+                w.mode = a
+                    .get(&["mode"], false, self, |v| enum_value(v, WATERMARK_MODES))?
+                    .unwrap_or(watermark::Mode::Single as i32);
+                w.spacing = a.get(&["spacing"], false, self, parse_u32)?.unwrap_or(0);
+                w.angle = a.get(&["angle"], false, self, parse_f32)?.unwrap_or(0.0);
+                w.opacity = a.get(&["opacity"], false, self, parse_f32)?.unwrap_or(0.0);
+                w.asset = a.get(&["asset"], false, self, |v| Ok(v.to_owned()))?.unwrap_or_default();
+                w.scale = a.get(&["scale"], false, self, parse_f32)?.unwrap_or(0.0);
+                w.min_width = a.get(&["min_width"], false, self, parse_u32)?.unwrap_or(0);
+                Spec {
+                    data: Some(spec::Data::Watermark(w)),
+                    when: None,
+                }
+            }
+            "text" => {
+                let text = a.get(&["text"], true, self, |v| Ok(v.to_owned()))?.unwrap_or_default();
+                let x = a.get(&["x"], false, self, parse_u32)?.unwrap_or(0);
+                let y = a.get(&["y"], false, self, parse_u32)?.unwrap_or(0);
+                let size = a.get(&["size"], false, self, parse_f32)?.unwrap_or(0.0);
+                let color = a.get(&["color"], false, self, parse_color)?.unwrap_or(0);
+                let font = a.get(&["font"], false, self, |v| Ok(v.to_owned()))?.unwrap_or_default();
+                Spec {
+                    data: Some(spec::Data::Text(Text { text, x, y, size, color, font })),
+                    when: None,
+                }
+            }
+            "auto_enhance" => Spec::new_auto_enhance(),
+            "lqip" => {
+                let width = a.get(&["width", "w"], true, self, parse_u32)?.unwrap_or(0);
+                let blur = a.get(&["blur"], false, self, parse_bool)?.unwrap_or(false);
+                let quality = a.get(&["quality", "q"], false, self, parse_u32)?.unwrap_or(0);
+                Spec {
+                    data: Some(spec::Data::Lqip(Lqip { width, blur, quality })),
+                    when: None,
+                }
+            }
+            "simulate" => {
+                let deficiency = a
+                    .get(&["deficiency"], true, self, |v| enum_value(v, DEFICIENCIES))?
+                    .unwrap_or(simulate::Deficiency::Unspecified as i32);
+                Spec {
+                    data: Some(spec::Data::Simulate(Simulate { deficiency })),
+                    when: None,
+                }
+            }
+            "invisible_watermark" => {
+                let id = a.get(&["id"], true, self, parse_u32)?.unwrap_or(0);
+                let strength = a.get(&["strength"], false, self, parse_f32)?.unwrap_or(0.0);
+                Spec {
+                    data: Some(spec::Data::InvisibleWatermark(InvisibleWatermark { id, strength })),
+                    when: None,
+                }
+            }
+            // caption:"{exif_date} {exif_camera}",size=14,position=top
+            "caption" => {
+                let text = a.get(&["text"], true, self, |v| Ok(v.to_owned()))?.unwrap_or_default();
+                let size = a.get(&["size"], false, self, parse_f32)?.unwrap_or(0.0);
+                let color = a.get(&["color"], false, self, parse_color)?.unwrap_or(0);
+                let background = a.get(&["background", "bg"], false, self, parse_color)?.unwrap_or(0);
+                let position = a
+                    .get(&["position"], false, self, |v| enum_value(v, CAPTION_POSITIONS))?
+                    .unwrap_or(caption::Position::Bottom as i32);
+                let font = a.get(&["font"], false, self, |v| Ok(v.to_owned()))?.unwrap_or_default();
+                Spec {
+                    data: Some(spec::Data::Caption(Caption { text, size, color, background, position, font })),
+                    when: None,
+                }
+            }
+            // blur_regions:0:0:200:40|0:560:800:600,sigma=8，每个区域是 x1:y1:x2:y2
+            "blur_regions" => {
+                let regions = a.get(&["regions"], true, self, parse_regions)?.unwrap_or_default();
+                let sigma = a.get(&["sigma"], false, self, parse_f32)?.unwrap_or(0.0);
+                Spec {
+                    data: Some(spec::Data::BlurRegions(BlurRegions { regions, sigma })),
+                    when: None,
+                }
+            }
+            "color_pop" => {
+                let hue = a.get(&["hue"], true, self, parse_f32)?.unwrap_or(0.0);
+                let tolerance = a.get(&["tolerance"], false, self, parse_f32)?.unwrap_or(0.0);
+                Spec::new_color_pop(hue, tolerance)
+            }
+            _ => {
+                return Err(SpecError::new(
+                    start,
+                    format!("one of {}", OPS.join(", ")),
+                    Some(self.op),
+                    format!("unknown op {}", name),
+                ))
+            }
+        };
+        spec.when = self.condition(&mut a)?;
+        a.finish(self)?;
+        Ok(spec)
+    }
+
+    // 所有操作共用的条件参数，一个都没有时返回 None
+    fn condition(&self, a: &mut Args) -> Result<Option<Condition>, SpecError> {
+        let known = a.known.len();
+        let condition = Condition {
+            min_width: a.get(&["when_min_width"], false, self, parse_u32)?.unwrap_or(0),
+            max_width: a.get(&["when_max_width"], false, self, parse_u32)?.unwrap_or(0),
+            min_height: a.get(&["when_min_height"], false, self, parse_u32)?.unwrap_or(0),
+            max_height: a.get(&["when_max_height"], false, self, parse_u32)?.unwrap_or(0),
+            source_formats: a
+                .get(&["when_source"], false, self, |v| {
+                    Ok(v.split('|').map(|f| f.trim().to_ascii_lowercase()).collect())
+                })?
+                .unwrap_or_default(),
+            orientation: a
+                .get(&["when_orientation"], false, self, |v| enum_value(v, ORIENTATIONS))?
+                .unwrap_or(condition::Orientation::Any as i32),
+        };
+        // 出错时的提示信息里合并成一项
+        a.known.truncate(known);
+        a.known.push("when_*");
+        Ok(if condition == Condition::default() { None } else { Some(condition) })
+    }
+}
+
+// 一个操作的参数，取出所有认识的 key 之后，剩下的就是不认识的
+struct Args {
+    op_name: String,
+    args: Vec<Option<Arg>>,
+    known: Vec<&'static str>,
+}
+
+impl Args {
+    fn new(op_name: &str, args: Vec<Arg>) -> Self {
+        Self {
+            op_name: op_name.to_owned(),
+            args: args.into_iter().map(Some).collect(),
+            known: vec![],
+        }
+    }
+
+    // main 表示这是主参数，第一个参数可以省略 key
+    fn get<T>(
+        &mut self,
+        keys: &[&'static str],
+        main: bool,
+        p: &Parser,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, SpecError> {
+        self.known.extend_from_slice(keys);
+        let index = self.args.iter().enumerate().position(|(i, a)| match a {
+            Some(Arg { key: Some(k), .. }) => keys.contains(&k.as_str()),
+            Some(Arg { key: None, .. }) => main && i == 0,
+            None => false,
+        });
+        let arg = match index {
+            Some(i) => self.args[i].take().unwrap(),
+            None => return Ok(None),
+        };
+        parse(&arg.value)
+            .map(Some)
+            .map_err(|expected| SpecError::new(arg.offset, expected, Some(p.op), format!("invalid value for {}", keys[0])))
+    }
+
+    fn finish(self, p: &Parser) -> Result<(), SpecError> {
+        match self.args.into_iter().flatten().next() {
+            None => Ok(()),
+            Some(arg) => {
+                let expected = if self.known.is_empty() {
+                    "no arguments".to_owned()
+                } else {
+                    format!("one of {}", self.known.join(", "))
+                };
+                let message = match arg.key {
+                    Some(k) => format!("unknown argument {} for {}", k, self.op_name),
+                    None => format!("unexpected value for {}", self.op_name),
+                };
+                Err(SpecError::new(arg.start, expected, Some(p.op), message))
+            }
+        }
+    }
+}
+
+pub(super) const SAMPLE_FILTERS: &[(&str, i32)] = &[
+    ("nearest", resize::SampleFilter::Nearest as i32),
+    ("triangle", resize::SampleFilter::Triangle as i32),
+    ("catmull_rom", resize::SampleFilter::CatmullRom as i32),
+    ("gaussian", resize::SampleFilter::Gaussian as i32),
+    ("lanczos3", resize::SampleFilter::Lanczos3 as i32),
+];
+pub(super) const RESIZE_TYPES: &[(&str, i32)] = &[
+    ("normal", resize::ResizeType::Normal as i32),
+    ("seam_carve", resize::ResizeType::SeamCarve as i32),
+];
+pub(super) const GRAVITIES: &[(&str, i32)] = &[
+    ("center", crop::Gravity::Center as i32),
+    ("north", crop::Gravity::North as i32),
+    ("south", crop::Gravity::South as i32),
+    ("east", crop::Gravity::East as i32),
+    ("west", crop::Gravity::West as i32),
+    ("north_east", crop::Gravity::NorthEast as i32),
+    ("north_west", crop::Gravity::NorthWest as i32),
+    ("south_east", crop::Gravity::SouthEast as i32),
+    ("south_west", crop::Gravity::SouthWest as i32),
+];
+pub(super) const ORIENTATIONS: &[(&str, i32)] = &[
+    ("portrait", condition::Orientation::Portrait as i32),
+    ("landscape", condition::Orientation::Landscape as i32),
+    ("square", condition::Orientation::Square as i32),
+];
+pub(super) const FILTERS: &[(&str, i32)] = &[
+    ("oceanic", filter::Filter::Oceanic as i32),
+    ("islands", filter::Filter::Islands as i32),
+    ("marine", filter::Filter::Marine as i32),
+];
+pub(super) const WATERMARK_MODES: &[(&str, i32)] = &[
+    ("single", watermark::Mode::Single as i32),
+    ("tiled", watermark::Mode::Tiled as i32),
+];
+pub(super) const CAPTION_POSITIONS: &[(&str, i32)] = &[
+    ("bottom", caption::Position::Bottom as i32),
+    ("top", caption::Position::Top as i32),
+];
+pub(super) const DEFICIENCIES: &[(&str, i32)] = &[
+    ("deuteranopia", simulate::Deficiency::Deuteranopia as i32),
+    ("protanopia", simulate::Deficiency::Protanopia as i32),
+    ("tritanopia", simulate::Deficiency::Tritanopia as i32),
+];
+
+pub(super) fn enum_value(v: &str, names: &[(&str, i32)]) -> Result<i32, String> {
+    let v = v.to_ascii_lowercase();
+    names
+        .iter()
+        .find(|(name, _)| *name == v)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| format!("one of {}", names.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")))
+}
+
+// enum_value 的反向查找，未设置（0）或者不认识的值返回 None
+pub(super) fn enum_name(value: i32, names: &[(&'static str, i32)]) -> Option<&'static str> {
+    names.iter().find(|(_, v)| *v == value).map(|(name, _)| *name)
+}
+
+fn parse_u32(v: &str) -> Result<u32, String> {
+    v.parse().map_err(|_| "unsigned integer".to_owned())
+}
+
+fn parse_i32(v: &str) -> Result<i32, String> {
+    v.parse().map_err(|_| "integer".to_owned())
+}
+
+fn parse_f32(v: &str) -> Result<f32, String> {
+    v.parse::<f32>()
+        .ok()
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| "number".to_owned())
+}
+
+// 宽高比 W:H，比如 16:9
+fn parse_ratio(v: &str) -> Result<(u32, u32), String> {
+    let mut parts = v.splitn(2, ':').map(|v| v.trim().parse::<u32>().ok().filter(|v| *v > 0));
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(w), Some(h)) => Ok((w, h)),
+        _ => Err("ratio of w:h".to_owned()),
+    }
+}
+
+fn parse_regions(v: &str) -> Result<Vec<blur_regions::Region>, String> {
+    v.split('|')
+        .map(|region| {
+            let n = region
+                .split(':')
+                .map(|v| v.trim().parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok()
+                .filter(|n| n.len() == 4)
+                .ok_or_else(|| "regions of x1:y1:x2:y2 separated by |".to_owned())?;
+            Ok(blur_regions::Region {
+                x1: n[0],
+                y1: n[1],
+                x2: n[2],
+                y2: n[3],
+            })
+        })
+        .collect()
+}
+
+fn parse_bool(v: &str) -> Result<bool, String> {
+    match v {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err("true or false".to_owned()),
+    }
+}
+
+// RRGGBB 或者 RRGGBBAA，可以带 #
+pub(super) fn parse_color(v: &str) -> Result<u32, String> {
+    let hex = v.trim_start_matches('#');
+    let value = u32::from_str_radix(hex, 16).map_err(|_| "hex color RRGGBB or RRGGBBAA".to_owned())?;
+    match hex.len() {
+        6 => Ok(value << 8 | 0xff),
+        8 => Ok(value),
+        _ => Err("hex color RRGGBB or RRGGBBAA".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gravity_crop_should_be_parsed() {
+        let (spec, _) = parse("crop:north,h=300;crop:gravity=south_east,w=10,h=20,dx=-3,dy=4").unwrap();
+        assert_eq!(spec.specs[0], Spec::new_crop_gravity(crop::Gravity::North, 0, 300, 0, 0));
+        assert_eq!(spec.specs[1], Spec::new_crop_gravity(crop::Gravity::SouthEast, 10, 20, -3, 4));
+        assert!(parse("crop:up").is_err());
+    }
+
+    #[test]
+    fn blur_regions_should_be_parsed() {
+        let (spec, _) = parse("blur_regions:0:0:200:40|10:560:800:600,sigma=8").unwrap();
+        assert_eq!(spec.specs[0], Spec::new_blur_regions(&[(0, 0, 200, 40), (10, 560, 800, 600)], 8.0));
+        let err = parse("blur_regions:0:0:200").unwrap_err();
+        assert_eq!(err.expected, "regions of x1:y1:x2:y2 separated by |");
+        assert!(ImageSpec::parse("blur_regions:0:40:200:40").is_err());
+        assert!(ImageSpec::parse("blur_regions:sigma=3").is_err());
+    }
+
+    #[test]
+    fn conditions_should_be_parsed() {
+        let (spec, _) = parse("resize:w=1200,h=800,when_min_width=1201;watermark:x=1,y=1,when_source=JPEG|png").unwrap();
+        assert_eq!(spec.specs[0].when.as_ref().unwrap().min_width, 1201);
+        assert_eq!(spec.specs[1].when.as_ref().unwrap().source_formats, vec!["jpeg", "png"]);
+        assert!(ImageSpec::parse("fliph:when_source=svg").is_err());
+        assert!(parse("fliph").unwrap().0.specs[0].when.is_none());
+    }
+
+    #[test]
+    fn text_spec_should_be_parsed() {
+        let (spec, offsets) =
+            parse(r#"resize: w=200, h=100, filter=lanczos3; filter:oceanic;text:"a, \"b\"",x=3,color=#ff0000;page:2"#)
+                .unwrap();
+        assert_eq!(spec.page, 2);
+        assert_eq!(offsets, vec![0, 39, 54]);
+        assert_eq!(spec.specs[0], Spec::new_resize(200, 100, resize::SampleFilter::Lanczos3));
+        assert_eq!(spec.specs[1], Spec::new_filter(filter::Filter::Oceanic));
+        assert_eq!(spec.specs[2], Spec::new_text("a, \"b\"", 3, 0, 0.0, 0xff0000ff));
+    }
+
+    #[test]
+    fn text_spec_errors_should_have_position() {
+        let err = parse("fliph;resize:w=10,hh=3").unwrap_err();
+        assert_eq!((err.offset, err.op), (18, Some(1)));
+        assert_eq!(err.expected, "one of w, width, h, height, filter, type, when_*");
+
+        let err = parse("filter:sepia").unwrap_err();
+        assert_eq!((err.offset, err.op), (7, Some(0)));
+        assert_eq!(err.expected, "one of oceanic, islands, marine");
+
+        let err = parse("fliph;blur:3").unwrap_err();
+        assert_eq!((err.offset, err.op), (6, Some(1)));
+
+        let err = parse("resize:w=10 x").unwrap_err();
+        assert_eq!(err.expected, "unsigned integer");
+    }
+}
@@ -0,0 +1,220 @@
+// 副本之间同步源图片缓存：新启动的副本在开始监听（加入负载均衡）之前，从已有的副本批量拉取
+// 最近使用的缓存，避免每次扩容时新副本同时回源
+use crate::{
+    cache_key::Key,
+    config::Config,
+    signing,
+    source_cache::{self, CachedSource},
+    Cache,
+};
+use axum::{
+    extract::{Extension, Query},
+    http::{HeaderMap, HeaderValue, StatusCode},
+};
+use bytes::{BufMut, Bytes};
+use serde::Deserialize;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+// 导出格式中表示一直有效的剩余时间
+const NO_EXPIRY: u64 = u64::MAX;
+
+// 缓存同步的配置（[peer_sync]），不配置则不导出也不拉取
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PeerSyncConfig {
+    // 副本之间共享的 token，访问 /peer/cache 时放在 Authorization: Bearer 中
+    pub token: String,
+    // 启动时拉取缓存的副本地址，比如 http://shanbor-0:3000（副本配置了 admin_listen 时使用它的管理地址），不配置则只导出
+    #[serde(default)]
+    pub peer: Option<String>,
+    // 最多拉取的条数，按最近使用的顺序
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    // 拉取的超时时间（秒），超时后不再等待，直接开始服务
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_max_entries() -> usize {
+    512
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    limit: Option<usize>,
+}
+
+// "GET /peer/cache?limit=N" 按最近使用的顺序导出仍然有效的缓存
+pub async fn export(
+    Query(params): Query<ExportParams>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Result<(HeaderMap, Vec<u8>), StatusCode> {
+    let sync = config.peer_sync.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    match signing::bearer(&req_headers) {
+        Some(v) if signing::token_eq(v, &sync.token) => {}
+        _ => return Err(StatusCode::UNAUTHORIZED),
+    }
+    let limit = params.limit.unwrap_or(sync.max_entries);
+    // Bytes 的 clone 只增加引用计数，锁只在复制列表时持有
+    let entries: Vec<_> = cache
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, v)| v.fresh())
+        .take(limit)
+        .map(|(k, v)| (*k, v.clone()))
+        .collect();
+    let body = encode(&entries, Instant::now());
+    info!("Exported {} cache entries, {} bytes", entries.len(), body.len());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/octet-stream"));
+    Ok((headers, body))
+}
+
+// 从 peer 拉取最多 limit 条缓存
+async fn fetch(sync: &PeerSyncConfig, peer: &str, limit: usize) -> Result<Bytes, String> {
+    let url = format!("{}/peer/cache?limit={}", peer, limit);
+    let fetch = async {
+        let resp = reqwest::Client::new()
+            .get(&url)
+            .bearer_auth(&sync.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        resp.bytes().await
+    };
+    match tokio::time::timeout(Duration::from_secs(sync.timeout), fetch).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err("timed out".to_owned()),
+    }
+}
+
+// `shanbor check` 确认 peer 可以访问并且 token 正确，只拉取 0 条缓存
+pub async fn probe(sync: &PeerSyncConfig) -> Result<String, String> {
+    let peer = match sync.peer {
+        Some(ref v) => v.trim_end_matches('/'),
+        None => return Ok("export only".to_owned()),
+    };
+    let body = fetch(sync, peer, 0).await.map_err(|e| format!("{}: {}", peer, e))?;
+    decode(body, Instant::now()).map_err(|e| format!("{}: invalid response: {}", peer, e))?;
+    Ok(format!("{} reachable", peer))
+}
+
+// 启动时从 peer 拉取缓存，失败只记录日志，不影响启动
+pub async fn warm(sync: &PeerSyncConfig, cache: &Cache) {
+    let peer = match sync.peer {
+        Some(ref v) => v.trim_end_matches('/'),
+        None => return,
+    };
+    let body = match fetch(sync, peer, sync.max_entries).await {
+        Ok(v) => v,
+        Err(e) => return warn!("Failed to warm cache from {}: {}", peer, e),
+    };
+    let entries = match decode(body, Instant::now()) {
+        Ok(v) => v,
+        Err(e) => return warn!("Invalid cache entries from {}: {}", peer, e),
+    };
+    let mut g = cache.lock().await;
+    // 从最久没有使用的开始写入，拉取后的 LRU 顺序和 peer 上一致
+    for (key, entry) in entries.iter().rev() {
+        g.put(*key, entry.clone());
+    }
+    info!("Warmed {} cache entries from {}", entries.len(), peer);
+}
+
+// 每一条：32 字节的 key，8 字节剩余的有效期（毫秒，大端序），4 字节长度，然后是数据
+fn encode(entries: &[(Key, CachedSource)], now: Instant) -> Vec<u8> {
+    let size = entries.iter().map(|(_, v)| 44 + v.data.len()).sum();
+    let mut buf = Vec::with_capacity(size);
+    for (key, entry) in entries {
+        let ttl = entry.expires.map_or(NO_EXPIRY, |v| v.saturating_duration_since(now).as_millis() as u64);
+        buf.put_slice(key);
+        buf.put_u64(ttl);
+        buf.put_u32(entry.data.len() as u32);
+        buf.put_slice(&entry.data);
+    }
+    buf
+}
+
+// 数据直接引用 body，不复制
+fn decode(body: Bytes, now: Instant) -> Result<Vec<(Key, CachedSource)>, &'static str> {
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        let header = body.get(i..i + 44).ok_or("truncated entry header")?;
+        let mut key = [0; 32];
+        key.copy_from_slice(&header[..32]);
+        let ttl = u64::from_be_bytes([
+            header[32], header[33], header[34], header[35], header[36], header[37], header[38], header[39],
+        ]);
+        let len = u32::from_be_bytes([header[40], header[41], header[42], header[43]]) as usize;
+        let start = i + 44;
+        if body.len() - start < len {
+            return Err("truncated entry data");
+        }
+        entries.push((
+            key,
+            CachedSource {
+                data: body.slice(start..start + len),
+                expires: (ttl != NO_EXPIRY)
+                    .then(|| Duration::from_millis(ttl))
+                    .and_then(|v| source_cache::expires_at(now, v)),
+            },
+        ));
+        i = start + len;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_should_survive_round_trip() {
+        let now = Instant::now();
+        let entries = vec![
+            (
+                [1; 32],
+                CachedSource {
+                    data: Bytes::from_static(b"cat"),
+                    expires: None,
+                },
+            ),
+            (
+                [2; 32],
+                CachedSource {
+                    data: Bytes::new(),
+                    expires: Some(now + Duration::from_secs(60)),
+                },
+            ),
+        ];
+        let body = Bytes::from(encode(&entries, now));
+        let decoded = decode(body.clone(), now).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!((decoded[0].0, &decoded[0].1.data[..], decoded[0].1.expires), ([1; 32], &b"cat"[..], None));
+        assert_eq!(decoded[1].1.expires, Some(now + Duration::from_secs(60)));
+
+        assert!(decode(body.slice(..body.len() - 1), now).is_err());
+        assert!(decode(body.slice(..10), now).is_err());
+        assert!(decode(Bytes::new(), now).unwrap().is_empty());
+
+        // 副本给出的有效期太长时不会溢出
+        let mut body = vec![3; 32];
+        body.extend_from_slice(&(NO_EXPIRY - 1).to_be_bytes());
+        body.extend_from_slice(&0u32.to_be_bytes());
+        let decoded = decode(Bytes::from(body), now).unwrap();
+        assert_eq!(decoded[0].1.expires, Some(now + source_cache::MAX_TTL));
+    }
+}
@@ -0,0 +1,212 @@
+// 预设的输出策略：限制允许的输出格式、JPEG 的最高质量，以及必须带水印。
+// 策略在服务端执行，请求参数（Accept、first_frame_only 等）不能绕过，比如新闻图片必须带水印
+// 策略只作用于使用预设的请求，租户需要开启 presets_only，否则请求可以直接写 spec 字符串绕过
+// 另外预设、租户和顶层配置可以设置静态图片默认的输出格式（jpeg 或 png）和质量
+// WebP 和 AVIF 暂不支持作为输出格式：image 0.23 没有 WebP 编码器，它的 AVIF 编码器（avif feature）
+// 依赖的 ravif 0.6 已经全部被 yank，无法编译。配置为默认格式时启动失败，而不是悄悄输出 JPEG
+use crate::pb::{spec, ImageSpec};
+use image::ImageOutputFormat;
+use serde::Deserialize;
+
+// 没有配置时 JPEG 的质量
+pub const DEFAULT_QUALITY: u8 = 85;
+
+// 一个预设的输出策略（租户的 policies 中按预设名配置），不配置则不限制
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputPolicy {
+    // 允许的输出格式，为空时不限制
+    pub formats: Vec<OutputKind>,
+    // JPEG 的最高质量，预设中 lqip 的质量和 client hints 都不能超过它
+    pub max_quality: Option<u8>,
+    // 必须带水印：预设（或者租户的水印）中需要有 watermark 操作，并且忽略它的条件
+    pub watermark: bool,
+    // 这个预设静态图片默认的输出格式，不配置则使用租户的 [output]
+    pub format: Option<OutputKind>,
+    // 这个预设默认的 JPEG 质量，不配置则使用租户的 [output]
+    pub quality: Option<u8>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    // 静态图片
+    Jpeg,
+    Png,
+    // 没有编码器，不能作为默认格式（见文件开头）
+    Webp,
+    Avif,
+    // 动图
+    Gif,
+    // 字符画
+    Text,
+}
+
+impl OutputKind {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            OutputKind::Jpeg => "jpeg",
+            OutputKind::Png => "png",
+            OutputKind::Webp => "webp",
+            OutputKind::Avif => "avif",
+            OutputKind::Gif => "gif",
+            OutputKind::Text => "text",
+        }
+    }
+}
+
+// 静态图片默认的输出格式和质量（[output]，租户中也可以单独配置），请求中的 lqip 操作仍然输出 JPEG
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputDefaults {
+    // jpeg（默认）或 png
+    pub format: Option<OutputKind>,
+    // JPEG 的质量，默认 85
+    pub quality: Option<u8>,
+}
+
+impl OutputDefaults {
+    // 没有设置的部分使用 fallback 中的值
+    pub fn or(self, fallback: OutputDefaults) -> OutputDefaults {
+        OutputDefaults {
+            format: self.format.or(fallback.format),
+            quality: self.quality.or(fallback.quality),
+        }
+    }
+
+    pub fn kind(&self) -> OutputKind {
+        self.format.unwrap_or(OutputKind::Jpeg)
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        match self.kind() {
+            OutputKind::Jpeg | OutputKind::Png => {}
+            OutputKind::Webp => {
+                return Err("output format webp is not supported: image 0.23 has no webp encoder".to_owned())
+            }
+            OutputKind::Avif => {
+                return Err("output format avif is not supported: the avif encoder of image 0.23 cannot be built".to_owned())
+            }
+            kind => return Err(format!("{} is not a static image format", kind.to_str())),
+        }
+        match self.quality {
+            Some(q) if q == 0 || q > 100 => Err("quality must be in 1..=100".to_owned()),
+            _ => Ok(()),
+        }
+    }
+
+    // 启动时已经检查过格式
+    pub fn image_format(&self) -> ImageOutputFormat {
+        match self.kind() {
+            OutputKind::Png => ImageOutputFormat::Png,
+            _ => ImageOutputFormat::Jpeg(self.quality.unwrap_or(DEFAULT_QUALITY)),
+        }
+    }
+}
+
+impl OutputPolicy {
+    pub fn allows(&self, kind: OutputKind) -> bool {
+        self.formats.is_empty() || self.formats.contains(&kind)
+    }
+
+    pub fn quality(&self, quality: u8) -> u8 {
+        self.max_quality.map_or(quality, |max| quality.min(max))
+    }
+
+    // 预设的默认输出，没有设置的部分使用租户的
+    pub fn output(&self, tenant: OutputDefaults) -> OutputDefaults {
+        OutputDefaults {
+            format: self.format,
+            quality: self.quality,
+        }
+        .or(tenant)
+    }
+
+    // 加载配置时检查策略和预设是否一致，spec 包括追加的租户水印
+    pub fn check(&self, spec: &ImageSpec) -> Result<(), String> {
+        if self.max_quality == Some(0) || self.max_quality > Some(100) {
+            return Err("max_quality must be in 1..=100".to_owned());
+        }
+        if self.watermark && !spec.specs.iter().any(is_watermark) {
+            return Err("watermark is required but the preset has no watermark op".to_owned());
+        }
+        Ok(())
+    }
+
+    // 默认的输出格式需要是允许的格式
+    pub fn check_output(&self, output: OutputDefaults) -> Result<(), String> {
+        output.check()?;
+        if !self.allows(output.kind()) {
+            return Err(format!("default format {} is not allowed", output.kind().to_str()));
+        }
+        Ok(())
+    }
+
+    // 在处理条件之前调用：必须带水印时去掉水印操作上的条件，小图片也不会跳过水印
+    pub fn enforce(&self, spec: &mut ImageSpec) {
+        if !self.watermark {
+            return;
+        }
+        for s in spec.specs.iter_mut().filter(|s| is_watermark(s)) {
+            s.when = None;
+        }
+    }
+}
+
+fn is_watermark(s: &crate::pb::Spec) -> bool {
+    matches!(s.data, Some(spec::Data::Watermark(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::SourceInfo;
+
+    #[test]
+    fn policy_should_keep_watermark() {
+        let policy: OutputPolicy = toml::from_str(
+            r#"
+            formats = ["jpeg"]
+            max_quality = 70
+            watermark = true
+            "#,
+        )
+        .unwrap();
+        assert!(policy.allows(OutputKind::Jpeg));
+        assert!(!policy.allows(OutputKind::Gif) && !policy.allows(OutputKind::Text));
+        assert_eq!((policy.quality(85), policy.quality(40)), (70, 40));
+        assert!(OutputPolicy::default().allows(OutputKind::Text));
+
+        assert!(policy.check(&ImageSpec::parse("resize:w=100,h=100").unwrap()).is_err());
+        let mut spec = ImageSpec::parse("resize:w=100,h=100;watermark:x=10,y=10,when_min_width=1000").unwrap();
+        assert!(policy.check(&spec).is_ok());
+        policy.enforce(&mut spec);
+        spec.resolve_conditions(&SourceInfo {
+            width: 200,
+            height: 200,
+            format: None,
+        });
+        assert_eq!(spec.specs.len(), 2);
+    }
+
+    #[test]
+    fn output_defaults_should_fall_back_to_tenant() {
+        let tenant = OutputDefaults {
+            format: Some(OutputKind::Png),
+            quality: Some(70),
+        };
+        assert_eq!(OutputPolicy::default().output(tenant).image_format(), ImageOutputFormat::Png);
+        let policy: OutputPolicy = toml::from_str(r#"format = "jpeg""#).unwrap();
+        assert_eq!(policy.output(tenant).image_format(), ImageOutputFormat::Jpeg(70));
+        assert_eq!(OutputDefaults::default().image_format(), ImageOutputFormat::Jpeg(DEFAULT_QUALITY));
+
+        assert!(policy.check_output(policy.output(tenant)).is_ok());
+        let jpeg_only: OutputPolicy = toml::from_str(r#"formats = ["jpeg"]"#).unwrap();
+        assert!(jpeg_only.check_output(tenant).is_err());
+        let webp: OutputDefaults = toml::from_str(r#"format = "webp""#).unwrap();
+        assert!(webp.check().unwrap_err().contains("no webp encoder"));
+        assert!(OutputDefaults { format: Some(OutputKind::Avif), quality: None }.check().is_err());
+        assert!(OutputDefaults { quality: Some(0), ..tenant }.check().is_err());
+        assert!(OutputDefaults { format: Some(OutputKind::Gif), quality: None }.check().is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{cache_key, pb::ImageSpec, policy::DEFAULT_QUALITY};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+// 处理结果写入对象存储的配置（[publish]），url 和 dir 二选一
+// url 用 HTTP PUT 上传，比如 GCS 的 XML API 或者 S3 兼容的网关；dir 写入本地目录，比如挂载的 bucket
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublishConfig {
+    // 对象的 URL 前缀，最终地址为 url + key
+    pub url: Option<String>,
+    // 上传时使用的 Bearer token
+    pub token: Option<String>,
+    // 本地目录
+    pub dir: Option<String>,
+}
+
+enum Target {
+    Http {
+        client: reqwest::Client,
+        url: String,
+        token: Option<String>,
+    },
+    Dir(PathBuf),
+}
+
+// 把处理结果异步写入对象存储，不影响请求的响应时间
+pub struct Publisher(Option<Target>);
+
+impl Publisher {
+    pub fn new(config: Option<&PublishConfig>) -> Result<Self> {
+        let target = match config {
+            None => None,
+            Some(PublishConfig { url: Some(_), dir: Some(_), .. }) => {
+                return Err(anyhow!("publish: url and dir are mutually exclusive"))
+            }
+            Some(PublishConfig { url: Some(url), token, .. }) => Some(Target::Http {
+                client: reqwest::Client::new(),
+                url: url.clone(),
+                token: token.clone(),
+            }),
+            Some(PublishConfig { dir: Some(dir), .. }) => Some(Target::Dir(dir.into())),
+            Some(_) => return Err(anyhow!("publish: either url or dir is required")),
+        };
+        Ok(Self(target))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    // 后台上传，失败只记录日志
+    pub fn publish(self: &std::sync::Arc<Self>, key: String, data: Bytes) {
+        if !self.enabled() {
+            return;
+        }
+        let publisher = self.clone();
+        tokio::spawn(async move {
+            match publisher.put(&key, data).await {
+                Ok(()) => info!("Published {}", key),
+                Err(e) => warn!("Failed to publish {}: {:#}", key, e),
+            }
+        });
+    }
+
+    // 写入再删除一个探测对象，确认可以写入，`shanbor check` 使用
+    pub async fn check(&self) -> Result<()> {
+        const KEY: &str = ".shanbor-check";
+        self.put(KEY, Bytes::from_static(b"ok")).await?;
+        self.remove(KEY).await
+    }
+
+    // 后台删除，对象不存在时不算失败
+    pub fn delete(self: &std::sync::Arc<Self>, key: String) {
+        if !self.enabled() {
+            return;
+        }
+        let publisher = self.clone();
+        tokio::spawn(async move {
+            match publisher.remove(&key).await {
+                Ok(()) => info!("Unpublished {}", key),
+                Err(e) => warn!("Failed to unpublish {}: {:#}", key, e),
+            }
+        });
+    }
+
+    async fn remove(&self, key: &str) -> Result<()> {
+        match self.0 {
+            Some(Target::Http { ref client, ref url, ref token }) => {
+                let mut req = client.delete(format!("{}{}", url, key));
+                if let Some(token) = token {
+                    req = req.bearer_auth(token);
+                }
+                let resp = req.send().await?;
+                if resp.status() != reqwest::StatusCode::NOT_FOUND {
+                    resp.error_for_status()?;
+                }
+            }
+            Some(Target::Dir(ref dir)) => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+            None => {}
+        }
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<()> {
+        match self.0 {
+            Some(Target::Http { ref client, ref url, ref token }) => {
+                let mut req = client
+                    .put(format!("{}{}", url, key))
+                    .header("content-type", "image/jpeg")
+                    .body(data);
+                if let Some(token) = token {
+                    req = req.bearer_auth(token);
+                }
+                req.send().await?.error_for_status()?;
+            }
+            Some(Target::Dir(ref dir)) => {
+                // 先写临时文件再改名，避免读到写了一半的对象
+                let path = dir.join(key);
+                let tmp = path.with_extension("tmp");
+                tokio::fs::create_dir_all(path.parent().unwrap_or(dir)).await?;
+                tokio::fs::write(&tmp, &data).await?;
+                tokio::fs::rename(&tmp, &path).await?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+// 对象的 key 由 spec、url、输出格式和 JPEG 的质量决定，同样的请求总是写到同一个位置
+// 多租户时以租户的命名空间作为目录；默认质量的 key 和加入质量之前相同，已经写入的对象仍然有效
+pub fn object_key(namespace: &str, spec: &ImageSpec, url: &str, ext: &str, quality: Option<u8>) -> String {
+    let format = match quality {
+        Some(q) if q != DEFAULT_QUALITY => format!("{}:q={}", ext, q),
+        _ => ext.to_owned(),
+    };
+    let name = format!("{}.{}", hex::encode(cache_key::output(namespace, url, spec, &format)), ext);
+    if namespace.is_empty() {
+        name
+    } else {
+        format!("{}/{}", namespace, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_key_should_be_deterministic() {
+        let spec = ImageSpec::new(vec![]);
+        let key = object_key("", &spec, "https://a.com/cat.png", "jpg", None);
+        assert_eq!(key, object_key("", &spec, "https://a.com/cat.png", "jpg", None));
+        assert_ne!(key, object_key("", &spec, "https://a.com/dog.png", "jpg", None));
+        assert_eq!(key.len(), 64 + 4);
+        assert_ne!(key, object_key("", &spec, "https://a.com/cat.png", "gif", None));
+        assert!(object_key("a", &spec, "https://a.com/cat.png", "jpg", None).starts_with("a/"));
+        assert_eq!(key, object_key("", &spec, "https://a.com/cat.png", "jpg", Some(DEFAULT_QUALITY)));
+        assert_ne!(key, object_key("", &spec, "https://a.com/cat.png", "jpg", Some(70)));
+    }
+}
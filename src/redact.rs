@@ -0,0 +1,88 @@
+// 日志、追踪和统计中的源图片 URL 脱敏（log_urls）：源站 URL 的 query 中可能有签名的 token，不能写入日志存储
+// 启动时按配置设置一次，之后所有输出 URL 的地方都经过 url()
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UrlRedaction {
+    // 原样输出
+    #[default]
+    Full,
+    // 只输出 URL 的 SHA-256 的前 16 个十六进制字符，同一个 URL 总是得到同样的值，可以用来关联日志
+    HashOnly,
+    // 只输出 scheme、host 和端口
+    HostOnly,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(UrlRedaction::Full as u8);
+
+pub fn init(mode: UrlRedaction) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+fn mode() -> UrlRedaction {
+    match MODE.load(Ordering::Relaxed) {
+        1 => UrlRedaction::HashOnly,
+        2 => UrlRedaction::HostOnly,
+        _ => UrlRedaction::Full,
+    }
+}
+
+// 按配置脱敏后的源图片 URL
+pub fn url(url: &str) -> Cow<'_, str> {
+    mode().apply(url)
+}
+
+// 请求路径中 percent 编码的源图片 URL，Full 时保持原样
+pub fn encoded(url: &str) -> Cow<'_, str> {
+    match mode() {
+        UrlRedaction::Full => Cow::Borrowed(url),
+        mode => Cow::Owned(mode.apply(&percent_decode_str(url).decode_utf8_lossy()).into_owned()),
+    }
+}
+
+// reqwest 的错误信息中带有完整的 URL
+pub fn fetch_error(e: reqwest::Error) -> String {
+    match mode() {
+        UrlRedaction::Full => e.to_string(),
+        _ => e.without_url().to_string(),
+    }
+}
+
+impl UrlRedaction {
+    pub fn apply(self, url: &str) -> Cow<'_, str> {
+        match self {
+            UrlRedaction::Full => Cow::Borrowed(url),
+            UrlRedaction::HashOnly => Cow::Owned(format!("sha256:{}", &hex::encode(Sha256::digest(url.as_bytes()))[..16])),
+            // 无法解析的 URL 不知道哪一部分是敏感的，不输出
+            UrlRedaction::HostOnly => Cow::Owned(match Url::parse(url) {
+                Ok(v) => v.origin().ascii_serialization(),
+                Err(_) => "<invalid url>".to_owned(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_should_be_redacted() {
+        let url = "https://cdn.a.com:8443/x/cat.png?token=secret";
+        assert_eq!(UrlRedaction::Full.apply(url), url);
+        assert_eq!(UrlRedaction::HostOnly.apply(url), "https://cdn.a.com:8443");
+        assert_eq!(UrlRedaction::HostOnly.apply("not a url?token=secret"), "<invalid url>");
+        let hash = UrlRedaction::HashOnly.apply(url);
+        assert_eq!(hash.len(), "sha256:".len() + 16);
+        assert!(!hash.contains("secret"));
+        assert_eq!(hash, UrlRedaction::HashOnly.apply(url));
+    }
+}
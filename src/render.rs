@@ -0,0 +1,39 @@
+// `shanbor render <engine> <spec> <format> <file>`：用指定的 engine 处理本地图片，编码后的结果写到标准输出
+// 方便在本地检查 spec 的效果；tests/golden.rs 也通过它比较每一种 op 在两个 engine 上的渲染结果
+use crate::{
+    engine::{self, Engine, Native, Photon},
+    pb::{ImageSpec, SourceInfo},
+};
+use anyhow::{anyhow, Result};
+use image::ImageOutputFormat;
+use std::io::Write;
+
+pub fn run(engine: &str, spec: &str, format: &str, path: &str) -> Result<()> {
+    let data = std::fs::read(path)?;
+    let mut spec = ImageSpec::parse(spec)?;
+    // 和 /image 一样按源图片的信息决定带条件的操作是否执行
+    let probe = engine::probe(&data, spec.page)?;
+    spec.resolve_conditions(&SourceInfo {
+        width: probe.width,
+        height: probe.height,
+        format: Some(probe.format),
+    });
+    let format = match format {
+        "png" => ImageOutputFormat::Png,
+        "jpeg" => ImageOutputFormat::Jpeg(85),
+        "gif" => ImageOutputFormat::Gif,
+        _ => return Err(anyhow!("unknown format {}, expected png, jpeg or gif", format)),
+    };
+    let image = match engine {
+        "photon" => render(Photon::open(&data, spec.page)?, &spec, format),
+        "native" => render(Native::open(&data, spec.page)?, &spec, format),
+        _ => return Err(anyhow!("unknown engine {}, expected photon or native", engine)),
+    };
+    std::io::stdout().write_all(&image)?;
+    Ok(())
+}
+
+fn render<E: Engine>(mut engine: E, spec: &ImageSpec, format: ImageOutputFormat) -> Vec<u8> {
+    engine.apply(&spec.specs);
+    engine.generate(format)
+}
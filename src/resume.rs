@@ -0,0 +1,142 @@
+// 断点续传：很大的源图片下载到一半连接断开时，用 Range 请求从已经收到的位置继续，不从头下载
+// 只有源站声明了 Accept-Ranges: bytes，并且给出了强 ETag 或者 Last-Modified 时才续传，
+// 续传的请求带上 If-Range，源站的图片在这期间被替换时会返回完整的新图片
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use serde::Deserialize;
+
+// [fetch_resume]，不配置则连接断开时下载直接失败
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResumeConfig {
+    // 一次下载最多续传几次
+    pub max_attempts: u32,
+    // 已经收到的字节数少于这个值时不续传，小图片重新下载一次的代价不大
+    pub min_bytes: u64,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            min_bytes: 1024 * 1024,
+        }
+    }
+}
+
+// 一次下载的进度，每个正在进行的下载各自记录
+#[derive(Debug, Default)]
+pub struct Progress {
+    // 已经续传的次数
+    pub attempts: u32,
+    // 第一个响应中的 ETag 或者 Last-Modified，为 None 时不能续传
+    validator: Option<HeaderValue>,
+}
+
+// 续传的响应怎么处理
+#[derive(Debug, PartialEq)]
+pub enum Resumed {
+    // 从已经收到的位置继续写入
+    Append,
+    // 源站返回了完整的图片，丢掉已经收到的部分
+    Restart,
+}
+
+impl Progress {
+    // headers 是第一个响应的响应头
+    pub fn new(headers: &HeaderMap) -> Self {
+        let ranges = headers
+            .get(header::ACCEPT_RANGES)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.eq_ignore_ascii_case("bytes"));
+        // If-Range 不能使用弱 ETag
+        let etag = headers
+            .get(header::ETAG)
+            .filter(|v| !v.as_bytes().starts_with(b"W/"));
+        let validator = etag.or_else(|| headers.get(header::LAST_MODIFIED)).filter(|_| ranges);
+        Self {
+            attempts: 0,
+            validator: validator.cloned(),
+        }
+    }
+
+    // 可以续传时返回 Range 和 If-Range 请求头的值，并计入一次续传
+    pub fn next(&mut self, config: &ResumeConfig, received: usize) -> Option<(String, HeaderValue)> {
+        let validator = self.validator.clone()?;
+        if self.attempts >= config.max_attempts || (received as u64) < config.min_bytes.max(1) {
+            return None;
+        }
+        self.attempts += 1;
+        Some((format!("bytes={}-", received), validator))
+    }
+}
+
+// 检查续传的响应：206 并且 Content-Range 从 received 开始时接着写入；
+// 200 表示 If-Range 不匹配（图片被替换了），从头开始；其他情况无法继续
+pub fn check(status: StatusCode, headers: &HeaderMap, received: usize) -> Result<Resumed, String> {
+    match status {
+        StatusCode::OK => Ok(Resumed::Restart),
+        StatusCode::PARTIAL_CONTENT => {
+            // bytes <start>-<end>/<total>
+            let start = headers
+                .get(header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("bytes "))
+                .and_then(|v| v.split('-').next())
+                .and_then(|v| v.trim().parse::<usize>().ok());
+            match start {
+                Some(start) if start == received => Ok(Resumed::Append),
+                _ => Err("unexpected content-range in resumed download".to_owned()),
+            }
+        }
+        status => Err(format!("resumed download failed with status {}", status.as_u16())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (k, v) in pairs {
+            headers.insert(*k, HeaderValue::from_static(v));
+        }
+        headers
+    }
+
+    #[test]
+    fn downloads_should_resume_with_validator() {
+        let config = ResumeConfig {
+            max_attempts: 2,
+            min_bytes: 100,
+        };
+        let mut progress = Progress::new(&headers(&[("accept-ranges", "bytes"), ("etag", "\"v1\"")]));
+        assert!(progress.next(&config, 99).is_none());
+        let (range, if_range) = progress.next(&config, 100).unwrap();
+        assert_eq!((range.as_str(), if_range.to_str().unwrap()), ("bytes=100-", "\"v1\""));
+        assert!(progress.next(&config, 200).is_some());
+        assert!(progress.next(&config, 300).is_none());
+        assert_eq!(progress.attempts, 2);
+
+        // 弱 ETag 时使用 Last-Modified，源站不支持 Range 或者没有校验值时不续传
+        let modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let mut progress = Progress::new(&headers(&[
+            ("accept-ranges", "bytes"),
+            ("etag", "W/\"v1\""),
+            ("last-modified", modified),
+        ]));
+        assert_eq!(progress.next(&config, 100).unwrap().1, modified);
+        assert!(Progress::new(&headers(&[("etag", "\"v1\"")])).next(&config, 100).is_none());
+        assert!(Progress::new(&headers(&[("accept-ranges", "bytes")])).next(&config, 100).is_none());
+    }
+
+    #[test]
+    fn resumed_response_should_continue_or_restart() {
+        let partial = headers(&[("content-range", "bytes 100-199/200")]);
+        assert_eq!(check(StatusCode::PARTIAL_CONTENT, &partial, 100), Ok(Resumed::Append));
+        assert!(check(StatusCode::PARTIAL_CONTENT, &partial, 150).is_err());
+        assert!(check(StatusCode::PARTIAL_CONTENT, &HeaderMap::new(), 100).is_err());
+        assert_eq!(check(StatusCode::OK, &HeaderMap::new(), 100), Ok(Resumed::Restart));
+        assert!(check(StatusCode::RANGE_NOT_SATISFIABLE, &HeaderMap::new(), 100).is_err());
+    }
+}
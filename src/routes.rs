@@ -0,0 +1,154 @@
+// 租户配置的公开路径（[[tenants.routes]]）：GET <path>/<rest> 等同于 GET /image/<preset>/<origin><rest>
+// 对外发布可读的 URL，比如 /thumb/2021/cat.jpg，spec 和源站地址都不出现在 URL 中
+use crate::{
+    config::Config,
+    error::AppError,
+    fallback::Fallback,
+    generate,
+    publish::Publisher,
+    tenant::{origin_allows, Tenants},
+    Cache, OutputParams, Params, SignedParams,
+};
+use axum::{
+    body::Body,
+    extract::{Extension, OriginalUri, Path, Query},
+    http::{HeaderMap, StatusCode},
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::Arc;
+
+// 内置接口的路径，公开路径不能和它们冲突
+const RESERVED: &[&str] = &[
+    "image", "diff", "meta", "stats", "collage", "sprite", "contactsheet", "verify", "estimate", "capabilities", "spec",
+    "metrics", "peer", "uploads", "admin",
+];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    // 公开的路径前缀，比如 /thumb 或者 /campaign/hero
+    pub path: String,
+    // 使用的预设名
+    pub preset: String,
+    // 源图片 URL 的前缀，必须是以 / 结尾的 http(s) URL，请求路径中前缀之后的部分按相对路径解析
+    pub origin: String,
+}
+
+impl RouteConfig {
+    // 路径只能由字母、数字、-、_ 组成的若干段，不能以内置接口的路径开头
+    // origin 的路径必须以 / 结尾，否则 https://b.com 加上 .evil.net/x.jpg 会变成另一个 host
+    pub fn check(&self) -> Result<(), String> {
+        let origin = Url::parse(&self.origin).map_err(|e| format!("route {}: invalid origin: {}", self.path, e))?;
+        if !matches!(origin.scheme(), "http" | "https")
+            || !origin.username().is_empty()
+            || origin.query().is_some()
+            || origin.fragment().is_some()
+            || !self.origin.ends_with('/')
+        {
+            return Err(format!("route {}: origin must be an http(s) URL ending with /", self.path));
+        }
+        let segments = match self.path.strip_prefix('/') {
+            Some(v) => v.split('/'),
+            None => return Err(format!("route {}: path must start with /", self.path)),
+        };
+        for (i, segment) in segments.enumerate() {
+            if segment.is_empty() || !segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(format!("route {}: invalid path segment {:?}", self.path, segment));
+            }
+            if i == 0 && RESERVED.contains(&segment) {
+                return Err(format!("route {}: /{} is a built-in endpoint", self.path, segment));
+            }
+        }
+        Ok(())
+    }
+
+    // 请求路径中前缀之后的部分（保持 percent 编码），不匹配或者为空时返回 None
+    pub fn rest<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(self.path.as_str())?
+            .strip_prefix('/')
+            .filter(|v| !v.is_empty())
+    }
+
+    // 源图片的 URL：rest 相对 origin 解析，%2e%2e 等点段在解析时处理，结果不在 origin 之下时返回 None
+    pub fn source(&self, rest: &str) -> Option<String> {
+        let url = Url::parse(&self.origin).ok()?.join(rest).ok()?;
+        origin_allows(&self.origin, url.as_str()).then(|| url.into())
+    }
+}
+
+// 所有公开路径都注册到这里，按请求的租户找到对应的预设和源站，然后和 /image 一样处理
+#[allow(clippy::too_many_arguments)]
+pub async fn generate_route(
+    OriginalUri(uri): OriginalUri,
+    output: Query<OutputParams>,
+    signed: Query<SignedParams>,
+    req_headers: HeaderMap,
+    cache: Extension<Cache>,
+    config: Extension<Arc<Config>>,
+    publisher: Extension<Arc<Publisher>>,
+    tenants: Extension<Arc<Tenants>>,
+    fallback: Extension<Arc<Fallback>>,
+) -> Result<(HeaderMap, Body), AppError> {
+    let tenant = tenants.resolve(&req_headers)?;
+    // generate 会对 spec 和 url 做 percent 解码
+    let (route, rest) = tenant.route(uri.path()).ok_or(StatusCode::NOT_FOUND)?;
+    let url = route.source(rest).ok_or(StatusCode::NOT_FOUND)?;
+    let params = Params {
+        spec: utf8_percent_encode(&route.preset, NON_ALPHANUMERIC).to_string(),
+        url: utf8_percent_encode(&url, NON_ALPHANUMERIC).to_string(),
+    };
+    generate(Path(params), output, signed, req_headers, cache, config, publisher, tenants, fallback).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_should_match_path_prefix() {
+        let route = |path: &str| RouteConfig {
+            path: path.to_owned(),
+            preset: "thumb".to_owned(),
+            origin: "https://a.com/img/".to_owned(),
+        };
+        let origin = |origin: &str| RouteConfig {
+            origin: origin.to_owned(),
+            ..route("/thumb")
+        };
+        let thumb = route("/thumb");
+        assert!(thumb.check().is_ok());
+        assert_eq!(thumb.rest("/thumb/2021/cat%20a.jpg"), Some("2021/cat%20a.jpg"));
+        assert_eq!(thumb.rest("/thumbnail/cat.jpg"), None);
+        assert_eq!(thumb.rest("/thumb/"), None);
+        assert!(route("/campaign/hero_2").check().is_ok());
+        assert!(route("thumb").check().is_err());
+        assert!(route("/thumb/").check().is_err());
+        assert!(route("/th.umb").check().is_err());
+        assert!(route("/image").check().is_err());
+        assert!(route("/admin/x").check().is_err());
+        assert!(origin("https://a.com/").check().is_ok());
+        assert!(origin("https://a.com").check().is_err());
+        assert!(origin("https://a.com/img").check().is_err());
+        assert!(origin("ftp://a.com/img/").check().is_err());
+        assert!(origin("https://a.com/img/?v=1").check().is_err());
+        assert!(origin("img/").check().is_err());
+    }
+
+    #[test]
+    fn source_should_stay_under_origin() {
+        let thumb = RouteConfig {
+            path: "/thumb".to_owned(),
+            preset: "thumb".to_owned(),
+            origin: "https://b.com/img/".to_owned(),
+        };
+        let source = |rest| thumb.source(rest);
+        assert_eq!(source("2021/cat%20a.jpg").as_deref(), Some("https://b.com/img/2021/cat%20a.jpg"));
+        assert_eq!(source("a/../cat.jpg").as_deref(), Some("https://b.com/img/cat.jpg"));
+        assert_eq!(source("%2e%2e/%2e%2e/private.jpg"), None);
+        assert_eq!(source("../private.jpg"), None);
+        assert_eq!(source("//evil.net/x.jpg"), None);
+        assert_eq!(source("https://evil.net/x.jpg"), None);
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{
+    diff,
+    engine::{Engine, Native},
+    pb::ImageSpec,
+    stats::STATS,
+};
+use bytes::Bytes;
+use image::RgbaImage;
+use serde::Deserialize;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+// shadow 模式的配置（[shadow]）：按比例抽取请求，用 Native engine 再处理一遍并和 Photon 的结果对比
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShadowConfig {
+    // 抽样比例，0 ~ 1
+    pub sample_rate: f64,
+}
+
+// 相似度低于这个值记为不一致
+const MISMATCH_THRESHOLD: f64 = 0.99;
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+// 按请求计数均匀抽样，不需要随机数，结果也可以复现
+pub fn sample(config: Option<&ShadowConfig>) -> bool {
+    let rate = match config {
+        Some(c) if c.sample_rate > 0.0 => c.sample_rate.min(1.0),
+        _ => return false,
+    };
+    let n = REQUESTS.fetch_add(1, Ordering::Relaxed) as f64;
+    ((n + 1.0) * rate).floor() > (n * rate).floor()
+}
+
+// 在后台线程中用 Native 处理同样的请求，记录和主 engine 的像素差异以及耗时差异
+pub fn compare(data: Bytes, spec: ImageSpec, primary: RgbaImage, primary_time: Duration) {
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let mut engine = match Native::open(&data, spec.page) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        engine.apply(&spec.specs);
+        let shadow_time = started.elapsed();
+        let shadow = engine.to_rgba();
+
+        let similarity = if shadow.dimensions() == primary.dimensions() {
+            diff::compare(&primary, &shadow).1
+        } else {
+            0.0
+        };
+        info!(
+            "Shadow: similarity {:.4}, primary {:?}, shadow {:?}",
+            similarity, primary_time, shadow_time
+        );
+        STATS.shadow(similarity, similarity < MISMATCH_THRESHOLD, primary_time, shadow_time);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_should_follow_rate() {
+        assert!(!sample(None));
+        let config = ShadowConfig { sample_rate: 0.25 };
+        let hits = (0..100).filter(|_| sample(Some(&config))).count();
+        assert_eq!(hits, 25);
+    }
+}
@@ -0,0 +1,51 @@
+use axum::http::{header::AUTHORIZATION, HeaderMap};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 使用 HMAC-SHA256 对消息签名，返回十六进制字符串
+// 签名一般由调用方生成，服务端只需要校验；上传 token 由服务端签发
+pub fn sign(key: &str, message: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("hmac accepts any key size");
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// 校验签名，使用常量时间比较
+pub fn verify(key: &str, message: &str, signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("hmac accepts any key size");
+    mac.update(message.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+// 请求头 Authorization: Bearer <token> 中的 token。token 不放在 URL 中，不会出现在访问日志里
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+// 常量时间比较 token，比较的时间不会透露相同前缀的长度；长度不同时直接返回 false
+pub fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_message_should_be_verified() {
+        let sig = sign("secret", "alice");
+        assert!(verify("secret", "alice", &sig));
+        assert!(!verify("secret", "bob", &sig));
+        assert!(!verify("other", "alice", &sig));
+        assert!(!verify("secret", "alice", "not-hex"));
+        assert!(verify("secret", "alice", &shanbor_client::sign("secret", "alice")));
+        assert!(token_eq("secret", "secret"));
+        assert!(!token_eq("secret", "secreT") && !token_eq("secret", "secret2") && !token_eq("", "x"));
+    }
+}
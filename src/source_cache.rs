@@ -0,0 +1,309 @@
+// 源图片缓存的有效期。默认缓存的图片一直有效，直到被 LRU 淘汰；
+// 可以配置固定的有效期，或者按源站响应的 Cache-Control / Expires 决定，并限制在 [min_ttl, max_ttl] 之间
+// 另外同一个源图片同时只下载一次（Inflight），并记录由每个源图片得到的对象存储中的结果（ResultIndex）
+use crate::{
+    cache_key::{self, Key},
+    tenant::Tenants,
+    Cache,
+};
+use axum::http::HeaderMap;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use lru::LruCache;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+lazy_static! {
+    // 正在下载的源图片
+    pub static ref INFLIGHT: Inflight = Inflight::default();
+    pub static ref RESULTS: ResultIndex = ResultIndex::default();
+}
+
+// 最多记录多少个源图片的结果，超出时丢弃最久没有写入结果的源图片
+const MAX_INDEXED_SOURCES: usize = 100_000;
+// 有效期的上限，源站或者副本给出更长的有效期时按一年处理（Instant 加上太大的值会溢出）
+pub const MAX_TTL: Duration = Duration::from_secs(365 * 24 * 3600);
+
+// now 之后 ttl 的时间点，不超过 MAX_TTL
+pub fn expires_at(now: Instant, ttl: Duration) -> Option<Instant> {
+    now.checked_add(ttl.min(MAX_TTL))
+}
+
+// 源图片缓存的配置（[source_cache]，租户中也可以单独配置），时间单位都是秒
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceCacheConfig {
+    // 固定的有效期，不配置则一直有效；honor_origin 时用于源站没有给出有效期的响应
+    pub ttl: Option<u64>,
+    // 是否按源站的 Cache-Control（s-maxage、max-age、no-store）和 Expires 决定有效期
+    pub honor_origin: bool,
+    // honor_origin 时有效期的下限，避免源站的 no-cache 让每个请求都回源
+    pub min_ttl: u64,
+    // honor_origin 时有效期的上限
+    pub max_ttl: Option<u64>,
+}
+
+// 缓存中的源图片，expires 为 None 时一直有效
+#[derive(Clone)]
+pub struct CachedSource {
+    pub data: Bytes,
+    pub expires: Option<Instant>,
+}
+
+impl CachedSource {
+    pub fn fresh(&self) -> bool {
+        self.expires.is_none_or(|v| Instant::now() < v)
+    }
+}
+
+impl SourceCacheConfig {
+    // 源图片可以缓存多久，None 表示一直有效，0 表示不缓存
+    pub fn ttl(&self, headers: &HeaderMap) -> Option<Duration> {
+        let ttl = if self.honor_origin {
+            let ttl = origin_ttl(headers, Utc::now()).or(self.ttl)?;
+            let ttl = ttl.max(self.min_ttl);
+            self.max_ttl.map_or(ttl, |max| ttl.min(max))
+        } else {
+            self.ttl?
+        };
+        Some(Duration::from_secs(ttl).min(MAX_TTL))
+    }
+
+    pub fn entry(&self, data: Bytes, headers: &HeaderMap) -> Option<CachedSource> {
+        match self.ttl(headers) {
+            Some(ttl) if ttl.is_zero() => None,
+            ttl => Some(CachedSource {
+                data,
+                expires: ttl.and_then(|v| expires_at(Instant::now(), v)),
+            }),
+        }
+    }
+}
+
+// 下载源图片的错误，所有等待的请求共享同一个结果，所以只保留信息
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum FetchError {
+    // 超出了 max_bytes 的限制，没有下载完
+    #[error("source is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("{0}")]
+    Failed(String),
+}
+
+pub type Fetched = Result<(Bytes, HeaderMap), FetchError>;
+
+// 同一个源图片同时只下载一次：同时到达的请求（比如同一张图片的不同尺寸）等待第一个请求下载的结果
+#[derive(Default)]
+pub struct Inflight(Mutex<HashMap<Key, Arc<OnceCell<Fetched>>>>);
+
+impl Inflight {
+    // 返回下载的结果，以及 fetch 是否由这次调用执行
+    // 执行 fetch 的请求被取消时，等待的请求中会有一个重新执行 fetch
+    pub async fn run<F, Fut>(&self, key: Key, fetch: F) -> (Fetched, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Fetched>,
+    {
+        let cell = self.0.lock().unwrap().entry(key).or_default().clone();
+        let mut leader = false;
+        let fetched = cell
+            .get_or_init(|| {
+                leader = true;
+                fetch()
+            })
+            .await
+            .clone();
+        // 结果已经写入缓存，之后的请求直接读缓存
+        if leader {
+            self.0.lock().unwrap().remove(&key);
+        }
+        (fetched, leader)
+    }
+}
+
+// 源图片 URL → 由它得到的对象存储中的 key（所有租户、spec 和格式），源站替换了同一个 URL 的图片时按 URL 清除
+// 只保存在内存中，重启之前写入的结果不在其中，需要依靠对象存储自己的过期策略
+pub struct ResultIndex(Mutex<LruCache<Key, Vec<String>>>);
+
+impl Default for ResultIndex {
+    fn default() -> Self {
+        Self(Mutex::new(LruCache::new(MAX_INDEXED_SOURCES)))
+    }
+}
+
+impl ResultIndex {
+    pub fn record(&self, url: &str, key: &str) {
+        let source = cache_key::source("", url);
+        let mut g = self.0.lock().unwrap();
+        match g.get_mut(&source) {
+            Some(keys) if keys.iter().any(|v| v == key) => {}
+            Some(keys) => keys.push(key.to_owned()),
+            None => {
+                g.put(source, vec![key.to_owned()]);
+            }
+        }
+    }
+
+    // 取出并删除 URL 的所有结果
+    pub fn take(&self, url: &str) -> Vec<String> {
+        self.0.lock().unwrap().pop(&cache_key::source("", url)).unwrap_or_default()
+    }
+}
+
+// 从所有租户的命名空间中删除缓存的源图片，返回删除的条数
+pub async fn evict(cache: &Cache, tenants: &Tenants, url: &str) -> usize {
+    let mut g = cache.lock().await;
+    tenants
+        .namespaces()
+        .filter(|namespace| g.pop(&cache_key::source(namespace, url)).is_some())
+        .count()
+}
+
+// 按 RFC 9111 计算响应的新鲜期（秒）：共享缓存优先使用 s-maxage，其次 max-age，再次 Expires - Date，
+// 减去 Age 头。没有任何有效期信息时返回 None
+fn origin_ttl(headers: &HeaderMap, now: DateTime<Utc>) -> Option<u64> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in header("cache-control").unwrap_or("").split(',') {
+        let mut kv = directive.trim().splitn(2, '=');
+        let key = kv.next().unwrap_or("").to_ascii_lowercase();
+        let value = kv.next().map(|v| v.trim().trim_matches('"'));
+        match key.as_str() {
+            "no-store" | "no-cache" | "private" => return Some(0),
+            "max-age" => max_age = value.and_then(|v| v.parse().ok()),
+            "s-maxage" => s_maxage = value.and_then(|v| v.parse().ok()),
+            _ => {}
+        }
+    }
+    let lifetime = s_maxage.or(max_age).or_else(|| {
+        // 无法解析的 Expires（比如 0）表示已经过期
+        let expires = header("expires")?;
+        let expires = match DateTime::parse_from_rfc2822(expires) {
+            Ok(v) => v.with_timezone(&Utc),
+            Err(_) => return Some(0),
+        };
+        let date = header("date")
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+            .map_or(now, |v| v.with_timezone(&Utc));
+        Some((expires - date).num_seconds().max(0) as u64)
+    })?;
+    let age: u64 = header("age").and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+    Some(lifetime.saturating_sub(age))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (k, v) in pairs {
+            headers.insert(*k, HeaderValue::from_static(v));
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn concurrent_fetches_should_be_coalesced() {
+        let inflight = Inflight::default();
+        let fetches = std::sync::atomic::AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok((Bytes::from_static(b"image"), HeaderMap::new()))
+        };
+        let results = tokio::join!(
+            inflight.run([1; 32], fetch),
+            inflight.run([1; 32], fetch),
+            inflight.run([1; 32], fetch),
+            inflight.run([2; 32], fetch),
+        );
+        assert_eq!(fetches.into_inner(), 2);
+        let leaders = [results.0 .1, results.1 .1, results.2 .1];
+        assert_eq!(leaders.iter().filter(|v| **v).count(), 1);
+        assert_eq!(results.2 .0.unwrap().0, Bytes::from_static(b"image"));
+        assert!(inflight.0.lock().unwrap().is_empty());
+
+        // 下载完成后再次请求会重新下载（正常情况下已经命中缓存）
+        let (_, leader) = inflight.run([1; 32], || async { Err(FetchError::TooLarge(1)) }).await;
+        assert!(leader);
+    }
+
+    #[test]
+    fn results_should_be_indexed_by_source_url() {
+        let index = ResultIndex::default();
+        index.record("https://a.com/cat.png", "a/1.jpg");
+        index.record("https://A.com/cat.png#x", "b/2.png");
+        index.record("https://a.com/cat.png", "a/1.jpg");
+        index.record("https://a.com/dog.png", "a/3.jpg");
+        assert_eq!(index.take("https://a.com/cat.png"), vec!["a/1.jpg", "b/2.png"]);
+        assert!(index.take("https://a.com/cat.png").is_empty());
+        assert_eq!(index.take("https://a.com/dog.png"), vec!["a/3.jpg"]);
+    }
+
+    #[test]
+    fn origin_ttl_should_follow_cache_headers() {
+        let now = Utc::now();
+        let ttl = |pairs| origin_ttl(&headers(pairs), now);
+        assert_eq!(ttl(&[]), None);
+        assert_eq!(ttl(&[("cache-control", "public, max-age=600")]), Some(600));
+        assert_eq!(ttl(&[("cache-control", "max-age=600, s-maxage=60")]), Some(60));
+        assert_eq!(ttl(&[("cache-control", "max-age=600"), ("age", "100")]), Some(500));
+        assert_eq!(ttl(&[("cache-control", "no-store, max-age=600")]), Some(0));
+        assert_eq!(
+            ttl(&[
+                ("date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                ("expires", "Wed, 21 Oct 2015 08:28:00 GMT")
+            ]),
+            Some(3600)
+        );
+        assert_eq!(ttl(&[("expires", "0")]), Some(0));
+    }
+
+    #[test]
+    fn ttl_should_be_clamped() {
+        let flat = SourceCacheConfig {
+            ttl: Some(300),
+            ..Default::default()
+        };
+        let origin = headers(&[("cache-control", "max-age=5")]);
+        assert_eq!(SourceCacheConfig::default().ttl(&origin), None);
+        assert_eq!(flat.ttl(&origin), Some(Duration::from_secs(300)));
+
+        let honor = SourceCacheConfig {
+            honor_origin: true,
+            min_ttl: 10,
+            max_ttl: Some(3600),
+            ..flat
+        };
+        assert_eq!(honor.ttl(&origin), Some(Duration::from_secs(10)));
+        assert_eq!(honor.ttl(&headers(&[("cache-control", "max-age=86400")])), Some(Duration::from_secs(3600)));
+        // 源站没有给出有效期时使用固定的有效期
+        assert_eq!(honor.ttl(&HeaderMap::new()), Some(Duration::from_secs(300)));
+
+        let strict = SourceCacheConfig {
+            honor_origin: true,
+            ..Default::default()
+        };
+        assert!(strict.entry(Bytes::new(), &headers(&[("cache-control", "no-store")])).is_none());
+        assert!(strict.entry(Bytes::new(), &HeaderMap::new()).unwrap().fresh());
+        // 超出范围的有效期按上限处理
+        let forever = headers(&[("cache-control", "max-age=18446744073709551615")]);
+        assert_eq!(strict.ttl(&forever), Some(MAX_TTL));
+        assert!(strict.entry(Bytes::new(), &forever).unwrap().fresh());
+        let flat = SourceCacheConfig {
+            ttl: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(flat.entry(Bytes::new(), &origin).unwrap().fresh());
+    }
+}
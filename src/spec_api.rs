@@ -0,0 +1,36 @@
+use crate::{
+    error::AppError,
+    pb::{ImageSpec, JsonSpec},
+};
+use axum::{extract::Path, http::StatusCode, Json};
+use bytes::Bytes;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct SpecResponse {
+    // 可以直接放进 /image/:spec/:url 的 spec 字符串（base64url 编码的 protobuf）
+    encoded: String,
+    spec: JsonSpec,
+}
+
+impl From<&ImageSpec> for SpecResponse {
+    fn from(spec: &ImageSpec) -> Self {
+        Self {
+            encoded: spec.into(),
+            spec: spec.into(),
+        }
+    }
+}
+
+// "GET /spec/:spec" 把任意形式的 spec 字符串转换成 JSON，方便查看和调试
+pub async fn describe(Path(spec): Path<String>) -> Result<Json<SpecResponse>, AppError> {
+    let spec = percent_decode_str(&spec).decode_utf8_lossy();
+    Ok(Json((&ImageSpec::parse(&spec)?).into()))
+}
+
+// "POST /spec" 请求体为 JSON 格式的 spec，返回编码后的 spec 字符串
+pub async fn encode(body: Bytes) -> Result<Json<SpecResponse>, AppError> {
+    let body = std::str::from_utf8(&body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(Json((&ImageSpec::from_json(body)?).into()))
+}
@@ -0,0 +1,174 @@
+use crate::{
+    config::Config,
+    error::AppError,
+    engine::{encode, Engine},
+    load_engine,
+    pb::{resize, Spec},
+    tenant::Tenants,
+    Cache,
+};
+use axum::{
+    extract::{Extension, Json},
+    http::{HeaderMap, HeaderValue, StatusCode},
+};
+use image::{imageops, ImageOutputFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+
+// 一张 sprite sheet 最多包含的图标数量
+pub const MAX_ICONS: usize = 256;
+// 图标的最大边长
+pub const MAX_ICON_SIZE: u32 = 512;
+// 接口本身和缩放图标用到的操作，disabled_ops 中关闭任何一个都不能使用
+pub const FEATURES: &[&str] = &["sprite", "resize"];
+
+#[derive(Deserialize)]
+pub struct Icon {
+    url: String,
+    // 在坐标表中的名字，默认使用 url
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SpriteRequest {
+    icons: Vec<Icon>,
+    // 图标等比缩放到不超过 size x size，不设置则保持原尺寸
+    size: Option<u32>,
+    // 图标之间的间隔
+    #[serde(default)]
+    padding: u32,
+}
+
+#[derive(Serialize)]
+struct Sprite {
+    name: String,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Serialize)]
+struct SpriteSheet {
+    width: u32,
+    height: u32,
+    // base64 编码的 PNG
+    image: String,
+    // 和请求中 icons 的顺序一致
+    sprites: Vec<Sprite>,
+}
+
+// "POST /sprite" 把多个小图标打包成一张 sprite sheet，同时返回每个图标的坐标
+pub async fn generate_sprite(
+    Json(req): Json<SpriteRequest>,
+    req_headers: HeaderMap,
+    Extension(cache): Extension<Cache>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<(HeaderMap, Vec<u8>), AppError> {
+    let tenant = tenants.admit(&req_headers)?;
+    let _permit = crate::overload::OVERLOAD.admit("sprite").await?;
+    tenant.check_features(FEATURES)?;
+    if req.icons.is_empty() || req.icons.len() > MAX_ICONS {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if matches!(req.size, Some(s) if s == 0 || s > MAX_ICON_SIZE) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let mut images = Vec::with_capacity(req.icons.len());
+    for icon in req.icons.iter() {
+        let mut engine = load_engine(&icon.url, cache.clone(), &config, &tenant).await?;
+        let (w, h) = engine.dimensions();
+        let max = req.size.unwrap_or(MAX_ICON_SIZE);
+        if w > max || h > max || req.size.is_some() {
+            let scale = (max as f64 / w as f64).min(max as f64 / h as f64);
+            let sw = ((w as f64 * scale).round() as u32).max(1);
+            let sh = ((h as f64 * scale).round() as u32).max(1);
+            engine.apply(&[Spec::new_resize(sw, sh, resize::SampleFilter::Lanczos3)]);
+        }
+        images.push(engine.to_rgba());
+    }
+
+    let sizes: Vec<(u32, u32)> = images.iter().map(|img| img.dimensions()).collect();
+    let (width, height, positions) = pack(&sizes, req.padding);
+
+    let mut sheet = RgbaImage::new(width, height);
+    let mut sprites = Vec::with_capacity(images.len());
+    for ((img, icon), (x, y)) in images.iter().zip(req.icons.iter()).zip(positions) {
+        imageops::overlay(&mut sheet, img, x, y);
+        sprites.push(Sprite {
+            name: icon.name.clone().unwrap_or_else(|| icon.url.clone()),
+            x,
+            y,
+            width: img.width(),
+            height: img.height(),
+        });
+    }
+
+    let image = encode(sheet, ImageOutputFormat::Png);
+
+    info!("Finished sprite: {} icons, size {}", sprites.len(), image.len());
+
+    let body = SpriteSheet {
+        width,
+        height,
+        image: base64::encode(image),
+        sprites,
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", HeaderValue::from_static("application/json"));
+    let body = serde_json::to_vec(&body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((headers, body))
+}
+
+// 简单的 shelf 装箱：按高度从高到低逐行摆放，行宽接近总面积的平方根
+// 返回 sheet 的宽高以及每个图标（按输入顺序）的左上角坐标
+fn pack(sizes: &[(u32, u32)], padding: u32) -> (u32, u32, Vec<(u32, u32)>) {
+    let area: u64 = sizes
+        .iter()
+        .map(|&(w, h)| (w + padding) as u64 * (h + padding) as u64)
+        .sum();
+    let widest = sizes.iter().map(|&(w, _)| w).max().unwrap_or(0);
+    let max_width = ((area as f64).sqrt().ceil() as u32).max(widest);
+
+    // 稳定排序，保证同样的输入总是得到同样的布局
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].1));
+
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut y, mut shelf, mut width) = (0, 0, 0, 0);
+    for i in order {
+        let (w, h) = sizes[i];
+        if x > 0 && x + w > max_width {
+            x = 0;
+            y += shelf + padding;
+            shelf = 0;
+        }
+        positions[i] = (x, y);
+        width = width.max(x + w);
+        shelf = shelf.max(h);
+        x += w + padding;
+    }
+
+    (width.max(1), (y + shelf).max(1), positions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_sprites_should_not_overlap() {
+        let sizes = [(16, 16), (32, 8), (10, 30), (16, 16), (4, 4)];
+        let (width, height, positions) = pack(&sizes, 2);
+        for (i, (&(w, h), &(x, y))) in sizes.iter().zip(positions.iter()).enumerate() {
+            assert!(x + w <= width && y + h <= height);
+            for (&(w2, h2), &(x2, y2)) in sizes.iter().zip(positions.iter()).skip(i + 1) {
+                let apart = x + w <= x2 || x2 + w2 <= x || y + h <= y2 || y2 + h2 <= y;
+                assert!(apart);
+            }
+        }
+    }
+}
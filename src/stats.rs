@@ -0,0 +1,200 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// 保留最近多少条错误
+const MAX_ERRORS: usize = 50;
+// 图片请求耗时直方图的上限（秒），最后还有一个 +Inf
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+lazy_static! {
+    // 进程内的运行统计，管理界面和调试时使用
+    pub static ref STATS: Stats = Stats::default();
+}
+
+#[derive(Serialize, Clone)]
+pub struct ErrorEntry {
+    pub time: String,
+    pub path: String,
+    pub status: u16,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    coalesced: AtomicU64,
+    resumed: AtomicU64,
+    errors: Mutex<VecDeque<ErrorEntry>>,
+    shadow: Mutex<ShadowStats>,
+    usage: Mutex<HashMap<(String, String), Usage>>,
+}
+
+// 图片请求按 (租户, 预设) 累计的用量，预设名只来自配置，标签的取值是有限的
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Usage {
+    pub requests: u64,
+    pub errors: u64,
+    // 请求的总耗时，包括下载源图片；边编码边发送时不包括发送的时间
+    pub seconds: f64,
+    // 响应体的字节数
+    pub bytes: u64,
+    // 按 LATENCY_BUCKETS 统计的请求数（不累计），最后一个是 +Inf
+    pub latency: [u64; LATENCY_BUCKETS.len() + 1],
+    // 每个桶中最近一次带有 trace id 的请求
+    pub exemplars: [Option<Exemplar>; LATENCY_BUCKETS.len() + 1],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exemplar {
+    pub trace_id: u128,
+    pub seconds: f64,
+    // Unix 时间（秒）
+    pub timestamp: f64,
+}
+
+// shadow 模式的累计结果
+#[derive(Default, Clone, Copy)]
+pub struct ShadowStats {
+    pub requests: u64,
+    pub mismatches: u64,
+    pub similarity_sum: f64,
+    pub primary_seconds: f64,
+    pub shadow_seconds: f64,
+}
+
+impl Stats {
+    pub fn cache(&self, hit: bool) {
+        let counter = if hit { &self.cache_hits } else { &self.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_counts(&self) -> (u64, u64) {
+        (self.cache_hits.load(Ordering::Relaxed), self.cache_misses.load(Ordering::Relaxed))
+    }
+
+    // 没有命中缓存，但是等待了同时进行的下载，没有自己下载
+    pub fn coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn coalesced_count(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    // 源图片的下载断开后用 Range 请求续传了一次
+    pub fn resumed(&self) {
+        self.resumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn resumed_count(&self) -> u64 {
+        self.resumed.load(Ordering::Relaxed)
+    }
+
+    pub fn error(&self, path: String, status: u16) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == MAX_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(ErrorEntry {
+            time: chrono::Utc::now().to_rfc3339(),
+            path,
+            status,
+        });
+    }
+
+    pub fn shadow(&self, similarity: f64, mismatch: bool, primary: Duration, shadow: Duration) {
+        let mut s = self.shadow.lock().unwrap();
+        s.requests += 1;
+        s.mismatches += mismatch as u64;
+        s.similarity_sum += similarity;
+        s.primary_seconds += primary.as_secs_f64();
+        s.shadow_seconds += shadow.as_secs_f64();
+    }
+
+    pub fn shadow_stats(&self) -> ShadowStats {
+        *self.shadow.lock().unwrap()
+    }
+
+    pub fn request(&self, tenant: &str, preset: &str, error: bool, elapsed: Duration, bytes: u64, trace_id: Option<u128>) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|le| seconds <= *le).unwrap_or(LATENCY_BUCKETS.len());
+        let mut usage = self.usage.lock().unwrap();
+        let u = usage.entry((tenant.to_owned(), preset.to_owned())).or_default();
+        u.requests += 1;
+        u.errors += error as u64;
+        u.seconds += seconds;
+        u.bytes += bytes;
+        u.latency[bucket] += 1;
+        if let Some(trace_id) = trace_id {
+            let timestamp = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+            u.exemplars[bucket] = Some(Exemplar {
+                trace_id,
+                seconds,
+                timestamp,
+            });
+        }
+    }
+
+    // 边编码边发送的响应在发送完之后才知道字节数
+    pub fn sent(&self, tenant: &str, preset: &str, bytes: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.entry((tenant.to_owned(), preset.to_owned())).or_default().bytes += bytes;
+    }
+
+    // 按租户和预设排序
+    pub fn usage(&self) -> Vec<(String, String, Usage)> {
+        let mut list: Vec<_> = self.usage.lock().unwrap().iter().map(|((t, p), u)| (t.clone(), p.clone(), *u)).collect();
+        list.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        list
+    }
+
+    // 最新的在前
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn recent_errors(&self) -> Vec<ErrorEntry> {
+        self.errors.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_errors_should_keep_latest() {
+        let stats = Stats::default();
+        for i in 0..MAX_ERRORS + 5 {
+            stats.error(format!("/image/{}", i), 400);
+        }
+        let errors = stats.recent_errors();
+        assert_eq!(errors.len(), MAX_ERRORS);
+        assert_eq!(errors[0].path, format!("/image/{}", MAX_ERRORS + 4));
+    }
+
+    #[test]
+    fn usage_should_be_grouped_by_tenant_and_preset() {
+        let stats = Stats::default();
+        stats.request("b", "thumb", false, Duration::from_millis(500), 100, None);
+        stats.request("a", "adhoc", true, Duration::from_millis(250), 0, None);
+        stats.request("b", "thumb", false, Duration::from_millis(500), 50, Some(7));
+        stats.request("b", "thumb", false, Duration::from_secs(60), 0, None);
+        stats.sent("a", "adhoc", 10);
+        let usage = stats.usage();
+        assert_eq!(usage.len(), 2);
+        assert_eq!((usage[0].0.as_str(), usage[0].1.as_str()), ("a", "adhoc"));
+        assert_eq!((usage[0].2.errors, usage[0].2.bytes), (1, 10));
+        let u = usage[1].2;
+        assert_eq!((u.requests, u.errors, u.seconds, u.bytes), (3, 0, 61.0, 150));
+        // 0.5 秒落在 le=0.5 的桶中，60 秒落在 +Inf
+        assert_eq!((u.latency[6], u.latency[LATENCY_BUCKETS.len()]), (2, 1));
+        assert_eq!(u.exemplars[6].map(|v| (v.trace_id, v.seconds)), Some((7, 0.5)));
+        assert!(u.exemplars[LATENCY_BUCKETS.len()].is_none());
+    }
+}
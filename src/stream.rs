@@ -0,0 +1,129 @@
+// 很大的输出边编码边发送：编码在 blocking 线程中进行，写满一块就发送给客户端，
+// 不需要把整个编码结果放在内存里。客户端断开时停止编码
+use axum::body::Body;
+use bytes::Bytes;
+use image::ImageResult;
+use std::io::{self, Write};
+use tokio::runtime::Handle;
+use tracing::{info, Span};
+
+// 每次发送的字节数
+const CHUNK_SIZE: usize = 64 << 10;
+
+// 输出的像素数达到 min_pixels 时才边编码边发送，不配置则总是先编码完
+pub fn enabled(min_pixels: Option<u64>, (width, height): (u32, u32)) -> bool {
+    min_pixels.is_some_and(|min| width as u64 * height as u64 >= min)
+}
+
+// encode 把编码结果写入给它的 Write，出错时客户端收到的响应会被中断
+// 结束之后（包括中断）用已经发送的字节数调用 done
+pub fn body<F, D>(encode: F, done: D) -> Body
+where
+    F: FnOnce(&mut dyn Write) -> ImageResult<()> + Send + 'static,
+    D: FnOnce(usize) + Send + 'static,
+{
+    let (mut sender, body) = Body::channel();
+    let handle = Handle::current();
+    // 编码线程中的日志也带上请求的租户和预设
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _enter = span.enter();
+        let mut writer = ChunkWriter::new(|chunk| {
+            handle
+                .block_on(sender.send_data(chunk))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        });
+        let result = encode(&mut writer).and_then(|_| writer.flush().map_err(Into::into));
+        let sent = writer.sent;
+        drop(writer);
+        match result {
+            Ok(()) => info!("Finished streaming: image size {}", sent),
+            Err(e) => {
+                info!("Streaming aborted after {} bytes: {}", sent, e);
+                sender.abort();
+            }
+        }
+        done(sent);
+    });
+    body
+}
+
+// 攒够 CHUNK_SIZE 再发送，避免编码器的小块写入变成很多很小的 chunk
+struct ChunkWriter<F> {
+    buffer: Vec<u8>,
+    send: F,
+    // 已经发送的字节数
+    sent: usize,
+}
+
+impl<F: FnMut(Bytes) -> io::Result<()>> ChunkWriter<F> {
+    fn new(send: F) -> Self {
+        Self {
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            send,
+            sent: 0,
+        }
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.sent += chunk.len();
+        (self.send)(Bytes::from(chunk))
+    }
+}
+
+impl<F: FnMut(Bytes) -> io::Result<()>> Write for ChunkWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine;
+    use image::{ImageOutputFormat, RgbaImage};
+
+    #[test]
+    fn chunks_should_match_buffered_output() {
+        // 噪声图片压缩后仍然有多个 chunk
+        let mut state = 0x2545_f491u32;
+        let img = RgbaImage::from_fn(300, 300, |_, _| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let [r, g, b, _] = state.to_le_bytes();
+            image::Rgba([r, g, b, 255])
+        });
+        let expected = engine::encode(img.clone(), ImageOutputFormat::Png);
+
+        let mut chunks = Vec::new();
+        let mut writer = ChunkWriter::new(|chunk: Bytes| {
+            chunks.push(chunk);
+            Ok(())
+        });
+        engine::encode_into(img, ImageOutputFormat::Png, &mut writer).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.sent, expected.len());
+        drop(writer);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len() == CHUNK_SIZE));
+        assert_eq!(chunks.concat(), expected);
+        assert!(enabled(Some(90_000), (300, 300)));
+        assert!(!enabled(Some(90_001), (300, 300)));
+        assert!(!enabled(None, (300, 300)));
+    }
+}
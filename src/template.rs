@@ -0,0 +1,115 @@
+use crate::{
+    engine::Exif,
+    pb::{spec, ImageSpec},
+};
+use std::collections::HashMap;
+
+// 模板变量，比如 {date}、{requester}
+pub type Vars = HashMap<&'static str, String>;
+
+// 替换文字中的 {name} 变量，"{{" 和 "}}" 分别表示字面上的 "{" 和 "}"
+// 遇到没有定义的变量时返回它的名字
+pub fn render(template: &str, vars: &Vars) -> Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+        } else if let Some(stripped) = tail.strip_prefix('{') {
+            let end = stripped.find('}').ok_or_else(|| stripped.to_owned())?;
+            let name = &stripped[..end];
+            out.push_str(vars.get(name).ok_or_else(|| name.to_owned())?);
+            rest = &stripped[end + 1..];
+        } else {
+            out.push('}');
+            rest = &tail[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// 替换 spec 中所有文字里的模板变量
+pub fn render_spec(image_spec: &mut ImageSpec, vars: &Vars) -> Result<(), String> {
+    for s in image_spec.specs.iter_mut() {
+        if let Some(spec::Data::Text(ref mut v)) = s.data {
+            v.text = render(&v.text, vars)?;
+        }
+    }
+    Ok(())
+}
+
+// caption 中可以使用的 EXIF 变量，EXIF 中没有的字段替换为空
+// 拍摄时间的格式和 {date}、{datetime} 一致，相机型号一般已经带着厂商名，不重复
+pub fn insert_exif(vars: &mut Vars, exif: &Exif) {
+    let taken = exif
+        .date_time
+        .as_deref()
+        .and_then(|v| chrono::NaiveDateTime::parse_from_str(v, "%Y:%m:%d %H:%M:%S").ok());
+    let camera = match (exif.make.as_deref(), exif.model.as_deref()) {
+        (Some(make), Some(model)) if model.to_lowercase().starts_with(&make.to_lowercase()) => model.to_owned(),
+        (Some(make), Some(model)) => format!("{} {}", make, model),
+        (make, model) => make.or(model).unwrap_or_default().to_owned(),
+    };
+    vars.insert("exif_date", taken.map(|v| v.format("%Y-%m-%d").to_string()).unwrap_or_default());
+    vars.insert("exif_datetime", taken.map(|v| v.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_default());
+    vars.insert("exif_camera", camera);
+    vars.insert("exif_copyright", exif.copyright.clone().unwrap_or_default());
+}
+
+// 替换 spec 中所有 caption 里的模板变量，需要先用 insert_exif 加入源图片的 EXIF
+pub fn render_captions(image_spec: &mut ImageSpec, vars: &Vars) -> Result<(), String> {
+    for s in image_spec.specs.iter_mut() {
+        if let Some(spec::Data::Caption(ref mut v)) = s.data {
+            v.text = render(&v.text, vars)?.trim().to_owned();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_variables_should_be_replaced() {
+        let mut vars = Vars::new();
+        vars.insert("requester", "alice".to_owned());
+        vars.insert("date", "2021-10-01".to_owned());
+        assert_eq!(
+            render("for {requester} on {date} {{x}}", &vars).unwrap(),
+            "for alice on 2021-10-01 {x}"
+        );
+        assert_eq!(render("{unknown}", &vars).unwrap_err(), "unknown");
+        assert!(render("{date", &vars).is_err());
+    }
+
+    #[test]
+    fn exif_variables_should_be_formatted() {
+        let mut vars = Vars::new();
+        let exif = Exif {
+            date_time: Some("2021:10:01 14:30:05".to_owned()),
+            make: Some("Canon".to_owned()),
+            model: Some("Canon EOS R5".to_owned()),
+            copyright: None,
+        };
+        insert_exif(&mut vars, &exif);
+        assert_eq!(
+            render("{exif_date} {exif_datetime} {exif_camera}|{exif_copyright}", &vars).unwrap(),
+            "2021-10-01 2021-10-01 14:30 Canon EOS R5|"
+        );
+
+        let exif = Exif {
+            make: Some("FUJIFILM".to_owned()),
+            model: Some("X-T4".to_owned()),
+            ..Default::default()
+        };
+        insert_exif(&mut vars, &exif);
+        let mut spec = ImageSpec::parse("caption:\"{exif_date} {exif_camera}\"").unwrap();
+        render_captions(&mut spec, &vars).unwrap();
+        assert!(matches!(spec.specs[0].data, Some(spec::Data::Caption(ref v)) if v.text == "FUJIFILM X-T4"));
+    }
+}
@@ -0,0 +1,658 @@
+use crate::{
+    assets,
+    config::Config,
+    error::AppError,
+    fonts,
+    limits::SourceLimits,
+    policy::{OutputDefaults, OutputPolicy},
+    routes::RouteConfig,
+    signing,
+    source_cache::SourceCacheConfig,
+    uploads,
+    pb::{self, ImageSpec, SpecError, SpecValue, UnknownPolicy},
+};
+use anyhow::{anyhow, Context, Result};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+// 配置中的一个租户（[[tenants]]），通过 API key 或者 Host 头识别
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    // 请求头 x-api-key 的取值
+    pub api_keys: Vec<String>,
+    // 请求头 Host 的取值（不含端口）
+    pub hosts: Vec<String>,
+    // 允许的源图片 URL 前缀，为空时不限制
+    pub origins: Vec<String>,
+    // 预设的 spec，请求中可以直接用名字代替 spec 字符串。值可以是 spec 字符串，也可以写成表（见 pb/json.rs）
+    pub presets: HashMap<String, SpecValue>,
+    // 预设的输出策略，键是预设名（见 policy.rs）
+    pub policies: HashMap<String, OutputPolicy>,
+    // 只允许使用预设，请求中的 spec 字符串返回 403。配置了策略时应该开启，否则可以用 spec 字符串绕过策略
+    pub presets_only: bool,
+    // 这个租户自己的签名密钥
+    pub signing_key: Option<String>,
+    // 追加到每个请求最后的 spec 字符串，一般用来打品牌水印
+    pub watermark: Option<SpecValue>,
+    // 每分钟最多的请求数，不设置则不限制
+    pub quota: Option<u32>,
+    // 源图片缓存的命名空间，默认使用 name
+    pub cache_namespace: Option<String>,
+    // 文字可以使用的字体名，为空时不限制；内置字体总是可以使用
+    pub fonts: Vec<String>,
+    // 源图片缓存的有效期，不配置则使用顶层的 [source_cache]
+    pub source_cache: Option<SourceCacheConfig>,
+    // 源图片的限制，不配置则使用顶层的 [source_limits]
+    pub source_limits: Option<SourceLimits>,
+    // 静态图片默认的输出格式和质量，没有设置的部分使用顶层的 [output]
+    pub output: OutputDefaults,
+    // 映射到预设的公开路径（见 routes.rs）
+    pub routes: Vec<RouteConfig>,
+    // 这个租户额外关闭的操作，请求中使用时返回 403；顶层关闭的操作对所有租户都关闭
+    pub disabled_ops: Vec<String>,
+}
+
+pub struct Tenant {
+    pub name: String,
+    api_keys: Vec<String>,
+    hosts: Vec<String>,
+    origins: Vec<String>,
+    presets: HashMap<String, ImageSpec>,
+    policies: HashMap<String, OutputPolicy>,
+    presets_only: bool,
+    pub signing_key: Option<String>,
+    watermark: Option<ImageSpec>,
+    quota: Option<u32>,
+    pub cache_namespace: String,
+    fonts: Vec<String>,
+    pub source_cache: SourceCacheConfig,
+    pub source_limits: SourceLimits,
+    output: OutputDefaults,
+    routes: Vec<RouteConfig>,
+    // 顶层和租户配置中关闭的操作
+    disabled_ops: Vec<String>,
+    tenant_disabled_ops: Vec<String>,
+    unknown: UnknownPolicy,
+    // 当前统计窗口的起始时间和请求数
+    window: Mutex<(Instant, u32)>,
+}
+
+// 所有租户。没有配置租户时只有一个默认租户，使用顶层的配置，行为和单租户时一致
+pub struct Tenants {
+    tenants: Vec<Arc<Tenant>>,
+    multi: bool,
+}
+
+// 指标和日志中不是预设的 spec 使用的预设名
+pub const ADHOC: &str = "adhoc";
+
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
+
+fn parse_spec(tenant: &str, spec: &SpecValue, unknown: UnknownPolicy) -> Result<ImageSpec> {
+    spec.parse(unknown).with_context(|| format!("tenant {}: invalid spec {:?}", tenant, spec))
+}
+
+impl Tenant {
+    fn from_config(c: &TenantConfig, config: &Config) -> Result<Self> {
+        let unknown = config.unknown_spec_fields;
+        if c.name.is_empty() {
+            return Err(anyhow!("tenant name is required"));
+        }
+        // 租户名会放进 x-shanbor-tenant 响应头
+        if HeaderValue::from_str(&c.name).is_err() {
+            return Err(anyhow!("tenant {:?}: name must be a valid header value", c.name));
+        }
+        let presets: HashMap<String, ImageSpec> = c
+            .presets
+            .iter()
+            .map(|(k, v)| Ok((k.clone(), parse_spec(&c.name, v, unknown)?)))
+            .collect::<Result<_>>()?;
+        let watermark = c.watermark.as_ref().map(|v| parse_spec(&c.name, v, unknown)).transpose()?;
+        let output = c.output.or(config.output);
+        output.check().map_err(|e| anyhow!("tenant {}: output: {}", c.name, e))?;
+        for (name, policy) in &c.policies {
+            let preset = presets
+                .get(name)
+                .ok_or_else(|| anyhow!("tenant {}: policy for unknown preset {}", c.name, name))?;
+            let mut spec = preset.clone();
+            spec.specs.extend(watermark.iter().flat_map(|w| w.specs.iter().cloned()));
+            policy
+                .check(&spec)
+                .and_then(|_| policy.check_output(policy.output(output)))
+                .map_err(|e| anyhow!("tenant {}: invalid policy for preset {}: {}", c.name, name, e))?;
+        }
+        if c.presets_only && presets.is_empty() {
+            return Err(anyhow!("tenant {}: presets_only requires at least one preset", c.name));
+        }
+        if !c.policies.is_empty() && !c.presets_only {
+            warn!("Tenant {} has policies but allows ad-hoc specs, set presets_only to enforce them", c.name);
+        }
+        pb::check_features(&c.disabled_ops).map_err(|e| anyhow!("tenant {}: disabled_ops: {}", c.name, e))?;
+        for route in &c.routes {
+            route.check().map_err(|e| anyhow!("tenant {}: {}", c.name, e))?;
+            if !presets.contains_key(&route.preset) {
+                return Err(anyhow!("tenant {}: route {} uses unknown preset {}", c.name, route.path, route.preset));
+            }
+            if !c.origins.is_empty() && !c.origins.iter().any(|o| origin_allows(o, &route.origin)) {
+                return Err(anyhow!("tenant {}: route {} origin {} is not allowed", c.name, route.path, route.origin));
+            }
+        }
+        let tenant = Self {
+            name: c.name.clone(),
+            api_keys: c.api_keys.clone(),
+            hosts: c.hosts.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            origins: c.origins.clone(),
+            presets,
+            policies: c.policies.clone(),
+            presets_only: c.presets_only,
+            signing_key: c.signing_key.clone(),
+            watermark,
+            quota: c.quota,
+            cache_namespace: c.cache_namespace.clone().unwrap_or_else(|| c.name.clone()),
+            fonts: c.fonts.clone(),
+            source_cache: c.source_cache.clone().unwrap_or_else(|| config.source_cache.clone()),
+            source_limits: c.source_limits.clone().unwrap_or_else(|| config.source_limits.clone()),
+            output,
+            routes: c.routes.clone(),
+            disabled_ops: config.disabled_ops.clone(),
+            tenant_disabled_ops: c.disabled_ops.clone(),
+            unknown,
+            window: Mutex::new((Instant::now(), 0)),
+        };
+        // 配置自己的预设和水印不能用到关闭的操作
+        for (name, spec) in tenant.configured_specs() {
+            if let Some((_, feature, _)) = tenant.disabled(spec) {
+                return Err(anyhow!("tenant {}: {} uses disabled operation {}", c.name, name, feature));
+            }
+        }
+        Ok(tenant)
+    }
+
+    fn default(config: &Config) -> Self {
+        Self {
+            name: "default".to_owned(),
+            api_keys: vec![],
+            hosts: vec![],
+            origins: vec![],
+            presets: HashMap::new(),
+            policies: HashMap::new(),
+            presets_only: false,
+            signing_key: config.signing_key.clone(),
+            watermark: None,
+            quota: None,
+            cache_namespace: String::new(),
+            fonts: vec![],
+            source_cache: config.source_cache.clone(),
+            source_limits: config.source_limits.clone(),
+            output: config.output,
+            routes: vec![],
+            disabled_ops: config.disabled_ops.clone(),
+            tenant_disabled_ops: vec![],
+            unknown: config.unknown_spec_fields,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    // spec 中的文字只能使用允许的字体
+    pub fn allows_fonts(&self, spec: &ImageSpec) -> bool {
+        self.fonts.is_empty() || fonts::referenced(spec).all(|f| self.fonts.iter().any(|v| v == f))
+    }
+
+    // 配置中的预设和水印，名字用于错误信息
+    fn configured_specs(&self) -> impl Iterator<Item = (String, &ImageSpec)> {
+        let presets = self.presets.iter().map(|(k, v)| (format!("preset {}", k), v));
+        presets.chain(self.watermark.iter().map(|w| ("watermark".to_owned(), w)))
+    }
+
+    // 预设和水印引用的素材、字体，以及允许的字体都已经加载，`shanbor check` 加载素材和字体之后调用
+    pub fn check_resources(&self) -> Result<(), String> {
+        for (name, spec) in self.configured_specs() {
+            if let Some(asset) = assets::ASSETS.missing(spec) {
+                return Err(format!("tenant {}: {} uses unknown asset {}", self.name, name, asset));
+            }
+            if let Some(font) = fonts::FONTS.missing(spec) {
+                return Err(format!("tenant {}: {} uses unknown font {}", self.name, name, font));
+            }
+        }
+        match self.fonts.iter().find(|f| fonts::FONTS.get(f).is_none()) {
+            Some(font) => Err(format!("tenant {}: allowed font {} is not registered", self.name, font)),
+            None => Ok(()),
+        }
+    }
+
+    // 第一个关闭的功能，以及是否是顶层关闭的
+    fn first_disabled<'a>(&self, mut features: impl Iterator<Item = &'a str>) -> Option<(&'a str, bool)> {
+        features.find_map(|feature| {
+            if self.disabled_ops.iter().any(|v| v == feature) {
+                Some((feature, true))
+            } else if self.tenant_disabled_ops.iter().any(|v| v == feature) {
+                Some((feature, false))
+            } else {
+                None
+            }
+        })
+    }
+
+    // 用到关闭的操作时返回 (操作的序号, 功能名, 是否是顶层关闭的)
+    fn disabled(&self, spec: &ImageSpec) -> Option<(usize, &'static str, bool)> {
+        spec.specs.iter().enumerate().find_map(|(i, s)| {
+            let (feature, global) = self.first_disabled(s.data.as_ref()?.features())?;
+            Some((i, feature, global))
+        })
+    }
+
+    // 顶层关闭的操作按不支持处理，返回 422；只对这个租户关闭的操作返回 403
+    pub fn check_ops(&self, spec: &ImageSpec) -> Result<(), AppError> {
+        match self.disabled(spec) {
+            Some((i, feature, global)) => Err(disabled_error(Some(i), feature, global)),
+            None => Ok(()),
+        }
+    }
+
+    // 拼图、sprite 等接口不经过 spec，检查接口本身和它内部用到的操作，返回的状态码和 check_ops 相同
+    pub fn check_features(&self, features: &[&str]) -> Result<(), AppError> {
+        match self.first_disabled(features.iter().copied()) {
+            Some((feature, global)) => Err(disabled_error(None, feature, global)),
+            None => Ok(()),
+        }
+    }
+
+    // 源图片必须来自允许的 origin，上传的图片只有上传它的租户可以使用
+    pub fn allows(&self, url: &str) -> bool {
+        if let Some(id) = url.strip_prefix(uploads::SCHEME) {
+            if uploads::UPLOADS.owner(id).as_deref() != Some(self.name.as_str()) {
+                return false;
+            }
+        }
+        self.origins.is_empty() || self.origins.iter().any(|o| origin_allows(o, url))
+    }
+
+    // 解析请求中的 spec：先查预设，再按 spec 字符串解码（presets_only 时返回 403），最后追加租户的水印
+    pub fn spec(&self, spec: &str) -> Result<ImageSpec, AppError> {
+        let mut spec = match self.presets.get(spec) {
+            Some(v) => v.clone(),
+            None if self.presets_only => {
+                return Err(AppError::Forbidden(SpecError::new(0, "preset name", None, "only presets are allowed")))
+            }
+            None => ImageSpec::parse_with(spec, self.unknown)?,
+        };
+        if let Some(ref watermark) = self.watermark {
+            spec.specs.extend(watermark.specs.iter().cloned());
+        }
+        Ok(spec)
+    }
+
+    // 请求使用的静态图片默认的输出格式和质量：预设的策略、租户、顶层配置依次回退
+    pub fn output(&self, spec: &str) -> OutputDefaults {
+        match self.policies.get(spec) {
+            Some(policy) => policy.output(self.output),
+            None => self.output,
+        }
+    }
+
+    // 指标和日志中的预设名
+    pub fn preset_label<'a>(&self, spec: &'a str) -> &'a str {
+        if self.presets.contains_key(spec) {
+            spec
+        } else {
+            ADHOC
+        }
+    }
+
+    // 匹配请求路径的公开路径，以及路径中前缀之后的部分
+    pub fn route<'a>(&self, path: &'a str) -> Option<(&RouteConfig, &'a str)> {
+        self.routes.iter().find_map(|r| r.rest(path).map(|rest| (r, rest)))
+    }
+
+    // 请求使用的预设的输出策略，不是预设或者预设没有配置策略时不限制
+    pub fn policy(&self, spec: &str) -> OutputPolicy {
+        self.policies.get(spec).cloned().unwrap_or_default()
+    }
+
+    // 固定窗口计数，超出配额时返回 false
+    pub fn acquire(&self) -> bool {
+        let quota = match self.quota {
+            Some(v) => v,
+            None => return true,
+        };
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= QUOTA_WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= quota {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+fn disabled_error(op: Option<usize>, feature: &str, global: bool) -> AppError {
+    let e = SpecError::new(0, "enabled operation", op, format!("operation {} is disabled", feature));
+    if global {
+        e.into()
+    } else {
+        AppError::Forbidden(e)
+    }
+}
+
+// http(s) 的 URL 按解析之后的结果比较：scheme、host 和端口完全相同，规范化之后的路径在 origin 的路径之下，
+// 和 reqwest 实际请求的地址一致。https://a.com 不匹配 https://a.com.evil.net/x，
+// https://a.com/public/../private/x 按 https://a.com/private/x 比较。upload:// 等其他 scheme 按前缀比较
+pub fn origin_allows(origin: &str, url: &str) -> bool {
+    let (o, u) = match (Url::parse(origin), Url::parse(url)) {
+        (Ok(o), Ok(u)) if matches!(o.scheme(), "http" | "https") => (o, u),
+        (Ok(_), _) => return url.starts_with(origin),
+        _ => return false,
+    };
+    if o.scheme() != u.scheme() || o.host_str() != u.host_str() || o.port_or_known_default() != u.port_or_known_default() {
+        return false;
+    }
+    // 配置中没有以 / 结尾的路径也按目录处理，/img 不匹配 /images/x
+    let dir = o.path().trim_end_matches('/');
+    u.path() == dir || u.path().starts_with(&format!("{}/", dir))
+}
+
+impl Tenants {
+    pub fn new(config: &Config) -> Result<Self> {
+        config.output.check().map_err(|e| anyhow!("output: {}", e))?;
+        pb::check_features(&config.disabled_ops).map_err(|e| anyhow!("disabled_ops: {}", e))?;
+        if config.tenants.is_empty() {
+            return Ok(Self {
+                tenants: vec![Arc::new(Tenant::default(config))],
+                multi: false,
+            });
+        }
+        let tenants = config
+            .tenants
+            .iter()
+            .map(|c| Tenant::from_config(c, config).map(Arc::new))
+            .collect::<Result<_>>()?;
+        Ok(Self { tenants, multi: true })
+    }
+
+    // 配置的租户数，没有配置租户时为 0
+    pub fn count(&self) -> usize {
+        if self.multi {
+            self.tenants.len()
+        } else {
+            0
+        }
+    }
+
+    // 按名字查找租户，上传 token 中记录的是租户名
+    pub fn get(&self, name: &str) -> Option<Arc<Tenant>> {
+        self.tenants.iter().find(|t| t.name == name).cloned()
+    }
+
+    pub fn check_resources(&self) -> Result<(), String> {
+        self.tenants.iter().try_for_each(|t| t.check_resources())
+    }
+
+    // 所有租户的缓存命名空间
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+        self.tenants.iter().map(|t| t.cache_namespace.as_str())
+    }
+
+    // 所有租户配置的公开路径，去掉重复的
+    pub fn route_paths(&self) -> Vec<String> {
+        let mut paths: Vec<_> = self.tenants.iter().flat_map(|t| t.routes.iter().map(|r| r.path.clone())).collect();
+        paths.sort_unstable();
+        paths.dedup();
+        paths
+    }
+
+    // 识别租户并计入配额，所有获取源图片的接口都通过这里
+    pub fn admit(&self, headers: &HeaderMap) -> Result<Arc<Tenant>, StatusCode> {
+        let tenant = self.resolve(headers)?;
+        if !tenant.acquire() {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        Ok(tenant)
+    }
+
+    // 优先匹配 API key，再匹配 Host；配置了租户但一个都没有匹配上时返回 401
+    pub fn resolve(&self, headers: &HeaderMap) -> Result<Arc<Tenant>, StatusCode> {
+        if !self.multi {
+            return Ok(self.tenants[0].clone());
+        }
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        if let Some(key) = header("x-api-key") {
+            return self
+                .tenants
+                .iter()
+                .find(|t| t.api_keys.iter().any(|k| signing::token_eq(k, key)))
+                .cloned()
+                .ok_or(StatusCode::UNAUTHORIZED);
+        }
+        let host = header("host")
+            .map(|h| h.split(':').next().unwrap_or("").to_ascii_lowercase())
+            .unwrap_or_default();
+        self.tenants
+            .iter()
+            .find(|t| t.hosts.contains(&host))
+            .cloned()
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            [[tenants]]
+            name = "a"
+            api_keys = ["key-a"]
+            origins = ["https://a.com/"]
+            presets = { thumb = "CgA", small = { ops = [{ op = "resize", width = 100, height = 100 }] }, press = "watermark:x=10,y=10" }
+            policies = { press = { formats = ["jpeg"], watermark = true } }
+            quota = 2
+            fonts = ["brand"]
+            source_cache = { honor_origin = true, min_ttl = 60 }
+            source_limits = { max_bytes = 1048576 }
+
+            [[tenants]]
+            name = "b"
+            hosts = ["img.b.com"]
+            presets = { thumb = "resize:w=100,h=100" }
+            routes = [{ path = "/thumb", preset = "thumb", origin = "https://b.com/img/" }]
+
+            [source_cache]
+            ttl = 300
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tenant_should_be_resolved_by_key_or_host() {
+        let tenants = Tenants::new(&config()).unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(tenants.resolve(&headers).err(), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(tenants.admit(&headers).err(), Some(StatusCode::UNAUTHORIZED));
+
+        headers.insert("host", HeaderValue::from_static("IMG.b.com:3000"));
+        let b = tenants.resolve(&headers).unwrap();
+        assert_eq!(b.name, "b");
+        assert_eq!(b.source_cache.ttl, Some(300));
+
+        headers.insert("x-api-key", HeaderValue::from_static("key-a"));
+        let a = tenants.resolve(&headers).unwrap();
+        assert_eq!(a.name, "a");
+        assert_eq!(a.cache_namespace, "a");
+        assert!(a.source_cache.honor_origin);
+        assert_eq!(a.source_limits.max_bytes, Some(1 << 20));
+        assert!(a.allows("https://a.com/cat.png"));
+        assert!(!a.allows("https://b.com/cat.png"));
+        assert!(a.spec("thumb").is_ok());
+        assert_eq!(a.spec("small").unwrap(), ImageSpec::parse("resize:w=100,h=100").unwrap());
+        assert!(a.policy("press").watermark);
+        assert_eq!(a.policy("small"), OutputPolicy::default());
+        assert!(a.acquire() && a.acquire() && !a.acquire());
+        assert!(a.allows_fonts(&ImageSpec::parse("text:a;text:b,font=brand").unwrap()));
+        assert!(!a.allows_fonts(&ImageSpec::parse("text:a,font=other").unwrap()));
+    }
+
+    #[test]
+    fn routes_should_use_tenant_presets() {
+        let tenants = Tenants::new(&config()).unwrap();
+        assert_eq!(tenants.route_paths(), vec!["/thumb"]);
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("img.b.com"));
+        let b = tenants.resolve(&headers).unwrap();
+        let (route, rest) = b.route("/thumb/2021/cat.jpg").unwrap();
+        assert_eq!((route.preset.as_str(), route.origin.as_str(), rest), ("thumb", "https://b.com/img/", "2021/cat.jpg"));
+        assert!(b.route("/hero/cat.jpg").is_none());
+
+        let load = |route: &str| {
+            let config: Config = toml::from_str(&format!(
+                "[[tenants]]\nname = \"a\"\norigins = [\"https://a.com/\"]\npresets = {{ thumb = \"resize:w=100,h=100\" }}\nroutes = [{}]",
+                route
+            ))
+            .unwrap();
+            Tenants::new(&config).map(|_| ())
+        };
+        assert!(load(r#"{ path = "/thumb", preset = "thumb", origin = "https://a.com/img/" }"#).is_ok());
+        assert!(load(r#"{ path = "/thumb", preset = "hero", origin = "https://a.com/img/" }"#).is_err());
+        assert!(load(r#"{ path = "/thumb", preset = "thumb", origin = "https://b.com/img/" }"#).is_err());
+        assert!(load(r#"{ path = "/image", preset = "thumb", origin = "https://a.com/img/" }"#).is_err());
+    }
+
+    #[test]
+    fn disabled_ops_should_be_rejected() {
+        let config: Config = toml::from_str(
+            r#"
+            disabled_ops = ["seam_carve", "diff"]
+
+            [[tenants]]
+            name = "a"
+            api_keys = ["key-a"]
+            disabled_ops = ["text", "watermark_asset"]
+            "#,
+        )
+        .unwrap();
+        let tenants = Tenants::new(&config).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("key-a"));
+        let a = tenants.resolve(&headers).unwrap();
+        let check = |spec: &str| a.check_ops(&ImageSpec::parse(spec).unwrap()).map_err(|e| e.status());
+        assert_eq!(check("resize:w=10,h=10;watermark:x=1,y=1"), Ok(()));
+        assert_eq!(check("fliph;resize:w=10,h=10,type=seam_carve"), Err(StatusCode::UNPROCESSABLE_ENTITY));
+        assert_eq!(check("text:hi"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(check("watermark:asset=badge"), Err(StatusCode::FORBIDDEN));
+        match a.check_ops(&ImageSpec::parse("fliph;text:hi").unwrap()) {
+            Err(AppError::Forbidden(e)) => assert_eq!((e.op, e.message.as_str()), (Some(1), "operation text is disabled")),
+            _ => panic!("expected forbidden"),
+        }
+        // 不经过 spec 的接口：接口本身和内部用到的操作
+        let check = |features: &[&str]| a.check_features(features).map_err(|e| e.status());
+        assert_eq!(check(crate::collage::FEATURES), Ok(()));
+        assert_eq!(check(crate::contactsheet::FEATURES), Err(StatusCode::FORBIDDEN));
+        assert_eq!(check(&["diff"]), Err(StatusCode::UNPROCESSABLE_ENTITY));
+
+        let load = |extra: &str| {
+            let config: Config = toml::from_str(&format!("[[tenants]]\nname = \"a\"\n{}", extra)).unwrap();
+            Tenants::new(&config).map(|_| ())
+        };
+        assert!(load("disabled_ops = [\"blur\"]").is_err());
+        assert!(load("disabled_ops = [\"page\"]").is_err());
+        assert!(load("disabled_ops = [\"text\"]\npresets = { hello = \"text:hi\" }").is_err());
+        assert!(load("disabled_ops = [\"text\"]\nwatermark = \"text:hi\"").is_err());
+        assert!(load("disabled_ops = [\"text\"]\npresets = { small = \"resize:w=10,h=10\" }").is_ok());
+    }
+
+    #[test]
+    fn policy_should_match_its_preset() {
+        let load = |policies| {
+            let config: Config = toml::from_str(&format!(
+                "[[tenants]]\nname = \"a\"\npresets = {{ thumb = \"resize:w=100,h=100\" }}\npolicies = {}",
+                policies
+            ))
+            .unwrap();
+            Tenants::new(&config).map(|_| ())
+        };
+        assert!(load("{ thumb = { max_quality = 80 } }").is_ok());
+        assert!(load("{ other = { max_quality = 80 } }").is_err());
+        assert!(load("{ thumb = { watermark = true } }").is_err());
+        assert!(load("{ thumb = { format = \"png\", quality = 70 } }").is_ok());
+        assert!(load("{ thumb = { formats = [\"jpeg\"], format = \"png\" } }").is_err());
+        assert!(load("{ thumb = { format = \"webp\" } }").is_err());
+    }
+
+    #[test]
+    fn presets_only_should_reject_adhoc_specs() {
+        let config: Config = toml::from_str(
+            r#"
+            [[tenants]]
+            name = "a"
+            api_keys = ["key-a"]
+            presets = { press = "watermark:x=10,y=10" }
+            policies = { press = { formats = ["jpeg"], watermark = true } }
+            presets_only = true
+            "#,
+        )
+        .unwrap();
+        let tenants = Tenants::new(&config).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("key-a"));
+        let a = tenants.resolve(&headers).unwrap();
+        assert!(a.spec("press").is_ok());
+        assert_eq!(a.spec("resize:w=100,h=100").err().map(|e| e.status()), Some(StatusCode::FORBIDDEN));
+
+        let config: Config = toml::from_str("[[tenants]]\nname = \"a\"\npresets_only = true").unwrap();
+        assert!(Tenants::new(&config).is_err());
+    }
+
+    #[test]
+    fn tenant_name_should_be_a_header_value() {
+        let load = |name: &str| {
+            let config: Config = toml::from_str(&format!("[[tenants]]\nname = \"{}\"", name)).unwrap();
+            Tenants::new(&config).map(|_| ())
+        };
+        assert!(load("shop-a").is_ok());
+        assert!(load("").is_err());
+        assert!(load("a\\nb").is_err());
+        assert!(load("a\\u007f").is_err());
+    }
+
+    #[test]
+    fn origins_should_match_parsed_url() {
+        assert!(origin_allows("https://a.com/", "https://a.com/x.png"));
+        assert!(origin_allows("https://a.com", "https://a.com/x.png"));
+        assert!(origin_allows("https://a.com/img/", "https://a.com:443/img/x.png"));
+        assert!(origin_allows("https://a.com/img", "https://A.com/img/x.png"));
+        assert!(!origin_allows("https://a.com", "https://a.com.evil.net/x.png"));
+        assert!(!origin_allows("https://a.com", "https://a.com@evil.net/x.png"));
+        assert!(!origin_allows("https://a.com", "http://a.com/x.png"));
+        assert!(!origin_allows("https://a.com", "https://a.com:8443/x.png"));
+        assert!(!origin_allows("https://a.com/img", "https://a.com/images/x.png"));
+        assert!(!origin_allows("https://a.com/public/", "https://a.com/public/../private/x.png"));
+        assert!(!origin_allows("https://a.com/public/", "https://a.com/public/%2e%2e/private/x.png"));
+        assert!(!origin_allows("https://a.com/", "not a url"));
+        assert!(origin_allows("upload://", "upload://abc"));
+        assert!(!origin_allows("upload://", "https://a.com/x.png"));
+    }
+
+    #[test]
+    fn default_tenant_should_allow_everything() {
+        let tenants = Tenants::new(&Config::default()).unwrap();
+        let tenant = tenants.resolve(&HeaderMap::new()).unwrap();
+        assert!(tenant.allows("https://any.com/x.png"));
+        // 上传的图片只有上传它的租户可以使用
+        assert!(!tenant.allows(&format!("{}{}", uploads::SCHEME, "0".repeat(32))));
+        assert!(tenant.acquire());
+        assert!(tenant.allows_fonts(&ImageSpec::parse("text:a,font=other").unwrap()));
+    }
+}
@@ -0,0 +1,47 @@
+// W3C Trace Context：接入了 OTLP 追踪的调用方（或者入口网关）在 traceparent 请求头中传递 trace id，
+// 记录到请求的 span 和耗时直方图的 exemplar 中，从监控上的延迟峰值可以直接找到对应的 trace
+// traceparent: <2 位版本>-<32 位十六进制 trace id>-<16 位 parent id>-<2 位 flags>
+use axum::http::HeaderMap;
+
+pub fn trace_id(headers: &HeaderMap) -> Option<u128> {
+    let value = headers.get("traceparent")?.to_str().ok()?.trim();
+    let mut parts = value.split('-');
+    let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    // 版本 ff 无效；以后的版本可能在后面增加字段
+    if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    let hex = |v: &str, len| v.len() == len && v.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'));
+    if !hex(version, 2) || !hex(trace_id, 32) || !hex(parent_id, 16) || !hex(flags, 2) {
+        return None;
+    }
+    // 全 0 的 trace id 无效
+    u128::from_str_radix(trace_id, 16).ok().filter(|v| *v != 0)
+}
+
+pub fn format(trace_id: u128) -> String {
+    format!("{:032x}", trace_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn trace_id_should_be_read_from_traceparent() {
+        let parse = |v: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("traceparent", HeaderValue::from_static(v));
+            trace_id(&headers)
+        };
+        let id = parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(format(id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7").is_none());
+        assert!(parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+        assert!(trace_id(&HeaderMap::new()).is_none());
+    }
+}
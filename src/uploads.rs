@@ -0,0 +1,447 @@
+// 预签名上传：业务后端用 secret 换取短时间有效、只能使用一次的上传 token 交给客户端，客户端把图片直接上传到这里，
+// 得到不透明的源图片 upload://<id>，之后像普通的源图片 URL 一样请求处理，不需要把源站的 bucket 暴露给客户端
+// 上传的图片属于签发 token 时的租户，只有这个租户可以使用和删除；id 是随机的，不能由内容推断出来
+// 上传的图片按 retention 定期清理，或者通过管理接口删除，由它得到的缓存和对象存储中的结果一起删除
+use crate::{
+    check_source, config::Config, decode_status, engine, error::AppError, publish::Publisher, signing, source_cache,
+    tenant::{Tenant, Tenants}, Cache,
+};
+use axum::{
+    extract::{ContentLengthLimit, Extension, Path},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+lazy_static! {
+    pub static ref UPLOADS: UploadStore = UploadStore::default();
+}
+
+// 上传的图片作为源图片时的 URL 前缀
+pub const SCHEME: &str = "upload://";
+pub const MAX_UPLOAD_BYTES: usize = 20 << 20;
+
+// 预签名上传的配置（[uploads]），不配置则不开启
+// 配置了 origins 的租户需要在 origins 中加上 upload:// 才能处理自己上传的图片
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UploadConfig {
+    // 上传的图片保存的目录
+    pub dir: String,
+    // 签发 token 的密钥，调用 POST /uploads/token 和 DELETE /uploads/:id 时放在 Authorization: Bearer 中，
+    // 配置了租户时同时用 x-api-key 或者 Host 识别租户
+    pub secret: String,
+    // token 的有效期（秒）
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+    // 上传的图片保留的时间（秒），从上传时开始计算；不配置则一直保留，只能通过管理接口删除
+    #[serde(default)]
+    pub retention: Option<u64>,
+    // 后台清理过期图片的间隔（秒）
+    #[serde(default = "default_gc_interval")]
+    pub gc_interval: u64,
+}
+
+fn default_token_ttl() -> u64 {
+    300
+}
+
+fn default_gc_interval() -> u64 {
+    600
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TokenError {
+    #[error("malformed token")]
+    Malformed,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("token expired")]
+    Expired,
+    #[error("token already used")]
+    Used,
+}
+
+#[derive(Default)]
+pub struct UploadStore {
+    dir: RwLock<Option<PathBuf>>,
+    // 已经使用过的 token 的 nonce 和过期时间，过期之后 token 本身就无效了，可以删除
+    used: Mutex<HashMap<String, i64>>,
+}
+
+#[derive(Serialize)]
+pub struct UploadToken {
+    token: String,
+    // unix 时间戳（秒）
+    expires_at: i64,
+    upload_url: String,
+}
+
+#[derive(Serialize)]
+pub struct Uploaded {
+    id: String,
+    // 请求处理时使用的源图片
+    source: String,
+    format: String,
+    width: u32,
+    height: u32,
+    bytes: usize,
+}
+
+// 业务后端的请求：检查 Bearer 中的 secret，再识别租户
+fn authorize(uploads: &UploadConfig, tenants: &Tenants, headers: &HeaderMap) -> Result<Arc<Tenant>, StatusCode> {
+    match signing::bearer(headers) {
+        Some(v) if signing::token_eq(v, &uploads.secret) => tenants.resolve(headers),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+// "POST /uploads/token" 签发上传 token，一般由业务后端调用，再把 token 交给客户端
+pub async fn issue_token(
+    req_headers: HeaderMap,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+) -> Result<Json<UploadToken>, StatusCode> {
+    let uploads = config.uploads.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let tenant = authorize(uploads, &tenants, &req_headers)?;
+    let (token, expires_at) = issue(&uploads.secret, &tenant.name, uploads.token_ttl, chrono::Utc::now().timestamp());
+    Ok(Json(UploadToken {
+        upload_url: format!("/uploads/{}", token),
+        token,
+        expires_at,
+    }))
+}
+
+// "PUT /uploads/:token" 客户端上传图片，返回之后请求处理时使用的源图片
+pub async fn upload(
+    Path(token): Path<String>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+    ContentLengthLimit(body): ContentLengthLimit<Bytes, { MAX_UPLOAD_BYTES as u64 }>,
+) -> Result<Json<Uploaded>, AppError> {
+    let uploads = config.uploads.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let now = chrono::Utc::now().timestamp();
+    let forbidden = |e: TokenError| {
+        info!("Rejected upload: {}", e);
+        AppError::Status(StatusCode::FORBIDDEN)
+    };
+    let (nonce, expires_at, owner) = verify(&uploads.secret, &token, now).map_err(forbidden)?;
+    // 签发之后租户被删除的 token 不能再使用
+    let tenant = tenants.get(&owner).ok_or(StatusCode::FORBIDDEN)?;
+    // 和这个租户下载的源图片一样检查，不能处理的图片不保存
+    check_source(&body, 0, &tenant.source_limits)?;
+    let probe = engine::probe(&body, 0).map_err(decode_status)?;
+    // 图片没问题才使用 token，保存失败时放回 token，上传失败时客户端可以用同一个 token 重试
+    UPLOADS.consume(nonce, expires_at, now).map_err(forbidden)?;
+    let id = UPLOADS.put(&uploads.secret, nonce, &tenant.name, &body).map_err(|e| {
+        warn!("Failed to save upload: {}", e);
+        UPLOADS.release(nonce);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    info!("Saved upload {} for tenant {}, {} bytes", id, tenant.name, body.len());
+    Ok(Json(Uploaded {
+        source: format!("{}{}", SCHEME, id),
+        id,
+        format: probe.format.to_string(),
+        width: probe.width,
+        height: probe.height,
+        bytes: body.len(),
+    }))
+}
+
+// "DELETE /uploads/:id" 业务后端删除自己租户上传的图片，认证方式和签发 token 相同；其他租户的图片返回 404
+pub async fn delete_upload(
+    Path(id): Path<String>,
+    req_headers: HeaderMap,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(cache): Extension<Cache>,
+    Extension(tenants): Extension<Arc<Tenants>>,
+    Extension(publisher): Extension<Arc<Publisher>>,
+) -> Result<StatusCode, StatusCode> {
+    let uploads = config.uploads.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let tenant = authorize(uploads, &tenants, &req_headers)?;
+    match purge(&id, Some(&tenant.name), &cache, &tenants, &publisher).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("Failed to delete upload {}: {}", id, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// 删除上传的图片，以及由它得到的源图片缓存和对象存储中的结果，返回图片是否存在
+// 指定了 owner 时只删除这个租户的图片；管理接口和定期清理不指定
+pub async fn purge(
+    id: &str,
+    owner: Option<&str>,
+    cache: &Cache,
+    tenants: &Tenants,
+    publisher: &Arc<Publisher>,
+) -> io::Result<bool> {
+    if owner.is_some() && UPLOADS.owner(id).as_deref() != owner {
+        return Ok(false);
+    }
+    let mut keys = match UPLOADS.delete(id)? {
+        Some(v) => v,
+        None => return Ok(false),
+    };
+    let url = format!("{}{}", SCHEME, id);
+    source_cache::evict(cache, tenants, &url).await;
+    keys.extend(source_cache::RESULTS.take(&url));
+    keys.sort_unstable();
+    keys.dedup();
+    info!("Deleted upload {} and {} published results", id, keys.len());
+    for key in keys {
+        publisher.delete(key);
+    }
+    Ok(true)
+}
+
+// 后台定期删除超过 retention 的图片，没有配置 retention 时不启动
+pub fn spawn_gc(config: &UploadConfig, cache: Cache, tenants: Arc<Tenants>, publisher: Arc<Publisher>) {
+    let retention = match config.retention {
+        Some(v) => Duration::from_secs(v),
+        None => return,
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(config.gc_interval.max(1)));
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            let before = SystemTime::now().checked_sub(retention).unwrap_or(SystemTime::UNIX_EPOCH);
+            let expired = match UPLOADS.expired(before) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to list uploads: {}", e);
+                    continue;
+                }
+            };
+            for id in expired {
+                if let Err(e) = purge(&id, None, &cache, &tenants, &publisher).await {
+                    warn!("Failed to delete upload {}: {}", id, e);
+                }
+            }
+        }
+    });
+}
+
+// token 的格式：<过期时间>.<nonce>.<租户名的十六进制>.<签名>，签名是前三部分的 HMAC-SHA256
+// nonce 只需要不重复，不需要随机：token 不能伪造由签名保证
+fn issue(secret: &str, tenant: &str, ttl: u64, now: i64) -> (String, i64) {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let expires_at = now + ttl as i64;
+    let seed = format!(
+        "{}:{}:{:?}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        std::time::SystemTime::now()
+    );
+    let nonce = &hex::encode(Sha256::digest(seed.as_bytes()))[..16];
+    let payload = format!("{}.{}.{}", expires_at, nonce, hex::encode(tenant));
+    let signature = signing::sign(secret, &payload);
+    (format!("{}.{}", payload, signature), expires_at)
+}
+
+// 校验签名和有效期，返回 nonce、过期时间和租户名
+fn verify<'a>(secret: &str, token: &'a str, now: i64) -> Result<(&'a str, i64, String), TokenError> {
+    let (payload, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+    let mut parts = payload.splitn(3, '.');
+    let (expires_at, nonce, tenant) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(a), Some(b), Some(c)) => (a, b, c),
+        _ => return Err(TokenError::Malformed),
+    };
+    let expires_at: i64 = expires_at.parse().map_err(|_| TokenError::Malformed)?;
+    if !signing::verify(secret, payload, signature) {
+        return Err(TokenError::InvalidSignature);
+    }
+    if expires_at <= now {
+        return Err(TokenError::Expired);
+    }
+    let tenant = hex::decode(tenant).ok().and_then(|v| String::from_utf8(v).ok()).ok_or(TokenError::Malformed)?;
+    Ok((nonce, expires_at, tenant))
+}
+
+impl UploadStore {
+    pub fn open(&self, dir: &str) -> io::Result<()> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        info!("Storing uploads in {}", dir.display());
+        *self.dir.write().unwrap() = Some(dir);
+        Ok(())
+    }
+
+    // 标记 token 已经使用，顺便删除已经过期的记录
+    fn consume(&self, nonce: &str, expires_at: i64, now: i64) -> Result<(), TokenError> {
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, v| *v > now);
+        if used.insert(nonce.to_owned(), expires_at).is_some() {
+            return Err(TokenError::Used);
+        }
+        Ok(())
+    }
+
+    // 保存失败时取消 token 的使用标记
+    fn release(&self, nonce: &str) {
+        self.used.lock().unwrap().remove(nonce);
+    }
+
+    // id 由密钥和 token 的 nonce 签名得到，每次上传都不同，不知道密钥时无法猜测；同样的图片也分别保存，
+    // 删除一个租户的图片不影响其他租户。所属的租户写在 <id>.owner 中，先写临时文件再改名，文件的修改时间就是上传时间
+    fn put(&self, secret: &str, nonce: &str, owner: &str, data: &[u8]) -> io::Result<String> {
+        let dir = self.dir.read().unwrap().clone().ok_or_else(|| io::Error::other("uploads are not configured"))?;
+        let id = signing::sign(secret, &format!("upload:{}", nonce))[..32].to_owned();
+        let path = dir.join(&id);
+        fs::write(path.with_extension("owner"), owner)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        Ok(id)
+    }
+
+    // 上传图片的租户，图片不存在时返回 None
+    pub fn owner(&self, id: &str) -> Option<String> {
+        if !valid_id(id) {
+            return None;
+        }
+        let path = self.dir.read().unwrap().as_ref()?.join(id).with_extension("owner");
+        fs::read_to_string(path).ok()
+    }
+
+    // 记录由上传的图片得到、写入对象存储的结果，每行一个 key，删除图片时一起删除
+    pub fn record(&self, id: &str, key: &str) -> io::Result<()> {
+        let dir = match self.dir.read().unwrap().clone() {
+            Some(v) if valid_id(id) => v,
+            _ => return Ok(()),
+        };
+        let mut file = fs::File::options().create(true).append(true).open(dir.join(id).with_extension("published"))?;
+        writeln!(file, "{}", key)
+    }
+
+    // 删除图片，返回记录的对象存储中的 key；图片不存在时返回 None
+    pub fn delete(&self, id: &str) -> io::Result<Option<Vec<String>>> {
+        let dir = match self.dir.read().unwrap().clone() {
+            Some(v) if valid_id(id) => v,
+            _ => return Ok(None),
+        };
+        match fs::remove_file(dir.join(id)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            r => r?,
+        }
+        match fs::remove_file(dir.join(id).with_extension("owner")) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            r => r?,
+        }
+        let published = dir.join(id).with_extension("published");
+        let keys = match fs::read_to_string(&published) {
+            Ok(v) => v.lines().map(str::to_owned).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(vec![])),
+            Err(e) => return Err(e),
+        };
+        fs::remove_file(published)?;
+        Ok(Some(keys))
+    }
+
+    // 上传时间早于 before 的图片
+    pub fn expired(&self, before: SystemTime) -> io::Result<Vec<String>> {
+        let dir = match self.dir.read().unwrap().clone() {
+            Some(v) => v,
+            None => return Ok(vec![]),
+        };
+        let mut ids = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            match entry.file_name().to_str() {
+                Some(id) if valid_id(id) && entry.metadata()?.modified()? < before => ids.push(id.to_owned()),
+                _ => {}
+            }
+        }
+        Ok(ids)
+    }
+
+    // 源图片 upload://<id> 的内容
+    pub fn get(&self, id: &str) -> Option<Bytes> {
+        if !valid_id(id) {
+            return None;
+        }
+        let path = self.dir.read().unwrap().as_ref()?.join(id);
+        fs::read(path).ok().map(Bytes::from)
+    }
+}
+
+// 只允许 32 个小写十六进制字符，不会访问目录之外的文件
+fn valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_should_be_single_use() {
+        let (token, expires_at) = issue("secret", "a.b", 300, 1000);
+        assert_eq!(expires_at, 1300);
+        assert_ne!(issue("secret", "a.b", 300, 1000).0, token);
+        let (nonce, _, tenant) = verify("secret", &token, 1000).unwrap();
+        assert_eq!(tenant, "a.b");
+        // 租户名包含在签名中，不能替换
+        let other = token.replacen(&hex::encode("a.b"), &hex::encode("c"), 1);
+        assert_eq!(verify("secret", &other, 1000), Err(TokenError::InvalidSignature));
+        assert_eq!(verify("secret", &token, 1300), Err(TokenError::Expired));
+        assert_eq!(verify("other", &token, 1000), Err(TokenError::InvalidSignature));
+        assert_eq!(verify("secret", &token.replacen("1300", "9999", 1), 1000), Err(TokenError::InvalidSignature));
+        assert_eq!(verify("secret", "not-a-token", 1000), Err(TokenError::Malformed));
+
+        let dir = std::env::temp_dir().join(format!("shanbor-uploads-{}", std::process::id()));
+        let store = UploadStore::default();
+        assert!(store.put("secret", nonce, "a", b"cat").is_err());
+        store.open(dir.to_str().unwrap()).unwrap();
+        assert!(store.consume(nonce, expires_at, 1000).is_ok());
+        assert_eq!(store.consume(nonce, expires_at, 1001), Err(TokenError::Used));
+        store.release(nonce);
+        assert!(store.consume(nonce, expires_at, 1001).is_ok());
+        // 过期的记录被删除
+        assert!(store.consume("other", 1100, 1000).is_ok());
+        assert!(store.consume(nonce, 2000, 1500).is_ok());
+        assert_eq!(store.used.lock().unwrap().len(), 1);
+
+        let id = store.put("secret", nonce, "a", b"cat").unwrap();
+        assert_eq!(store.get(&id).unwrap(), &b"cat"[..]);
+        assert_eq!(store.owner(&id).as_deref(), Some("a"));
+        // 同样的内容每次上传得到不同的 id
+        let other = store.put("secret", "other", "b", b"cat").unwrap();
+        assert_ne!(other, id);
+        assert_eq!(store.owner(&other).as_deref(), Some("b"));
+        assert_eq!(store.delete(&other).unwrap(), Some(vec![]));
+        assert_eq!(store.owner(&other), None);
+        assert_eq!(store.get("../../etc/passwd"), None);
+        assert_eq!(store.get(&"0".repeat(32)), None);
+
+        store.record(&id, "a/1.jpg").unwrap();
+        store.record(&id, "a/2.gif").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(1);
+        assert!(store.expired(SystemTime::UNIX_EPOCH).unwrap().is_empty());
+        assert_eq!(store.expired(later).unwrap(), vec![id.clone()]);
+        assert_eq!(store.delete(&id).unwrap(), Some(vec!["a/1.jpg".to_owned(), "a/2.gif".to_owned()]));
+        assert_eq!(store.delete(&id).unwrap(), None);
+        assert_eq!(store.get(&id), None);
+        assert!(store.expired(later).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+use crate::engine::{stego, Photon};
+use anyhow::Result;
+use axum::{http::StatusCode, Json};
+use bytes::Bytes;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct VerifyReport {
+    found: bool,
+    id: Option<u32>,
+    confidence: Option<f32>,
+}
+
+fn inspect(data: &[u8]) -> Result<VerifyReport> {
+    let img = Photon::open(data, 0)?.to_rgba();
+    let (width, height) = img.dimensions();
+    let found = stego::detect(img.as_raw(), width, height);
+    Ok(VerifyReport {
+        found: found.is_some(),
+        id: found.map(|d| d.id),
+        confidence: found.map(|d| d.confidence),
+    })
+}
+
+// "POST /verify" 请求体为图片内容，检测其中的不可见水印
+pub async fn verify_upload(body: Bytes) -> Result<Json<VerifyReport>, StatusCode> {
+    inspect(&body)
+        .map(Json)
+        .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)
+}
+
+// `shanbor verify <file>`：检测本地图片中的不可见水印，找到时退出码为 0
+pub fn run(path: &str) -> Result<bool> {
+    let report = inspect(&std::fs::read(path)?)?;
+    match (report.id, report.confidence) {
+        (Some(id), Some(confidence)) => {
+            println!("found watermark id {} (0x{:08x}), confidence {:.2}", id, id, confidence);
+        }
+        _ => println!("no watermark found"),
+    }
+    Ok(report.found)
+}
@@ -0,0 +1,183 @@
+// 渲染回归测试：tests/golden/input 下的样例图片经过每一种 spec 和每一个 engine 处理后，
+// 和 tests/golden/expected 下保存的结果比较。比较的是感知哈希，允许编码误差之类的细微差别，
+// 但滤镜、缩放算法或者编码参数改变了画面时测试会失败。图片通过 `shanbor render` 生成，测试的是编译出的二进制
+//
+// 样例图片：tower（埃菲尔铁塔，天空的渐变和细节丰富的边缘）和 elephant（自然的颜色）分别缩小自
+// jpeg-decoder 的 benches/tower.jpg 和 imageproc 的 tests/data/elephant.png；
+// chart 是合成的测试图，包括饱和的色相、灰阶、硬边缘和半透明区域
+//
+// 有意改变了渲染结果时，用 SHANBOR_UPDATE_GOLDEN=1 cargo test --test golden 重新生成期望的结果，
+// 检查图片的变化后和代码一起提交
+use image::{imageops, imageops::FilterType, Rgba, RgbaImage};
+use std::{env, fs, path::PathBuf, process::Command};
+
+// (名字, spec, 输出格式)，每一种 op 至少出现一次
+const CASES: &[(&str, &str, &str)] = &[
+    ("original", "", "png"),
+    ("resize", "resize:w=40,h=24,filter=lanczos3", "png"),
+    ("resize_nearest", "resize:w=96,h=96,filter=nearest", "png"),
+    ("resize_seam_carve", "resize:w=48,h=64,type=seam_carve", "png"),
+    ("crop", "crop:x1=8,y1=8,x2=56,y2=40", "png"),
+    ("crop_gravity", "crop:south_east,w=32,h=24,dx=4,dy=2", "png"),
+    ("crop_ratio", "crop:ratio=16:9,when_orientation=square", "png"),
+    ("flipv", "flipv", "png"),
+    ("fliph", "fliph", "png"),
+    ("contrast", "contrast:30", "png"),
+    ("filter_oceanic", "filter:oceanic", "png"),
+    ("filter_islands", "filter:islands", "png"),
+    ("filter_marine", "filter:marine", "png"),
+    ("watermark", "watermark:x=8,y=8", "png"),
+    ("watermark_tiled", "watermark:mode=tiled,spacing=16,angle=30,opacity=0.5", "png"),
+    ("text", "text:\"Hi\",x=4,y=4,size=24,color=#ff0000", "png"),
+    ("auto_enhance", "auto_enhance", "png"),
+    ("lqip", "lqip:16,blur=true", "png"),
+    ("simulate", "simulate:deuteranopia", "png"),
+    ("invisible_watermark", "invisible_watermark:42", "png"),
+    ("caption", "caption:\"Hi there\",size=12,position=top", "png"),
+    ("color_pop", "color_pop:0,tolerance=40", "png"),
+    ("blur_regions", "blur_regions:0:0:32:16|40:30:64:64,sigma=4", "png"),
+    ("jpeg", "", "jpeg"),
+    ("gif", "", "gif"),
+];
+
+const INPUTS: &[&str] = &["tower", "elephant", "chart"];
+const ENGINES: &[&str] = &["photon", "native"];
+
+// dHash 允许不同的位数
+const MAX_HASH_DISTANCE: u32 = 6;
+// 4x4 缩略图每个通道允许的差值
+const MAX_COLOR_DISTANCE: u8 = 10;
+// 计算哈希之前把图片合成到这个背景上，透明度的变化也会体现在颜色中
+const BACKGROUND: [u8; 3] = [128, 128, 128];
+
+// 感知哈希：尺寸 + 亮度梯度的 dHash（结构）+ 4x4 的缩略图（颜色）
+#[derive(Debug)]
+struct PerceptualHash {
+    dimensions: (u32, u32),
+    dhash: u64,
+    colors: Vec<u8>,
+}
+
+impl PerceptualHash {
+    fn new(img: &RgbaImage) -> Self {
+        let img = composite(img);
+        let small = imageops::resize(&img, 9, 8, FilterType::Triangle);
+        let luma = |x, y| {
+            let [r, g, b, _] = small.get_pixel(x, y).0;
+            r as u32 * 299 + g as u32 * 587 + b as u32 * 114
+        };
+        let mut dhash = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                dhash = dhash << 1 | (luma(x, y) < luma(x + 1, y)) as u64;
+            }
+        }
+        Self {
+            dimensions: img.dimensions(),
+            dhash,
+            colors: imageops::resize(&img, 4, 4, FilterType::Triangle).into_raw(),
+        }
+    }
+
+    // 不匹配时返回原因
+    fn compare(&self, expected: &Self) -> Result<(), String> {
+        if self.dimensions != expected.dimensions {
+            return Err(format!("dimensions {:?}, expected {:?}", self.dimensions, expected.dimensions));
+        }
+        let distance = (self.dhash ^ expected.dhash).count_ones();
+        if distance > MAX_HASH_DISTANCE {
+            return Err(format!("dhash distance {}", distance));
+        }
+        let color = self.colors.iter().zip(&expected.colors).map(|(a, b)| a.abs_diff(*b)).max();
+        match color {
+            Some(v) if v > MAX_COLOR_DISTANCE => Err(format!("color distance {}", v)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// 按 alpha 合成到不透明的背景上
+fn composite(img: &RgbaImage) -> RgbaImage {
+    let mut out = img.clone();
+    for p in out.pixels_mut() {
+        let alpha = p[3] as u32;
+        let mix = |c: u8, bg: u8| ((c as u32 * alpha + bg as u32 * (255 - alpha) + 127) / 255) as u8;
+        *p = Rgba([mix(p[0], BACKGROUND[0]), mix(p[1], BACKGROUND[1]), mix(p[2], BACKGROUND[2]), 255]);
+    }
+    out
+}
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn decode(data: &[u8]) -> RgbaImage {
+    image::load_from_memory(data).unwrap().to_rgba8()
+}
+
+// 编码后再解码，编码器的变化也能被发现
+fn render(engine: &str, input: &str, spec: &str, format: &str) -> RgbaImage {
+    let path = golden_dir().join("input").join(format!("{}.png", input));
+    let output = Command::new(env!("CARGO_BIN_EXE_shanbor"))
+        .args(["render", engine, spec, format])
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    decode(&output.stdout)
+}
+
+#[test]
+fn rendering_should_match_golden_images() {
+    let dir = golden_dir();
+    let update = env::var_os("SHANBOR_UPDATE_GOLDEN").is_some();
+    let mut failures = vec![];
+    for input in INPUTS {
+        for (name, spec, format) in CASES {
+            for engine in ENGINES {
+                let id = format!("{}-{}-{}", input, name, engine);
+                let path = dir.join("expected").join(format!("{}.png", id));
+                let actual = render(engine, input, spec, format);
+                if update {
+                    actual.save(&path).unwrap();
+                    continue;
+                }
+                let result = match fs::read(&path) {
+                    Ok(data) => PerceptualHash::new(&actual).compare(&PerceptualHash::new(&decode(&data))),
+                    Err(_) => Err("missing expected image".to_owned()),
+                };
+                if let Err(e) = result {
+                    failures.push(format!("{}: {}", id, e));
+                }
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "rendering changed, run with SHANBOR_UPDATE_GOLDEN=1 if intended:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn perceptual_hash_should_detect_changes() {
+    for input in INPUTS {
+        let plain = render("photon", input, "", "png");
+        let hash = PerceptualHash::new(&plain);
+        // 有损编码的误差在容忍范围内（JPEG 丢掉了 alpha，所以和不透明的原图比较）
+        let mut opaque = plain.clone();
+        opaque.pixels_mut().for_each(|p| p[3] = 255);
+        let jpeg = render("photon", input, "", "jpeg");
+        assert_eq!(PerceptualHash::new(&jpeg).compare(&PerceptualHash::new(&opaque)), Ok(()), "{}", input);
+        // 滤镜、翻转、对比度、颜色和亮度的变化都会被发现
+        for spec in ["filter:marine", "flipv", "fliph", "contrast:60", "simulate:deuteranopia", "auto_enhance"] {
+            let changed = render("photon", input, spec, "png");
+            assert!(PerceptualHash::new(&changed).compare(&hash).is_err(), "{} {}", input, spec);
+        }
+    }
+    // 只有透明度不同时也会被发现
+    let chart = decode(&fs::read(golden_dir().join("input/chart.png")).unwrap());
+    let mut opaque = chart.clone();
+    opaque.pixels_mut().for_each(|p| p[3] = 255);
+    assert!(PerceptualHash::new(&opaque).compare(&PerceptualHash::new(&chart)).is_err());
+}